@@ -0,0 +1,7 @@
+// `sqlx::migrate!` で埋め込むマイグレーションが変わったら再ビルドする
+// gRPCのサーバーとクライアントはprotoから生成する
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=migrations");
+    tonic_build::compile_protos("proto/todo.proto")?;
+    Ok(())
+}
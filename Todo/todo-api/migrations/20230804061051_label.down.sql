@@ -0,0 +1,3 @@
+DROP TABLE todo_labels;
+
+DROP TABLE labels;
\ No newline at end of file
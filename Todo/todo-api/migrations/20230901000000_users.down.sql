@@ -0,0 +1,7 @@
+ALTER TABLE labels
+    DROP COLUMN user_id;
+
+ALTER TABLE todos
+    DROP COLUMN user_id;
+
+DROP TABLE users;
\ No newline at end of file
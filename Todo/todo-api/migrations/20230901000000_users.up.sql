@@ -0,0 +1,24 @@
+CREATE TABLE users
+(
+    id    SERIAL PRIMARY KEY,
+    name  TEXT NOT NULL UNIQUE,
+    token TEXT NOT NULL UNIQUE
+);
+
+-- ユーザーを導入する前のTodoとラベルは `legacy` ユーザーに持たせる
+-- トークンは推測できない値にしておき、使う場合は新しいトークンを発行する
+INSERT INTO users (name, token)
+SELECT 'legacy', gen_random_uuid()::text
+WHERE EXISTS (SELECT 1 FROM todos) OR EXISTS (SELECT 1 FROM labels);
+
+ALTER TABLE todos
+    ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+UPDATE todos SET user_id = (SELECT id FROM users WHERE name = 'legacy');
+ALTER TABLE todos
+    ALTER COLUMN user_id SET NOT NULL;
+
+ALTER TABLE labels
+    ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+UPDATE labels SET user_id = (SELECT id FROM users WHERE name = 'legacy');
+ALTER TABLE labels
+    ALTER COLUMN user_id SET NOT NULL;
\ No newline at end of file
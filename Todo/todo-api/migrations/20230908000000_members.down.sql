@@ -0,0 +1,3 @@
+DROP TABLE members;
+
+DROP TYPE member_role;
\ No newline at end of file
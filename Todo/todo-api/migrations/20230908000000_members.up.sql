@@ -0,0 +1,10 @@
+CREATE TYPE member_role AS ENUM ('owner', 'editor', 'viewer');
+
+CREATE TABLE members
+(
+    id       SERIAL PRIMARY KEY,
+    owner_id INTEGER     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    user_id  INTEGER     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role     member_role NOT NULL,
+    UNIQUE (owner_id, user_id)
+);
\ No newline at end of file
@@ -0,0 +1,13 @@
+-- 平文のトークンは復元できないので、戻した後は各ユーザーのトークンを再発行する必要がある
+ALTER TABLE users
+    ADD COLUMN token TEXT UNIQUE;
+
+UPDATE users
+SET token = md5(random()::text || id::text);
+
+ALTER TABLE users
+    ALTER COLUMN token SET NOT NULL;
+
+DROP TABLE tokens;
+
+DROP TYPE token_scope;
\ No newline at end of file
@@ -0,0 +1,21 @@
+CREATE TYPE token_scope AS ENUM ('read', 'read_write');
+
+CREATE TABLE tokens
+(
+    id           SERIAL PRIMARY KEY,
+    user_id      INTEGER     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name         TEXT        NOT NULL,
+    token_hash   TEXT        NOT NULL UNIQUE,
+    scope        token_scope NOT NULL,
+    expires_at   TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- 既存のユーザートークンはハッシュ化してトークンテーブルへ移し、平文は残さない
+INSERT INTO tokens (user_id, name, token_hash, scope)
+SELECT id, 'default', encode(sha256(token::bytea), 'hex'), 'read_write'
+FROM users;
+
+ALTER TABLE users
+    DROP COLUMN token;
\ No newline at end of file
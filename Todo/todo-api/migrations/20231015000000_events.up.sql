@@ -0,0 +1,14 @@
+-- SSEの再接続で取りこぼしたイベントを返すための変更履歴
+-- IDはコミットした順ではなく採番した順なので、保存する側がロックで1件ずつ保存して順序をそろえる
+CREATE TABLE events
+(
+    id           BIGSERIAL PRIMARY KEY,
+    workspace_id INTEGER     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    kind         TEXT        NOT NULL,
+    entity_id    INTEGER,
+    data         JSONB,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX events_workspace_id_idx ON events (workspace_id, id);
+CREATE INDEX events_created_at_idx ON events (created_at);
@@ -0,0 +1,31 @@
+-- 署名に使うので、シークレットはハッシュにせずに保存する
+CREATE TABLE webhooks
+(
+    id         SERIAL PRIMARY KEY,
+    owner_id   INTEGER     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    url        TEXT        NOT NULL,
+    events     TEXT        NOT NULL DEFAULT '',
+    secret     TEXT        NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TYPE webhook_delivery_status AS ENUM ('pending', 'succeeded', 'dead');
+
+-- 再送しても同じ本文とIDを送るので、受信側はIDで重複を取り除ける
+CREATE TABLE webhook_deliveries
+(
+    id              TEXT PRIMARY KEY,
+    webhook_id      INTEGER                 NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event           TEXT                    NOT NULL,
+    payload         JSONB                   NOT NULL,
+    status          webhook_delivery_status NOT NULL DEFAULT 'pending',
+    attempts        INTEGER                 NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ,
+    response_status INTEGER,
+    error           TEXT,
+    created_at      TIMESTAMPTZ             NOT NULL DEFAULT now(),
+    updated_at      TIMESTAMPTZ             NOT NULL DEFAULT now()
+);
+
+CREATE INDEX webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id, created_at);
+CREATE INDEX webhook_deliveries_due_idx ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
@@ -0,0 +1,2 @@
+-- 期限日。時刻は持たず、なければNULL
+ALTER TABLE todos ADD COLUMN due DATE;
@@ -0,0 +1,6 @@
+DROP TABLE tokens;
+DROP TABLE members;
+DROP TABLE todo_labels;
+DROP TABLE labels;
+DROP TABLE todos;
+DROP TABLE users;
\ No newline at end of file
@@ -0,0 +1,51 @@
+-- SQLiteはPostgreSQLのマイグレーションの最終的なスキーマをまとめて作る
+-- AUTOINCREMENTにして、削除したIDを再利用しないようにする
+CREATE TABLE users
+(
+    id   INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE todos
+(
+    id        INTEGER PRIMARY KEY AUTOINCREMENT,
+    text      TEXT    NOT NULL,
+    completed BOOLEAN NOT NULL DEFAULT false,
+    user_id   INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE labels
+(
+    id      INTEGER PRIMARY KEY AUTOINCREMENT,
+    name    TEXT    NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE todo_labels
+(
+    id       INTEGER PRIMARY KEY AUTOINCREMENT,
+    todo_id  INTEGER NOT NULL REFERENCES todos (id) DEFERRABLE INITIALLY DEFERRED,
+    label_id INTEGER NOT NULL REFERENCES labels (id) DEFERRABLE INITIALLY DEFERRED
+);
+
+CREATE TABLE members
+(
+    id       INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    user_id  INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role     TEXT    NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+    UNIQUE (owner_id, user_id)
+);
+
+-- 日時はsqlxのchronoと同じ `YYYY-MM-DD HH:MM:SS.SSS`（UTC）の文字列で持つ
+CREATE TABLE tokens
+(
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id      INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name         TEXT    NOT NULL,
+    token_hash   TEXT    NOT NULL UNIQUE,
+    scope        TEXT    NOT NULL CHECK (scope IN ('read', 'read_write')),
+    expires_at   TEXT,
+    last_used_at TEXT,
+    created_at   TEXT    NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
@@ -0,0 +1,13 @@
+-- AUTOINCREMENTにして、古いイベントを消した後もIDを再利用しない
+CREATE TABLE events
+(
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    workspace_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    kind         TEXT    NOT NULL,
+    entity_id    INTEGER,
+    data         TEXT,
+    created_at   TEXT    NOT NULL
+);
+
+CREATE INDEX events_workspace_id_idx ON events (workspace_id, id);
+CREATE INDEX events_created_at_idx ON events (created_at);
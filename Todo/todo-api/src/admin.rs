@@ -0,0 +1,450 @@
+use anyhow::Context;
+use sqlx::migrate::{Migrate, Migrator};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    config::MigrateAction,
+    database::DatabasePool,
+    handlers::token::generate_token,
+    repositories::{
+        label::LabelRepository,
+        todo::{CreateTodo, TodoRepository, UpdateTodo},
+        token::{CreateToken, Scope, TokenRepository},
+        unit_of_work::UnitOfWork,
+        user::UserRepository,
+    },
+    transfer::{self, Document, Format, ImportMode, ImportReport},
+};
+
+const SEED_USER: &str = "demo";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // 適用後にファイルの内容が変わっている
+    Modified,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+pub async fn migrate(database: &DatabasePool, action: MigrateAction) -> anyhow::Result<()> {
+    match action {
+        MigrateAction::Up => {
+            migrate_up(database).await?;
+            println!("migrations are up to date");
+        }
+        MigrateAction::Status => {
+            for status in migration_status(database).await? {
+                let state = match status.state {
+                    MigrationState::Applied => "applied",
+                    MigrationState::Pending => "pending",
+                    MigrationState::Modified => "modified",
+                };
+                println!("{:<16}{:<10}{}", status.version, state, status.description);
+            }
+        }
+        MigrateAction::Down { target } => {
+            let target = migrate_down(database, target).await?;
+            println!("reverted migrations newer than [{}]", target);
+        }
+    }
+    Ok(())
+}
+
+pub async fn migrate_up(database: &DatabasePool) -> anyhow::Result<()> {
+    let result = match database {
+        DatabasePool::Postgres(pool) => database.migrator().run(pool).await,
+        DatabasePool::Sqlite(pool) => database.migrator().run(pool).await,
+    };
+    result.context("fail run migrations")
+}
+
+pub async fn migration_status(database: &DatabasePool) -> anyhow::Result<Vec<MigrationStatus>> {
+    let migrator = database.migrator();
+    match database {
+        DatabasePool::Postgres(pool) => {
+            let mut conn = pool.acquire().await.context("fail acquire connection")?;
+            statuses(&mut *conn, migrator).await
+        }
+        DatabasePool::Sqlite(pool) => {
+            let mut conn = pool.acquire().await.context("fail acquire connection")?;
+            statuses(&mut *conn, migrator).await
+        }
+    }
+}
+
+async fn statuses(
+    conn: &mut impl Migrate,
+    migrator: &Migrator,
+) -> anyhow::Result<Vec<MigrationStatus>> {
+    conn.ensure_migrations_table()
+        .await
+        .context("fail prepare migrations table")?;
+    if let Some(version) = conn.dirty_version().await? {
+        anyhow::bail!(
+            "migration [{}] is partially applied, fix the database manually",
+            version
+        );
+    }
+    let applied: HashMap<i64, Vec<u8>> = conn
+        .list_applied_migrations()
+        .await
+        .context("fail list applied migrations")?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum.into_owned()))
+        .collect();
+
+    let statuses = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| {
+            let state = match applied.get(&migration.version) {
+                Some(checksum) if checksum[..] == migration.checksum[..] => {
+                    MigrationState::Applied
+                }
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+            }
+        })
+        .collect();
+    Ok(statuses)
+}
+
+// `target` より新しいマイグレーションを戻す。省略時は最後に適用した1つだけ戻す
+pub async fn migrate_down(database: &DatabasePool, target: Option<i64>) -> anyhow::Result<i64> {
+    let target = match target {
+        Some(target) => target,
+        None => {
+            let mut applied: Vec<i64> = migration_status(database)
+                .await?
+                .into_iter()
+                .filter(|status| status.state != MigrationState::Pending)
+                .map(|status| status.version)
+                .collect();
+            applied.sort_unstable();
+            if applied.pop().is_none() {
+                anyhow::bail!("no migrations have been applied");
+            }
+            applied.pop().unwrap_or(0)
+        }
+    };
+    let result = match database {
+        DatabasePool::Postgres(pool) => database.migrator().undo(pool, target).await,
+        DatabasePool::Sqlite(pool) => database.migrator().undo(pool, target).await,
+    };
+    result.with_context(|| format!("fail revert migrations to [{}]", target))?;
+    Ok(target)
+}
+
+// 何度実行しても同じ状態になるよう、ユーザーがすでにいれば何もしない
+pub async fn seed<User, Token, Label, Todo>(
+    user_repository: &User,
+    token_repository: &Token,
+    label_repository: &Label,
+    todo_repository: &Todo,
+) -> anyhow::Result<()>
+where
+    User: UserRepository,
+    Token: TokenRepository,
+    Label: LabelRepository,
+    Todo: TodoRepository,
+{
+    if user_repository.find_by_name(SEED_USER).await.is_ok() {
+        println!("user [{}] already exists, skip seeding", SEED_USER);
+        return Ok(());
+    }
+    let user = user_repository
+        .create(SEED_USER.to_string())
+        .await
+        .context("fail create seed user")?;
+
+    let token = generate_token();
+    token_repository
+        .create(
+            user.id,
+            CreateToken {
+                name: "seed".to_string(),
+                scope: Scope::ReadWrite,
+                expires_at: None,
+            },
+            &token,
+        )
+        .await
+        .context("fail create seed token")?;
+
+    for name in ["work", "home"] {
+        label_repository
+            .create(user.id, name.to_string())
+            .await
+            .context("fail create seed label")?;
+    }
+    for (text, completed) in [("write docs", true), ("review PR", false), ("buy milk", false)] {
+        let todo = todo_repository
+            .create(user.id, CreateTodo::new(text.to_string()))
+            .await
+            .context("fail create seed todo")?;
+        if completed {
+            todo_repository
+                .update(user.id, todo.id, UpdateTodo::new(None, Some(true)))
+                .await
+                .context("fail update seed todo")?;
+        }
+    }
+
+    println!("created user [{}] with token: {}", SEED_USER, token);
+    Ok(())
+}
+
+pub async fn export<User: UserRepository, Work: UnitOfWork>(
+    user_repository: &User,
+    unit_of_work: &Work,
+    user: &str,
+    output: Option<&Path>,
+    format: Format,
+) -> anyhow::Result<()> {
+    let user = user_repository
+        .find_by_name(user)
+        .await
+        .with_context(|| format!("fail find user [{}]", user))?;
+    let data = transfer::export(unit_of_work, user.id)
+        .await
+        .context("fail export todos")?;
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(path)
+                .with_context(|| format!("fail create file [{}]", path.display()))?,
+        ),
+        None => Box::new(io::stdout()),
+    };
+    writer
+        .write_all(&data.render(format)?)
+        .context("fail write export")?;
+    // JSONは末尾に改行がない
+    if format == Format::Json {
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+// 取り込めない行があれば何も取り込まず、行ごとのエラーを表示して失敗する
+pub async fn import<User: UserRepository, Work: UnitOfWork>(
+    user_repository: &User,
+    unit_of_work: &Work,
+    user: &str,
+    input: Option<&Path>,
+    format: Format,
+    mode: ImportMode,
+) -> anyhow::Result<()> {
+    let user = user_repository
+        .find_by_name(user)
+        .await
+        .with_context(|| format!("fail find user [{}]", user))?;
+
+    let mut bytes = Vec::new();
+    match input {
+        Some(path) => File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .with_context(|| format!("fail read file [{}]", path.display()))?,
+        None => io::stdin()
+            .read_to_end(&mut bytes)
+            .context("fail read stdin")?,
+    };
+    let report = match Document::parse(format, &bytes) {
+        Ok(document) => transfer::import(unit_of_work, user.id, document, mode)
+            .await
+            .context("fail import todos")?,
+        Err(errors) => ImportReport::rejected(errors),
+    };
+    if !report.committed {
+        for error in &report.errors {
+            eprintln!("{}: {}", error.row, error.message);
+        }
+        anyhow::bail!("nothing was imported, {} rows have errors", report.errors.len());
+    }
+    if mode == ImportMode::Replace {
+        println!(
+            "deleted {} todos, {} labels",
+            report.todos_deleted, report.labels_deleted
+        );
+    }
+    println!(
+        "imported {} todos, {} labels ({} labels already existed), {} label links",
+        report.todos_created, report.labels_created, report.labels_merged, report.links_created
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::LabelRepositoryForSqlite,
+        todo::TodoRepositoryForSqlite,
+        token::TokenRepositoryForSqlite,
+        user::UserRepositoryForSqlite,
+    };
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn states(statuses: &[MigrationStatus]) -> Vec<MigrationState> {
+        statuses.iter().map(|status| status.state.clone()).collect()
+    }
+
+    // マイグレーションを適用していないデータベースで、適用・確認・取り消しを一通り行う
+    async fn migrate_scenario(database: &DatabasePool) {
+        let count = database
+            .migrator()
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .count();
+
+        let statuses = migration_status(database).await.expect("[status] returned Err");
+        assert_eq!(vec![MigrationState::Pending; count], states(&statuses));
+
+        migrate_up(database).await.expect("[up] returned Err");
+        let statuses = migration_status(database).await.expect("[status] returned Err");
+        assert_eq!(vec![MigrationState::Applied; count], states(&statuses));
+
+        // 最後の1つだけ戻す
+        migrate_down(database, None).await.expect("[down] returned Err");
+        let statuses = migration_status(database).await.expect("[status] returned Err");
+        assert_eq!(MigrationState::Pending, statuses[count - 1].state);
+        if count > 1 {
+            assert_eq!(MigrationState::Applied, statuses[count - 2].state);
+        }
+
+        // すべて戻してから再適用できる
+        migrate_down(database, Some(0)).await.expect("[down] returned Err");
+        let statuses = migration_status(database).await.expect("[status] returned Err");
+        assert_eq!(vec![MigrationState::Pending; count], states(&statuses));
+        migrate_up(database).await.expect("[up] returned Err");
+    }
+
+    #[tokio::test]
+    async fn sqlite_migrate_scenario() {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("fail connect sqlite");
+        let database = DatabasePool::Sqlite(pool.clone());
+        migrate_scenario(&database).await;
+
+        let user_repository = UserRepositoryForSqlite::new(pool.clone());
+        let token_repository = TokenRepositoryForSqlite::new(pool.clone());
+        let label_repository = LabelRepositoryForSqlite::new(pool.clone());
+        let todo_repository = TodoRepositoryForSqlite::new(pool);
+        for _ in 0..2 {
+            seed(
+                &user_repository,
+                &token_repository,
+                &label_repository,
+                &todo_repository,
+            )
+            .await
+            .expect("[seed] returned Err");
+        }
+        let user = user_repository
+            .find_by_name(SEED_USER)
+            .await
+            .expect("[find_by_name] returned Err");
+        let todos = todo_repository.all(user.id).await.expect("[all] returned Err");
+        assert_eq!(3, todos.len());
+        assert_eq!(2, label_repository.all(user.id).await.unwrap().len());
+    }
+
+    #[cfg(feature = "database-test")]
+    mod database {
+        use super::*;
+        use crate::repositories::{
+            label::LabelRepositoryForDb, todo::TodoRepositoryForDb, user::UserRepositoryForDb,
+        };
+        use dotenv::dotenv;
+        use sqlx::PgPool;
+        use std::env;
+
+        // 既存のデータベースに影響しないよう、空のデータベースを作り直して使う
+        async fn recreate_database(name: &str) -> PgPool {
+            dotenv().ok();
+            let database_url = env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+            let pool = PgPool::connect(&database_url)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+            sqlx::query(&format!("drop database if exists {} with (force)", name))
+                .execute(&pool)
+                .await
+                .expect("[drop database] returned Err");
+            sqlx::query(&format!("create database {}", name))
+                .execute(&pool)
+                .await
+                .expect("[create database] returned Err");
+            pool.close().await;
+
+            let (base, _) = database_url.rsplit_once('/').expect("invalid DATABASE_URL");
+            PgPool::connect(&format!("{}/{}", base, name))
+                .await
+                .expect("fail connect test database")
+        }
+
+        #[tokio::test]
+        async fn postgres_migrate_scenario() {
+            let database = DatabasePool::Postgres(recreate_database("todos_migrate_test").await);
+            migrate_scenario(&database).await;
+            database.close().await;
+        }
+
+        // ユーザーを導入する前からあるTodoとラベルは `legacy` ユーザーの持ち物になる
+        #[tokio::test]
+        async fn postgres_migrate_existing_data() {
+            let pool = recreate_database("todos_migrate_legacy_test").await;
+            let database = DatabasePool::Postgres(pool.clone());
+            let migrator = database.migrator();
+            let before_users = Migrator {
+                migrations: migrator
+                    .iter()
+                    .filter(|migration| migration.version < 20230901000000)
+                    .cloned()
+                    .collect(),
+                ignore_missing: false,
+            };
+            before_users.run(&pool).await.expect("[up] returned Err");
+            sqlx::query("insert into todos (text) values ('legacy todo')")
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("insert into labels (name) values ('legacy label')")
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            migrate_up(&database).await.expect("[up] returned Err");
+            let user = UserRepositoryForDb::new(pool.clone())
+                .find_by_name("legacy")
+                .await
+                .expect("[find_by_name] returned Err");
+            let todos = TodoRepositoryForDb::new(pool.clone()).all(user.id).await.unwrap();
+            assert_eq!(1, todos.len());
+            assert_eq!("legacy todo", todos[0].text);
+            let labels = LabelRepositoryForDb::new(pool).all(user.id).await.unwrap();
+            assert_eq!(1, labels.len());
+            assert_eq!("legacy label", labels[0].name);
+            database.close().await;
+        }
+    }
+}
@@ -0,0 +1,784 @@
+use crate::{
+    handlers::WORKSPACE_HEADER,
+    request_id::REQUEST_ID_HEADER,
+    transfer::{Format, ImportMode},
+};
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use hyper::{header::HeaderName, Method};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use tower_http::cors::{Any, CorsLayer, Origin};
+
+// 設定はデフォルト値 < TOMLファイル < 環境変数・コマンドライン引数 の順に上書きする
+// 設定のオプションはサブコマンドの後ろにも書けるようにglobalにする
+#[derive(Debug, Parser)]
+#[command(about = "Todo API server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML形式の設定ファイル
+    #[arg(long, global = true, env = "TODO_API_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true, env = "BIND_ADDRESS")]
+    pub bind_address: Option<SocketAddr>,
+    /// リクエストボディの上限（バイト）
+    #[arg(long, global = true, env = "BODY_LIMIT")]
+    pub body_limit: Option<usize>,
+    /// シャットダウン時に処理中のリクエストを待つ秒数
+    #[arg(long, global = true, env = "SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// シャットダウン時にreadinessを落としてから、新しいリクエストを断り始めるまでの秒数
+    #[arg(long, global = true, env = "DRAIN_DELAY")]
+    pub drain_delay_secs: Option<u64>,
+    /// WebSocketでpingを送る間隔（秒）
+    #[arg(long, global = true, env = "WS_HEARTBEAT")]
+    pub ws_heartbeat_secs: Option<u64>,
+    /// `/events` の再接続に備えて変更イベントを残す秒数
+    #[arg(long, global = true, env = "EVENT_RETENTION")]
+    pub event_retention_secs: Option<u64>,
+    /// カンマ区切り、`*` ですべて許可
+    #[arg(long, global = true, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    pub cors_allowed_origins: Option<Vec<String>>,
+    #[arg(long, global = true, env = "CORS_ALLOWED_METHODS", value_delimiter = ',')]
+    pub cors_allowed_methods: Option<Vec<String>>,
+    #[arg(long, global = true, env = "CORS_ALLOWED_HEADERS", value_delimiter = ',')]
+    pub cors_allowed_headers: Option<Vec<String>>,
+    /// `memory` にするとデータベースを使わずにメモリに保存する
+    #[arg(long = "storage", global = true, env = "STORAGE", value_enum)]
+    pub storage_backend: Option<StorageBackend>,
+    /// メモリに保存する場合に、スナップショットと追記ログを書き出すファイル
+    #[arg(long, global = true, env = "STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+    #[arg(long, global = true, env = "DATABASE_URL")]
+    pub database_url: Option<String>,
+    #[arg(long, global = true, env = "DB_MAX_CONNECTIONS")]
+    pub db_max_connections: Option<u32>,
+    #[arg(long, global = true, env = "DB_CONNECT_TIMEOUT")]
+    pub db_connect_timeout_secs: Option<u64>,
+    #[arg(long, global = true, env = "DB_IDLE_TIMEOUT")]
+    pub db_idle_timeout_secs: Option<u64>,
+    /// 起動時に未適用のマイグレーションを実行する
+    #[arg(
+        long,
+        global = true,
+        env = "MIGRATE_ON_STARTUP",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub migrate_on_startup: Option<bool>,
+    #[arg(long, global = true, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+    #[arg(long, global = true, env = "LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// 指定するとOTLPでトレースを送信する
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_PROTOCOL", value_enum)]
+    pub otlp_protocol: Option<OtlpProtocol>,
+    #[arg(long, global = true, env = "OTEL_SERVICE_NAME")]
+    pub otel_service_name: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// APIサーバーを起動する（サブコマンドを省略した場合）
+    Serve,
+    /// バイナリに埋め込んだマイグレーションを操作する
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// 動作確認用のユーザー・ラベル・Todoを作成する
+    Seed,
+    /// ユーザーのTodoとラベルをJSON・CSV・todo.txt・iCalendar・Markdownで書き出す
+    Export {
+        #[arg(long)]
+        user: String,
+        /// 省略時は標準出力
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+    },
+    /// `export` で書き出したファイルをユーザーのワークスペースに取り込む
+    Import {
+        #[arg(long)]
+        user: String,
+        /// 省略時は標準入力
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+        /// replaceは既存のTodoとラベルを消してから取り込む
+        #[arg(long, value_enum, default_value = "merge")]
+        mode: ImportMode,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateAction {
+    /// 未適用のマイグレーションをすべて実行する
+    Up,
+    /// マイグレーションごとの適用状況を表示する
+    Status,
+    /// 指定したバージョンまで戻す。省略時は最後の1つだけ戻す
+    Down {
+        #[arg(long)]
+        target: Option<i64>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub storage: StorageConfig,
+    pub events: EventsConfig,
+    pub webhooks: WebhooksConfig,
+    pub cache: CacheConfig,
+    pub database: DatabaseConfig,
+    pub log: LogConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: SocketAddr,
+    pub body_limit: usize,
+    pub shutdown_timeout_secs: u64,
+    // readinessを落としてから、ロードバランサーが振り分けをやめるのを待つ秒数
+    // この間に届いたリクエストも処理する
+    pub drain_delay_secs: u64,
+    // WebSocketの接続が生きているかをpingで確かめる間隔
+    pub ws_heartbeat_secs: u64,
+    // `GET /graphql` でGraphiQLを返す。デバッグビルドでは既定で有効
+    pub graphiql: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            body_limit: 1024 * 1024,
+            shutdown_timeout_secs: 30,
+            drain_delay_secs: 5,
+            ws_heartbeat_secs: 30,
+            graphiql: cfg!(debug_assertions),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["http://localhost:3001".to_string()],
+            allowed_methods: vec!["*".to_string()],
+            allowed_headers: vec![
+                "content-type".to_string(),
+                "authorization".to_string(),
+                WORKSPACE_HEADER.to_string(),
+                REQUEST_ID_HEADER.to_string(),
+                "last-event-id".to_string(),
+            ],
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn layer(&self) -> anyhow::Result<CorsLayer> {
+        let mut layer = CorsLayer::new();
+
+        layer = if is_wildcard(&self.allowed_origins) {
+            layer.allow_origin(Any)
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|origin| {
+                    origin
+                        .parse()
+                        .with_context(|| format!("invalid cors origin [{}]", origin))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            layer.allow_origin(Origin::list(origins))
+        };
+
+        layer = if is_wildcard(&self.allowed_methods) {
+            layer.allow_methods(Any)
+        } else {
+            let methods = self
+                .allowed_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.to_uppercase().as_bytes())
+                        .with_context(|| format!("invalid cors method [{}]", method))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            layer.allow_methods(methods)
+        };
+
+        layer = if is_wildcard(&self.allowed_headers) {
+            layer.allow_headers(Any)
+        } else {
+            let headers = self
+                .allowed_headers
+                .iter()
+                .map(|header| {
+                    HeaderName::from_bytes(header.as_bytes())
+                        .with_context(|| format!("invalid cors header [{}]", header))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            layer.allow_headers(headers)
+        };
+
+        // エラーの問い合わせに使うので、ブラウザからも参照できるようにする
+        Ok(layer.expose_headers(vec![HeaderName::from_static(REQUEST_ID_HEADER)]))
+    }
+}
+
+fn is_wildcard(values: &[String]) -> bool {
+    values.iter().any(|value| value == "*")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // 未設定の場合、メモリの内容は終了時に失われる
+    pub path: Option<PathBuf>,
+    // 追記ログがこの件数に達したらスナップショットを書き直してログを空にする
+    pub snapshot_every: usize,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Database,
+            path: None,
+            snapshot_every: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    // `database.url` のスキームでPostgreSQLかSQLiteを選ぶ
+    Database,
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub connect_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub migrate_on_startup: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            max_connections: 10,
+            connect_timeout_secs: 30,
+            idle_timeout_secs: 600,
+            migrate_on_startup: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn drain_delay(&self) -> Duration {
+        Duration::from_secs(self.drain_delay_secs)
+    }
+
+    pub fn ws_heartbeat(&self) -> Duration {
+        Duration::from_secs(self.ws_heartbeat_secs)
+    }
+}
+
+impl DatabaseConfig {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+// SSEの再接続で取りこぼしを返すための変更イベントの履歴
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    // これより古いイベントは消す。消した分より前から再開しようとしたクライアントには再同期を求める
+    pub retention_secs: u64,
+    // 購読中の接続が、まだトークンとワークスペースの権限を持っているかを確かめ直す間隔
+    pub access_check_secs: u64,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            retention_secs: 24 * 60 * 60,
+            access_check_secs: 10,
+        }
+    }
+}
+
+impl EventsConfig {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_secs)
+    }
+
+    pub fn access_check(&self) -> Duration {
+        Duration::from_secs(self.access_check_secs)
+    }
+}
+
+// Webhookの送信。失敗した配信は間隔を倍にしながら送り直し、上限に達したら諦める
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    // 最初の送信も含めた回数
+    pub max_attempts: u32,
+    // 1回目の送り直しまでの間隔（ミリ秒）
+    pub retry_base_ms: u64,
+    pub timeout_secs: u64,
+    // ループバックやプライベートのアドレスでも送ってよいホスト。URLのホストと同じ形で書く
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            retry_base_ms: 30_000,
+            timeout_secs: 10,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl WebhooksConfig {
+    pub fn retry_base(&self) -> Duration {
+        Duration::from_millis(self.retry_base_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+// TodoとラベルのリポジトリのfindとallをTTLと件数の上限付きでキャッシュする
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    // リポジトリごと、find・allごとの件数の上限
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 30,
+            max_entries: 10_000,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // `RUST_LOG` と同じ書式（例: `info,sqlx=warn`）
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // 未設定の場合はエクスポートしない
+    pub otlp_endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    pub service_name: String,
+    // 0.0〜1.0 の割合でトレースをサンプリングする
+    pub sample_ratio: f64,
+    pub timeout_secs: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            protocol: OtlpProtocol::Grpc,
+            service_name: "todo-api".to_string(),
+            sample_ratio: 1.0,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    Grpc,
+    Http,
+}
+
+impl Config {
+    pub fn load(cli: Cli) -> anyhow::Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("fail read config file [{}]", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("fail parse config file [{}]", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.merge(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn merge(&mut self, cli: Cli) {
+        if let Some(bind_address) = cli.bind_address {
+            self.server.bind_address = bind_address;
+        }
+        if let Some(body_limit) = cli.body_limit {
+            self.server.body_limit = body_limit;
+        }
+        if let Some(secs) = cli.shutdown_timeout_secs {
+            self.server.shutdown_timeout_secs = secs;
+        }
+        if let Some(secs) = cli.drain_delay_secs {
+            self.server.drain_delay_secs = secs;
+        }
+        if let Some(secs) = cli.ws_heartbeat_secs {
+            self.server.ws_heartbeat_secs = secs;
+        }
+        if let Some(secs) = cli.event_retention_secs {
+            self.events.retention_secs = secs;
+        }
+        if let Some(origins) = cli.cors_allowed_origins {
+            self.cors.allowed_origins = origins;
+        }
+        if let Some(methods) = cli.cors_allowed_methods {
+            self.cors.allowed_methods = methods;
+        }
+        if let Some(headers) = cli.cors_allowed_headers {
+            self.cors.allowed_headers = headers;
+        }
+        if let Some(backend) = cli.storage_backend {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = cli.storage_path {
+            self.storage.path = Some(path);
+        }
+        if let Some(url) = cli.database_url {
+            self.database.url = url;
+        }
+        if let Some(max_connections) = cli.db_max_connections {
+            self.database.max_connections = max_connections;
+        }
+        if let Some(secs) = cli.db_connect_timeout_secs {
+            self.database.connect_timeout_secs = secs;
+        }
+        if let Some(secs) = cli.db_idle_timeout_secs {
+            self.database.idle_timeout_secs = secs;
+        }
+        if let Some(migrate) = cli.migrate_on_startup {
+            self.database.migrate_on_startup = migrate;
+        }
+        if let Some(level) = cli.log_level {
+            self.log.level = level;
+        }
+        if let Some(format) = cli.log_format {
+            self.log.format = format;
+        }
+        if let Some(endpoint) = cli.otlp_endpoint {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+        if let Some(protocol) = cli.otlp_protocol {
+            self.telemetry.protocol = protocol;
+        }
+        if let Some(service_name) = cli.otel_service_name {
+            self.telemetry.service_name = service_name;
+        }
+    }
+
+    // 起動時に不正な設定を検出して、原因が分かるエラーを返す
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.server.body_limit == 0 {
+            bail!("server.body_limit must be greater than 0");
+        }
+        if self.server.ws_heartbeat_secs == 0 {
+            bail!("server.ws_heartbeat_secs must be greater than 0");
+        }
+        if self.events.retention_secs == 0 {
+            bail!("events.retention_secs must be greater than 0");
+        }
+        if self.events.access_check_secs == 0 {
+            bail!("events.access_check_secs must be greater than 0");
+        }
+        if self.webhooks.max_attempts == 0 {
+            bail!("webhooks.max_attempts must be greater than 0");
+        }
+        if self.webhooks.timeout_secs == 0 {
+            bail!("webhooks.timeout_secs must be greater than 0");
+        }
+        if self.cache.enabled && (self.cache.ttl_secs == 0 || self.cache.max_entries == 0) {
+            bail!("cache.ttl_secs and cache.max_entries must be greater than 0");
+        }
+        if self.cors.allowed_origins.is_empty() {
+            bail!("cors.allowed_origins must not be empty");
+        }
+        self.cors.layer()?;
+        if self.storage.snapshot_every == 0 {
+            bail!("storage.snapshot_every must be greater than 0");
+        }
+        if self.storage.backend == StorageBackend::Database && self.database.url.is_empty() {
+            bail!("database.url is required (set DATABASE_URL or --database-url)");
+        }
+        if self.database.max_connections == 0 {
+            bail!("database.max_connections must be greater than 0");
+        }
+        if self.database.connect_timeout_secs == 0 {
+            bail!("database.connect_timeout_secs must be greater than 0");
+        }
+        tracing_subscriber::EnvFilter::try_new(&self.log.level)
+            .with_context(|| format!("invalid log.level [{}]", self.log.level))?;
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            endpoint
+                .parse::<hyper::Uri>()
+                .ok()
+                .filter(|uri| uri.scheme().is_some() && uri.host().is_some())
+                .with_context(|| format!("invalid telemetry.otlp_endpoint [{}]", endpoint))?;
+        }
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            bail!("telemetry.sample_ratio must be between 0.0 and 1.0");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn cli_result(args: &[&str]) -> Result<Cli, clap::Error> {
+        let mut argv = vec!["todo-api"];
+        argv.extend_from_slice(args);
+        Cli::try_parse_from(argv)
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        cli_result(args).expect("failed parse cli")
+    }
+
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.database.url = "postgres://localhost/todos".to_string();
+        config
+    }
+
+    #[test]
+    fn should_merge_file_and_cli() {
+        let path = std::env::temp_dir().join("todo-api-config-test.toml");
+        let mut file = std::fs::File::create(&path).unwrap();
+        write!(
+            file,
+            r#"
+            [server]
+            bind_address = "127.0.0.1:8080"
+            body_limit = 2048
+
+            [cors]
+            allowed_origins = ["https://example.com"]
+
+            [database]
+            url = "postgres://file/todos"
+            max_connections = 3
+
+            [log]
+            format = "json"
+            "#
+        )
+        .unwrap();
+
+        let path = path.to_str().unwrap();
+        let config = Config::load(cli(&[
+            "--config",
+            path,
+            "--bind-address",
+            "127.0.0.1:9090",
+            "--database-url",
+            "postgres://cli/todos",
+            "--log-level",
+            "debug",
+        ]))
+        .expect("failed load config");
+
+        // コマンドライン引数がファイルより優先される
+        assert_eq!(
+            "127.0.0.1:9090".parse::<SocketAddr>().unwrap(),
+            config.server.bind_address
+        );
+        assert_eq!("postgres://cli/todos", config.database.url);
+        assert_eq!("debug", config.log.level);
+        // ファイルの値
+        assert_eq!(2048, config.server.body_limit);
+        assert_eq!(
+            vec!["https://example.com".to_string()],
+            config.cors.allowed_origins
+        );
+        assert_eq!(3, config.database.max_connections);
+        assert_eq!(LogFormat::Json, config.log.format);
+        // デフォルト値
+        assert_eq!(30, config.database.connect_timeout_secs);
+    }
+
+    #[test]
+    fn should_split_comma_separated_cli_values() {
+        let mut config = valid_config();
+        config.merge(cli(&[
+            "--cors-allowed-origins",
+            "https://a.example.com,https://b.example.com",
+            "--cors-allowed-methods",
+            "get,post",
+        ]));
+        assert_eq!(2, config.cors.allowed_origins.len());
+        assert_eq!(
+            vec!["get".to_string(), "post".to_string()],
+            config.cors.allowed_methods
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn should_parse_subcommands() {
+        let down = cli(&["migrate", "down", "--target", "20230901000000"]);
+        assert!(matches!(
+            down.command,
+            Some(Command::Migrate {
+                action: MigrateAction::Down {
+                    target: Some(20230901000000)
+                }
+            })
+        ));
+
+        // 設定のオプションはサブコマンドの後ろにも書ける
+        let mut serve = cli(&["serve", "--migrate-on-startup", "--bind-address", "127.0.0.1:8000"]);
+        assert!(matches!(serve.command.take(), Some(Command::Serve)));
+        let mut config = valid_config();
+        config.merge(serve);
+        assert!(config.database.migrate_on_startup);
+        assert_eq!("127.0.0.1:8000".parse::<SocketAddr>().unwrap(), config.server.bind_address);
+
+        assert!(cli_result(&["export"]).is_err());
+        assert!(cli_result(&["import", "--user", "alice", "-i", "todos.json"]).is_ok());
+    }
+
+    #[test]
+    fn should_reject_invalid_config() {
+        assert!(valid_config().validate().is_ok());
+        assert!(Config::default().validate().is_err());
+
+        let mut config = valid_config();
+        config.cors.allowed_origins = vec!["not a\norigin".to_string()];
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.cors.allowed_headers = vec!["bad header".to_string()];
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.server.body_limit = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.database.max_connections = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.webhooks.max_attempts = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = valid_config();
+        config.cache.max_entries = 0;
+        assert!(config.validate().is_err());
+        config.cache.enabled = false;
+        assert!(config.validate().is_ok());
+
+        let mut config = valid_config();
+        config.telemetry.otlp_endpoint = Some("localhost".to_string());
+        assert!(config.validate().is_err());
+        config.telemetry.otlp_endpoint = Some("http://localhost:4317".to_string());
+        assert!(config.validate().is_ok());
+        config.telemetry.sample_ratio = 1.5;
+        assert!(config.validate().is_err());
+
+        // メモリに保存する場合はデータベースのURLは不要
+        let memory = cli(&["--storage", "memory", "--storage-path", "todos.json"]);
+        let mut config = Config::default();
+        config.merge(memory);
+        assert_eq!(StorageBackend::Memory, config.storage.backend);
+        assert_eq!(Some(PathBuf::from("todos.json")), config.storage.path);
+        assert!(config.validate().is_ok());
+        config.storage.snapshot_every = 0;
+        assert!(config.validate().is_err());
+
+        assert!(toml::from_str::<Config>("[server]\nunknown = 1").is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::Context;
+use sqlx::{
+    migrate::Migrator,
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    PgPool, SqlitePool,
+};
+use std::str::FromStr;
+
+use crate::config::DatabaseConfig;
+
+// `migrations/` をバイナリに埋め込むので、実行時にsqlx CLIやファイルは不要
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+// SQLiteは型や関数が異なるので、別のマイグレーションを持つ
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+// `DATABASE_URL` のスキームで接続先のデータベースを選ぶ
+#[derive(Debug, Clone)]
+pub enum DatabasePool {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+impl DatabasePool {
+    pub async fn connect(config: &DatabaseConfig) -> anyhow::Result<Self> {
+        tracing::debug!("start connect database...");
+        if config.url.starts_with("sqlite:") {
+            // ファイルがなければ作成し、1ファイルだけで動かせるようにする
+            let options = SqliteConnectOptions::from_str(&config.url)
+                .context("invalid sqlite database url")?
+                .create_if_missing(true);
+            let pool = SqlitePoolOptions::new()
+                .max_connections(config.max_connections)
+                .connect_timeout(config.connect_timeout())
+                .idle_timeout(config.idle_timeout())
+                .connect_with(options)
+                .await
+                .map_err(|e| anyhow::anyhow!("fail connect database: {}", e))?;
+            return Ok(Self::Sqlite(pool));
+        }
+
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_timeout(config.connect_timeout())
+            .idle_timeout(config.idle_timeout())
+            .connect(&config.url)
+            .await
+            .map_err(|e| anyhow::anyhow!("fail connect database: {}", e))?;
+        Ok(Self::Postgres(pool))
+    }
+
+    pub fn migrator(&self) -> &'static Migrator {
+        match self {
+            Self::Postgres(_) => &MIGRATOR,
+            Self::Sqlite(_) => &SQLITE_MIGRATOR,
+        }
+    }
+
+    pub async fn close(&self) {
+        match self {
+            Self::Postgres(pool) => pool.close().await,
+            Self::Sqlite(pool) => pool.close().await,
+        }
+    }
+}
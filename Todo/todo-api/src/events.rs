@@ -0,0 +1,411 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, mpsc, Notify};
+
+use crate::repositories::event::{EventRepository, StoredEvent};
+
+// 購読者が読み切れずに溜められるイベントの数。超えた購読者には `Lagged` が返る
+const BUS_CAPACITY: usize = 1024;
+// 保持期間を過ぎたイベントを消す間隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// 他のインスタンスが保存したイベントを探しに行く間隔
+const TAIL_INTERVAL: Duration = Duration::from_millis(200);
+// 履歴から一度に読み出す件数
+const TAIL_PAGE: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    #[serde(rename = "todo.created")]
+    TodoCreated,
+    #[serde(rename = "todo.updated")]
+    TodoUpdated,
+    #[serde(rename = "todo.deleted")]
+    TodoDeleted,
+    // 未完了から完了に変わった。`todo.updated` と合わせて発行する
+    #[serde(rename = "todo.completed")]
+    TodoCompleted,
+    #[serde(rename = "label.created")]
+    LabelCreated,
+    #[serde(rename = "label.deleted")]
+    LabelDeleted,
+    // 一括で取り込んだので、個別のイベントの代わりに全体を読み直してもらう
+    #[serde(rename = "workspace.imported")]
+    WorkspaceImported,
+    // 他のインスタンスの変更を取りこぼしたかもしれないので、すべてのワークスペースで読み直してもらう
+    // 発行はせず、このインスタンスのキャッシュと購読者にだけ配る
+    #[serde(rename = "resync")]
+    Resync,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::TodoCreated => "todo.created",
+            ChangeKind::TodoUpdated => "todo.updated",
+            ChangeKind::TodoDeleted => "todo.deleted",
+            ChangeKind::TodoCompleted => "todo.completed",
+            ChangeKind::LabelCreated => "label.created",
+            ChangeKind::LabelDeleted => "label.deleted",
+            ChangeKind::WorkspaceImported => "workspace.imported",
+            ChangeKind::Resync => "resync",
+        }
+    }
+}
+
+impl FromStr for ChangeKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "todo.created" => Ok(ChangeKind::TodoCreated),
+            "todo.updated" => Ok(ChangeKind::TodoUpdated),
+            "todo.deleted" => Ok(ChangeKind::TodoDeleted),
+            "todo.completed" => Ok(ChangeKind::TodoCompleted),
+            "label.created" => Ok(ChangeKind::LabelCreated),
+            "label.deleted" => Ok(ChangeKind::LabelDeleted),
+            "workspace.imported" => Ok(ChangeKind::WorkspaceImported),
+            "resync" => Ok(ChangeKind::Resync),
+            _ => Err(()),
+        }
+    }
+}
+
+// どのワークスペースの何が変わったか。削除の場合は `data` を持たない
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    #[serde(rename = "type")]
+    pub kind: ChangeKind,
+    pub workspace_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl ChangeEvent {
+    pub fn new(kind: ChangeKind, workspace_id: i32, id: i32, data: &impl Serialize) -> Self {
+        Self {
+            kind,
+            workspace_id,
+            id: Some(id),
+            data: serde_json::to_value(data).ok(),
+        }
+    }
+
+    pub fn deleted(kind: ChangeKind, workspace_id: i32, id: i32) -> Self {
+        Self {
+            kind,
+            workspace_id,
+            id: Some(id),
+            data: None,
+        }
+    }
+
+    pub fn imported(workspace_id: i32) -> Self {
+        Self {
+            kind: ChangeKind::WorkspaceImported,
+            workspace_id,
+            id: None,
+            data: None,
+        }
+    }
+
+    // 特定のワークスペースに属さないので、`workspace_id` は使わない
+    pub fn resync() -> Self {
+        Self {
+            kind: ChangeKind::Resync,
+            workspace_id: 0,
+            id: None,
+            data: None,
+        }
+    }
+
+    // `workspace_id` のワークスペースの購読者に配るイベントか
+    pub fn concerns(&self, workspace_id: i32) -> bool {
+        self.kind == ChangeKind::Resync || self.workspace_id == workspace_id
+    }
+}
+
+// 発行したその場で呼ばれる受け手。キャッシュは応答を返す前に古いデータを捨てる
+pub trait ChangeObserver: Send + Sync + 'static {
+    fn observe(&self, event: &ChangeEvent);
+}
+
+// 書き込みに成功したハンドラーが発行し、WebSocketなどの購読者に配る
+// 履歴の記録係やWebhookのように1件も落とせない受け手には、溢れないチャネルで渡す
+#[derive(Clone)]
+pub struct ChangeBus {
+    sender: broadcast::Sender<ChangeEvent>,
+    sinks: Vec<mpsc::UnboundedSender<ChangeEvent>>,
+    observers: Vec<Arc<dyn ChangeObserver>>,
+}
+
+impl fmt::Debug for ChangeBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeBus")
+            .field("sinks", &self.sinks.len())
+            .field("observers", &self.observers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ChangeBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangeBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        Self {
+            sender,
+            sinks: Vec::new(),
+            observers: Vec::new(),
+        }
+    }
+
+    // 発行したイベントを `repository` に保存する記録係と、保存されたイベントを配る係を起動する
+    // 配る係は表を読んで `EventFeed` に流すので、同じ表を共有する他のインスタンスが
+    // 保存したイベントも配る。配るイベントのIDは常に大きくなっていく
+    pub fn with_journal<E: EventRepository>(
+        repository: E,
+        retention: Duration,
+    ) -> (Self, EventFeed) {
+        let mut bus = Self::new();
+        let receiver = bus.sink();
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        let feed = EventFeed { sender };
+        tokio::spawn(journal(repository, receiver, feed.clone(), retention));
+        (bus, feed)
+    }
+
+    // 以降に発行したイベントをすべて受け取るチャネル。複製する前に作っておく
+    pub fn sink(&mut self) -> mpsc::UnboundedReceiver<ChangeEvent> {
+        let (sink, receiver) = mpsc::unbounded_channel();
+        self.sinks.push(sink);
+        receiver
+    }
+
+    // 他のインスタンスから届いたイベントも含めて `observer` に渡す。複製する前に登録しておく
+    pub fn observe(&mut self, observer: impl ChangeObserver) {
+        self.observers.push(Arc::new(observer));
+    }
+
+    // 購読者がいなくても書き込み自体は成功しているので、送れなかったことは無視する
+    pub fn publish(&self, event: ChangeEvent) {
+        tracing::debug!(
+            kind = event.kind.as_str(),
+            workspace_id = event.workspace_id,
+            "publish change"
+        );
+        for sink in &self.sinks {
+            sink.send(event.clone()).ok();
+        }
+        self.relay(event);
+    }
+
+    // 他のインスタンスで発行されたイベントを購読者に配る
+    // 履歴の記録やWebhookの送信は発行したインスタンスが行うので、溢れないチャネルには渡さない
+    pub fn relay(&self, event: ChangeEvent) {
+        for observer in &self.observers {
+            observer.observe(&event);
+        }
+        self.sender.send(event).ok();
+    }
+
+    // キャッシュをすべて捨て、購読者にも全体を読み直してもらう
+    pub fn resync(&self) {
+        self.relay(ChangeEvent::resync());
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// 保存したイベントを配る。SSEの購読者はこれを購読し、IDで再開位置を覚える
+#[derive(Debug, Clone)]
+pub struct EventFeed {
+    sender: broadcast::Sender<StoredEvent>,
+}
+
+impl EventFeed {
+    pub fn subscribe(&self) -> broadcast::Receiver<StoredEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// すべての `ChangeBus` が破棄されるまで保存を続ける
+// 保存に失敗したイベントは履歴に残らないが、書き込み自体は成功しているので止めずに続ける
+async fn record<E: EventRepository>(
+    repository: E,
+    mut receiver: mpsc::UnboundedReceiver<ChangeEvent>,
+    appended: Arc<Notify>,
+    retention: Duration,
+) {
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let event = match event {
+                    Some(event) => event,
+                    None => break,
+                };
+                match repository.append(event).await {
+                    // 自分が保存したイベントは待たずに配る
+                    Ok(_) => appended.notify_one(),
+                    Err(e) => tracing::error!("fail record change event: {:?}", e),
+                }
+            }
+            _ = prune.tick() => {
+                // 日時で表せないほど長い保持期間なら消さない
+                let before = match chrono::Duration::from_std(retention)
+                    .ok()
+                    .and_then(|retention| Utc::now().checked_sub_signed(retention))
+                {
+                    Some(before) => before,
+                    None => continue,
+                };
+                match repository.prune(before).await {
+                    Ok(0) => {}
+                    Ok(pruned) => tracing::debug!(pruned, "pruned change events"),
+                    Err(e) => tracing::error!("fail prune change events: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+// 起動した時点より後に保存されたイベントを、保存された順に配り続ける
+// 配り始める位置を決めてから保存を始めるので、自分が保存したイベントは必ず配る
+async fn journal<E: EventRepository>(
+    repository: E,
+    receiver: mpsc::UnboundedReceiver<ChangeEvent>,
+    feed: EventFeed,
+    retention: Duration,
+) {
+    let mut last_id = loop {
+        match repository.bounds().await {
+            Ok(bounds) => break bounds.map_or(0, |(_, newest)| newest),
+            Err(e) => {
+                tracing::error!("fail read change events: {:?}", e);
+                tokio::time::sleep(TAIL_INTERVAL).await;
+            }
+        }
+    };
+    let appended = Arc::new(Notify::new());
+    let recorder = tokio::spawn(record(
+        repository.clone(),
+        receiver,
+        appended.clone(),
+        retention,
+    ));
+
+    let mut interval = tokio::time::interval(TAIL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = appended.notified() => {}
+            _ = interval.tick() => {}
+        }
+        loop {
+            let events = match repository.tail(last_id, TAIL_PAGE).await {
+                Ok(events) => events,
+                Err(e) => {
+                    tracing::error!("fail read change events: {:?}", e);
+                    break;
+                }
+            };
+            let full = events.len() == TAIL_PAGE as usize;
+            for event in events {
+                last_id = event.id;
+                feed.sender.send(event).ok();
+            }
+            if !full {
+                break;
+            }
+        }
+        // すべての `ChangeBus` が破棄されて記録係が止まったら、配り終えて止まる
+        if recorder.is_finished() {
+            break;
+        }
+    }
+}
+
+// `todo.created,label.*` のようにカンマで区切った種類で購読するイベントを絞る
+// `*` で終わるものは前方一致、空なら全部
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    patterns: Vec<String>,
+}
+
+impl EventFilter {
+    pub fn parse(value: &str) -> Self {
+        Self {
+            patterns: value
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, kind: ChangeKind) -> bool {
+        let kind = kind.as_str();
+        self.patterns.is_empty()
+            || self.patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => kind.starts_with(prefix),
+                None => kind == pattern,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::event::EventRepositoryForMemory;
+
+    #[test]
+    fn should_filter_by_kind_pattern() {
+        let filter = EventFilter::parse("todo.created, label.*");
+        assert!(filter.matches(ChangeKind::TodoCreated));
+        assert!(filter.matches(ChangeKind::LabelDeleted));
+        assert!(!filter.matches(ChangeKind::TodoDeleted));
+        assert!(EventFilter::parse("").matches(ChangeKind::WorkspaceImported));
+    }
+
+    #[tokio::test]
+    async fn should_record_events_in_order() {
+        let repository = EventRepositoryForMemory::new();
+        let (bus, feed) = ChangeBus::with_journal(repository.clone(), Duration::from_secs(60));
+        let mut receiver = feed.subscribe();
+        bus.publish(ChangeEvent::imported(1));
+        bus.publish(ChangeEvent::deleted(ChangeKind::TodoDeleted, 2, 3));
+
+        let first = receiver.recv().await.unwrap();
+        let second = receiver.recv().await.unwrap();
+        assert_eq!(ChangeEvent::imported(1), first.event);
+        assert!(first.id < second.id);
+        assert_eq!(vec![second], repository.after(2, 0, 10).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_deliver_to_every_subscriber() {
+        let bus = ChangeBus::new();
+        // 購読者がいなくても失敗しない
+        bus.publish(ChangeEvent::imported(1));
+
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        bus.publish(ChangeEvent::deleted(ChangeKind::TodoDeleted, 1, 3));
+        let expected = ChangeEvent::deleted(ChangeKind::TodoDeleted, 1, 3);
+        assert_eq!(expected, first.recv().await.unwrap());
+        assert_eq!(expected, second.recv().await.unwrap());
+        assert_eq!(
+            r#"{"type":"todo.deleted","workspace_id":1,"id":3}"#,
+            serde_json::to_string(&expected).unwrap()
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgListener, PgNotification},
+    PgConnection, PgPool,
+};
+use std::{sync::OnceLock, time::Duration};
+use tracing::Instrument;
+
+use crate::{
+    events::{ChangeBus, ChangeEvent},
+    repositories::db_span,
+};
+
+// 複数のインスタンスで同じデータベースを使う場合に、他のインスタンスの書き込みを知らせる
+// TodoとラベルのビューはPostgreSQLに書き込むたびにこのチャネルへNOTIFYし、
+// 各インスタンスの `ChangeListener` がLISTENしてローカルの `ChangeBus` に流す
+pub const CHANNEL: &str = "todo_api_changes";
+
+// NOTIFYのペイロードの上限は8000バイト。超える場合はデータを省いて種類とIDだけを送る
+const MAX_PAYLOAD: usize = 7900;
+// 接続できないときに待つ時間。失敗するたびに倍にする
+const RETRY_MIN: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+// 自分の書き込みは発行済みなので、通知を受け取っても流さないための印
+pub fn instance_id() -> &'static str {
+    static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+    INSTANCE_ID.get_or_init(|| uuid::Uuid::new_v4().to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Notification {
+    origin: String,
+    event: ChangeEvent,
+}
+
+// トランザクションの中で呼ぶと、コミットしたときにだけ届く
+pub async fn notify(conn: &mut PgConnection, mut event: ChangeEvent) -> anyhow::Result<()> {
+    let mut payload = serde_json::to_string(&Notification {
+        origin: instance_id().to_string(),
+        event: event.clone(),
+    })?;
+    if payload.len() > MAX_PAYLOAD {
+        event.data = None;
+        payload = serde_json::to_string(&Notification {
+            origin: instance_id().to_string(),
+            event,
+        })?;
+    }
+
+    let sql = r#"
+        select pg_notify($1, $2)
+        "#;
+    sqlx::query(sql)
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(&mut *conn)
+        .instrument(db_span(sql))
+        .await?;
+    Ok(())
+}
+
+// 他のインスタンスの変更を受け取り、ローカルのWebSocketなどの購読者とキャッシュに届ける
+// 接続が切れている間の通知は失われるので、つなぎ直すたびにキャッシュを捨てて購読者にも読み直してもらう
+#[derive(Debug, Clone)]
+pub struct ChangeListener {
+    pool: PgPool,
+    origin: String,
+}
+
+impl ChangeListener {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            origin: instance_id().to_string(),
+        }
+    }
+
+    // 接続が切れても、つなぎ直して受け取り続ける
+    pub async fn run(self, bus: ChangeBus) {
+        let mut retry = RETRY_MIN;
+        loop {
+            if let Err(e) = self.listen(&bus, &mut retry).await {
+                tracing::warn!("change listener disconnected, retry in {:?}: {:?}", retry, e);
+            }
+            tokio::time::sleep(retry).await;
+            retry = (retry * 2).min(RETRY_MAX);
+        }
+    }
+
+    async fn listen(&self, bus: &ChangeBus, retry: &mut Duration) -> anyhow::Result<()> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        tracing::info!(channel = CHANNEL, "listening for changes from other instances");
+        *retry = RETRY_MIN;
+        // つながっていなかった間の変更は届かないので、取りこぼしたものとして扱う
+        bus.resync();
+        loop {
+            // 接続が切れるとNoneを返す。つなぎ直したときに読み直してもらうため、ここで終える
+            match listener.try_recv().await? {
+                Some(notification) => self.relay(bus, &notification),
+                None => anyhow::bail!("change listener lost connection"),
+            }
+        }
+    }
+
+    fn relay(&self, bus: &ChangeBus, notification: &PgNotification) {
+        match serde_json::from_str::<Notification>(notification.payload()) {
+            Ok(Notification { origin, event }) if origin != self.origin => bus.relay(event),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("discard malformed change notification: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::{
+        config::Config,
+        create_app,
+        events::ChangeKind,
+        repositories::{
+            event::EventRepositoryForDb,
+            health::HealthRepositoryForDb,
+            label::{LabelRepository, LabelRepositoryForDb},
+            member::MemberRepositoryForDb,
+            todo::{CreateTodo, TodoRepository, TodoRepositoryForDb, UpdateTodo},
+            token::{CreateToken, Scope, TokenRepository, TokenRepositoryForDb},
+            unit_of_work::UnitOfWorkForDb,
+            user::{User, UserRepository, UserRepositoryForDb},
+            webhook::WebhookRepositoryForDb,
+        },
+        Repositories,
+    };
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        Router,
+    };
+    use dotenv::dotenv;
+    use sqlx::postgres::PgConnectOptions;
+    use std::{env, str::FromStr};
+    use tokio::sync::broadcast::{error::TryRecvError, Receiver};
+    use tower::ServiceExt;
+
+    const LISTENER_NAME: &str = "todo-api fanout test";
+
+    async fn recreate_user(pool: &PgPool, name: &str) -> User {
+        sqlx::query("delete from users where name=$1")
+            .bind(name)
+            .execute(pool)
+            .await
+            .expect("[recreate_user] delete returned Err");
+        UserRepositoryForDb::new(pool.clone())
+            .create(name.to_string())
+            .await
+            .expect("[recreate_user] create returned Err")
+    }
+
+    // 他のテストの書き込みも届くので、ワークスペースで絞る
+    async fn next_change(receiver: &mut Receiver<ChangeEvent>, workspace_id: i32) -> ChangeEvent {
+        next_change_within(receiver, workspace_id, Duration::from_secs(5))
+            .await
+            .expect("no change notified")
+    }
+
+    async fn next_change_within(
+        receiver: &mut Receiver<ChangeEvent>,
+        workspace_id: i32,
+        timeout: Duration,
+    ) -> Option<ChangeEvent> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let event = receiver.recv().await.unwrap();
+                if event.workspace_id == workspace_id {
+                    return event;
+                }
+            }
+        })
+        .await
+        .ok()
+    }
+
+    #[tokio::test]
+    async fn should_relay_changes_from_other_instances() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+        let user = recreate_user(&pool, "[fanout relay]").await;
+        let todos = TodoRepositoryForDb::new(pool.clone());
+        let labels = LabelRepositoryForDb::new(pool.clone());
+
+        // 別のインスタンスとして受け取る。接続を切るときに他のテストのLISTENを巻き込まないよう名前を付ける
+        let options = PgConnectOptions::from_str(database_url)
+            .unwrap()
+            .application_name(LISTENER_NAME);
+        let remote = ChangeBus::new();
+        let mut receiver = remote.subscribe();
+        let listener = ChangeListener {
+            pool: PgPool::connect_with(options).await.unwrap(),
+            origin: "other instance".to_string(),
+        };
+        tokio::spawn(listener.run(remote));
+        // 自分の書き込みは流さない
+        let local = ChangeBus::new();
+        let mut local_receiver = local.subscribe();
+        tokio::spawn(ChangeListener::new(pool.clone()).run(local));
+
+        // LISTENを始めるまでの書き込みは届かないので、届くまで書き直す
+        let mut created = Vec::new();
+        let mut todo = None;
+        for _ in 0..20 {
+            created.push(
+                todos
+                    .create(user.id, CreateTodo::new("relayed".to_string()))
+                    .await
+                    .unwrap(),
+            );
+            let event = next_change_within(&mut receiver, user.id, Duration::from_millis(500));
+            if let Some(event) = event.await {
+                assert_eq!(ChangeKind::TodoCreated, event.kind);
+                assert_eq!("relayed", event.data.unwrap()["text"]);
+                todo = created.into_iter().find(|todo| Some(todo.id) == event.id);
+                break;
+            }
+        }
+        let todo = todo.expect("listener did not start");
+        // 届かなかった分の通知はもう届かないが、遅れて届いたものは読み捨てる
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while receiver.try_recv().is_ok() {}
+
+        let updated = todos
+            .update(user.id, todo.id, UpdateTodo::new(None, Some(true)))
+            .await
+            .unwrap();
+        let event = next_change(&mut receiver, user.id).await;
+        assert_eq!(ChangeEvent::new(ChangeKind::TodoUpdated, user.id, todo.id, &updated), event);
+        let event = next_change(&mut receiver, user.id).await;
+        assert_eq!(ChangeKind::TodoCompleted, event.kind);
+
+        let label = labels.create(user.id, "relayed".to_string()).await.unwrap();
+        let event = next_change(&mut receiver, user.id).await;
+        assert_eq!(ChangeEvent::new(ChangeKind::LabelCreated, user.id, label.id, &label), event);
+        todos.delete(user.id, todo.id).await.unwrap();
+        let event = next_change(&mut receiver, user.id).await;
+        assert_eq!(ChangeEvent::deleted(ChangeKind::TodoDeleted, user.id, todo.id), event);
+
+        // 自分の書き込みは届かず、つないだときの読み直しだけが届く
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(ChangeKind::Resync, local_receiver.try_recv().unwrap().kind);
+        assert!(matches!(local_receiver.try_recv(), Err(TryRecvError::Empty)));
+
+        // LISTENしている接続を切っても、つなぎ直して受け取り続ける
+        let terminated = sqlx::query_as::<_, (bool,)>(
+            r#"
+            select pg_terminate_backend(pid) from pg_stat_activity
+            where application_name=$1
+            "#,
+        )
+        .bind(LISTENER_NAME)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert!(terminated.iter().any(|(terminated,)| *terminated));
+        // 切れていた間の変更は届かないので、つなぎ直したらキャッシュと購読者に読み直させる
+        let resync = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if receiver.recv().await.unwrap().kind == ChangeKind::Resync {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(resync.is_ok(), "listener did not resync");
+        let mut reconnected = false;
+        for _ in 0..20 {
+            todos
+                .create(user.id, CreateTodo::new("after reconnect".to_string()))
+                .await
+                .unwrap();
+            let event = next_change_within(&mut receiver, user.id, Duration::from_millis(500));
+            if let Some(event) = event.await {
+                assert_eq!(ChangeKind::TodoCreated, event.kind);
+                assert_eq!("after reconnect", event.data.unwrap()["text"]);
+                reconnected = true;
+                break;
+            }
+        }
+        assert!(reconnected, "listener did not reconnect");
+    }
+
+    // 同じデータベースを共有する別のインスタンスとして立ち上げる
+    fn instance(pool: &PgPool) -> Router {
+        let repositories = Repositories {
+            todo: TodoRepositoryForDb::new(pool.clone()),
+            label: LabelRepositoryForDb::new(pool.clone()),
+            user: UserRepositoryForDb::new(pool.clone()),
+            member: MemberRepositoryForDb::new(pool.clone()),
+            token: TokenRepositoryForDb::new(pool.clone()),
+            health: HealthRepositoryForDb::new(pool.clone()),
+            unit_of_work: UnitOfWorkForDb::new(pool.clone()),
+            event: EventRepositoryForDb::new(pool.clone()),
+            webhook: WebhookRepositoryForDb::new(pool.clone()),
+            listener: None,
+        };
+        create_app(repositories, &Config::default())
+    }
+
+    #[tokio::test]
+    async fn should_stream_events_written_by_other_instances() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+        let user = recreate_user(&pool, "[fanout events]").await;
+        let token = "fanout events token";
+        TokenRepositoryForDb::new(pool.clone())
+            .create(
+                user.id,
+                CreateToken::new("test".to_string(), Scope::ReadWrite, None),
+                token,
+            )
+            .await
+            .unwrap();
+        let writer = instance(&pool);
+        let reader = instance(&pool);
+
+        let req = Request::builder()
+            .uri("/events?types=todo.created")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        // インスタンスを破棄するとイベントを配らなくなるので、読み終えるまで残しておく
+        let res = reader.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let mut body = res.into_body();
+
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(r#"{ "text": "from writer" }"#))
+            .unwrap();
+        let res = writer.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        // 最初に再開位置のIDだけが届き、その後に書き込んだインスタンスのイベントが届く
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            use hyper::body::HttpBody;
+            loop {
+                let chunk = body.data().await.unwrap().unwrap();
+                let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+                if chunk.starts_with("event: todo.created\n") {
+                    return chunk;
+                }
+            }
+        })
+        .await
+        .expect("event from other instance did not arrive");
+        assert!(received.contains(r#""text":"from writer""#));
+    }
+}
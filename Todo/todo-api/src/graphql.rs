@@ -0,0 +1,501 @@
+use async_graphql::{
+    connection::{self, Connection, Edge},
+    dataloader::{DataLoader, Loader},
+    Context, EmptySubscription, ErrorExtensions, InputObject, Object, Result, Schema, SimpleObject,
+};
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+use validator::Validate;
+
+use crate::{
+    events::ChangeBus,
+    handlers::{label, todo},
+    repositories::{
+        is_not_found,
+        label::{Label, LabelRepository},
+        todo::{CreateTodo, Todo, TodoRepository, UpdateTodo},
+        unit_of_work::{Transaction, UnitOfWork},
+    },
+};
+
+// 1ページに返すTodoの上限。`first` を省略した場合もこの件数を返す
+const PAGE_LIMIT: usize = 100;
+// Todo→ラベル→Todo…と入れ子にできる深さの上限
+const DEPTH_LIMIT: usize = 8;
+
+// RESTと同じリポジトリを使うGraphQLのスキーマ。書き込みもRESTと同じく変更を発行する
+pub type TodoSchema<T, L, U> = Schema<Query<T, L, U>, Mutation<T, L, U>, EmptySubscription>;
+
+pub fn schema<T: TodoRepository, L: LabelRepository, U: UnitOfWork>(
+    todos: T,
+    labels: L,
+    unit_of_work: U,
+    bus: ChangeBus,
+) -> TodoSchema<T, L, U> {
+    Schema::build(Query(PhantomData), Mutation(PhantomData), EmptySubscription)
+        .data(todos)
+        .data(labels)
+        .data(unit_of_work)
+        .data(bus)
+        .limit_depth(DEPTH_LIMIT)
+        .finish()
+}
+
+// リクエストしたユーザーの対象のワークスペースと、書き込めるか
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    pub owner_id: i32,
+    pub can_write: bool,
+}
+
+// リクエストごとに呼び出し元とDataLoaderを渡す。DataLoaderはリクエストの間だけ読み込んだ結果を覚える
+pub fn prepare<U: UnitOfWork>(
+    request: async_graphql::Request,
+    unit_of_work: &U,
+    session: Session,
+) -> async_graphql::Request {
+    let labels = TodoLabels {
+        unit_of_work: unit_of_work.clone(),
+        owner_id: session.owner_id,
+    };
+    let todos = LabelTodos {
+        unit_of_work: unit_of_work.clone(),
+        owner_id: session.owner_id,
+    };
+    request
+        .data(session)
+        .data(DataLoader::new(labels, tokio::spawn))
+        .data(DataLoader::new(todos, tokio::spawn))
+}
+
+fn error(code: &'static str, message: &str) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", code))
+}
+
+// 内部のエラーの詳細はログにだけ残す
+fn internal(e: impl std::fmt::Debug) -> async_graphql::Error {
+    tracing::error!("graphql resolver failed: {:?}", e);
+    error("INTERNAL_SERVER_ERROR", "Internal server error")
+}
+
+// RESTと同じく、他のユーザーのデータや存在しないラベルは見つからないものとして扱う
+fn not_found(e: anyhow::Error) -> async_graphql::Error {
+    if !is_not_found(&e) {
+        return internal(e);
+    }
+    error("NOT_FOUND", "Not found")
+}
+
+fn invalid(e: validator::ValidationErrors) -> async_graphql::Error {
+    let message = format!("Validation error: [{}]", e).replace('\n', ", ");
+    error("BAD_REQUEST", &message)
+}
+
+fn writable<'a>(ctx: &Context<'a>) -> Result<&'a Session> {
+    let session = ctx.data_unchecked::<Session>();
+    if !session.can_write {
+        return Err(error("FORBIDDEN", "Permission denied"));
+    }
+    Ok(session)
+}
+
+// Todoごとのラベルを、同じリクエストの中でまとめて1回で読み込む
+pub struct TodoLabels<U> {
+    unit_of_work: U,
+    owner_id: i32,
+}
+
+impl<U: UnitOfWork> Loader<i32> for TodoLabels<U> {
+    type Value = Vec<Label>;
+    type Error = Arc<anyhow::Error>;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Vec<Label>>, Self::Error> {
+        let mut tx = self.unit_of_work.begin().await?;
+        let rows = tx.todos().labels_of(self.owner_id, keys).await?;
+        // 読み取りだけなので取り消して終える
+        tx.rollback().await?;
+
+        let mut labels: HashMap<i32, Vec<Label>> = HashMap::new();
+        for (todo_id, label) in rows {
+            labels.entry(todo_id).or_default().push(label);
+        }
+        Ok(labels)
+    }
+}
+
+// ラベルごとのTodoを、同じリクエストの中でまとめて1回で読み込む
+pub struct LabelTodos<U> {
+    unit_of_work: U,
+    owner_id: i32,
+}
+
+impl<U: UnitOfWork> Loader<i32> for LabelTodos<U> {
+    type Value = Vec<Todo>;
+    type Error = Arc<anyhow::Error>;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Vec<Todo>>, Self::Error> {
+        let mut tx = self.unit_of_work.begin().await?;
+        let rows = tx.todos().todos_of(self.owner_id, keys).await?;
+        tx.rollback().await?;
+
+        let mut todos: HashMap<i32, Vec<Todo>> = HashMap::new();
+        for (label_id, todo) in rows {
+            todos.entry(label_id).or_default().push(todo);
+        }
+        Ok(todos)
+    }
+}
+
+pub struct TodoNode<U>(Todo, PhantomData<U>);
+
+impl<U> TodoNode<U> {
+    fn new(todo: Todo) -> Self {
+        Self(todo, PhantomData)
+    }
+}
+
+#[Object(name = "Todo")]
+impl<U: UnitOfWork> TodoNode<U> {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn text(&self) -> &str {
+        &self.0.text
+    }
+
+    async fn completed(&self) -> bool {
+        self.0.completed
+    }
+
+    async fn labels(&self, ctx: &Context<'_>) -> Result<Vec<LabelNode<U>>> {
+        let loader = ctx.data_unchecked::<DataLoader<TodoLabels<U>>>();
+        let labels = loader.load_one(self.0.id).await.map_err(internal)?;
+        Ok(labels
+            .unwrap_or_default()
+            .into_iter()
+            .map(LabelNode::new)
+            .collect())
+    }
+}
+
+pub struct LabelNode<U>(Label, PhantomData<U>);
+
+impl<U> LabelNode<U> {
+    fn new(label: Label) -> Self {
+        Self(label, PhantomData)
+    }
+}
+
+#[Object(name = "Label")]
+impl<U: UnitOfWork> LabelNode<U> {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    // このラベルが付いたTodoを新しい順に返す
+    async fn todos(&self, ctx: &Context<'_>) -> Result<Vec<TodoNode<U>>> {
+        let loader = ctx.data_unchecked::<DataLoader<LabelTodos<U>>>();
+        let todos = loader.load_one(self.0.id).await.map_err(internal)?;
+        Ok(todos
+            .unwrap_or_default()
+            .into_iter()
+            .map(TodoNode::new)
+            .collect())
+    }
+}
+
+// 指定した条件をすべて満たすTodoに絞る
+#[derive(Debug, Default, InputObject)]
+pub struct TodoFilter {
+    completed: Option<bool>,
+    // 部分一致。大文字と小文字は区別しない
+    text: Option<String>,
+    label_id: Option<i32>,
+}
+
+impl TodoFilter {
+    fn matches(&self, todo: &Todo) -> bool {
+        self.completed
+            .is_none_or(|completed| todo.completed == completed)
+            && self
+                .text
+                .as_ref()
+                .is_none_or(|text| todo.text.to_lowercase().contains(&text.to_lowercase()))
+    }
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct TodoPage {
+    // 絞り込んだ後の、ページに分ける前の件数
+    total_count: usize,
+}
+
+pub struct Query<T, L, U>(PhantomData<(T, L, U)>);
+
+#[Object]
+impl<T: TodoRepository, L: LabelRepository, U: UnitOfWork> Query<T, L, U> {
+    // 新しい順に返す。次のページは前のページの `endCursor` を `after` に渡す
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: TodoFilter,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> Result<Connection<usize, TodoNode<U>, TodoPage>> {
+        let session = ctx.data_unchecked::<Session>();
+        let mut todos = ctx
+            .data_unchecked::<T>()
+            .all(session.owner_id)
+            .await
+            .map_err(internal)?;
+        if let Some(label_id) = filter.label_id {
+            let loader = ctx.data_unchecked::<DataLoader<LabelTodos<U>>>();
+            let labelled = loader.load_one(label_id).await.map_err(internal)?;
+            let labelled = labelled.unwrap_or_default();
+            todos.retain(|todo| labelled.iter().any(|labelled| labelled.id == todo.id));
+        }
+        todos.retain(|todo| filter.matches(todo));
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<usize>, _: Option<usize>, first: Option<usize>, _| async move {
+                let start = after.map_or(0, |after| after + 1).min(todos.len());
+                let end = (start + first.unwrap_or(PAGE_LIMIT).min(PAGE_LIMIT)).min(todos.len());
+                let mut connection = Connection::with_additional_fields(
+                    start > 0,
+                    end < todos.len(),
+                    TodoPage {
+                        total_count: todos.len(),
+                    },
+                );
+                connection.edges.extend(
+                    todos
+                        .drain(start..end)
+                        .enumerate()
+                        .map(|(i, todo)| Edge::new(start + i, TodoNode::new(todo))),
+                );
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+
+    async fn todo(&self, ctx: &Context<'_>, id: i32) -> Result<Option<TodoNode<U>>> {
+        let session = ctx.data_unchecked::<Session>();
+        let todo = ctx.data_unchecked::<T>().find(session.owner_id, id).await;
+        Ok(todo.ok().map(TodoNode::new))
+    }
+
+    async fn labels(&self, ctx: &Context<'_>) -> Result<Vec<LabelNode<U>>> {
+        let session = ctx.data_unchecked::<Session>();
+        let labels = ctx
+            .data_unchecked::<L>()
+            .all(session.owner_id)
+            .await
+            .map_err(internal)?;
+        Ok(labels.into_iter().map(LabelNode::new).collect())
+    }
+}
+
+// ViewerのロールやRead権限のトークンでは書き込めない
+pub struct Mutation<T, L, U>(PhantomData<(T, L, U)>);
+
+#[Object]
+impl<T: TodoRepository, L: LabelRepository, U: UnitOfWork> Mutation<T, L, U> {
+    // `labels` を指定すると、Todoの作成とラベルの付与を1つのトランザクションで行う
+    async fn create_todo(
+        &self,
+        ctx: &Context<'_>,
+        text: String,
+        #[graphql(default)] labels: Vec<i32>,
+    ) -> Result<TodoNode<U>> {
+        let session = writable(ctx)?;
+        let payload = CreateTodo::new(text);
+        payload.validate().map_err(invalid)?;
+        let todo = todo::create(
+            ctx.data_unchecked::<T>(),
+            ctx.data_unchecked::<U>(),
+            ctx.data_unchecked::<ChangeBus>(),
+            session.owner_id,
+            payload,
+            &labels,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(TodoNode::new(todo))
+    }
+
+    async fn update_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        text: Option<String>,
+        completed: Option<bool>,
+    ) -> Result<TodoNode<U>> {
+        let session = writable(ctx)?;
+        let payload = UpdateTodo::new(text, completed);
+        payload.validate().map_err(invalid)?;
+        let todo = todo::update(
+            ctx.data_unchecked::<T>(),
+            ctx.data_unchecked::<ChangeBus>(),
+            session.owner_id,
+            id,
+            payload,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(TodoNode::new(todo))
+    }
+
+    // 付いているラベルを置き換える。存在しないラベルが含まれていれば何も変えない
+    async fn set_todo_labels(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        labels: Vec<i32>,
+    ) -> Result<TodoNode<U>> {
+        let session = writable(ctx)?;
+        let (todo, _) = todo::set_labels(
+            ctx.data_unchecked::<U>(),
+            ctx.data_unchecked::<ChangeBus>(),
+            session.owner_id,
+            id,
+            &labels,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(TodoNode::new(todo))
+    }
+
+    async fn delete_todo(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let session = writable(ctx)?;
+        todo::delete(
+            ctx.data_unchecked::<T>(),
+            ctx.data_unchecked::<ChangeBus>(),
+            session.owner_id,
+            id,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(true)
+    }
+
+    async fn create_label(&self, ctx: &Context<'_>, name: String) -> Result<LabelNode<U>> {
+        let session = writable(ctx)?;
+        let payload = label::CreateLabel::new(name);
+        payload.validate().map_err(invalid)?;
+        let label = label::create(
+            ctx.data_unchecked::<L>(),
+            ctx.data_unchecked::<ChangeBus>(),
+            session.owner_id,
+            payload,
+        )
+        .await
+        .map_err(internal)?;
+        Ok(LabelNode::new(label))
+    }
+
+    async fn delete_label(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let session = writable(ctx)?;
+        label::delete(
+            ctx.data_unchecked::<L>(),
+            ctx.data_unchecked::<ChangeBus>(),
+            session.owner_id,
+            id,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::LabelRepositoryForMemory, memory::MemoryDatabase, todo::TodoRepositoryForMemory,
+        unit_of_work::UnitOfWorkForMemory,
+    };
+    use axum::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // トランザクションを始めた回数を数える
+    #[derive(Clone)]
+    struct CountingUnitOfWork {
+        inner: UnitOfWorkForMemory,
+        begins: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl UnitOfWork for CountingUnitOfWork {
+        type Transaction = <UnitOfWorkForMemory as UnitOfWork>::Transaction;
+
+        async fn begin(&self) -> anyhow::Result<Self::Transaction> {
+            self.begins.fetch_add(1, Ordering::SeqCst);
+            self.inner.begin().await
+        }
+    }
+
+    #[tokio::test]
+    async fn should_batch_relations_into_one_load_per_level() {
+        let database = MemoryDatabase::default();
+        let todos = TodoRepositoryForMemory::with_database(database.clone());
+        let labels = LabelRepositoryForMemory::with_database(database.clone());
+        let unit_of_work = CountingUnitOfWork {
+            inner: UnitOfWorkForMemory::with_database(database),
+            begins: Arc::new(AtomicUsize::new(0)),
+        };
+        let work = labels.create(1, "work".to_string()).await.unwrap();
+        let home = labels.create(1, "home".to_string()).await.unwrap();
+        let mut tx = unit_of_work.begin().await.unwrap();
+        for i in 0..5 {
+            let todo = tx
+                .todos()
+                .create(1, CreateTodo::new(format!("todo {}", i)))
+                .await
+                .unwrap();
+            let label_ids = if i % 2 == 0 {
+                vec![work.id]
+            } else {
+                vec![work.id, home.id]
+            };
+            tx.todos().set_labels(1, todo.id, &label_ids).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+        unit_of_work.begins.store(0, Ordering::SeqCst);
+
+        let schema = schema(todos, labels, unit_of_work.clone(), ChangeBus::new());
+        let request = async_graphql::Request::new(
+            "{ todos { edges { node { labels { name todos { id } } } } } }",
+        );
+        let session = Session {
+            owner_id: 1,
+            can_write: false,
+        };
+        let response = schema
+            .execute(prepare(request, &unit_of_work, session))
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+        let data = response.data.into_json().unwrap();
+        let edges = data["todos"]["edges"].as_array().unwrap();
+        assert_eq!(5, edges.len());
+        assert_eq!(2, edges[1]["node"]["labels"].as_array().unwrap().len());
+        assert_eq!(
+            5,
+            edges[0]["node"]["labels"][0]["todos"]
+                .as_array()
+                .unwrap()
+                .len()
+        );
+        // Todoごとのラベルで1回、ラベルごとのTodoで1回だけ読み込む
+        assert_eq!(2, unit_of_work.begins.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,426 @@
+use axum::{
+    body::{boxed, Body},
+    http::{Request, StatusCode},
+    response::Response,
+};
+use std::{convert::Infallible, pin::Pin, time::Duration};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{codegen::Never, metadata::MetadataMap, Status};
+use tower::{Service, ServiceExt};
+use validator::Validate;
+
+use crate::{
+    events::{ChangeBus, ChangeEvent, ChangeKind},
+    handlers::{label, todo, workspace_role, WORKSPACE_HEADER},
+    repositories::{
+        is_not_found,
+        label::LabelRepository,
+        member::{MemberRepository, Role},
+        todo::{CreateTodo, TodoRepository, UpdateTodo},
+        token::{Credential, Scope, TokenRepository},
+        unit_of_work::{Transaction, UnitOfWork},
+    },
+};
+
+pub mod proto {
+    tonic::include_proto!("todo.v1");
+}
+
+use proto::{
+    todo_event::Kind,
+    todos_server::{Todos, TodosServer},
+};
+
+// RESTと同じポートで受ける。gRPCのリクエストはすべてこのパスに届く
+pub const ROUTE: &str = "/todo.v1.Todos/:method";
+// 購読者に渡す前に溜めておける変更の数
+const WATCH_BUFFER: usize = 64;
+
+// axumのルーターに載せられるように、レスポンスとエラーの型を合わせる
+pub fn service<T, L, U, K, M>(
+    service: TodosService<T, L, U, K, M>,
+) -> impl Service<Request<Body>, Response = Response, Error = Infallible, Future: Send + 'static>
+       + Clone
+       + Send
+       + 'static
+where
+    T: TodoRepository,
+    L: LabelRepository,
+    U: UnitOfWork,
+    K: TokenRepository,
+    M: MemberRepository,
+{
+    let server = TodosServer::new(service);
+    let server = ServiceExt::<Request<Body>>::map_response(server, |response| response.map(boxed));
+    ServiceExt::<Request<Body>>::map_err(server, |never: Never| match never {})
+}
+
+// 書き込みはRESTのハンドラーと同じ関数を通すので、WebSocketやWebhookにも同じ変更が届く
+#[derive(Clone)]
+pub struct TodosService<T, L, U, K, M> {
+    todos: T,
+    labels: L,
+    unit_of_work: U,
+    tokens: K,
+    members: M,
+    bus: ChangeBus,
+    // WatchTodosの購読中に、権限がなくなっていないかを確かめ直す間隔
+    access_check: Duration,
+}
+
+impl<T, L, U, K, M> TodosService<T, L, U, K, M> {
+    pub fn new(
+        todos: T,
+        labels: L,
+        unit_of_work: U,
+        tokens: K,
+        members: M,
+        bus: ChangeBus,
+        access_check: Duration,
+    ) -> Self {
+        Self {
+            todos,
+            labels,
+            unit_of_work,
+            tokens,
+            members,
+            bus,
+            access_check,
+        }
+    }
+}
+
+impl<T, L, U, K, M> TodosService<T, L, U, K, M>
+where
+    K: TokenRepository,
+    M: MemberRepository,
+{
+    // RESTの `Permission` と同じく、参照はViewer以上、書き込みはEditor以上と書き込めるトークンが必要
+    async fn authorize(&self, metadata: &MetadataMap, write: bool) -> Result<i32, Status> {
+        let token =
+            bearer(metadata).ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+        let credential = self.tokens.authenticate(token).await;
+        self.permit(metadata, credential, write).await
+    }
+
+    // 開いているストリームの定期的な確認。トークンの最終利用日時は更新しない
+    async fn reauthorize(&self, metadata: &MetadataMap) -> Result<i32, Status> {
+        let token =
+            bearer(metadata).ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+        let credential = self.tokens.verify(token).await;
+        self.permit(metadata, credential, false).await
+    }
+
+    async fn permit(
+        &self,
+        metadata: &MetadataMap,
+        credential: anyhow::Result<Credential>,
+        write: bool,
+    ) -> Result<i32, Status> {
+        let credential = credential.map_err(|_| Status::unauthenticated("Invalid bearer token"))?;
+        if write && credential.scope == Scope::Read {
+            return Err(Status::permission_denied("Token is read-only"));
+        }
+
+        let workspace = match metadata.get(WORKSPACE_HEADER) {
+            None => None,
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.parse::<i32>().ok())
+                    .ok_or_else(|| Status::invalid_argument("Invalid workspace id"))?,
+            ),
+        };
+        let (owner_id, role) = workspace_role(&self.members, credential.user_id, workspace)
+            .await
+            .map_err(|(status, message)| match status {
+                StatusCode::NOT_FOUND => Status::not_found(message),
+                _ => Status::internal(message),
+            })?;
+
+        let required = if write { Role::Editor } else { Role::Viewer };
+        if role < required {
+            return Err(Status::permission_denied("Permission denied"));
+        }
+        Ok(owner_id)
+    }
+}
+
+fn bearer(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// RESTと同じく、他のユーザーのデータや存在しないラベルは見つからないものとして扱う
+fn not_found(e: anyhow::Error) -> Status {
+    if !is_not_found(&e) {
+        return internal(e);
+    }
+    Status::not_found("Not found")
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    tracing::error!("grpc call failed: {:?}", e);
+    Status::internal("Internal server error")
+}
+
+fn invalid(e: validator::ValidationErrors) -> Status {
+    Status::invalid_argument(format!("Validation error: [{}]", e).replace('\n', ", "))
+}
+
+impl From<crate::repositories::todo::Todo> for proto::Todo {
+    fn from(todo: crate::repositories::todo::Todo) -> Self {
+        Self {
+            id: todo.id,
+            text: todo.text,
+            completed: todo.completed,
+        }
+    }
+}
+
+impl From<crate::repositories::label::Label> for proto::Label {
+    fn from(label: crate::repositories::label::Label) -> Self {
+        Self {
+            id: label.id,
+            name: label.name,
+        }
+    }
+}
+
+fn todo_labels(labels: Vec<crate::repositories::label::Label>) -> proto::TodoLabels {
+    proto::TodoLabels {
+        labels: labels.into_iter().map(Into::into).collect(),
+    }
+}
+
+// ワークスペースのTodoの変更だけを流す。ラベルの変更は流さない
+fn watch_event(event: &ChangeEvent) -> Option<proto::TodoEvent> {
+    let kind = match event.kind {
+        ChangeKind::TodoCreated => Kind::Created,
+        ChangeKind::TodoUpdated => Kind::Updated,
+        ChangeKind::TodoDeleted => Kind::Deleted,
+        ChangeKind::TodoCompleted => Kind::Completed,
+        ChangeKind::WorkspaceImported | ChangeKind::Resync => Kind::Resync,
+        ChangeKind::LabelCreated | ChangeKind::LabelDeleted => return None,
+    };
+    let todo = event
+        .data
+        .clone()
+        .and_then(|data| serde_json::from_value::<crate::repositories::todo::Todo>(data).ok());
+    Some(proto::TodoEvent {
+        kind: kind as i32,
+        todo_id: event.id.unwrap_or_default(),
+        todo: todo.map(Into::into),
+    })
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::TodoEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<T, L, U, K, M> Todos for TodosService<T, L, U, K, M>
+where
+    T: TodoRepository,
+    L: LabelRepository,
+    U: UnitOfWork,
+    K: TokenRepository,
+    M: MemberRepository,
+{
+    async fn create_todo(
+        &self,
+        request: tonic::Request<proto::CreateTodoRequest>,
+    ) -> Result<tonic::Response<proto::Todo>, Status> {
+        let owner_id = self.authorize(request.metadata(), true).await?;
+        let request = request.into_inner();
+        let payload = CreateTodo::new(request.text);
+        payload.validate().map_err(invalid)?;
+        let todo = todo::create(
+            &self.todos,
+            &self.unit_of_work,
+            &self.bus,
+            owner_id,
+            payload,
+            &request.label_ids,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(tonic::Response::new(todo.into()))
+    }
+
+    async fn get_todo(
+        &self,
+        request: tonic::Request<proto::GetTodoRequest>,
+    ) -> Result<tonic::Response<proto::Todo>, Status> {
+        let owner_id = self.authorize(request.metadata(), false).await?;
+        let todo = self
+            .todos
+            .find(owner_id, request.get_ref().id)
+            .await
+            .map_err(not_found)?;
+        Ok(tonic::Response::new(todo.into()))
+    }
+
+    async fn list_todos(
+        &self,
+        request: tonic::Request<proto::ListTodosRequest>,
+    ) -> Result<tonic::Response<proto::ListTodosResponse>, Status> {
+        let owner_id = self.authorize(request.metadata(), false).await?;
+        let completed = request.get_ref().completed;
+        let todos = self.todos.all(owner_id).await.map_err(internal)?;
+        let todos = todos
+            .into_iter()
+            .filter(|todo| completed.is_none_or(|completed| todo.completed == completed))
+            .map(Into::into)
+            .collect();
+        Ok(tonic::Response::new(proto::ListTodosResponse { todos }))
+    }
+
+    async fn update_todo(
+        &self,
+        request: tonic::Request<proto::UpdateTodoRequest>,
+    ) -> Result<tonic::Response<proto::Todo>, Status> {
+        let owner_id = self.authorize(request.metadata(), true).await?;
+        let request = request.into_inner();
+        let payload = UpdateTodo::new(request.text, request.completed);
+        payload.validate().map_err(invalid)?;
+        let todo = todo::update(&self.todos, &self.bus, owner_id, request.id, payload)
+            .await
+            .map_err(not_found)?;
+        Ok(tonic::Response::new(todo.into()))
+    }
+
+    async fn delete_todo(
+        &self,
+        request: tonic::Request<proto::DeleteTodoRequest>,
+    ) -> Result<tonic::Response<proto::DeleteTodoResponse>, Status> {
+        let owner_id = self.authorize(request.metadata(), true).await?;
+        todo::delete(&self.todos, &self.bus, owner_id, request.get_ref().id)
+            .await
+            .map_err(not_found)?;
+        Ok(tonic::Response::new(proto::DeleteTodoResponse {}))
+    }
+
+    async fn get_todo_labels(
+        &self,
+        request: tonic::Request<proto::GetTodoLabelsRequest>,
+    ) -> Result<tonic::Response<proto::TodoLabels>, Status> {
+        let owner_id = self.authorize(request.metadata(), false).await?;
+        let mut tx = self.unit_of_work.begin().await.map_err(internal)?;
+        let labels = tx
+            .todos()
+            .labels(owner_id, request.get_ref().id)
+            .await
+            .map_err(not_found)?;
+        // 読み取りだけなので取り消して終える
+        tx.rollback().await.map_err(internal)?;
+        Ok(tonic::Response::new(todo_labels(labels)))
+    }
+
+    async fn set_todo_labels(
+        &self,
+        request: tonic::Request<proto::SetTodoLabelsRequest>,
+    ) -> Result<tonic::Response<proto::TodoLabels>, Status> {
+        let owner_id = self.authorize(request.metadata(), true).await?;
+        let request = request.into_inner();
+        let (_, labels) = todo::set_labels(
+            &self.unit_of_work,
+            &self.bus,
+            owner_id,
+            request.id,
+            &request.label_ids,
+        )
+        .await
+        .map_err(not_found)?;
+        Ok(tonic::Response::new(todo_labels(labels)))
+    }
+
+    async fn create_label(
+        &self,
+        request: tonic::Request<proto::CreateLabelRequest>,
+    ) -> Result<tonic::Response<proto::Label>, Status> {
+        let owner_id = self.authorize(request.metadata(), true).await?;
+        let payload = label::CreateLabel::new(request.into_inner().name);
+        payload.validate().map_err(invalid)?;
+        let label = label::create(&self.labels, &self.bus, owner_id, payload)
+            .await
+            .map_err(internal)?;
+        Ok(tonic::Response::new(label.into()))
+    }
+
+    async fn list_labels(
+        &self,
+        request: tonic::Request<proto::ListLabelsRequest>,
+    ) -> Result<tonic::Response<proto::ListLabelsResponse>, Status> {
+        let owner_id = self.authorize(request.metadata(), false).await?;
+        let labels = self.labels.all(owner_id).await.map_err(internal)?;
+        Ok(tonic::Response::new(proto::ListLabelsResponse {
+            labels: labels.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn delete_label(
+        &self,
+        request: tonic::Request<proto::DeleteLabelRequest>,
+    ) -> Result<tonic::Response<proto::DeleteLabelResponse>, Status> {
+        let owner_id = self.authorize(request.metadata(), true).await?;
+        label::delete(&self.labels, &self.bus, owner_id, request.get_ref().id)
+            .await
+            .map_err(not_found)?;
+        Ok(tonic::Response::new(proto::DeleteLabelResponse {}))
+    }
+
+    type WatchTodosStream = WatchStream;
+
+    async fn watch_todos(
+        &self,
+        request: tonic::Request<proto::WatchTodosRequest>,
+    ) -> Result<tonic::Response<Self::WatchTodosStream>, Status> {
+        let owner_id = self.authorize(request.metadata(), false).await?;
+        let mut receiver = self.bus.subscribe();
+        let (sender, stream) = mpsc::channel(WATCH_BUFFER);
+        let service = self.clone();
+        let metadata = request.metadata().clone();
+        tokio::spawn(async move {
+            let check = service.access_check;
+            let mut access = tokio::time::interval_at(tokio::time::Instant::now() + check, check);
+            loop {
+                let event = tokio::select! {
+                    event = receiver.recv() => event,
+                    // 新しい変更がなくても、切断されたら終わる
+                    _ = sender.closed() => break,
+                    // トークンを消されたりメンバーから外されたりしたら、その理由で終える
+                    _ = access.tick() => {
+                        let status = match service.reauthorize(&metadata).await {
+                            Ok(id) if id == owner_id => continue,
+                            Ok(_) => Status::not_found("Workspace not found"),
+                            Err(status) => status,
+                        };
+                        let _ = sender.send(Err(status)).await;
+                        break;
+                    }
+                };
+                let event = match event {
+                    Ok(event) if event.concerns(owner_id) => match watch_event(&event) {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                    Ok(_) => continue,
+                    // 読み切れずに落とした変更があるので、全体を読み直してもらう
+                    Err(RecvError::Lagged(_)) => proto::TodoEvent {
+                        kind: Kind::Resync as i32,
+                        ..Default::default()
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                if sender.send(Ok(event)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(ReceiverStream::new(stream))))
+    }
+}
@@ -1,18 +1,42 @@
 use axum::{
     async_trait,
-    extract::{FromRequest, RequestParts},
-    http::StatusCode,
-    BoxError, Json,
+    body::Bytes,
+    extract::{Extension, FromRequest, RequestParts},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, Method, StatusCode,
+    },
+    BoxError,
 };
+use http_body::{LengthLimitError, Limited};
 use serde::de::DeserializeOwned;
+use std::{marker::PhantomData, sync::Arc};
 use validator::Validate;
 
+use crate::repositories::{
+    member::{MemberRepository, Role},
+    token::{Credential, Scope, TokenRepository},
+};
+
+pub mod events;
+pub mod graphql;
+pub mod health;
 pub mod label;
+pub mod member;
+pub mod metrics;
 pub mod todo;
+pub mod token;
+pub mod transfer;
+pub mod user;
+pub mod webhook;
 
 #[derive(Debug)]
 pub struct ValidatedJson<T>(T);
 
+// リクエストボディの上限（バイト）。create_appで設定から登録する
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
 #[async_trait]
 impl<T, B> FromRequest<B> for ValidatedJson<T>
 where
@@ -24,7 +48,15 @@ where
     type Rejection = (StatusCode, String);
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        let Json(value) = Json::<T>::from_request(req).await.map_err(|rejection| {
+        if !has_json_content_type(req) {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected request with `Content-Type: application/json`".to_string(),
+            ));
+        }
+        let bytes = read_body(req).await?;
+
+        let value: T = serde_json::from_slice(&bytes).map_err(|rejection| {
             let message = format!("Json parse error:[{}]", rejection);
             (StatusCode::BAD_REQUEST, message)
         })?;
@@ -35,3 +67,250 @@ where
         Ok(ValidatedJson(value))
     }
 }
+
+// JSON以外のボディをそのまま受け取る。上限は `ValidatedJson` と同じ
+#[derive(Debug)]
+pub struct LimitedBody(pub Bytes);
+
+#[async_trait]
+impl<B> FromRequest<B> for LimitedBody
+where
+    B: http_body::Body + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(LimitedBody(read_body(req).await?))
+    }
+}
+
+async fn read_body<B>(req: &mut RequestParts<B>) -> Result<Bytes, (StatusCode, String)>
+where
+    B: http_body::Body + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let Extension(BodyLimit(limit)) = Extension::<BodyLimit>::from_request(req)
+        .await
+        .map_err(|rejection| (StatusCode::INTERNAL_SERVER_ERROR, rejection.to_string()))?;
+    let body = req.take_body().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Body already extracted".to_string(),
+    ))?;
+    // 上限を超えた時点で読み込みをやめる
+    hyper::body::to_bytes(Limited::new(body, limit))
+        .await
+        .map_err(|e| {
+            if e.downcast_ref::<LengthLimitError>().is_some() {
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Request body is over the limit of {} bytes", limit),
+                )
+            } else {
+                (StatusCode::BAD_REQUEST, format!("Failed to read body:[{}]", e))
+            }
+        })
+}
+
+fn has_json_content_type<B>(req: &RequestParts<B>) -> bool {
+    req.headers()
+        .and_then(|headers| headers.get(CONTENT_TYPE))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .is_some_and(|mime| {
+            mime.type_() == "application"
+                && (mime.subtype() == "json" || mime.suffix().is_some_and(|suffix| suffix == "json"))
+        })
+}
+
+// `Authorization: Bearer <token>` ヘッダーのトークンからリクエストしたユーザーを解決する
+// 読み取り専用のトークンでは参照系（GET）以外のリクエストはできない
+#[derive(Debug)]
+pub struct AuthUser<K> {
+    pub user_id: i32,
+    _repository: PhantomData<K>,
+}
+
+#[async_trait]
+impl<K, B> FromRequest<B> for AuthUser<K>
+where
+    K: TokenRepository,
+    B: Send,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let credential = authenticate::<K, B>(req).await?;
+        if credential.scope == Scope::Read && !is_read_method(req.method()) {
+            return Err((StatusCode::FORBIDDEN, "Token is read-only".to_string()));
+        }
+
+        Ok(AuthUser {
+            user_id: credential.user_id,
+            _repository: PhantomData,
+        })
+    }
+}
+
+async fn authenticate<K: TokenRepository, B: Send>(
+    req: &mut RequestParts<B>,
+) -> Result<Credential, (StatusCode, String)> {
+    let token = req
+        .headers()
+        .and_then(bearer_token)
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing bearer token".to_string()))?;
+    let Extension(repository) = Extension::<Arc<K>>::from_request(req)
+        .await
+        .map_err(|rejection| (StatusCode::INTERNAL_SERVER_ERROR, rejection.to_string()))?;
+    repository
+        .authenticate(&token)
+        .await
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid bearer token".to_string()))
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+// 自分のワークスペースならオーナー、他のユーザーのワークスペースならメンバーとしてのロールを返す
+// メンバーでなければワークスペースの存在自体を隠す
+pub async fn workspace_role<M: MemberRepository>(
+    repository: &M,
+    user_id: i32,
+    workspace: Option<i32>,
+) -> Result<(i32, Role), (StatusCode, String)> {
+    match workspace {
+        None => Ok((user_id, Role::Owner)),
+        Some(owner_id) if owner_id == user_id => Ok((user_id, Role::Owner)),
+        Some(owner_id) => {
+            let member = repository
+                .find(owner_id, user_id)
+                .await
+                .map_err(|_| (StatusCode::NOT_FOUND, "Workspace not found".to_string()))?;
+            Ok((owner_id, member.role))
+        }
+    }
+}
+
+fn is_read_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD)
+}
+
+// 他のユーザーのワークスペースを操作する場合に、そのユーザーのIDを指定する
+pub const WORKSPACE_HEADER: &str = "x-workspace-id";
+
+// すべてのTodo・ラベルのハンドラーはこの権限チェックを通す
+// 対象のワークスペースは `X-Workspace-Id` ヘッダーで指定し、省略時は自分のワークスペースになる
+// 参照系（GET）はViewer以上、それ以外はEditor以上のロールが必要
+#[derive(Debug)]
+pub struct Permission<K, M> {
+    pub owner_id: i32,
+    pub role: Role,
+    _repository: PhantomData<(K, M)>,
+}
+
+impl<K, M> Permission<K, M> {
+    pub fn require(&self, role: Role) -> Result<(), StatusCode> {
+        if self.role < role {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K, M, B> FromRequest<B> for Permission<K, M>
+where
+    K: TokenRepository,
+    M: MemberRepository,
+    B: Send,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let AuthUser { user_id, .. } = AuthUser::<K>::from_request(req).await?;
+        let (owner_id, role) = requested_workspace::<M, B>(req, user_id).await?;
+
+        let required = if is_read_method(req.method()) {
+            Role::Viewer
+        } else {
+            Role::Editor
+        };
+        if role < required {
+            return Err((StatusCode::FORBIDDEN, "Permission denied".to_string()));
+        }
+
+        Ok(Permission {
+            owner_id,
+            role,
+            _repository: PhantomData,
+        })
+    }
+}
+
+// `X-Workspace-Id` ヘッダーで指定されたワークスペースと、そこでのロールを返す
+async fn requested_workspace<M: MemberRepository, B: Send>(
+    req: &mut RequestParts<B>,
+    user_id: i32,
+) -> Result<(i32, Role), (StatusCode, String)> {
+    let workspace = req
+        .headers()
+        .and_then(|headers| headers.get(WORKSPACE_HEADER))
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse::<i32>().ok())
+                .ok_or((StatusCode::BAD_REQUEST, "Invalid workspace id".to_string()))
+        })
+        .transpose()?;
+
+    let Extension(repository) = Extension::<Arc<M>>::from_request(req)
+        .await
+        .map_err(|rejection| (StatusCode::INTERNAL_SERVER_ERROR, rejection.to_string()))?;
+    workspace_role(&*repository, user_id, workspace).await
+}
+
+// GraphQLのように参照も更新も同じPOSTで受けるエンドポイント向けに、メソッドによらず権限を解決する
+// ワークスペースのメンバーであることだけを確かめ、書き込めるかは `can_write` で判断する
+#[derive(Debug)]
+pub struct Caller<K, M> {
+    pub owner_id: i32,
+    pub role: Role,
+    pub scope: Scope,
+    _repository: PhantomData<(K, M)>,
+}
+
+impl<K, M> Caller<K, M> {
+    pub fn can_write(&self) -> bool {
+        self.scope != Scope::Read && self.role >= Role::Editor
+    }
+}
+
+#[async_trait]
+impl<K, M, B> FromRequest<B> for Caller<K, M>
+where
+    K: TokenRepository,
+    M: MemberRepository,
+    B: Send,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let credential = authenticate::<K, B>(req).await?;
+        let (owner_id, role) = requested_workspace::<M, B>(req, credential.user_id).await?;
+
+        Ok(Caller {
+            owner_id,
+            role,
+            scope: credential.scope,
+            _repository: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,366 @@
+use axum::{
+    async_trait,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Extension, FromRequest, Query, RequestParts,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+};
+use serde::Deserialize;
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    events::{ChangeBus, ChangeEvent, ChangeKind, EventFeed, EventFilter},
+    repositories::{
+        event::{EventRepository, StoredEvent},
+        member::MemberRepository,
+        token::{Credential, TokenRepository},
+    },
+};
+
+use super::{bearer_token, workspace_role, WORKSPACE_HEADER};
+
+// EventSourceは再接続するときに、最後に受け取ったイベントのIDをこのヘッダーで送る
+const LAST_EVENT_ID: &str = "last-event-id";
+// 再接続したときに履歴から一度に読み出す件数
+const REPLAY_PAGE: i64 = 500;
+// 全体を読み直してもらうメッセージ
+const RESYNC: &str = r#"{"type":"resync"}"#;
+// 権限がなくなったWebSocketを閉じるときのステータス（Policy Violation）
+const CLOSE_POLICY: u16 = 1008;
+
+// 心拍の間隔。create_appで設定から登録する
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat(pub Duration);
+
+// 購読中の権限を確かめ直す間隔。create_appで設定から登録する
+#[derive(Debug, Clone, Copy)]
+pub struct AccessCheck(pub Duration);
+
+// ブラウザのWebSocketやEventSourceはヘッダーを付けられないので、
+// トークンとワークスペース、再開位置はクエリでも渡せる
+#[derive(Debug, Deserialize)]
+pub struct SubscribeQuery {
+    token: Option<String>,
+    workspace: Option<i32>,
+    #[serde(default)]
+    types: String,
+    last_event_id: Option<i64>,
+}
+
+// 接続中に `{"types": "todo.*"}` を送ると購読するイベントを変えられる
+#[derive(Debug, Deserialize)]
+struct FilterMessage {
+    types: String,
+}
+
+pub async fn subscribe_changes<K: TokenRepository, M: MemberRepository>(
+    ws: WebSocketUpgrade,
+    subscriber: Subscriber<K, M>,
+    Query(query): Query<SubscribeQuery>,
+    Extension(bus): Extension<ChangeBus>,
+    Extension(Heartbeat(interval)): Extension<Heartbeat>,
+    Extension(AccessCheck(check)): Extension<AccessCheck>,
+) -> impl IntoResponse {
+    // 接続を切り替える前に購読して、その間の変更も取りこぼさない
+    let receiver = bus.subscribe();
+    let filter = EventFilter::parse(&query.types);
+    ws.on_upgrade(move |socket| {
+        stream_changes(socket, receiver, subscriber, filter, interval, check)
+    })
+}
+
+// 購読を始めたときのトークンとワークスペース
+// 接続している間も、トークンの削除やメンバーからの削除で閲覧できなくなっていないかを確かめ直す
+// 閲覧できないワークスペースは存在しないものとして扱う
+pub struct Subscriber<K, M> {
+    tokens: Arc<K>,
+    members: Arc<M>,
+    token: String,
+    workspace: Option<i32>,
+    owner_id: i32,
+}
+
+#[async_trait]
+impl<K, M, B> FromRequest<B> for Subscriber<K, M>
+where
+    K: TokenRepository,
+    M: MemberRepository,
+    B: Send,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<SubscribeQuery>::from_request(req)
+            .await
+            .map_err(|rejection| (StatusCode::BAD_REQUEST, rejection.to_string()))?;
+        let Extension(tokens) = Extension::<Arc<K>>::from_request(req)
+            .await
+            .map_err(|rejection| (StatusCode::INTERNAL_SERVER_ERROR, rejection.to_string()))?;
+        let Extension(members) = Extension::<Arc<M>>::from_request(req)
+            .await
+            .map_err(|rejection| (StatusCode::INTERNAL_SERVER_ERROR, rejection.to_string()))?;
+
+        let headers = req.headers();
+        let token = headers
+            .and_then(bearer_token)
+            .or(query.token)
+            .ok_or((StatusCode::UNAUTHORIZED, "Missing token".to_string()))?;
+        let workspace = query
+            .workspace
+            .or_else(|| headers.and_then(|headers| header_value(headers, WORKSPACE_HEADER)));
+        let credential = tokens.authenticate(&token).await;
+        let owner_id = authorize(&*members, credential, workspace).await?;
+        Ok(Self {
+            tokens,
+            members,
+            token,
+            workspace,
+            owner_id,
+        })
+    }
+}
+
+impl<K: TokenRepository, M: MemberRepository> Subscriber<K, M> {
+    // 接続している間は何度も確かめるので、トークンの最終利用日時は更新しない
+    async fn is_allowed(&self) -> bool {
+        let credential = self.tokens.verify(&self.token).await;
+        let result = authorize(&*self.members, credential, self.workspace).await;
+        match result {
+            Ok(owner_id) => owner_id == self.owner_id,
+            Err((_, message)) => {
+                tracing::debug!("subscription revoked: {}", message);
+                false
+            }
+        }
+    }
+}
+
+async fn authorize<M: MemberRepository>(
+    members: &M,
+    credential: anyhow::Result<Credential>,
+    workspace: Option<i32>,
+) -> Result<i32, (StatusCode, String)> {
+    let credential =
+        credential.map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token".to_string()))?;
+    let (owner_id, _) = workspace_role(members, credential.user_id, workspace).await?;
+    Ok(owner_id)
+}
+
+// 最初の確認は接続したときに済ませているので、1周期後から確かめる
+fn access_checks(check: Duration) -> tokio::time::Interval {
+    tokio::time::interval_at(tokio::time::Instant::now() + check, check)
+}
+
+fn header_value<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+async fn stream_changes<K: TokenRepository, M: MemberRepository>(
+    mut socket: WebSocket,
+    mut receiver: Receiver<ChangeEvent>,
+    subscriber: Subscriber<K, M>,
+    mut filter: EventFilter,
+    interval: Duration,
+    check: Duration,
+) {
+    let owner_id = subscriber.owner_id;
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut access = access_checks(check);
+    let mut awaiting_pong = false;
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let message = match event {
+                    // 取りこぼしたかもしれないので、購読しているイベントによらず読み直してもらう
+                    Ok(event) if event.kind == ChangeKind::Resync => RESYNC.to_string(),
+                    Ok(event) if event.workspace_id == owner_id && filter.matches(event.kind) => {
+                        match serde_json::to_string(&event) {
+                            Ok(message) => message,
+                            Err(_) => continue,
+                        }
+                    }
+                    Ok(_) => continue,
+                    // 読み切れずに落としたイベントがあるので、全体を読み直してもらう
+                    Err(RecvError::Lagged(_)) => RESYNC.to_string(),
+                    Err(RecvError::Closed) => break,
+                };
+                if socket.send(Message::Text(message)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(FilterMessage { types }) = serde_json::from_str(&text) {
+                        filter = EventFilter::parse(&types);
+                    }
+                }
+                Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = heartbeat.tick() => {
+                // 前回のpingに応答がなければ切れたとみなす
+                if awaiting_pong || socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+                awaiting_pong = true;
+            }
+            _ = access.tick() => {
+                if !subscriber.is_allowed().await {
+                    let frame = CloseFrame {
+                        code: CLOSE_POLICY,
+                        reason: "Access revoked".into(),
+                    };
+                    let _ = socket.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// WebSocketを使えないクライアント向けのServer-Sent Events
+// イベントには保存したときのIDを付けるので、再接続すると `Last-Event-ID` の後から続きを受け取れる
+pub async fn stream_events<K: TokenRepository, M: MemberRepository, E: EventRepository>(
+    // `HeaderMap` はヘッダーを取り出してしまうので、先に権限を確かめる
+    subscriber: Subscriber<K, M>,
+    headers: HeaderMap,
+    Query(query): Query<SubscribeQuery>,
+    Extension(repository): Extension<Arc<E>>,
+    Extension(feed): Extension<EventFeed>,
+    Extension(Heartbeat(interval)): Extension<Heartbeat>,
+    Extension(AccessCheck(check)): Extension<AccessCheck>,
+) -> impl IntoResponse {
+    let last_event_id = header_value(&headers, LAST_EVENT_ID).or(query.last_event_id);
+
+    // 履歴を読む前に購読して、読んでいる間に保存されたイベントも取りこぼさない
+    let receiver = feed.subscribe();
+    let filter = EventFilter::parse(&query.types);
+    let (sender, events) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let result = follow_events(
+            &*repository,
+            receiver,
+            subscriber,
+            filter,
+            last_event_id,
+            sender,
+            check,
+        )
+        .await;
+        if let Err(e) = result {
+            tracing::debug!("event stream closed: {:?}", e);
+        }
+    });
+    Sse::new(ReceiverStream::new(events)).keep_alive(KeepAlive::new().interval(interval))
+}
+
+type EventSender = mpsc::Sender<Result<Event, Infallible>>;
+
+// 再開位置より後の履歴を送ってから、新しく保存されたイベントを送り続ける
+// 保持期間を過ぎて消した履歴や知らないIDから再開しようとした場合は、
+// 取りこぼしがあるので `resync` を送って全体を読み直してもらう
+// 閲覧できなくなったら終える。EventSourceが再接続しても、認証で断られる
+async fn follow_events<E: EventRepository, K: TokenRepository, M: MemberRepository>(
+    repository: &E,
+    mut receiver: Receiver<StoredEvent>,
+    subscriber: Subscriber<K, M>,
+    filter: EventFilter,
+    last_event_id: Option<i64>,
+    sender: EventSender,
+    check: Duration,
+) -> anyhow::Result<()> {
+    let owner_id = subscriber.owner_id;
+    let (oldest, newest) = repository.bounds().await?.unwrap_or((1, 0));
+    let mut last_id = newest;
+    match last_event_id {
+        // 初めての接続は今の位置から始める。データのないイベントはIDだけを覚えさせる
+        None => sender.send(Ok(Event::default().id(newest.to_string()))).await?,
+        Some(last_event_id) if oldest - 1 <= last_event_id && last_event_id <= newest => {
+            let mut replayed = last_event_id;
+            loop {
+                let events = repository.after(owner_id, replayed, REPLAY_PAGE).await?;
+                for event in &events {
+                    send_event(&sender, event, &filter).await?;
+                }
+                match events.last() {
+                    Some(event) => replayed = event.id,
+                    None => break,
+                }
+                if events.len() < REPLAY_PAGE as usize {
+                    break;
+                }
+            }
+            last_id = last_id.max(replayed);
+        }
+        Some(_) => sender.send(Ok(resync(newest))).await?,
+    }
+
+    let mut access = access_checks(check);
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv() => event,
+            // 新しいイベントがなくても、切断されたら終わる
+            _ = sender.closed() => break,
+            _ = access.tick() => {
+                if !subscriber.is_allowed().await {
+                    break;
+                }
+                continue;
+            }
+        };
+        match event {
+            Ok(event) if event.id > last_id => {
+                last_id = event.id;
+                if event.event.workspace_id == owner_id {
+                    send_event(&sender, &event, &filter).await?;
+                }
+            }
+            // 履歴から送ったイベント
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => {
+                let (_, newest) = repository.bounds().await?.unwrap_or((1, 0));
+                last_id = last_id.max(newest);
+                sender.send(Ok(resync(last_id))).await?;
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    Ok(())
+}
+
+async fn send_event(
+    sender: &EventSender,
+    event: &StoredEvent,
+    filter: &EventFilter,
+) -> anyhow::Result<()> {
+    if !filter.matches(event.event.kind) {
+        return Ok(());
+    }
+    let message = Event::default()
+        .id(event.id.to_string())
+        .event(event.event.kind.as_str())
+        .json_data(&event.event)?;
+    sender.send(Ok(message)).await?;
+    Ok(())
+}
+
+fn resync(last_id: i64) -> Event {
+    Event::default()
+        .id(last_id.to_string())
+        .event("resync")
+        .data(RESYNC)
+}
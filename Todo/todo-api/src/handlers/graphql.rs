@@ -0,0 +1,51 @@
+use async_graphql::http::GraphiQLSource;
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    response::{Html, IntoResponse},
+    Json,
+};
+use std::sync::Arc;
+
+use crate::{
+    graphql::{self, Session, TodoSchema},
+    repositories::{
+        label::LabelRepository, member::MemberRepository, todo::TodoRepository,
+        token::TokenRepository, unit_of_work::UnitOfWork,
+    },
+};
+
+use super::{Caller, LimitedBody};
+
+// 参照も更新も同じPOSTで受けるので、ロールとトークンの権限はリゾルバーで確かめる
+// GraphQLのエラーは200のレスポンスの `errors` で返す
+pub async fn execute_graphql<
+    T: TodoRepository,
+    L: LabelRepository,
+    U: UnitOfWork,
+    K: TokenRepository,
+    M: MemberRepository,
+>(
+    caller: Caller<K, M>,
+    LimitedBody(body): LimitedBody,
+    Extension(schema): Extension<TodoSchema<T, L, U>>,
+    Extension(unit_of_work): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let request: async_graphql::Request = serde_json::from_slice(&body).map_err(|rejection| {
+        let message = format!("Json parse error:[{}]", rejection);
+        (StatusCode::BAD_REQUEST, message)
+    })?;
+    let session = Session {
+        owner_id: caller.owner_id,
+        can_write: caller.can_write(),
+    };
+    let response = schema
+        .execute(graphql::prepare(request, &*unit_of_work, session))
+        .await;
+    Ok(Json(response))
+}
+
+// 開発用のクエリエディター。トークンは画面のHeadersに `Authorization` として入力する
+pub async fn graphiql() -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
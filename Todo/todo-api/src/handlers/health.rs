@@ -0,0 +1,57 @@
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::repositories::health::{HealthRepository, PoolStatus};
+
+// プロセスが応答できるかだけを返し、依存先は確認しない
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+// シャットダウン中かデータベースに接続できない場合は503を返す
+pub async fn readyz<H: HealthRepository>(
+    Extension(repository): Extension<Arc<H>>,
+) -> impl IntoResponse {
+    if !repository.readiness().is_ready() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ReadinessStatus {
+                status: "shutting_down".to_string(),
+                pool: None,
+                error: None,
+            }),
+        );
+    }
+
+    match repository.check().await {
+        Ok(pool) => (
+            StatusCode::OK,
+            Json(ReadinessStatus {
+                status: "ok".to_string(),
+                pool: Some(pool),
+                error: None,
+            }),
+        ),
+        Err(e) => {
+            tracing::warn!("readiness check failed: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ReadinessStatus {
+                    status: "unavailable".to_string(),
+                    pool: None,
+                    error: Some(e.to_string()),
+                }),
+            )
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReadinessStatus {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
@@ -8,38 +8,74 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use validator::Validate;
 
-use crate::repositories::label::LabelRepository;
+use crate::{
+    events::{ChangeBus, ChangeEvent, ChangeKind},
+    repositories::{
+        label::{Label, LabelRepository},
+        member::MemberRepository,
+        token::TokenRepository,
+    },
+};
 
-use super::ValidatedJson;
+use super::{Permission, ValidatedJson};
 
-pub async fn create_label<T: LabelRepository>(
+pub async fn create_label<T: LabelRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     ValidatedJson(payload): ValidatedJson<CreateLabel>,
     Extension(repository): Extension<Arc<T>>,
+    Extension(bus): Extension<ChangeBus>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let label = repository
-        .create(payload.name)
+    let label = create(&*repository, &bus, owner_id, payload)
         .await
         .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-
     Ok((StatusCode::CREATED, Json(label)))
 }
 
-pub async fn all_label<T: LabelRepository>(
+// RESTとGraphQLで共有する書き込み。成功したら変更を発行する
+pub async fn create<T: LabelRepository>(
+    repository: &T,
+    bus: &ChangeBus,
+    owner_id: i32,
+    payload: CreateLabel,
+) -> anyhow::Result<Label> {
+    let label = repository.create(owner_id, payload.name).await?;
+    bus.publish(ChangeEvent::new(ChangeKind::LabelCreated, owner_id, label.id, &label));
+    Ok(label)
+}
+
+pub async fn all_label<T: LabelRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     Extension(repository): Extension<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let labels = repository.all().await.unwrap();
+    let labels = repository
+        .all(owner_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
     Ok((StatusCode::OK, Json(labels)))
 }
 
-pub async fn delete_label<T: LabelRepository>(
+// 他のユーザーのラベルは存在しないものとして404を返す
+pub async fn delete_label<T: LabelRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     Path(id): Path<i32>,
     Extension(repository): Extension<Arc<T>>,
+    Extension(bus): Extension<ChangeBus>,
 ) -> StatusCode {
-    repository
-        .delete(id)
-        .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    match delete(&*repository, &bus, owner_id, id).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+pub async fn delete<T: LabelRepository>(
+    repository: &T,
+    bus: &ChangeBus,
+    owner_id: i32,
+    id: i32,
+) -> anyhow::Result<()> {
+    repository.delete(owner_id, id).await?;
+    bus.publish(ChangeEvent::deleted(ChangeKind::LabelDeleted, owner_id, id));
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Validate)]
@@ -47,4 +83,10 @@ pub struct CreateLabel {
     #[validate(length(min = 1, message = "Can not be empty"))]
     #[validate(length(max = 100, message = "Over text length"))]
     name: String,
-}
\ No newline at end of file
+}
+
+impl CreateLabel {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+}
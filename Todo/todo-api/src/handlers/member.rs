@@ -0,0 +1,92 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use validator::Validate;
+
+use crate::repositories::{
+    member::{MemberRepository, Role},
+    token::TokenRepository,
+    user::UserRepository,
+};
+
+use super::{AuthUser, Permission, ValidatedJson};
+
+// メンバーの招待・削除はワークスペースのオーナーのみ可能
+pub async fn create_member<M: MemberRepository, U: UserRepository, K: TokenRepository>(
+    permission: Permission<K, M>,
+    ValidatedJson(payload): ValidatedJson<InviteMember>,
+    Extension(member_repository): Extension<Arc<M>>,
+    Extension(user_repository): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    permission.require(Role::Owner)?;
+    if payload.role == Role::Owner {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let user = user_repository
+        .find_by_name(&payload.username)
+        .await
+        .or(Err(StatusCode::NOT_FOUND))?;
+    if user.id == permission.owner_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let member = member_repository
+        .save(permission.owner_id, user.id, payload.role)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok((StatusCode::CREATED, Json(member)))
+}
+
+pub async fn all_member<M: MemberRepository, K: TokenRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
+    Extension(repository): Extension<Arc<M>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let members = repository
+        .all(owner_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(members)))
+}
+
+// 削除したメンバーは次のリクエストから権限チェックで弾かれる
+pub async fn delete_member<M: MemberRepository, K: TokenRepository>(
+    permission: Permission<K, M>,
+    Path(user_id): Path<i32>,
+    Extension(repository): Extension<Arc<M>>,
+) -> StatusCode {
+    if let Err(status) = permission.require(Role::Owner) {
+        return status;
+    }
+    repository
+        .delete(permission.owner_id, user_id)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .unwrap_or(StatusCode::NOT_FOUND)
+}
+
+// 自分が招待されているワークスペースの一覧
+pub async fn shared_workspace<M: MemberRepository, K: TokenRepository>(
+    AuthUser { user_id, .. }: AuthUser<K>,
+    Extension(repository): Extension<Arc<M>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let members = repository
+        .shared_with(user_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(members)))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Validate)]
+pub struct InviteMember {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    username: String,
+    role: Role,
+}
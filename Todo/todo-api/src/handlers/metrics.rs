@@ -0,0 +1,26 @@
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use std::sync::Arc;
+
+use crate::{metrics::Metrics, repositories::health::HealthRepository};
+
+// コネクションプールの状態は取得時点の値を記録する
+pub async fn render_metrics<H: HealthRepository>(
+    Extension(metrics): Extension<Metrics>,
+    Extension(repository): Extension<Arc<H>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    metrics.set_pool_status(repository.pool_status());
+    let body = metrics
+        .render()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(prometheus::TEXT_FORMAT),
+    );
+    Ok((headers, body))
+}
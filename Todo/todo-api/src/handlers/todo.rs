@@ -4,58 +4,226 @@ use axum::{
     response::IntoResponse,
     Json,
 };
+use serde::Deserialize;
 use std::sync::Arc;
+use validator::Validate;
 
-use crate::repositories::todo::{CreateTodo, TodoRepository, UpdateTodo};
+use crate::{
+    events::{ChangeBus, ChangeEvent, ChangeKind},
+    repositories::{
+        is_not_found,
+        label::Label,
+        member::MemberRepository,
+        todo::{CreateTodo, Todo, TodoRepository, UpdateTodo},
+        token::TokenRepository,
+        unit_of_work::{Transaction, UnitOfWork},
+    },
+};
+
+use super::{Permission, ValidatedJson};
 
-use super::ValidatedJson;
+// `labels` を指定すると、Todoの作成とラベルの付与を1つのトランザクションで行う
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateTodoWithLabels {
+    #[serde(flatten)]
+    #[validate]
+    todo: CreateTodo,
+    #[serde(default)]
+    labels: Vec<i32>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateTodoLabels {
+    labels: Vec<i32>,
+}
 
-pub async fn create_todo<T: TodoRepository>(
-    ValidatedJson(payload): ValidatedJson<CreateTodo>,
+pub async fn create_todo<
+    T: TodoRepository,
+    K: TokenRepository,
+    M: MemberRepository,
+    U: UnitOfWork,
+>(
+    Permission { owner_id, .. }: Permission<K, M>,
+    ValidatedJson(payload): ValidatedJson<CreateTodoWithLabels>,
     Extension(repository): Extension<Arc<T>>,
+    Extension(unit_of_work): Extension<Arc<U>>,
+    Extension(bus): Extension<ChangeBus>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository
-        .create(payload)
-        .await
-        .or(Err(StatusCode::NOT_FOUND))?;
-
+    let todo = create(
+        &*repository,
+        &*unit_of_work,
+        &bus,
+        owner_id,
+        payload.todo,
+        &payload.labels,
+    )
+    .await
+    .map_err(not_found_or_internal)?;
     Ok((StatusCode::CREATED, Json(todo)))
 }
 
-pub async fn find_todo<T: TodoRepository>(
+// RESTとGraphQLで共有する書き込み。成功したら変更を発行する
+// 存在しないラベルがあればTodoも作らない
+pub async fn create<T: TodoRepository, U: UnitOfWork>(
+    repository: &T,
+    unit_of_work: &U,
+    bus: &ChangeBus,
+    owner_id: i32,
+    payload: CreateTodo,
+    label_ids: &[i32],
+) -> anyhow::Result<Todo> {
+    let todo = if label_ids.is_empty() {
+        repository.create(owner_id, payload).await?
+    } else {
+        let mut tx = unit_of_work.begin().await?;
+        let todo = tx.todos().create(owner_id, payload).await?;
+        tx.todos().set_labels(owner_id, todo.id, label_ids).await?;
+        tx.commit().await?;
+        todo
+    };
+
+    bus.publish(ChangeEvent::new(ChangeKind::TodoCreated, owner_id, todo.id, &todo));
+    Ok(todo)
+}
+
+pub async fn find_todo<T: TodoRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     Path(id): Path<i32>,
     Extension(repository): Extension<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.find(id).await.or(Err(StatusCode::NOT_FOUND))?;
+    let todo = repository
+        .find(owner_id, id)
+        .await
+        .or(Err(StatusCode::NOT_FOUND))?;
     Ok((StatusCode::OK, Json(todo)))
 }
 
-pub async fn all_todo<T: TodoRepository>(
+pub async fn all_todo<T: TodoRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     Extension(repository): Extension<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.all().await.unwrap();
+    let todo = repository
+        .all(owner_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
     Ok((StatusCode::OK, Json(todo)))
 }
 
-pub async fn update_todo<T: TodoRepository>(
+pub async fn update_todo<T: TodoRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     Path(id): Path<i32>,
     ValidatedJson(payload): ValidatedJson<UpdateTodo>,
     Extension(repository): Extension<Arc<T>>,
+    Extension(bus): Extension<ChangeBus>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository
-        .update(id, payload)
+    let todo = update(&*repository, &bus, owner_id, id, payload)
         .await
         .or(Err(StatusCode::NOT_FOUND))?;
     Ok((StatusCode::CREATED, Json(todo)))
 }
 
-pub async fn delete_todo<T: TodoRepository>(
+pub async fn update<T: TodoRepository>(
+    repository: &T,
+    bus: &ChangeBus,
+    owner_id: i32,
+    id: i32,
+    payload: UpdateTodo,
+) -> anyhow::Result<Todo> {
+    // 完了にする場合だけ、前の状態を見て完了に変わったかを判断する
+    let was_completed = match payload.completed() {
+        Some(true) => repository
+            .find(owner_id, id)
+            .await
+            .map(|todo| todo.completed)
+            .unwrap_or(true),
+        _ => true,
+    };
+    let todo = repository.update(owner_id, id, payload).await?;
+    bus.publish(ChangeEvent::new(ChangeKind::TodoUpdated, owner_id, todo.id, &todo));
+    if todo.completed && !was_completed {
+        bus.publish(ChangeEvent::new(ChangeKind::TodoCompleted, owner_id, todo.id, &todo));
+    }
+    Ok(todo)
+}
+
+pub async fn delete_todo<T: TodoRepository, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
     Path(id): Path<i32>,
     Extension(repository): Extension<Arc<T>>,
+    Extension(bus): Extension<ChangeBus>,
 ) -> StatusCode {
-    repository
-        .delete(id)
+    match delete(&*repository, &bus, owner_id, id).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+pub async fn delete<T: TodoRepository>(
+    repository: &T,
+    bus: &ChangeBus,
+    owner_id: i32,
+    id: i32,
+) -> anyhow::Result<()> {
+    repository.delete(owner_id, id).await?;
+    bus.publish(ChangeEvent::deleted(ChangeKind::TodoDeleted, owner_id, id));
+    Ok(())
+}
+
+pub async fn all_todo_label<U: UnitOfWork, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
+    Path(id): Path<i32>,
+    Extension(unit_of_work): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let mut tx = unit_of_work
+        .begin()
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let labels = tx
+        .todos()
+        .labels(owner_id, id)
         .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::NOT_FOUND)
-}
\ No newline at end of file
+        .or(Err(StatusCode::NOT_FOUND))?;
+    // 読み取りだけなので取り消して終える
+    tx.rollback()
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(labels)))
+}
+
+// 付いているラベルを置き換える。存在しないラベルが含まれていれば何も変えない
+pub async fn update_todo_label<U: UnitOfWork, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
+    Path(id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<UpdateTodoLabels>,
+    Extension(unit_of_work): Extension<Arc<U>>,
+    Extension(bus): Extension<ChangeBus>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (_, labels) = set_labels(&*unit_of_work, &bus, owner_id, id, &payload.labels)
+        .await
+        .map_err(not_found_or_internal)?;
+    Ok((StatusCode::OK, Json(labels)))
+}
+
+// 存在しないTodoやラベルは404にし、それ以外の失敗まで404に見せない
+fn not_found_or_internal(e: anyhow::Error) -> StatusCode {
+    if is_not_found(&e) {
+        return StatusCode::NOT_FOUND;
+    }
+    tracing::error!("todo write failed: {:?}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+pub async fn set_labels<U: UnitOfWork>(
+    unit_of_work: &U,
+    bus: &ChangeBus,
+    owner_id: i32,
+    id: i32,
+    label_ids: &[i32],
+) -> anyhow::Result<(Todo, Vec<Label>)> {
+    let mut tx = unit_of_work.begin().await?;
+    let labels = tx.todos().set_labels(owner_id, id, label_ids).await?;
+    let todo = tx.todos().find(owner_id, id).await?;
+    tx.commit().await?;
+    bus.publish(ChangeEvent::new(ChangeKind::TodoUpdated, owner_id, id, &todo));
+    Ok((todo, labels))
+}
@@ -0,0 +1,78 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::repositories::token::{CreateToken, Token, TokenRepository};
+
+use super::{AuthUser, ValidatedJson};
+
+// 平文のトークンを返すのは作成時の一度だけ
+pub async fn create_token<K: TokenRepository>(
+    AuthUser { user_id, .. }: AuthUser<K>,
+    ValidatedJson(payload): ValidatedJson<CreateToken>,
+    Extension(repository): Extension<Arc<K>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if payload.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let plaintext = generate_token();
+    let token = repository
+        .create(user_id, payload, &plaintext)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedToken {
+            token,
+            plaintext,
+        }),
+    ))
+}
+
+pub async fn all_token<K: TokenRepository>(
+    AuthUser { user_id, .. }: AuthUser<K>,
+    Extension(repository): Extension<Arc<K>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let tokens = repository
+        .all(user_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+pub async fn delete_token<K: TokenRepository>(
+    AuthUser { user_id, .. }: AuthUser<K>,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<K>>,
+) -> StatusCode {
+    repository
+        .delete(user_id, id)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .unwrap_or(StatusCode::NOT_FOUND)
+}
+
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub token: Token,
+    #[serde(rename = "token")]
+    pub plaintext: String,
+}
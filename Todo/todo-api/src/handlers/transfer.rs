@@ -0,0 +1,124 @@
+use axum::{
+    extract::{Extension, Query},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::{
+    events::{ChangeBus, ChangeEvent},
+    repositories::{
+        member::{MemberRepository, Role},
+        token::TokenRepository,
+        unit_of_work::UnitOfWork,
+    },
+    transfer::{self, Document, Format, ImportMode, ImportReport},
+};
+
+use super::{LimitedBody, Permission};
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CalendarQuery {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    mode: ImportMode,
+}
+
+pub async fn export_todos<U: UnitOfWork, K: TokenRepository, M: MemberRepository>(
+    Permission { owner_id, .. }: Permission<K, M>,
+    Query(query): Query<ExportQuery>,
+    Extension(unit_of_work): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let data = transfer::export(&*unit_of_work, owner_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let body = data
+        .render(query.format)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(query.format.content_type()),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!(
+            "attachment; filename=\"todos.{}\"",
+            query.format.extension()
+        ))
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
+    );
+    Ok((headers, body))
+}
+
+// カレンダーアプリはヘッダーを付けられないので、トークンをURLに含めて購読する
+// URLが漏れても書き換えられないように、読み取り専用のトークンを使うとよい
+pub async fn calendar_feed<U: UnitOfWork, K: TokenRepository>(
+    Query(query): Query<CalendarQuery>,
+    Extension(tokens): Extension<Arc<K>>,
+    Extension(unit_of_work): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let credential = tokens
+        .authenticate(&query.token)
+        .await
+        .or(Err(StatusCode::UNAUTHORIZED))?;
+    let data = transfer::export(&*unit_of_work, credential.user_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let body = data
+        .render(Format::Ics)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(Format::Ics.content_type()),
+    );
+    Ok((headers, body))
+}
+
+// 取り込めない行が1つでもあれば何も変えず、行ごとのエラーを422で返す
+// 既存のデータを消すreplaceはワークスペースのオーナーだけができる
+pub async fn import_todos<U: UnitOfWork, K: TokenRepository, M: MemberRepository>(
+    permission: Permission<K, M>,
+    Query(query): Query<ImportQuery>,
+    LimitedBody(body): LimitedBody,
+    Extension(unit_of_work): Extension<Arc<U>>,
+    Extension(bus): Extension<ChangeBus>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if query.mode == ImportMode::Replace {
+        permission.require(Role::Owner)?;
+    }
+    let report = match Document::parse(query.format, &body) {
+        Ok(document) => transfer::import(&*unit_of_work, permission.owner_id, document, query.mode)
+            .await
+            .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
+        Err(errors) => ImportReport::rejected(errors),
+    };
+
+    let status = if report.committed {
+        bus.publish(ChangeEvent::imported(permission.owner_id));
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    Ok((status, Json(report)))
+}
@@ -0,0 +1,67 @@
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use validator::Validate;
+
+use crate::repositories::{
+    token::{CreateToken, Scope, TokenRepository},
+    user::UserRepository,
+};
+
+use super::{token::generate_token, ValidatedJson};
+
+// 登録時に読み書きできるトークンを一つ発行し、平文はこのレスポンスでのみ返す
+pub async fn create_user<U: UserRepository, K: TokenRepository>(
+    ValidatedJson(payload): ValidatedJson<CreateUser>,
+    Extension(user_repository): Extension<Arc<U>>,
+    Extension(token_repository): Extension<Arc<K>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let user = user_repository
+        .create(payload.name)
+        .await
+        .or(Err(StatusCode::CONFLICT))?;
+
+    let token = generate_token();
+    let created = token_repository
+        .create(
+            user.id,
+            CreateToken {
+                name: "default".to_string(),
+                scope: Scope::ReadWrite,
+                expires_at: None,
+            },
+            &token,
+        )
+        .await;
+    // トークンを発行できなければ、使えないユーザーが名前を取ったまま残らないよう消しておく
+    if let Err(e) = created {
+        tracing::error!("token creation failed: {:?}", e);
+        if let Err(e) = user_repository.delete(user.id).await {
+            tracing::error!("user cleanup failed: {:?}", e);
+        }
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedUser {
+            id: user.id,
+            name: user.name,
+            token,
+        }),
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Validate)]
+pub struct CreateUser {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CreatedUser {
+    pub id: i32,
+    pub name: String,
+    pub token: String,
+}
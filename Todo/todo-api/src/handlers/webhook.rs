@@ -0,0 +1,92 @@
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::repositories::{
+    member::{MemberRepository, Role},
+    token::TokenRepository,
+    webhook::{CreateWebhook, Webhook, WebhookRepository},
+};
+
+use super::{token::generate_token, Permission, ValidatedJson};
+
+// 配信の一覧で返す最大の件数
+const DELIVERY_PAGE: i64 = 100;
+
+// Webhookはワークスペースの変更を外に送るので、登録・削除・配信の確認はオーナーのみ可能
+// 署名のシークレットを返すのは作成時の一度だけ
+pub async fn create_webhook<W: WebhookRepository, K: TokenRepository, M: MemberRepository>(
+    permission: Permission<K, M>,
+    ValidatedJson(payload): ValidatedJson<CreateWebhook>,
+    Extension(repository): Extension<Arc<W>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    permission.require(Role::Owner)?;
+
+    let secret = generate_token();
+    let webhook = repository
+        .create(permission.owner_id, payload, &secret)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok((StatusCode::CREATED, Json(CreatedWebhook { webhook, secret })))
+}
+
+pub async fn all_webhook<W: WebhookRepository, K: TokenRepository, M: MemberRepository>(
+    permission: Permission<K, M>,
+    Extension(repository): Extension<Arc<W>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    permission.require(Role::Owner)?;
+
+    let webhooks = repository
+        .all(permission.owner_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(webhooks)))
+}
+
+pub async fn delete_webhook<W: WebhookRepository, K: TokenRepository, M: MemberRepository>(
+    permission: Permission<K, M>,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<W>>,
+) -> StatusCode {
+    if let Err(status) = permission.require(Role::Owner) {
+        return status;
+    }
+
+    repository
+        .delete(permission.owner_id, id)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .unwrap_or(StatusCode::NOT_FOUND)
+}
+
+// 新しい順に返す。諦めた配信は `status` が `dead` になる
+pub async fn all_delivery<W: WebhookRepository, K: TokenRepository, M: MemberRepository>(
+    permission: Permission<K, M>,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<W>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    permission.require(Role::Owner)?;
+
+    repository
+        .find(permission.owner_id, id)
+        .await
+        .or(Err(StatusCode::NOT_FOUND))?;
+    let deliveries = repository
+        .deliveries(permission.owner_id, id, DELIVERY_PAGE)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok((StatusCode::OK, Json(deliveries)))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
@@ -0,0 +1,2802 @@
+mod admin;
+mod config;
+mod database;
+mod events;
+mod fanout;
+mod graphql;
+mod grpc;
+mod handlers;
+mod metrics;
+mod repositories;
+mod request_id;
+mod telemetry;
+mod transfer;
+mod webhooks;
+
+use crate::repositories::{
+    cached::{CachedLabelRepository, CachedTodoRepository},
+    event::{
+        EventRepository, EventRepositoryForDb, EventRepositoryForMemory, EventRepositoryForSqlite,
+    },
+    health::{
+        HealthRepository, HealthRepositoryForDb, HealthRepositoryForMemory,
+        HealthRepositoryForSqlite, Readiness,
+    },
+    instrumented::{InstrumentedRepository, InstrumentedUnitOfWork},
+    label::{LabelRepositoryForDb, LabelRepositoryForMemory, LabelRepositoryForSqlite},
+    member::{
+        MemberRepository, MemberRepositoryForDb, MemberRepositoryForMemory,
+        MemberRepositoryForSqlite,
+    },
+    memory::MemoryDatabase,
+    todo::{TodoRepository, TodoRepositoryForDb, TodoRepositoryForMemory, TodoRepositoryForSqlite},
+    token::{
+        TokenRepository, TokenRepositoryForDb, TokenRepositoryForMemory, TokenRepositoryForSqlite,
+    },
+    unit_of_work::{UnitOfWork, UnitOfWorkForDb, UnitOfWorkForMemory, UnitOfWorkForSqlite},
+    user::{UserRepository, UserRepositoryForDb, UserRepositoryForMemory, UserRepositoryForSqlite},
+    webhook::{
+        WebhookRepository, WebhookRepositoryForDb, WebhookRepositoryForMemory,
+        WebhookRepositoryForSqlite,
+    },
+};
+use axum::{
+    extract::Extension,
+    routing::{delete, get, post},
+    Router,
+};
+use events::ChangeBus;
+use fanout::ChangeListener;
+use handlers::{
+    events::{stream_events, subscribe_changes, AccessCheck, Heartbeat},
+    graphql::{execute_graphql, graphiql},
+    health::{healthz, readyz},
+    label::{all_label, create_label, delete_label},
+    member::{all_member, create_member, delete_member, shared_workspace},
+    metrics::render_metrics,
+    todo::{
+        all_todo, all_todo_label, create_todo, delete_todo, find_todo, update_todo,
+        update_todo_label,
+    },
+    token::{all_token, create_token, delete_token},
+    transfer::{calendar_feed, export_todos, import_todos},
+    user::create_user,
+    webhook::{all_delivery, all_webhook, create_webhook, delete_webhook},
+    BodyLimit,
+};
+use axum::middleware::from_fn;
+use clap::Parser;
+use config::{Cli, Command, Config, StorageBackend};
+use database::DatabasePool;
+use metrics::{track_http, Metrics};
+use request_id::trace_request;
+use repositories::label::LabelRepository;
+use std::{future::Future, net::TcpListener, sync::Arc, time::Duration};
+
+use dotenv::dotenv;
+
+// バイナリの `main` から呼ぶ。コマンドライン引数と環境変数から設定を読んでサブコマンドを実行する
+pub async fn start() -> anyhow::Result<()> {
+    dotenv().ok();
+    let mut cli = Cli::parse();
+    let command = cli.command.take().unwrap_or(Command::Serve);
+    let config = Config::load(cli)?;
+
+    telemetry::init(&config)?;
+
+    if config.storage.backend == StorageBackend::Memory {
+        // パスがなければ永続化せず、プロセスの終了とともにデータは消える
+        let memory = match &config.storage.path {
+            Some(path) => MemoryDatabase::open(path, config.storage.snapshot_every)?,
+            None => MemoryDatabase::default(),
+        };
+        let repositories = Repositories {
+            todo: TodoRepositoryForMemory::with_database(memory.clone()),
+            label: LabelRepositoryForMemory::with_database(memory.clone()),
+            user: UserRepositoryForMemory::with_database(memory.clone()),
+            member: MemberRepositoryForMemory::with_database(memory.clone()),
+            token: TokenRepositoryForMemory::with_database(memory.clone()),
+            health: HealthRepositoryForMemory::new(),
+            unit_of_work: UnitOfWorkForMemory::with_database(memory.clone()),
+            event: EventRepositoryForMemory::new(),
+            webhook: WebhookRepositoryForMemory::with_database(memory),
+            listener: None,
+        };
+        let result = run(command, &config, None, repositories).await;
+        telemetry::shutdown().await;
+        return result;
+    }
+
+    let database = DatabasePool::connect(&config.database).await?;
+    let result = match &database {
+        DatabasePool::Postgres(pool) => {
+            let repositories = Repositories {
+                todo: TodoRepositoryForDb::new(pool.clone()),
+                label: LabelRepositoryForDb::new(pool.clone()),
+                user: UserRepositoryForDb::new(pool.clone()),
+                member: MemberRepositoryForDb::new(pool.clone()),
+                token: TokenRepositoryForDb::new(pool.clone()),
+                health: HealthRepositoryForDb::new(pool.clone()),
+                unit_of_work: UnitOfWorkForDb::new(pool.clone()),
+                event: EventRepositoryForDb::new(pool.clone()),
+                webhook: WebhookRepositoryForDb::new(pool.clone()),
+                listener: Some(ChangeListener::new(pool.clone())),
+            };
+            run(command, &config, Some(&database), repositories).await
+        }
+        DatabasePool::Sqlite(pool) => {
+            let repositories = Repositories {
+                todo: TodoRepositoryForSqlite::new(pool.clone()),
+                label: LabelRepositoryForSqlite::new(pool.clone()),
+                user: UserRepositoryForSqlite::new(pool.clone()),
+                member: MemberRepositoryForSqlite::new(pool.clone()),
+                token: TokenRepositoryForSqlite::new(pool.clone()),
+                health: HealthRepositoryForSqlite::new(pool.clone()),
+                unit_of_work: UnitOfWorkForSqlite::new(pool.clone()),
+                event: EventRepositoryForSqlite::new(pool.clone()),
+                webhook: WebhookRepositoryForSqlite::new(pool.clone()),
+                listener: None,
+            };
+            run(command, &config, Some(&database), repositories).await
+        }
+    };
+
+    // 処理中のリクエストを終えてからコネクションを閉じる
+    database.close().await;
+    telemetry::shutdown().await;
+    result
+}
+
+// バックエンドごとのリポジトリをまとめて、サブコマンドの処理をバックエンドに依存させない
+// create_appもこれを受け取り、テストではメモリのリポジトリを渡す
+struct Repositories<Todo, Label, User, Member, Token, Health, Work, Event, Webhook> {
+    todo: Todo,
+    label: Label,
+    user: User,
+    member: Member,
+    token: Token,
+    health: Health,
+    unit_of_work: Work,
+    event: Event,
+    webhook: Webhook,
+    // 他のインスタンスの書き込みを受け取る。同じデータベースを共有できるPostgreSQLの場合だけ使う
+    listener: Option<ChangeListener>,
+}
+
+// テストと `memory_app` では1つの `MemoryDatabase` を共有するリポジトリを使う
+type MemoryRepositories = Repositories<
+    TodoRepositoryForMemory,
+    LabelRepositoryForMemory,
+    UserRepositoryForMemory,
+    MemberRepositoryForMemory,
+    TokenRepositoryForMemory,
+    HealthRepositoryForMemory,
+    UnitOfWorkForMemory,
+    EventRepositoryForMemory,
+    WebhookRepositoryForMemory,
+>;
+
+impl MemoryRepositories {
+    fn memory() -> Self {
+        let memory = MemoryDatabase::default();
+        Repositories {
+            todo: TodoRepositoryForMemory::with_database(memory.clone()),
+            label: LabelRepositoryForMemory::with_database(memory.clone()),
+            user: UserRepositoryForMemory::with_database(memory.clone()),
+            member: MemberRepositoryForMemory::with_database(memory.clone()),
+            token: TokenRepositoryForMemory::with_database(memory.clone()),
+            health: HealthRepositoryForMemory::new(),
+            unit_of_work: UnitOfWorkForMemory::with_database(memory.clone()),
+            event: EventRepositoryForMemory::new(),
+            webhook: WebhookRepositoryForMemory::with_database(memory),
+            listener: None,
+        }
+    }
+}
+
+async fn run<Todo, Label, User, Member, Token, Health, Work, Event, Webhook>(
+    command: Command,
+    config: &Config,
+    database: Option<&DatabasePool>,
+    repositories: Repositories<Todo, Label, User, Member, Token, Health, Work, Event, Webhook>,
+) -> anyhow::Result<()>
+where
+    Todo: TodoRepository,
+    Label: LabelRepository,
+    User: UserRepository,
+    Member: MemberRepository,
+    Token: TokenRepository,
+    Health: HealthRepository,
+    Work: UnitOfWork,
+    Event: EventRepository,
+    Webhook: WebhookRepository,
+{
+    match command {
+        Command::Serve => run_server(config, database, repositories).await,
+        Command::Migrate { action } => match database {
+            Some(database) => admin::migrate(database, action).await,
+            None => anyhow::bail!("migrations are not available for memory storage"),
+        },
+        Command::Seed => {
+            admin::seed(
+                &repositories.user,
+                &repositories.token,
+                &repositories.label,
+                &repositories.todo,
+            )
+            .await
+        }
+        Command::Export {
+            user,
+            output,
+            format,
+        } => {
+            admin::export(
+                &repositories.user,
+                &repositories.unit_of_work,
+                &user,
+                output.as_deref(),
+                format,
+            )
+            .await
+        }
+        Command::Import {
+            user,
+            input,
+            format,
+            mode,
+        } => {
+            admin::import(
+                &repositories.user,
+                &repositories.unit_of_work,
+                &user,
+                input.as_deref(),
+                format,
+                mode,
+            )
+            .await
+        }
+    }
+}
+
+async fn run_server<Todo, Label, User, Member, Token, Health, Work, Event, Webhook>(
+    config: &Config,
+    database: Option<&DatabasePool>,
+    repositories: Repositories<Todo, Label, User, Member, Token, Health, Work, Event, Webhook>,
+) -> anyhow::Result<()>
+where
+    Todo: TodoRepository,
+    Label: LabelRepository,
+    User: UserRepository,
+    Member: MemberRepository,
+    Token: TokenRepository,
+    Health: HealthRepository,
+    Work: UnitOfWork,
+    Event: EventRepository,
+    Webhook: WebhookRepository,
+{
+    // 未適用のマイグレーションがあれば、リクエストを受け付ける前に適用する
+    if let Some(database) = database.filter(|_| config.database.migrate_on_startup) {
+        admin::migrate_up(database).await?;
+        tracing::info!("migrations are up to date");
+    }
+
+    let readiness = repositories.health.readiness().clone();
+    let app = create_app(repositories, config);
+    let addr = config.server.bind_address;
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| anyhow::anyhow!("fail bind address [{}]: {}", addr, e))?;
+    tracing::debug!("listening on {}", addr);
+    serve(
+        listener,
+        app,
+        shutdown_signal(),
+        readiness,
+        config.server.drain_delay(),
+        config.server.shutdown_timeout(),
+    )
+    .await?;
+    tracing::info!("shutdown completed");
+    Ok(())
+}
+
+// SIGTERM（コンテナの停止）かCtrl+Cを待つ
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("fail install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("fail install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// シグナルを受けたらreadinessを落とし、ロードバランサーが振り分けをやめるまで `drain_delay` の間は
+// 新しいリクエストも受け付ける。その後に新しいリクエストを止め、処理中のリクエストが終わるのを
+// `deadline` まで待つ
+async fn serve(
+    listener: TcpListener,
+    app: Router,
+    signal: impl Future<Output = ()>,
+    readiness: Readiness,
+    drain_delay: Duration,
+    deadline: Duration,
+) -> anyhow::Result<()> {
+    let (draining_tx, draining_rx) = tokio::sync::oneshot::channel();
+    let server = axum::Server::from_tcp(listener)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async move {
+            signal.await;
+            readiness.set_ready(false);
+            tracing::info!("shutdown signal received, draining requests in {:?}", drain_delay);
+            tokio::time::sleep(drain_delay).await;
+            tracing::info!("draining requests...");
+            draining_tx.send(()).ok();
+        });
+
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            if draining_rx.await.is_ok() {
+                tokio::time::sleep(deadline).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        } => {
+            tracing::warn!("drain deadline of {:?} exceeded, dropping in-flight requests", deadline);
+        }
+    }
+    Ok(())
+}
+
+type CachedTodos<T> = CachedTodoRepository<InstrumentedRepository<T>>;
+type CachedLabels<T> = CachedLabelRepository<InstrumentedRepository<T>>;
+type InstrumentedWork<T> = InstrumentedUnitOfWork<T>;
+
+// テスト対象を切り出す
+fn create_app<
+    Todo: TodoRepository,
+    Label: LabelRepository,
+    User: UserRepository,
+    Member: MemberRepository,
+    Token: TokenRepository,
+    Health: HealthRepository,
+    Work: UnitOfWork,
+    Event: EventRepository,
+    Webhook: WebhookRepository,
+>(
+    repositories: Repositories<Todo, Label, User, Member, Token, Health, Work, Event, Webhook>,
+    config: &Config,
+) -> Router {
+    //repositoryを引数に取ることで、テスト時にモックを渡せるようにする
+    // TodoとラベルのリポジトリはどのバックエンドでもメトリクスをとるようにInstrumentedRepositoryで包む
+    let metrics = Metrics::new();
+    // 読み込みはさらにキャッシュで包む。キャッシュから返した分はバックエンドの呼び出しに数えない
+    let todo_repository = CachedTodoRepository::new(
+        InstrumentedRepository::new(repositories.todo, "todo", metrics.clone()),
+        "todo",
+        &config.cache,
+        metrics.clone(),
+    );
+    let label_repository = CachedLabelRepository::new(
+        InstrumentedRepository::new(repositories.label, "label", metrics.clone()),
+        "label",
+        &config.cache,
+        metrics.clone(),
+    );
+    // トランザクションの中の操作も同じように記録する
+    let unit_of_work = InstrumentedUnitOfWork::new(repositories.unit_of_work, metrics.clone());
+    let user_repository = repositories.user;
+    let member_repository = repositories.member;
+    let token_repository = repositories.token;
+    let health_repository = repositories.health;
+    let (mut bus, feed) =
+        ChangeBus::with_journal(repositories.event.clone(), config.events.retention());
+    // ユニットオブワークや他のインスタンスの書き込みも、応答を返す前にキャッシュから捨てる
+    bus.observe(todo_repository.clone());
+    bus.observe(label_repository.clone());
+    tokio::spawn(webhooks::dispatch(
+        repositories.webhook.clone(),
+        bus.sink(),
+        config.webhooks.clone(),
+    ));
+    if let Some(listener) = repositories.listener {
+        tokio::spawn(listener.run(bus.clone()));
+    }
+    let schema = graphql::schema(
+        todo_repository.clone(),
+        label_repository.clone(),
+        unit_of_work.clone(),
+        bus.clone(),
+    );
+    let graphql = post(
+        execute_graphql::<
+            CachedTodos<Todo>,
+            CachedLabels<Label>,
+            InstrumentedWork<Work>,
+            Token,
+            Member,
+        >,
+    );
+    let graphql = if config.server.graphiql {
+        graphql.get(graphiql)
+    } else {
+        graphql
+    };
+    // gRPCはRESTと同じポートで、HTTP/2のリクエストとして受ける
+    let grpc = grpc::service(grpc::TodosService::new(
+        todo_repository.clone(),
+        label_repository.clone(),
+        unit_of_work.clone(),
+        token_repository.clone(),
+        member_repository.clone(),
+        bus.clone(),
+        config.events.access_check(),
+    ));
+
+    Router::new()
+        .route("/", get(root))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz::<Health>))
+        .route("/metrics", get(render_metrics::<Health>))
+        .route("/users", post(create_user::<User, Token>))
+        .route(
+            "/todos",
+            post(create_todo::<CachedTodos<Todo>, Token, Member, InstrumentedWork<Work>>)
+                .get(all_todo::<CachedTodos<Todo>, Token, Member>),
+        )
+        .route(
+            "/todos/:id",
+            get(find_todo::<CachedTodos<Todo>, Token, Member>)
+                .delete(delete_todo::<CachedTodos<Todo>, Token, Member>)
+                .patch(update_todo::<CachedTodos<Todo>, Token, Member>),
+        )
+        .route(
+            "/todos/:id/labels",
+            get(all_todo_label::<InstrumentedWork<Work>, Token, Member>)
+                .put(update_todo_label::<InstrumentedWork<Work>, Token, Member>),
+        )
+        .route(
+            "/labels",
+            post(create_label::<CachedLabels<Label>, Token, Member>)
+                .get(all_label::<CachedLabels<Label>, Token, Member>),
+        )
+        .route(
+            "/labels/:id",
+            delete(delete_label::<CachedLabels<Label>, Token, Member>),
+        )
+        .route(
+            "/members",
+            post(create_member::<Member, User, Token>).get(all_member::<Member, Token>),
+        )
+        .route("/members/:user_id", delete(delete_member::<Member, Token>))
+        .route("/workspaces", get(shared_workspace::<Member, Token>))
+        .route("/export", get(export_todos::<InstrumentedWork<Work>, Token, Member>))
+        .route("/import", post(import_todos::<InstrumentedWork<Work>, Token, Member>))
+        .route("/calendar.ics", get(calendar_feed::<InstrumentedWork<Work>, Token>))
+        .route("/ws", get(subscribe_changes::<Token, Member>))
+        .route("/events", get(stream_events::<Token, Member, Event>))
+        .route("/graphql", graphql)
+        .route(grpc::ROUTE, grpc)
+        .route(
+            "/webhooks",
+            post(create_webhook::<Webhook, Token, Member>)
+                .get(all_webhook::<Webhook, Token, Member>),
+        )
+        .route("/webhooks/:id", delete(delete_webhook::<Webhook, Token, Member>))
+        .route(
+            "/webhooks/:id/deliveries",
+            get(all_delivery::<Webhook, Token, Member>),
+        )
+        .route(
+            "/tokens",
+            post(create_token::<Token>).get(all_token::<Token>),
+        )
+        .route("/tokens/:id", delete(delete_token::<Token>))
+        .layer(Extension(Arc::new(todo_repository)))
+        .layer(Extension(Arc::new(label_repository)))
+        .layer(Extension(Arc::new(user_repository)))
+        .layer(Extension(Arc::new(member_repository)))
+        .layer(Extension(Arc::new(token_repository)))
+        .layer(Extension(Arc::new(health_repository)))
+        .layer(Extension(Arc::new(unit_of_work)))
+        .layer(Extension(Arc::new(repositories.event)))
+        .layer(Extension(Arc::new(repositories.webhook)))
+        .layer(Extension(schema))
+        .layer(Extension(bus))
+        .layer(Extension(feed))
+        .layer(Extension(Heartbeat(config.server.ws_heartbeat())))
+        .layer(Extension(AccessCheck(config.events.access_check())))
+        .layer(from_fn(track_http))
+        .layer(Extension(metrics))
+        .layer(from_fn(trace_request))
+        .layer(Extension(BodyLimit(config.server.body_limit)))
+        // 設定は起動時に検証済み
+        .layer(config.cors.layer().expect("invalid cors config"))
+}
+
+// 永続化しないメモリのリポジトリで組み立てたアプリ
+// CLIなど他のクレートのテストから、サーバーをプロセス内で立ち上げるために使う
+pub fn memory_app() -> Router {
+    create_app(MemoryRepositories::memory(), &Config::default())
+}
+
+// ルートハンドラーは `async fn` でなければならない
+async fn root() -> &'static str {
+    "Hello, world!"
+}
+
+//testモジュールはプロダクションコードからは削除される
+#[cfg(test)]
+mod test {
+    use super::*;
+    // use crate::handlers::label;
+    use crate::handlers::health::ReadinessStatus;
+    use crate::repositories::health::{HealthRepositoryForMemory, PoolStatus};
+    use crate::request_id::REQUEST_ID_HEADER;
+    use crate::repositories::label::{LabelRepositoryForMemory, Label};
+    use crate::repositories::member::{MemberRepositoryForMemory, Member, Role};
+    use crate::repositories::todo::{TodoRepositoryForMemory, CreateTodo, Todo, UpdateTodo};
+    use crate::grpc::proto::todos_client::TodosClient;
+    use crate::handlers::{token::CreatedToken, user::CreatedUser, WORKSPACE_HEADER};
+    use crate::repositories::token::{
+        TokenRepositoryForMemory, CreateToken, Credential, Scope, Token,
+    };
+    use crate::repositories::user::UserRepositoryForMemory;
+    use axum::{async_trait, response::Response};
+    use std::net::SocketAddr;
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    // repositories()とtoken_repository()で作成されるユーザー
+    const USER_ID: i32 = 1;
+    const TOKEN: &str = "test_user_token";
+    const OTHER_USER_ID: i32 = 2;
+    const OTHER_TOKEN: &str = "other_user_token";
+
+    async fn token_repository() -> TokenRepositoryForMemory {
+        let repository = TokenRepositoryForMemory::new();
+        for (user_id, token) in [(USER_ID, TOKEN), (OTHER_USER_ID, OTHER_TOKEN)] {
+            repository
+                .create(
+                    user_id,
+                    CreateToken::new("test".to_string(), Scope::ReadWrite, None),
+                    token,
+                )
+                .await
+                .expect("failed create token");
+        }
+        repository
+    }
+
+    // テスト用のユーザーとトークンを作成済みのリポジトリ
+    async fn repositories() -> MemoryRepositories {
+        let repositories = Repositories::memory();
+        for (name, token) in [("test_user", TOKEN), ("other_user", OTHER_TOKEN)] {
+            let user = repositories
+                .user
+                .create(name.to_string())
+                .await
+                .expect("failed create user");
+            repositories
+                .token
+                .create(
+                    user.id,
+                    CreateToken::new("test".to_string(), Scope::ReadWrite, None),
+                    token,
+                )
+                .await
+                .expect("failed create token");
+        }
+        repositories
+    }
+
+    fn build_todo_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .method(method)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::from(json_body))
+            .unwrap()
+    }
+
+    fn build_todo_req_with_empty(method: Method, path: &str) -> Request<Body> {
+        build_req_with_token(method, path, TOKEN)
+    }
+
+    fn build_req_with_token(method: Method, path: &str, token: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .method(method)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn res_to_todo(res: Response) -> Todo {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todo: Todo = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body:{}", body));
+        todo
+    }
+
+    async fn res_to_label(res: Response) -> Label {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let label: Label = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Label instance. body:{}", body));
+        label
+    }
+
+    #[tokio::test]
+    async fn should_create_todo() {
+        let expected = Todo::new(1, "should_return_created_todo".to_string());
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_return_created_todo" }"#.to_string(),
+        );
+        let res = create_app(repositories().await, &Config::default())
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(expected, todo);
+    }
+
+    // create以外はリクエストする前にrepository.create()でデータを作成しておく
+    #[tokio::test]
+    async fn should_find_todo() {
+        let expected = Todo::new(1, "should_find_todo".to_string());
+
+        let repository = TodoRepositoryForMemory::new();
+        repository
+            .create(USER_ID, CreateTodo::new("should_find_todo".to_string()))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = create_app(
+            Repositories {
+                todo: repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(expected, todo);
+    }
+
+    #[tokio::test]
+    async fn should_get_all_todos() {
+        let expected = Todo::new(1, "should_get_all_todos".to_string());
+        let repository = TodoRepositoryForMemory::new();
+
+        repository
+            .create(USER_ID, CreateTodo::new("should_get_all_todos".to_string()))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = create_app(
+            Repositories {
+                todo: repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todo: Vec<Todo> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body:{}", body));
+        assert_eq!(vec![expected], todo);
+    }
+
+    #[tokio::test]
+    async fn should_update_todo() {
+        let expected = Todo::new(1, "should_update_todo".to_string());
+
+        let repository = TodoRepositoryForMemory::new();
+        repository
+            .create(USER_ID, CreateTodo::new("before_update_todo".to_string()))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{
+                "id": 1,
+                "text": "should_update_todo",
+                "completed": false
+            }"#
+            .to_string(),
+        );
+        let res = create_app(
+            Repositories {
+                todo: repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(expected, todo);
+    }
+
+    // 期限日は作成時に指定でき、nullを送ると消える
+    #[tokio::test]
+    async fn should_set_and_clear_due_date() {
+        let app = create_app(repositories().await, &Config::default());
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "pay rent", "due": "2024-03-05" }"#.to_string(),
+        );
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        assert_eq!(chrono::NaiveDate::from_ymd_opt(2024, 3, 5), todo.due);
+
+        // dueを送らなければそのまま残る
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        assert_eq!(chrono::NaiveDate::from_ymd_opt(2024, 3, 5), todo.due);
+
+        let req =
+            build_todo_req_with_json("/todos/1", Method::PATCH, r#"{ "due": null }"#.to_string());
+        let todo = res_to_todo(app.oneshot(req).await.unwrap()).await;
+        assert_eq!(None, todo.due);
+        assert!(todo.completed);
+    }
+
+    #[tokio::test]
+    async fn should_delete_todo() {
+        let repository = TodoRepositoryForMemory::new();
+        repository
+            .create(USER_ID, CreateTodo::new("should_delete_todo".to_string()))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        let res = create_app(
+            Repositories {
+                todo: repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_created_label() {
+        let expected = Label::new(1, "should_created_label".to_string());
+        let req = build_todo_req_with_json(
+            "/labels",
+            Method::POST,
+            r#"{ "name": "should_created_label" }"#.to_string(),
+        );
+        let res = create_app(repositories().await, &Config::default())
+        .oneshot(req)
+        .await
+        .unwrap();
+        let label = res_to_label(res).await;
+        assert_eq!(expected, label);
+    }
+
+    #[tokio::test]
+    async fn should_all_label_readed() {
+        let expected = Label::new(1, "should_all_label_readed".to_string());
+        let label_repository = LabelRepositoryForMemory::new();
+        label_repository
+            .create(USER_ID, "should_all_label_readed".to_string())
+            .await
+            .expect("failed create label");
+        let req = build_todo_req_with_empty(Method::GET, "/labels");
+        let res = create_app(
+            Repositories {
+                label: label_repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let label: Vec<Label> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Label instance. body:{}", body));
+        assert_eq!(vec![expected], label);
+    }
+
+    #[tokio::test]
+    async fn should_delete_label() {
+        let label_repository = LabelRepositoryForMemory::new();
+        label_repository
+            .create(USER_ID, "should_delete_label".to_string())
+            .await
+            .expect("failed create label");
+        let req = build_todo_req_with_empty(Method::DELETE, "/labels/1");
+        let res = create_app(
+            Repositories {
+                label: label_repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_create_todo_with_labels() {
+        let repositories = repositories().await;
+        let label = repositories
+            .label
+            .create(USER_ID, "work".to_string())
+            .await
+            .expect("failed create label");
+        let app = create_app(repositories, &Config::default());
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(r#"{{ "text": "with labels", "labels": [{}] }}"#, label.id),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let todo = res_to_todo(res).await;
+
+        let req = build_todo_req_with_empty(Method::GET, &format!("/todos/{}/labels", todo.id));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(vec![label], labels);
+
+        // ラベルを外す
+        let req = build_todo_req_with_json(
+            &format!("/todos/{}/labels", todo.id),
+            Method::PUT,
+            r#"{ "labels": [] }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        assert!(labels.is_empty());
+    }
+
+    // キャッシュを通らないユニットオブワークでの書き込みの後も、古い一覧を返さない
+    #[tokio::test]
+    async fn should_not_return_stale_todos_after_unit_of_work_writes() {
+        let repositories = repositories().await;
+        let label = repositories
+            .label
+            .create(USER_ID, "work".to_string())
+            .await
+            .expect("failed create label");
+        let app = create_app(repositories, &Config::default());
+        let all_todos = |app: Router| async move {
+            let res = app
+                .oneshot(build_todo_req_with_empty(Method::GET, "/todos"))
+                .await
+                .unwrap();
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice::<Vec<Todo>>(&bytes).unwrap()
+        };
+        assert!(all_todos(app.clone()).await.is_empty());
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(r#"{{ "text": "with labels", "labels": [{}] }}"#, label.id),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let todo = res_to_todo(res).await;
+        assert_eq!(vec![todo], all_todos(app.clone()).await);
+
+        let empty = "record,id,text,completed,todo_id,label_id\n";
+        let res = app
+            .clone()
+            .oneshot(build_import_req("format=csv&mode=replace", TOKEN, empty))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(all_todos(app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_not_create_todo_with_unknown_label() {
+        let repositories = repositories().await;
+        let todo_repository = repositories.todo.clone();
+        // 他のユーザーのラベルも存在しないものとして扱う
+        let label = repositories
+            .label
+            .create(OTHER_USER_ID, "other".to_string())
+            .await
+            .expect("failed create label");
+        let app = create_app(repositories, &Config::default());
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(r#"{{ "text": "with labels", "labels": [{}] }}"#, label.id),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        // Todoの作成も取り消される
+        assert!(todo_repository.all(USER_ID).await.unwrap().is_empty());
+    }
+
+    fn build_import_req(query: &str, token: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .uri(format!("/import?{}", query))
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "text/csv")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_export_and_import_csv() {
+        let repositories = repositories().await;
+        let todo_repository = repositories.todo.clone();
+        let member_repository = repositories.member.clone();
+        let label = repositories
+            .label
+            .create(USER_ID, "work".to_string())
+            .await
+            .expect("failed create label");
+        let app = create_app(repositories, &Config::default());
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(r#"{{ "text": "exported", "labels": [{}] }}"#, label.id),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/export?format=csv");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "text/csv; charset=utf-8",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let csv = String::from_utf8(bytes.to_vec()).unwrap();
+
+        // 別のユーザーのワークスペースへ、IDを振り直して取り込む
+        let req = build_import_req("format=csv", OTHER_TOKEN, &csv);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let imported = todo_repository.all(OTHER_USER_ID).await.unwrap();
+        assert_eq!(1, imported.len());
+        assert_eq!("exported", imported[0].text);
+        let req = build_req_with_token(
+            Method::GET,
+            &format!("/todos/{}/labels", imported[0].id),
+            OTHER_TOKEN,
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(1, labels.len());
+        assert_eq!("work", labels[0].name);
+
+        // 1行でも誤りがあれば何も取り込まず、その行を返す
+        let broken = format!("{}todo,99,,false,,\n", csv);
+        let req = build_import_req("format=csv&mode=replace", OTHER_TOKEN, &broken);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(false, report["committed"]);
+        assert_eq!("line 5", report["errors"][0]["row"]);
+        assert_eq!(1, todo_repository.all(OTHER_USER_ID).await.unwrap().len());
+
+        // replaceはワークスペースのオーナーだけができる
+        member_repository
+            .save(USER_ID, OTHER_USER_ID, Role::Editor)
+            .await
+            .expect("failed save member");
+        let mut req = build_import_req("format=csv&mode=replace", OTHER_TOKEN, &csv);
+        req.headers_mut()
+            .insert(WORKSPACE_HEADER, USER_ID.to_string().parse().unwrap());
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_import_and_export_todotxt() {
+        let app = create_app(repositories().await, &Config::default());
+        let todotxt = "x (A) 2024-03-02 2024-03-01 call mom +family @phone\nbuy milk\n";
+        let res = app
+            .clone()
+            .oneshot(build_import_req("format=todotxt", TOKEN, todotxt))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        // 優先度と日付は保存しないので書き出されない
+        let req = build_todo_req_with_empty(Method::GET, "/export?format=todotxt");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            "x call mom +family @phone\nbuy milk\n",
+            String::from_utf8(bytes.to_vec()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_subscribe_to_calendar_with_token_in_url() {
+        let app = create_app(repositories().await, &Config::default());
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Example//EN\r\n\
+BEGIN:VTODO\r\nUID:a\r\nSUMMARY:from calendar\r\nCATEGORIES:work\r\nEND:VTODO\r\n\
+END:VCALENDAR\r\n";
+        let res = app
+            .clone()
+            .oneshot(build_import_req("format=ics", TOKEN, ics))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = Request::builder()
+            .uri(format!("/calendar.ics?token={}", TOKEN))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "text/calendar; charset=utf-8",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains("SUMMARY:from calendar\r\nSTATUS:NEEDS-ACTION\r\n"));
+        assert!(body.contains("CATEGORIES:work\r\n"));
+
+        let req = Request::builder()
+            .uri("/calendar.ics?token=unknown")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    // 書き込みはoneshotで送り、変更は実際にポートを開いたサーバーからWebSocketで受け取る
+    #[tokio::test]
+    async fn should_push_changes_over_websocket() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::{
+            connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
+        };
+
+        // 心拍のpingは読み飛ばして、次のイベントを返す
+        async fn next_event(
+            socket: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+        ) -> serde_json::Value {
+            loop {
+                let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+                    .await
+                    .expect("no message")
+                    .unwrap()
+                    .unwrap();
+                match message {
+                    Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                    other => panic!("unexpected message {:?}", other),
+                }
+            }
+        }
+
+        let mut config = Config::default();
+        config.server.ws_heartbeat_secs = 1;
+        let app = create_app(repositories().await, &config);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.clone().into_make_service());
+        tokio::spawn(server);
+
+        let err = connect_async(format!("ws://{}/ws?token=unknown", addr))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                tokio_tungstenite::tungstenite::Error::Http(res)
+                    if res.status() == StatusCode::UNAUTHORIZED
+            ),
+            "{:?}",
+            err
+        );
+
+        let url = format!("ws://{}/ws?token={}&types=todo.*", addr, TOKEN);
+        let (mut socket, _) = connect_async(url).await.expect("failed connect");
+
+        // ラベルの変更と他のユーザーのワークスペースの変更は届かない
+        let req =
+            build_todo_req_with_json("/labels", Method::POST, r#"{ "name": "work" }"#.to_string());
+        assert_eq!(StatusCode::CREATED, app.clone().oneshot(req).await.unwrap().status());
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {}", OTHER_TOKEN))
+            .body(Body::from(r#"{ "text": "other" }"#))
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, app.clone().oneshot(req).await.unwrap().status());
+        let req =
+            build_todo_req_with_json("/todos", Method::POST, r#"{ "text": "mine" }"#.to_string());
+        assert_eq!(StatusCode::CREATED, app.clone().oneshot(req).await.unwrap().status());
+
+        let event = next_event(&mut socket).await;
+        assert_eq!("todo.created", event["type"]);
+        assert_eq!(USER_ID, event["workspace_id"]);
+        assert_eq!("mine", event["data"]["text"]);
+
+        // 接続中に購読するイベントを変える
+        socket
+            .send(Message::Text(r#"{ "types": "label.deleted" }"#.to_string()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/2");
+        assert_eq!(StatusCode::NO_CONTENT, app.clone().oneshot(req).await.unwrap().status());
+        let req = build_todo_req_with_empty(Method::DELETE, "/labels/1");
+        assert_eq!(StatusCode::NO_CONTENT, app.clone().oneshot(req).await.unwrap().status());
+        let event = next_event(&mut socket).await;
+        assert_eq!(r#"{"id":1,"type":"label.deleted","workspace_id":1}"#, event.to_string());
+
+        // 心拍のpingが届く
+        let ping = tokio::time::timeout(Duration::from_secs(3), async {
+            while let Some(message) = socket.next().await {
+                if let Message::Ping(_) = message.unwrap() {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+        .expect("no ping");
+        assert!(ping);
+    }
+
+    // イベントは1件ずつ届くので、届いた順に読む
+    async fn next_sse(body: &mut axum::body::BoxBody) -> String {
+        use hyper::body::HttpBody;
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("no event")
+            .unwrap()
+            .unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    fn build_events_req(last_event_id: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder()
+            .uri("/events?types=todo.*,label.created")
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN));
+        if let Some(id) = last_event_id {
+            builder = builder.header("last-event-id", id);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_resume_server_sent_events_from_last_event_id() {
+        let repositories = repositories().await;
+        let events = repositories.event.clone();
+        let app = create_app(repositories, &Config::default());
+
+        // 初めての接続ではIDだけを送り、再開位置を覚えさせる
+        let res = app.clone().oneshot(build_events_req(None)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            mime::TEXT_EVENT_STREAM.as_ref(),
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        let mut body = res.into_body();
+        assert_eq!("id: 0\n\n", next_sse(&mut body).await);
+        let req =
+            build_todo_req_with_json("/todos", Method::POST, r#"{ "text": "first" }"#.to_string());
+        app.clone().oneshot(req).await.unwrap();
+        assert_eq!(
+            "event: todo.created\ndata:{\"type\":\"todo.created\",\"workspace_id\":1,\"id\":1,\
+\"data\":{\"completed\":false,\"due\":null,\"id\":1,\"text\":\"first\"}}\nid: 1\n\n",
+            next_sse(&mut body).await
+        );
+        drop(body);
+
+        // 切断している間の変更は、再接続したときに続きから届く
+        let req =
+            build_todo_req_with_json("/labels", Method::POST, r#"{ "name": "work" }"#.to_string());
+        app.clone().oneshot(req).await.unwrap();
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        app.clone().oneshot(req).await.unwrap();
+        let res = app.clone().oneshot(build_events_req(Some("1"))).await.unwrap();
+        let mut body = res.into_body();
+        assert!(next_sse(&mut body).await.starts_with("event: label.created\n"));
+        assert_eq!(
+            "event: todo.deleted\ndata:{\"type\":\"todo.deleted\",\"workspace_id\":1,\"id\":1}\n\
+id: 3\n\n",
+            next_sse(&mut body).await
+        );
+        drop(body);
+
+        // 保持期間を過ぎて消した履歴や、知らないIDからは再開できないので再同期を求める
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        events.prune(chrono::Utc::now()).await.unwrap();
+        for last_event_id in ["1", "42"] {
+            let res = app
+                .clone()
+                .oneshot(build_events_req(Some(last_event_id)))
+                .await
+                .unwrap();
+            let mut body = res.into_body();
+            assert_eq!(
+                "event: resync\ndata: {\"type\":\"resync\"}\nid: 3\n\n",
+                next_sse(&mut body).await
+            );
+        }
+        let res = app.oneshot(build_events_req(Some("2"))).await.unwrap();
+        let mut body = res.into_body();
+        assert!(next_sse(&mut body).await.ends_with("id: 3\n\n"));
+    }
+
+    // 実際にポートを開いた受け手に送り、署名と送り直し、諦めた配信を確かめる
+    #[tokio::test]
+    async fn should_deliver_signed_webhooks_with_retries() {
+        use crate::webhooks::{sign, DELIVERY_HEADER, EVENT_HEADER, SIGNATURE_HEADER};
+        use axum::{body::Bytes, http::HeaderMap};
+        use std::sync::Mutex;
+
+        // `/flaky` は最初の1回だけ失敗し、`/broken` は常に失敗する
+        type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+        async fn flaky(
+            Extension(received): Extension<Received>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> StatusCode {
+            let mut received = received.lock().unwrap();
+            received.push((headers, body));
+            if received.len() == 1 {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                StatusCode::NO_CONTENT
+            }
+        }
+        async fn broken() -> StatusCode {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+
+        let received = Received::default();
+        let receiver = Router::new()
+            .route("/flaky", post(flaky))
+            .route("/broken", post(broken))
+            .layer(Extension(received.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(receiver.into_make_service()));
+
+        let mut config = Config::default();
+        config.webhooks.max_attempts = 3;
+        config.webhooks.retry_base_ms = 10;
+        config.webhooks.allowed_hosts = vec!["127.0.0.1".to_string()];
+        let app = create_app(repositories().await, &config);
+
+        let body = format!(r#"{{ "url": "http://{}/flaky", "events": "todo.completed" }}"#, addr);
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_json("/webhooks", Method::POST, body))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let created: serde_json::Value = serde_json::from_slice(
+            &hyper::body::to_bytes(res.into_body()).await.unwrap(),
+        )
+        .unwrap();
+        let secret = created["secret"].as_str().unwrap().to_string();
+        let body = format!(r#"{{ "url": "http://{}/broken" }}"#, addr);
+        let res = app
+            .clone()
+            .oneshot(build_todo_req_with_json("/webhooks", Method::POST, body))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        for url in ["not a url", "ftp://example.com/hook"] {
+            let body = format!(r#"{{ "url": "{}" }}"#, url);
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_json("/webhooks", Method::POST, body))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        }
+
+        let req =
+            build_todo_req_with_json("/todos", Method::POST, r#"{ "text": "ship" }"#.to_string());
+        assert_eq!(StatusCode::CREATED, app.clone().oneshot(req).await.unwrap().status());
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        assert_eq!(StatusCode::CREATED, app.clone().oneshot(req).await.unwrap().status());
+
+        // 送り直しと諦めるまでを待つ
+        let deliveries = |id: i32, token: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = Request::builder()
+                    .uri(format!("/webhooks/{}/deliveries", id))
+                    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap();
+                let res = app.oneshot(req).await.unwrap();
+                let status = res.status();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                (status, serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null))
+            }
+        };
+        let settled = |deliveries: &serde_json::Value, count: usize| {
+            let deliveries = deliveries.as_array().unwrap();
+            deliveries.len() == count && deliveries.iter().all(|d| d["status"] != "pending")
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !settled(&deliveries(1, TOKEN).await.1, 1)
+                || !settled(&deliveries(2, TOKEN).await.1, 3)
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("webhook deliveries not settled");
+
+        // 完了したときだけ送り、送り直しても同じ配信IDと署名で届く
+        let received = received.lock().unwrap().clone();
+        assert_eq!(2, received.len());
+        for (headers, body) in &received {
+            assert_eq!(sign(&secret, body), headers[SIGNATURE_HEADER]);
+            assert_eq!("todo.completed", headers[EVENT_HEADER]);
+            assert_eq!(received[0].0[DELIVERY_HEADER], headers[DELIVERY_HEADER]);
+        }
+        let payload: serde_json::Value = serde_json::from_slice(&received[0].1).unwrap();
+        assert_eq!("todo.completed", payload["type"]);
+        assert_eq!(true, payload["data"]["completed"]);
+
+        let (_, flaky) = deliveries(1, TOKEN).await;
+        assert_eq!(received[0].0[DELIVERY_HEADER].to_str().unwrap(), flaky[0]["id"]);
+        assert_eq!("succeeded", flaky[0]["status"]);
+        assert_eq!(2, flaky[0]["attempts"]);
+        assert_eq!(204, flaky[0]["response_status"]);
+
+        let (_, broken) = deliveries(2, TOKEN).await;
+        for delivery in broken.as_array().unwrap() {
+            assert_eq!("dead", delivery["status"]);
+            assert_eq!(3, delivery["attempts"]);
+            assert_eq!(503, delivery["response_status"]);
+        }
+
+        // 他のワークスペースのWebhookは見えない
+        assert_eq!(StatusCode::NOT_FOUND, deliveries(1, OTHER_TOKEN).await.0);
+        let req = Request::builder()
+            .uri("/webhooks/1")
+            .method(Method::DELETE)
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, app.clone().oneshot(req).await.unwrap().status());
+        assert_eq!(StatusCode::NOT_FOUND, deliveries(1, TOKEN).await.0);
+    }
+
+    // 許可していなければ、ループバックのアドレスには名前解決した場合も含めて送らない
+    #[tokio::test]
+    async fn should_refuse_webhooks_to_internal_addresses() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let receiver = Router::new().route(
+            "/hook",
+            post(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { StatusCode::NO_CONTENT }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(receiver.into_make_service()));
+
+        let mut config = Config::default();
+        config.webhooks.max_attempts = 1;
+        let app = create_app(repositories().await, &config);
+        for host in ["127.0.0.1", "localhost"] {
+            let body = format!(r#"{{ "url": "http://{}:{}/hook" }}"#, host, port);
+            let res = app
+                .clone()
+                .oneshot(build_todo_req_with_json("/webhooks", Method::POST, body))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+        let req =
+            build_todo_req_with_json("/todos", Method::POST, r#"{ "text": "ship" }"#.to_string());
+        assert_eq!(StatusCode::CREATED, app.clone().oneshot(req).await.unwrap().status());
+
+        for id in [1, 2] {
+            let deliveries = tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    let req = build_todo_req_with_empty(
+                        Method::GET,
+                        &format!("/webhooks/{}/deliveries", id),
+                    );
+                    let res = app.clone().oneshot(req).await.unwrap();
+                    let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                    let deliveries: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                    if deliveries[0]["status"] == "dead" {
+                        return deliveries;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await
+            .expect("webhook delivery not refused");
+            assert_eq!(serde_json::Value::Null, deliveries[0]["response_status"]);
+        }
+        assert_eq!(0, received.load(Ordering::SeqCst));
+    }
+
+    async fn post_graphql(
+        app: &Router,
+        token: &str,
+        workspace: Option<i32>,
+        query: &str,
+    ) -> serde_json::Value {
+        let body = serde_json::json!({ "query": query }).to_string();
+        let mut req = build_req_with_token(Method::POST, "/graphql", token);
+        req.headers_mut().insert(
+            header::CONTENT_TYPE,
+            mime::APPLICATION_JSON.as_ref().parse().unwrap(),
+        );
+        if let Some(owner_id) = workspace {
+            req.headers_mut()
+                .insert(WORKSPACE_HEADER, owner_id.to_string().parse().unwrap());
+        }
+        *req.body_mut() = Body::from(body);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn error_code(response: &serde_json::Value) -> &str {
+        response["errors"][0]["extensions"]["code"]
+            .as_str()
+            .unwrap_or_else(|| panic!("no error returned: {}", response))
+    }
+
+    #[tokio::test]
+    async fn should_query_and_mutate_with_graphql() {
+        let app = create_app(repositories().await, &Config::default());
+
+        let mutation = r#"mutation { createLabel(name: "work") { id } }"#;
+        let res = post_graphql(&app, TOKEN, None, mutation).await;
+        let label_id = res["data"]["createLabel"]["id"].as_i64().unwrap();
+        let query = format!(
+            r#"mutation {{
+                first: createTodo(text: "Write report", labels: [{}]) {{ id }}
+                second: createTodo(text: "buy milk") {{ id }}
+                third: createTodo(text: "Review report", labels: [{}]) {{ id }}
+            }}"#,
+            label_id, label_id
+        );
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert!(res.get("errors").is_none(), "{}", res);
+        let first_id = res["data"]["first"]["id"].as_i64().unwrap();
+
+        // 1回のリクエストでTodoとラベル、ラベルの付いたTodoまで取得する
+        let query = r#"{
+            todos(first: 2) {
+                totalCount
+                pageInfo { hasNextPage endCursor }
+                edges { node { text labels { name todos { text } } } }
+            }
+        }"#;
+        let res = post_graphql(&app, TOKEN, None, query).await;
+        let todos = &res["data"]["todos"];
+        assert_eq!(3, todos["totalCount"]);
+        assert_eq!(true, todos["pageInfo"]["hasNextPage"]);
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "node": {
+                        "text": "Review report",
+                        "labels": [
+                            { "name": "work", "todos": [
+                                { "text": "Review report" },
+                                { "text": "Write report" },
+                            ] }
+                        ],
+                    }
+                },
+                { "node": { "text": "buy milk", "labels": [] } },
+            ]),
+            todos["edges"]
+        );
+        let query = format!(
+            r#"{{ todos(after: {}) {{ pageInfo {{ hasNextPage }} edges {{ node {{ id }} }} }} }}"#,
+            todos["pageInfo"]["endCursor"]
+        );
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert_eq!(false, res["data"]["todos"]["pageInfo"]["hasNextPage"]);
+        assert_eq!(first_id, res["data"]["todos"]["edges"][0]["node"]["id"]);
+
+        let query = format!(
+            r#"mutation {{ updateTodo(id: {}, completed: true) {{ completed }} }}"#,
+            first_id
+        );
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert_eq!(true, res["data"]["updateTodo"]["completed"]);
+        let query = format!(
+            r#"{{
+                done: todos(filter: {{ completed: true }}) {{ edges {{ node {{ id }} }} }}
+                labelled: todos(filter: {{ labelId: {}, text: "REPORT" }}) {{ totalCount }}
+                unlabelled: todos(filter: {{ text: "milk" }}) {{ totalCount }}
+            }}"#,
+            label_id
+        );
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert_eq!(first_id, res["data"]["done"]["edges"][0]["node"]["id"]);
+        assert_eq!(2, res["data"]["labelled"]["totalCount"]);
+        assert_eq!(1, res["data"]["unlabelled"]["totalCount"]);
+
+        let query = format!(
+            r#"mutation {{ setTodoLabels(id: {}, labels: []) {{ labels {{ id }} }} }}"#,
+            first_id
+        );
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert_eq!(serde_json::json!([]), res["data"]["setTodoLabels"]["labels"]);
+        let query = format!(r#"mutation {{ deleteTodo(id: {}) }}"#, first_id);
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert_eq!(true, res["data"]["deleteTodo"]);
+        let query = format!(r#"{{ todo(id: {}) {{ id }} }}"#, first_id);
+        let res = post_graphql(&app, TOKEN, None, &query).await;
+        assert!(res["data"]["todo"].is_null());
+
+        // RESTと同じ入力の検証をし、他のユーザーのデータは見つからないものとして扱う
+        let res = post_graphql(&app, TOKEN, None, r#"mutation { createTodo(text: "") { id } }"#)
+            .await;
+        assert_eq!("BAD_REQUEST", error_code(&res));
+        let query = format!(r#"mutation {{ deleteLabel(id: {}) }}"#, label_id);
+        let res = post_graphql(&app, OTHER_TOKEN, None, &query).await;
+        assert_eq!("NOT_FOUND", error_code(&res));
+        let res = post_graphql(&app, OTHER_TOKEN, None, "{ todos { totalCount } }").await;
+        assert_eq!(0, res["data"]["todos"]["totalCount"]);
+    }
+
+    #[tokio::test]
+    async fn should_allow_graphql_mutations_only_to_writers() {
+        let (app, _) = shared_app(Role::Viewer).await;
+        let res = post_graphql(&app, OTHER_TOKEN, Some(USER_ID), "{ todo(id: 1) { text } }").await;
+        assert_eq!("shared_todo", res["data"]["todo"]["text"]);
+        let mutation = r#"mutation { createTodo(text: "viewer") { id } }"#;
+        let res = post_graphql(&app, OTHER_TOKEN, Some(USER_ID), mutation).await;
+        assert_eq!("FORBIDDEN", error_code(&res));
+
+        // 読み取り専用のトークンでも同じ
+        let repositories = repositories().await;
+        repositories
+            .token
+            .create(
+                USER_ID,
+                CreateToken::new("read".to_string(), Scope::Read, None),
+                "read-only-token",
+            )
+            .await
+            .expect("failed create token");
+        let app = create_app(repositories, &Config::default());
+        let res = post_graphql(&app, "read-only-token", None, "{ labels { id } }").await;
+        assert_eq!(serde_json::json!([]), res["data"]["labels"]);
+        let res = post_graphql(&app, "read-only-token", None, mutation).await;
+        assert_eq!("FORBIDDEN", error_code(&res));
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(r#"{ "query": "{ labels { id } }" }"#))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_serve_graphiql_only_when_enabled() {
+        let mut config = Config::default();
+        config.server.graphiql = true;
+        let app = create_app(repositories().await, &config);
+        let req = Request::builder().uri("/graphql").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(String::from_utf8(bytes.to_vec()).unwrap().contains("graphiql"));
+
+        config.server.graphiql = false;
+        let app = create_app(repositories().await, &config);
+        let req = Request::builder().uri("/graphql").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+    }
+
+    // gRPCはHTTP/2で話すので、oneshotではなく実際にサーバーを立てて接続する
+    async fn grpc_client(app: Router) -> TodosClient<tonic::transport::Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        TodosClient::connect(format!("http://{}", addr))
+            .await
+            .expect("failed connect")
+    }
+
+    fn grpc_req<T>(message: T, token: &str, workspace: Option<i32>) -> tonic::Request<T> {
+        let mut req = tonic::Request::new(message);
+        let metadata = req.metadata_mut();
+        metadata.insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        if let Some(workspace) = workspace {
+            metadata.insert(WORKSPACE_HEADER, workspace.to_string().parse().unwrap());
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn should_round_trip_todos_and_labels_over_grpc() {
+        use grpc::proto::*;
+
+        let app = create_app(repositories().await, &Config::default());
+        let mut client = grpc_client(app.clone()).await;
+
+        let label = client
+            .create_label(grpc_req(CreateLabelRequest { name: "work".to_string() }, TOKEN, None))
+            .await
+            .unwrap()
+            .into_inner();
+        let create = CreateTodoRequest {
+            text: "grpc".to_string(),
+            label_ids: vec![label.id],
+        };
+        let todo = client.create_todo(grpc_req(create, TOKEN, None)).await.unwrap().into_inner();
+        assert_eq!(Todo { id: todo.id, text: "grpc".to_string(), completed: false }, todo);
+        let found = client
+            .get_todo(grpc_req(GetTodoRequest { id: todo.id }, TOKEN, None))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(todo, found);
+        let labels = client
+            .get_todo_labels(grpc_req(GetTodoLabelsRequest { id: todo.id }, TOKEN, None))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(vec![label.clone()], labels.labels);
+
+        // RESTと同じリポジトリを使っている
+        let req = build_req_with_token(Method::GET, &format!("/todos/{}", todo.id), TOKEN);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let update = UpdateTodoRequest {
+            id: todo.id,
+            text: None,
+            completed: Some(true),
+        };
+        let updated = client.update_todo(grpc_req(update, TOKEN, None)).await.unwrap().into_inner();
+        assert!(updated.completed);
+        let list = |completed| grpc_req(ListTodosRequest { completed }, TOKEN, None);
+        let todos = client.list_todos(list(Some(false))).await.unwrap().into_inner();
+        assert!(todos.todos.is_empty());
+        let todos = client.list_todos(list(None)).await.unwrap().into_inner();
+        assert_eq!(vec![updated], todos.todos);
+
+        let set = SetTodoLabelsRequest {
+            id: todo.id,
+            label_ids: vec![],
+        };
+        let labels = client.set_todo_labels(grpc_req(set, TOKEN, None)).await.unwrap().into_inner();
+        assert!(labels.labels.is_empty());
+        client
+            .delete_label(grpc_req(DeleteLabelRequest { id: label.id }, TOKEN, None))
+            .await
+            .unwrap();
+        let labels = client
+            .list_labels(grpc_req(ListLabelsRequest {}, TOKEN, None))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(labels.labels.is_empty());
+        client
+            .delete_todo(grpc_req(DeleteTodoRequest { id: todo.id }, TOKEN, None))
+            .await
+            .unwrap();
+        let status = client
+            .get_todo(grpc_req(GetTodoRequest { id: todo.id }, TOKEN, None))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+
+        // 存在しないラベルを付けようとすればTodoも作らない
+        let create = CreateTodoRequest {
+            text: "unknown label".to_string(),
+            label_ids: vec![999],
+        };
+        let status = client.create_todo(grpc_req(create, TOKEN, None)).await.unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+        let create = CreateTodoRequest {
+            text: "".to_string(),
+            label_ids: vec![],
+        };
+        let status = client.create_todo(grpc_req(create, TOKEN, None)).await.unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        let todos = client.list_todos(list(None)).await.unwrap().into_inner();
+        assert!(todos.todos.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_allow_grpc_writes_only_to_writers() {
+        use grpc::proto::*;
+
+        let (app, _) = shared_app(Role::Viewer).await;
+        let mut client = grpc_client(app).await;
+        let req = grpc_req(ListTodosRequest { completed: None }, OTHER_TOKEN, Some(USER_ID));
+        let todos = client.list_todos(req).await.unwrap().into_inner();
+        assert_eq!("shared_todo", todos.todos[0].text);
+        let create = || CreateTodoRequest {
+            text: "viewer".to_string(),
+            label_ids: vec![],
+        };
+        let req = grpc_req(create(), OTHER_TOKEN, Some(USER_ID));
+        let status = client.create_todo(req).await.unwrap_err();
+        assert_eq!(tonic::Code::PermissionDenied, status.code());
+        // メンバーでないワークスペースは見つからない
+        let req = grpc_req(ListTodosRequest { completed: None }, TOKEN, Some(OTHER_USER_ID));
+        let status = client.list_todos(req).await.unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+
+        let status = client.create_todo(tonic::Request::new(create())).await.unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+        let status = client.create_todo(grpc_req(create(), "unknown", None)).await.unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+
+        // 読み取り専用のトークンでも書き込めない
+        let repositories = repositories().await;
+        repositories
+            .token
+            .create(
+                USER_ID,
+                CreateToken::new("read".to_string(), Scope::Read, None),
+                "read-only-token",
+            )
+            .await
+            .expect("failed create token");
+        let mut client = grpc_client(create_app(repositories, &Config::default())).await;
+        let req = grpc_req(ListLabelsRequest {}, "read-only-token", None);
+        client.list_labels(req).await.unwrap();
+        let req = grpc_req(create(), "read-only-token", None);
+        let status = client.create_todo(req).await.unwrap_err();
+        assert_eq!(tonic::Code::PermissionDenied, status.code());
+    }
+
+    #[tokio::test]
+    async fn should_watch_todo_changes_over_grpc() {
+        use grpc::proto::{todo_event::Kind, TodoEvent, WatchTodosRequest};
+        use tokio_stream::StreamExt;
+
+        async fn next_event(stream: &mut tonic::Streaming<TodoEvent>) -> TodoEvent {
+            tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("no event")
+                .unwrap()
+                .unwrap()
+        }
+
+        let app = create_app(repositories().await, &Config::default());
+        let mut client = grpc_client(app.clone()).await;
+        let mut stream = client
+            .watch_todos(grpc_req(WatchTodosRequest {}, TOKEN, None))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // 他のユーザーのワークスペースの変更は届かない
+        let mut req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "other" }"#.to_string(),
+        );
+        let bearer = format!("Bearer {}", OTHER_TOKEN);
+        req.headers_mut().insert(header::AUTHORIZATION, bearer.parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        // RESTで書き込んだ変更も届く
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "watched" }"#.to_string(),
+        );
+        let todo = res_to_todo(app.clone().oneshot(req).await.unwrap()).await;
+        let event = next_event(&mut stream).await;
+        assert_eq!(Kind::Created as i32, event.kind);
+        assert_eq!(todo.id, event.todo_id);
+        assert_eq!("watched", event.todo.unwrap().text);
+
+        let req = build_todo_req_with_json(
+            &format!("/todos/{}", todo.id),
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+        assert_eq!(Kind::Updated as i32, next_event(&mut stream).await.kind);
+        assert_eq!(Kind::Completed as i32, next_event(&mut stream).await.kind);
+
+        let req = build_todo_req_with_empty(Method::DELETE, &format!("/todos/{}", todo.id));
+        app.oneshot(req).await.unwrap();
+        let event = next_event(&mut stream).await;
+        assert_eq!(Kind::Deleted as i32, event.kind);
+        assert_eq!(todo.id, event.todo_id);
+        assert_eq!(None, event.todo);
+    }
+
+    #[tokio::test]
+    async fn should_create_user_and_use_token() {
+        let app = create_app(
+            Repositories {
+                user: UserRepositoryForMemory::new(),
+                token: TokenRepositoryForMemory::new(),
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(r#"{ "name": "new_user" }"#))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let user: CreatedUser = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("new_user", user.name);
+
+        let req = build_req_with_token(Method::GET, "/todos", &user.token);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // トークンを発行できないリポジトリ
+    #[derive(Clone)]
+    struct UnavailableTokenRepository;
+
+    #[async_trait]
+    impl TokenRepository for UnavailableTokenRepository {
+        async fn create(&self, _: i32, _: CreateToken, _: &str) -> anyhow::Result<Token> {
+            anyhow::bail!("token store unavailable")
+        }
+
+        async fn all(&self, _: i32) -> anyhow::Result<Vec<Token>> {
+            anyhow::bail!("token store unavailable")
+        }
+
+        async fn delete(&self, _: i32, _: i32) -> anyhow::Result<()> {
+            anyhow::bail!("token store unavailable")
+        }
+
+        async fn authenticate(&self, _: &str) -> anyhow::Result<Credential> {
+            anyhow::bail!("token store unavailable")
+        }
+
+        async fn verify(&self, _: &str) -> anyhow::Result<Credential> {
+            anyhow::bail!("token store unavailable")
+        }
+    }
+
+    // 読み書きできないTodoのリポジトリ
+    #[derive(Clone)]
+    struct UnavailableTodoRepository;
+
+    #[async_trait]
+    impl TodoRepository for UnavailableTodoRepository {
+        async fn create(&self, _: i32, _: CreateTodo) -> anyhow::Result<Todo> {
+            anyhow::bail!("todo store unavailable")
+        }
+
+        async fn find(&self, _: i32, _: i32) -> anyhow::Result<Todo> {
+            anyhow::bail!("todo store unavailable")
+        }
+
+        async fn all(&self, _: i32) -> anyhow::Result<Vec<Todo>> {
+            anyhow::bail!("todo store unavailable")
+        }
+
+        async fn update(&self, _: i32, _: i32, _: UpdateTodo) -> anyhow::Result<Todo> {
+            anyhow::bail!("todo store unavailable")
+        }
+
+        async fn delete(&self, _: i32, _: i32) -> anyhow::Result<()> {
+            anyhow::bail!("todo store unavailable")
+        }
+    }
+
+    #[tokio::test]
+    async fn should_return_internal_server_error_when_listing_todos_fails() {
+        let memory = repositories().await;
+        let app = create_app(
+            Repositories {
+                todo: UnavailableTodoRepository,
+                label: memory.label,
+                user: memory.user,
+                member: memory.member,
+                token: memory.token,
+                health: memory.health,
+                unit_of_work: memory.unit_of_work,
+                event: memory.event,
+                webhook: memory.webhook,
+                listener: None,
+            },
+            &Config::default(),
+        );
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_not_keep_user_when_token_creation_fails() {
+        let memory = Repositories::memory();
+        let user_repository = memory.user.clone();
+        let app = create_app(
+            Repositories {
+                todo: memory.todo,
+                label: memory.label,
+                user: memory.user,
+                member: memory.member,
+                token: UnavailableTokenRepository,
+                health: memory.health,
+                unit_of_work: memory.unit_of_work,
+                event: memory.event,
+                webhook: memory.webhook,
+                listener: None,
+            },
+            &Config::default(),
+        );
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(r#"{ "name": "new_user" }"#))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+
+        // 同じ名前でもう一度登録できる
+        assert!(user_repository.find_by_name("new_user").await.is_err());
+        user_repository
+            .create("new_user".to_string())
+            .await
+            .expect("failed create user");
+    }
+
+    #[tokio::test]
+    async fn should_reject_request_without_valid_token() {
+        let app = create_app(repositories().await, &Config::default());
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        let req = build_req_with_token(Method::GET, "/todos", "unknown_token");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    // 他のユーザーのTodoは403ではなく404として扱う
+    #[tokio::test]
+    async fn should_not_access_other_users_todo() {
+        let repository = TodoRepositoryForMemory::new();
+        repository
+            .create(USER_ID, CreateTodo::new("owner_todo".to_string()))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            Repositories {
+                todo: repository.clone(),
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+
+        let req = build_req_with_token(Method::GET, "/todos/1", OTHER_TOKEN);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        let req = Request::builder()
+            .uri("/todos/1")
+            .method(Method::PATCH)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {}", OTHER_TOKEN))
+            .body(Body::from(r#"{ "completed": true }"#))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        let req = build_req_with_token(Method::DELETE, "/todos/1", OTHER_TOKEN);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        let req = build_req_with_token(Method::GET, "/todos", OTHER_TOKEN);
+        let res = app.oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let todos: Vec<Todo> = serde_json::from_slice(&bytes).unwrap();
+        assert!(todos.is_empty());
+
+        // 所有者のTodoはそのまま残っている
+        let todo = repository.find(USER_ID, 1).await.expect("failed find todo");
+        assert_eq!(Todo::new(1, "owner_todo".to_string()), todo);
+    }
+
+    #[tokio::test]
+    async fn should_not_access_other_users_label() {
+        let label_repository = LabelRepositoryForMemory::new();
+        label_repository
+            .create(USER_ID, "owner_label".to_string())
+            .await
+            .expect("failed create label");
+        label_repository
+            .create(OTHER_USER_ID, "owner_label".to_string())
+            .await
+            .expect("failed create label");
+        let app = create_app(
+            Repositories {
+                label: label_repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+
+        let req = build_req_with_token(Method::GET, "/labels", OTHER_TOKEN);
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(vec![Label::new(2, "owner_label".to_string())], labels);
+
+        let req = build_req_with_token(Method::DELETE, "/labels/1", OTHER_TOKEN);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    fn build_req_in_workspace(
+        method: Method,
+        path: &str,
+        token: &str,
+        owner_id: i32,
+        json_body: Option<&str>,
+    ) -> Request<Body> {
+        let builder = Request::builder()
+            .uri(path)
+            .method(method)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(WORKSPACE_HEADER, owner_id.to_string());
+        match json_body {
+            Some(json_body) => builder
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(json_body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    }
+
+    async fn shared_app(role: Role) -> (Router, MemberRepositoryForMemory) {
+        let repository = TodoRepositoryForMemory::new();
+        repository
+            .create(USER_ID, CreateTodo::new("shared_todo".to_string()))
+            .await
+            .expect("failed create todo");
+        let member_repository = MemberRepositoryForMemory::new();
+        member_repository
+            .save(USER_ID, OTHER_USER_ID, role)
+            .await
+            .expect("failed save member");
+        let app = create_app(
+            Repositories {
+                todo: repository,
+                member: member_repository.clone(),
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+        (app, member_repository)
+    }
+
+    #[tokio::test]
+    async fn should_invite_member_by_username() {
+        let member_repository = MemberRepositoryForMemory::new();
+        let app = create_app(
+            Repositories {
+                member: member_repository.clone(),
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+        let req = build_todo_req_with_json(
+            "/members",
+            Method::POST,
+            r#"{ "username": "other_user", "role": "viewer" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let member: Member = serde_json::from_slice(&bytes).unwrap();
+        let expected = Member {
+            owner_id: USER_ID,
+            user_id: OTHER_USER_ID,
+            role: Role::Viewer,
+        };
+        assert_eq!(expected, member);
+
+        // 招待されたユーザーは共有されたワークスペースを確認できる
+        let req = build_req_with_token(Method::GET, "/workspaces", OTHER_TOKEN);
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let members: Vec<Member> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(vec![expected], members);
+
+        // 存在しないユーザーは招待できない
+        let req = build_todo_req_with_json(
+            "/members",
+            Method::POST,
+            r#"{ "username": "unknown_user", "role": "editor" }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_allow_viewer_to_read_only() {
+        let (app, _) = shared_app(Role::Viewer).await;
+
+        let req = build_req_in_workspace(Method::GET, "/todos/1", OTHER_TOKEN, USER_ID, None);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let todo = res_to_todo(res).await;
+        assert_eq!(Todo::new(1, "shared_todo".to_string()), todo);
+
+        let req = build_req_in_workspace(
+            Method::PATCH,
+            "/todos/1",
+            OTHER_TOKEN,
+            USER_ID,
+            Some(r#"{ "completed": true }"#),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+
+        let req = build_req_in_workspace(Method::DELETE, "/todos/1", OTHER_TOKEN, USER_ID, None);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+
+        let req = build_req_in_workspace(
+            Method::POST,
+            "/labels",
+            OTHER_TOKEN,
+            USER_ID,
+            Some(r#"{ "name": "viewer_label" }"#),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_allow_editor_to_update() {
+        let (app, _) = shared_app(Role::Editor).await;
+
+        let req = build_req_in_workspace(
+            Method::PATCH,
+            "/todos/1",
+            OTHER_TOKEN,
+            USER_ID,
+            Some(r#"{ "text": "edited_by_editor" }"#),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(Todo::new(1, "edited_by_editor".to_string()), todo);
+
+        // オーナー以外はメンバーを招待できない
+        let req = build_req_in_workspace(
+            Method::POST,
+            "/members",
+            OTHER_TOKEN,
+            USER_ID,
+            Some(r#"{ "username": "other_user", "role": "editor" }"#),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_revoke_access_when_member_removed() {
+        let (app, _) = shared_app(Role::Editor).await;
+
+        let req = build_req_in_workspace(Method::GET, "/todos", OTHER_TOKEN, USER_ID, None);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/members/2");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        // メンバーでなくなった時点でワークスペースは見えなくなる
+        let req = build_req_in_workspace(Method::GET, "/todos", OTHER_TOKEN, USER_ID, None);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    // 購読中の接続も、メンバーから外されたら閉じられる
+    #[tokio::test]
+    async fn should_close_streams_when_member_removed() {
+        use futures_util::StreamExt;
+        use grpc::proto::WatchTodosRequest;
+        use hyper::body::HttpBody;
+        use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+        let member_repository = MemberRepositoryForMemory::new();
+        member_repository
+            .save(USER_ID, OTHER_USER_ID, Role::Viewer)
+            .await
+            .expect("failed save member");
+        let mut config = Config::default();
+        config.events.access_check_secs = 1;
+        let app = create_app(
+            Repositories {
+                member: member_repository,
+                ..repositories().await
+            },
+            &config,
+        );
+
+        let req = build_req_in_workspace(Method::GET, "/events", OTHER_TOKEN, USER_ID, None);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let mut body = res.into_body();
+        assert_eq!("id: 0\n\n", next_sse(&mut body).await);
+
+        let mut client = grpc_client(app.clone()).await;
+        let req = grpc_req(WatchTodosRequest {}, OTHER_TOKEN, Some(USER_ID));
+        let mut stream = client.watch_todos(req).await.unwrap().into_inner();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.clone().into_make_service());
+        tokio::spawn(server);
+        let url = format!("ws://{}/ws?token={}&workspace={}", addr, OTHER_TOKEN, USER_ID);
+        let (mut socket, _) = connect_async(url).await.expect("failed connect");
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/members/2");
+        assert_eq!(StatusCode::NO_CONTENT, app.oneshot(req).await.unwrap().status());
+
+        let timeout = Duration::from_secs(5);
+        let chunk = tokio::time::timeout(timeout, body.data()).await.expect("sse not closed");
+        assert!(chunk.is_none());
+        let status = tokio::time::timeout(timeout, stream.next())
+            .await
+            .expect("grpc not closed")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+        let close = tokio::time::timeout(timeout, async {
+            while let Some(message) = socket.next().await {
+                if let Message::Close(frame) = message.unwrap() {
+                    return frame;
+                }
+            }
+            None
+        })
+        .await
+        .expect("websocket not closed")
+        .expect("no close frame");
+        assert_eq!(1008, u16::from(close.code));
+    }
+
+    // 接続中の確認ではトークンの最終利用日時を更新しない
+    #[tokio::test]
+    async fn should_not_touch_token_on_access_checks() {
+        use grpc::proto::WatchTodosRequest;
+        use hyper::body::HttpBody;
+
+        let repositories = repositories().await;
+        let token_repository = repositories.token.clone();
+        let mut config = Config::default();
+        config.events.access_check_secs = 1;
+        let app = create_app(repositories, &config);
+        let last_used = || async { token_repository.all(USER_ID).await.unwrap()[0].last_used_at };
+
+        let req = build_todo_req_with_empty(Method::GET, "/events");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let mut body = res.into_body();
+        assert_eq!("id: 0\n\n", next_sse(&mut body).await);
+        let mut client = grpc_client(app).await;
+        let req = grpc_req(WatchTodosRequest {}, TOKEN, None);
+        let _stream = client.watch_todos(req).await.unwrap().into_inner();
+        let connected = last_used().await;
+        assert!(connected.is_some());
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(connected, last_used().await);
+        // 確認を通ったので、ストリームは開いたまま
+        let chunk = tokio::time::timeout(Duration::from_millis(100), body.data()).await;
+        assert!(chunk.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_issue_and_revoke_personal_access_token() {
+        let app = create_app(repositories().await, &Config::default());
+        let req = build_todo_req_with_json(
+            "/tokens",
+            Method::POST,
+            r#"{ "name": "cron", "scope": "read", "expires_at": null }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let created: CreatedToken = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("cron", created.token.name);
+
+        // 読み取り専用のトークンは参照のみ可能
+        let req = build_req_with_token(Method::GET, "/todos", &created.plaintext);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {}", created.plaintext))
+            .body(Body::from(r#"{ "text": "read_only" }"#))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+
+        // 一覧には平文を含めず、最終利用日時が記録される
+        let req = build_todo_req_with_empty(Method::GET, "/tokens");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(!body.contains(&created.plaintext));
+        let tokens: Vec<Token> = serde_json::from_str(&body).unwrap();
+        let token = tokens.iter().find(|token| token.id == created.token.id).unwrap();
+        assert!(token.last_used_at.is_some());
+
+        // 削除したトークンは使えなくなる
+        let req = build_todo_req_with_empty(
+            Method::DELETE,
+            &format!("/tokens/{}", created.token.id),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        let req = build_req_with_token(Method::GET, "/todos", &created.plaintext);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_expired_token() {
+        let token_repository = token_repository().await;
+        token_repository
+            .create(
+                USER_ID,
+                CreateToken::new(
+                    "expired".to_string(),
+                    Scope::ReadWrite,
+                    Some(chrono::Utc::now() - chrono::Duration::minutes(1)),
+                ),
+                "expired_token",
+            )
+            .await
+            .expect("failed create token");
+        let app = create_app(
+            Repositories {
+                token: token_repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+
+        let req = build_req_with_token(Method::GET, "/todos", "expired_token");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        // 過去の有効期限ではトークンを作成できない
+        let req = build_todo_req_with_json(
+            "/tokens",
+            Method::POST,
+            r#"{ "name": "past", "scope": "read_write", "expires_at": "2000-01-01T00:00:00Z" }"#
+                .to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_apply_cors_config() {
+        let mut config = Config::default();
+        config.cors.allowed_origins = vec!["https://todo.example.com".to_string()];
+        config.cors.allowed_methods = vec!["GET".to_string(), "POST".to_string()];
+        let app = create_app(repositories().await, &config);
+
+        let preflight = |origin: &str| {
+            Request::builder()
+                .uri("/todos")
+                .method(Method::OPTIONS)
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(preflight("https://todo.example.com"))
+            .await
+            .unwrap();
+        let headers = res.headers();
+        assert_eq!(
+            "https://todo.example.com",
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!("GET,POST", headers[header::ACCESS_CONTROL_ALLOW_METHODS]);
+
+        // デフォルトで許可していたオリジンは許可されない
+        let res = app
+            .oneshot(preflight("http://localhost:3001"))
+            .await
+            .unwrap();
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn should_reject_body_over_limit() {
+        let mut config = Config::default();
+        config.server.body_limit = 64;
+        let app = create_app(repositories().await, &config);
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            format!(r#"{{ "text": "{}" }}"#, "a".repeat(64)),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+
+        let req = build_todo_req_with_json("/todos", Method::POST, r#"{ "text": "a" }"#.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        // JSON以外のContent-Typeは受け付けない
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::TEXT_PLAIN.as_ref())
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::from(r#"{ "text": "a" }"#))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_report_health_and_readiness() {
+        let health_repository = HealthRepositoryForMemory::new();
+        let app = create_app(
+            Repositories {
+                user: UserRepositoryForMemory::new(),
+                token: TokenRepositoryForMemory::new(),
+                health: health_repository.clone(),
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+        let get = |path: &str| Request::builder().uri(path).body(Body::empty()).unwrap();
+        let res_to_status = |res: Response| async {
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice::<ReadinessStatus>(&bytes).unwrap()
+        };
+
+        // 認証なしで参照できる
+        let res = app.clone().oneshot(get("/healthz")).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let res = app.clone().oneshot(get("/readyz")).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let status = res_to_status(res).await;
+        assert_eq!(Some(PoolStatus { size: 1, idle: 1 }), status.pool);
+
+        // データベースに接続できない
+        health_repository.set_healthy(false);
+        let res = app.clone().oneshot(get("/readyz")).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("unavailable", res_to_status(res).await.status);
+
+        // シャットダウン中でもlivenessは成功する
+        health_repository.set_healthy(true);
+        health_repository.readiness().set_ready(false);
+        let res = app.clone().oneshot(get("/readyz")).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!("shutting_down", res_to_status(res).await.status);
+        let res = app.oneshot(get("/healthz")).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    // `/slow` は `delay` だけ待ってから応答する
+    fn start_server(
+        delay: Duration,
+        drain_delay: Duration,
+        deadline: Duration,
+    ) -> (
+        SocketAddr,
+        Readiness,
+        tokio::sync::oneshot::Sender<()>,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let app = Router::new().route(
+            "/slow",
+            get(move || async move {
+                tokio::time::sleep(delay).await;
+                "done"
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let readiness = Readiness::default();
+        let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            app,
+            async {
+                signal_rx.await.ok();
+            },
+            readiness.clone(),
+            drain_delay,
+            deadline,
+        ));
+        (addr, readiness, signal_tx, server)
+    }
+
+    #[tokio::test]
+    async fn should_drain_in_flight_requests_on_shutdown() {
+        let (addr, readiness, signal_tx, server) =
+            start_server(Duration::from_millis(300), Duration::ZERO, Duration::from_secs(5));
+
+        let in_flight = tokio::spawn(async move {
+            let uri = format!("http://{}/slow", addr).parse().unwrap();
+            hyper::Client::new().get(uri).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        signal_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!readiness.is_ready());
+
+        // シグナルを受ける前のリクエストは最後まで処理される
+        let res = in_flight.await.unwrap().expect("in-flight request was dropped");
+        assert_eq!(StatusCode::OK, res.status());
+        server.await.unwrap().expect("server returned Err");
+    }
+
+    // readinessを落としてもすぐには止めず、振り分けが止まるまでに届いたリクエストも処理する
+    #[tokio::test]
+    async fn should_accept_requests_until_drain_delay_passes() {
+        let (addr, readiness, signal_tx, server) =
+            start_server(Duration::ZERO, Duration::from_millis(500), Duration::from_secs(5));
+
+        signal_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!readiness.is_ready());
+        let uri = format!("http://{}/slow", addr).parse().unwrap();
+        let res = hyper::Client::new().get(uri).await.expect("request was refused");
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(!server.is_finished());
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not stop after the drain delay")
+            .unwrap()
+            .expect("server returned Err");
+    }
+
+    #[tokio::test]
+    async fn should_stop_waiting_after_drain_deadline() {
+        let (addr, _, signal_tx, server) =
+            start_server(Duration::from_secs(60), Duration::ZERO, Duration::from_millis(200));
+
+        tokio::spawn(async move {
+            let uri = format!("http://{}/slow", addr).parse().unwrap();
+            hyper::Client::new().get(uri).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        signal_tx.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not stop after the deadline")
+            .unwrap()
+            .expect("server returned Err");
+    }
+
+    #[tokio::test]
+    async fn should_expose_prometheus_metrics() {
+        let repository = TodoRepositoryForMemory::new();
+        repository
+            .create(USER_ID, CreateTodo::new("should_expose_metrics".to_string()))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            Repositories {
+                todo: repository,
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+
+        for path in ["/todos/1", "/todos/2", "/todos/1", "/export?format=csv"] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            app.clone().oneshot(req).await.unwrap();
+        }
+
+        let req = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+
+        // 実際のパスではなくルートごとに集計される
+        assert!(body.contains(
+            r#"http_requests_total{method="GET",route="/todos/:id",status="200"} 2"#
+        ));
+        assert!(body.contains(
+            r#"http_requests_total{method="GET",route="/todos/:id",status="404"} 1"#
+        ));
+        assert!(body.contains(
+            r#"http_request_duration_seconds_count{method="GET",route="/todos/:id",status="200"} 2"#
+        ));
+        assert!(body.contains(
+            r#"repository_call_duration_seconds_count{method="find",repository="todo",result="error"} 1"#
+        ));
+        // 2回目の /todos/1 はキャッシュから返す
+        assert!(body.contains(r#"cache_requests_total{cache="todo",result="hit"} 1"#));
+        assert!(body.contains(
+            r#"repository_call_duration_seconds_count{method="find",repository="todo",result="ok"} 1"#
+        ));
+        // ユニットオブワークの中の操作も数える
+        assert!(body.contains(
+            r#"repository_call_duration_seconds_count{method="begin_snapshot",repository="unit_of_work",result="ok"} 1"#
+        ));
+        assert!(body.contains(
+            r#"repository_call_duration_seconds_count{method="all",repository="todo_view",result="ok"} 1"#
+        ));
+        assert!(body.contains(
+            r#"repository_call_duration_seconds_count{method="all",repository="label_view",result="ok"} 1"#
+        ));
+        assert!(body.contains(
+            r#"repository_call_duration_seconds_count{method="rollback",repository="unit_of_work",result="ok"} 1"#
+        ));
+        assert!(body.contains(r#"db_pool_connections{state="idle"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn should_propagate_request_id_to_responses() {
+        let health_repository = HealthRepositoryForMemory::new();
+        let app = create_app(
+            Repositories {
+                health: health_repository.clone(),
+                ..repositories().await
+            },
+            &Config::default(),
+        );
+        let res_to_json = |res: Response| async {
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        // 指定したIDはそのまま返す
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos");
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, "client-id-1".parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("client-id-1", res.headers()[REQUEST_ID_HEADER]);
+
+        // 指定がなければ生成する
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let request_id = res.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        assert!(!request_id.is_empty());
+        let body = res_to_json(res).await;
+        assert_eq!(request_id, body["request_id"]);
+        assert_eq!("Not Found", body["error"]);
+
+        // 本文のあるエラーはメッセージを残す
+        let mut req = build_todo_req_with_json("/todos", Method::POST, r#"{ "text": "" }"#.to_string());
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, "client-id-2".parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let body = res_to_json(res).await;
+        assert_eq!("client-id-2", body["request_id"]);
+        assert!(body["error"].as_str().unwrap().starts_with("Validation error"));
+
+        // JSONのエラーにはフィールドを追加する
+        health_repository.set_healthy(false);
+        let req = Request::builder()
+            .uri("/readyz")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        let body = res_to_json(res).await;
+        assert_eq!("unavailable", body["status"]);
+        assert!(body["request_id"].is_string());
+    }
+}
@@ -0,0 +1,147 @@
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::IntoResponse};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::time::{Duration, Instant};
+
+use crate::repositories::health::PoolStatus;
+
+// create_appごとにレジストリを持つので、テストごとに値が独立する
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    repository_duration: HistogramVec,
+    cache_requests: IntCounterVec,
+    pool_connections: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency in seconds",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let repository_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "repository_call_duration_seconds",
+                "Repository call latency in seconds",
+            ),
+            &["repository", "method", "result"],
+        )
+        .unwrap();
+        let cache_requests = IntCounterVec::new(
+            Opts::new("cache_requests_total", "Number of repository cache lookups"),
+            &["cache", "result"],
+        )
+        .unwrap();
+        let pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections"),
+            &["state"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry
+            .register(Box::new(repository_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cache_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_connections.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            repository_duration,
+            cache_requests,
+            pool_connections,
+        }
+    }
+
+    pub fn observe_http(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_repository(&self, repository: &str, method: &str, ok: bool, elapsed: Duration) {
+        let result = if ok { "ok" } else { "error" };
+        self.repository_duration
+            .with_label_values(&[repository, method, result])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_cache(&self, cache: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_requests.with_label_values(&[cache, result]).inc();
+    }
+
+    // (ヒットした数, 外れた数)
+    #[cfg(test)]
+    pub fn cache_stats(&self, cache: &str) -> (u64, u64) {
+        let count = |result| self.cache_requests.with_label_values(&[cache, result]).get();
+        (count("hit"), count("miss"))
+    }
+
+    pub fn set_pool_status(&self, status: PoolStatus) {
+        let idle = status.idle as i64;
+        self.pool_connections
+            .with_label_values(&["total"])
+            .set(status.size as i64);
+        self.pool_connections.with_label_values(&["idle"]).set(idle);
+        self.pool_connections
+            .with_label_values(&["in_use"])
+            .set(status.size as i64 - idle);
+    }
+
+    // Prometheusのテキスト形式で出力する
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+// ルーティング後に呼ばれるので、ラベルには実際のパスではなく `/todos/:id` のようなルートを使う
+pub async fn track_http<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let metrics = req.extensions().get::<Metrics>().cloned();
+
+    let res = next.run(req).await;
+
+    if let Some(metrics) = metrics {
+        metrics.observe_http(&method, &route, res.status().as_u16(), start.elapsed());
+    }
+    res
+}
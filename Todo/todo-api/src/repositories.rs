@@ -1,5 +1,17 @@
 pub mod todo;
+#[cfg(test)]
+mod conformance;
+pub mod cached;
+pub mod event;
+pub mod health;
+pub mod instrumented;
 pub mod label;
+pub mod member;
+pub mod memory;
+pub mod token;
+pub mod unit_of_work;
+pub mod user;
+pub mod webhook;
 
 use thiserror::Error;
 
@@ -9,6 +21,57 @@ enum RepositoryError {
     Unexpected(String),
     #[error("NotFound, id id [{0}]")]
     NotFound(i32),
+    #[error("NotFound, name is [{0}]")]
+    NotFoundByName(String),
     #[error("Duplicate data, id is {0}")]
     Duplicate(i32),
-}
\ No newline at end of file
+    #[error("Unauthorized, token is unknown")]
+    Unauthorized,
+}
+
+// 呼び出し側で、見つからない場合とそれ以外の失敗を分けて扱うために使う
+pub fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<RepositoryError>(),
+        Some(RepositoryError::NotFound(_) | RepositoryError::NotFoundByName(_))
+    )
+}
+
+// sqlxのクエリを包むspan。OpenTelemetryへ送るときに実行したSQLを属性として付ける
+pub fn db_span(statement: &str) -> tracing::Span {
+    query_span("postgresql", statement)
+}
+
+fn sqlite_span(statement: &str) -> tracing::Span {
+    query_span("sqlite", statement)
+}
+
+fn query_span(system: &'static str, statement: &str) -> tracing::Span {
+    let statement = statement.split_whitespace().collect::<Vec<_>>().join(" ");
+    tracing::info_span!(
+        "db.query",
+        otel.kind = "client",
+        db.system = system,
+        db.statement = %statement,
+    )
+}
+
+// SQLiteのテストはサーバーが不要なので、`database-test` がなくても実行する
+// 共有キャッシュのインメモリデータベースをテストごとに作り、複数のコネクションから使う
+#[cfg(test)]
+async fn sqlite_test_pool() -> sqlx::SqlitePool {
+    let url = format!(
+        "sqlite:file:{}?mode=memory&cache=shared",
+        uuid::Uuid::new_v4()
+    );
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(4)
+        .connect(&url)
+        .await
+        .expect("fail connect sqlite");
+    crate::database::SQLITE_MIGRATOR
+        .run(&pool)
+        .await
+        .expect("fail run sqlite migrations");
+    pool
+}
@@ -0,0 +1,450 @@
+use axum::async_trait;
+use lru::LruCache;
+use std::{
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    label::{Label, LabelRepository},
+    todo::{CreateTodo, Todo, TodoRepository, UpdateTodo},
+};
+use crate::{
+    config::CacheConfig,
+    events::{ChangeEvent, ChangeKind, ChangeObserver},
+    metrics::Metrics,
+};
+
+// 有効期限と件数の上限を持つLRUキャッシュ。無効にした場合は何も保存しない
+struct Cache<K: Hash + Eq, V> {
+    state: Option<Mutex<CacheState<K, V>>>,
+    ttl: Duration,
+}
+
+struct CacheState<K: Hash + Eq, V> {
+    entries: LruCache<K, (Instant, V)>,
+    // 捨てるたびに増やす。読み込み中に捨てられた値は古い可能性があるので保存しない
+    generation: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+    fn new(config: &CacheConfig) -> Self {
+        let state = NonZeroUsize::new(config.max_entries)
+            .filter(|_| config.enabled)
+            .map(|capacity| {
+                Mutex::new(CacheState {
+                    entries: LruCache::new(capacity),
+                    generation: 0,
+                })
+            });
+        Self {
+            state,
+            ttl: config.ttl(),
+        }
+    }
+
+    // 値と、読み込んだ値を保存するときに渡す世代を返す
+    fn get(&self, key: &K) -> (Option<V>, u64) {
+        let mut state = match &self.state {
+            Some(state) => state.lock().unwrap(),
+            None => return (None, 0),
+        };
+        let generation = state.generation;
+        let value = match state.entries.get(key) {
+            Some((expires_at, value)) if *expires_at > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                state.entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        (value, generation)
+    }
+
+    fn insert(&self, key: K, value: V, generation: u64) {
+        if let Some(state) = &self.state {
+            let mut state = state.lock().unwrap();
+            if state.generation == generation {
+                state.entries.put(key, (Instant::now() + self.ttl, value));
+            }
+        }
+    }
+
+    fn remove_if(&self, predicate: impl Fn(&K) -> bool) {
+        if let Some(state) = &self.state {
+            let mut state = state.lock().unwrap();
+            state.generation += 1;
+            let keys: Vec<K> = state
+                .entries
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| predicate(key))
+                .cloned()
+                .collect();
+            for key in keys {
+                state.entries.pop(&key);
+            }
+        }
+    }
+}
+
+// Todoのfindとallの結果をキャッシュする
+// 自分の書き込みに加えて、ユニットオブワークでの書き込みや他のインスタンスの書き込みも
+// `ChangeBus` から受け取って捨てるので、書き込んだ後に古いデータを返さない
+#[derive(Clone)]
+pub struct CachedTodoRepository<R> {
+    inner: R,
+    todos: Arc<Cache<(i32, i32), Todo>>,
+    lists: Arc<Cache<i32, Vec<Todo>>>,
+    name: &'static str,
+    metrics: Metrics,
+}
+
+impl<R> CachedTodoRepository<R> {
+    pub fn new(inner: R, name: &'static str, config: &CacheConfig, metrics: Metrics) -> Self {
+        Self {
+            inner,
+            todos: Arc::new(Cache::new(config)),
+            lists: Arc::new(Cache::new(config)),
+            name,
+            metrics,
+        }
+    }
+
+    // ワークスペースの一覧と、`id` を指定すればそのTodoを捨てる。Noneならワークスペースのすべて
+    fn invalidate(&self, user_id: i32, id: Option<i32>) {
+        match id {
+            Some(id) => self.todos.remove_if(|key| *key == (user_id, id)),
+            None => self.todos.remove_if(|key| key.0 == user_id),
+        }
+        self.lists.remove_if(|key| *key == user_id);
+    }
+}
+
+#[async_trait]
+impl<R: TodoRepository> TodoRepository for CachedTodoRepository<R> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        let todo = self.inner.create(user_id, payload).await?;
+        self.invalidate(user_id, Some(todo.id));
+        Ok(todo)
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        let (cached, generation) = self.todos.get(&(user_id, id));
+        self.metrics.observe_cache(self.name, cached.is_some());
+        if let Some(todo) = cached {
+            return Ok(todo);
+        }
+        let todo = self.inner.find(user_id, id).await?;
+        self.todos.insert((user_id, id), todo.clone(), generation);
+        Ok(todo)
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        let (cached, generation) = self.lists.get(&user_id);
+        self.metrics.observe_cache(self.name, cached.is_some());
+        if let Some(todos) = cached {
+            return Ok(todos);
+        }
+        let todos = self.inner.all(user_id).await?;
+        self.lists.insert(user_id, todos.clone(), generation);
+        Ok(todos)
+    }
+
+    // 失敗しても途中まで書き込んでいるかもしれないので、結果によらず捨てる
+    async fn update(&self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let result = self.inner.update(user_id, id, payload).await;
+        self.invalidate(user_id, Some(id));
+        result
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = self.inner.delete(user_id, id).await;
+        self.invalidate(user_id, Some(id));
+        result
+    }
+}
+
+impl<R: TodoRepository> ChangeObserver for CachedTodoRepository<R> {
+    fn observe(&self, event: &ChangeEvent) {
+        match event.kind {
+            ChangeKind::TodoCreated
+            | ChangeKind::TodoUpdated
+            | ChangeKind::TodoDeleted
+            | ChangeKind::TodoCompleted
+            | ChangeKind::WorkspaceImported => self.invalidate(event.workspace_id, event.id),
+            ChangeKind::Resync => {
+                self.todos.remove_if(|_| true);
+                self.lists.remove_if(|_| true);
+            }
+            ChangeKind::LabelCreated | ChangeKind::LabelDeleted => {}
+        }
+    }
+}
+
+// ラベルのallの結果をキャッシュする
+#[derive(Clone)]
+pub struct CachedLabelRepository<R> {
+    inner: R,
+    lists: Arc<Cache<i32, Vec<Label>>>,
+    name: &'static str,
+    metrics: Metrics,
+}
+
+impl<R> CachedLabelRepository<R> {
+    pub fn new(inner: R, name: &'static str, config: &CacheConfig, metrics: Metrics) -> Self {
+        Self {
+            inner,
+            lists: Arc::new(Cache::new(config)),
+            name,
+            metrics,
+        }
+    }
+
+    fn invalidate(&self, user_id: i32) {
+        self.lists.remove_if(|key| *key == user_id);
+    }
+}
+
+#[async_trait]
+impl<R: LabelRepository> LabelRepository for CachedLabelRepository<R> {
+    async fn create(&self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        let result = self.inner.create(user_id, name).await;
+        self.invalidate(user_id);
+        result
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        let (cached, generation) = self.lists.get(&user_id);
+        self.metrics.observe_cache(self.name, cached.is_some());
+        if let Some(labels) = cached {
+            return Ok(labels);
+        }
+        let labels = self.inner.all(user_id).await?;
+        self.lists.insert(user_id, labels.clone(), generation);
+        Ok(labels)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = self.inner.delete(user_id, id).await;
+        self.invalidate(user_id);
+        result
+    }
+}
+
+impl<R: LabelRepository> ChangeObserver for CachedLabelRepository<R> {
+    fn observe(&self, event: &ChangeEvent) {
+        match event.kind {
+            ChangeKind::LabelCreated | ChangeKind::LabelDeleted | ChangeKind::WorkspaceImported => {
+                self.invalidate(event.workspace_id)
+            }
+            ChangeKind::Resync => self.lists.remove_if(|_| true),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::LabelRepositoryForMemory, memory::MemoryDatabase, todo::TodoRepositoryForMemory,
+        RepositoryError,
+    };
+
+    #[tokio::test]
+    async fn should_count_hits_and_misses() {
+        let metrics = Metrics::new();
+        let repository = CachedTodoRepository::new(
+            TodoRepositoryForMemory::new(),
+            "todo",
+            &CacheConfig::default(),
+            metrics.clone(),
+        );
+        let todo = repository
+            .create(1, CreateTodo::new("cached".to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(todo, repository.find(1, todo.id).await.unwrap());
+        assert_eq!(todo, repository.find(1, todo.id).await.unwrap());
+        assert_eq!(vec![todo.clone()], repository.all(1).await.unwrap());
+        assert_eq!(vec![todo], repository.all(1).await.unwrap());
+        assert_eq!((2, 2), metrics.cache_stats("todo"));
+        // 見つからなかった結果は保存しない
+        assert!(repository.find(1, 99).await.is_err());
+        assert!(repository.find(1, 99).await.is_err());
+        assert_eq!((2, 4), metrics.cache_stats("todo"));
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered.contains(r#"cache_requests_total{cache="todo",result="hit"} 2"#));
+    }
+
+    #[tokio::test]
+    async fn should_not_return_stale_todos_after_writes() {
+        let repository = CachedTodoRepository::new(
+            TodoRepositoryForMemory::new(),
+            "todo",
+            &CacheConfig::default(),
+            Metrics::new(),
+        );
+        let first = repository
+            .create(1, CreateTodo::new("first".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(vec![first.clone()], repository.all(1).await.unwrap());
+        assert_eq!(first, repository.find(1, first.id).await.unwrap());
+
+        let second = repository
+            .create(1, CreateTodo::new("second".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![second.clone(), first.clone()],
+            repository.all(1).await.unwrap()
+        );
+
+        let updated = repository
+            .update(1, first.id, UpdateTodo::new(None, Some(true)))
+            .await
+            .unwrap();
+        assert_eq!(updated, repository.find(1, first.id).await.unwrap());
+        assert_eq!(
+            vec![second.clone(), updated],
+            repository.all(1).await.unwrap()
+        );
+
+        repository.delete(1, first.id).await.unwrap();
+        let err = repository.find(1, first.id).await.unwrap_err();
+        assert!(matches!(
+            err.downcast::<RepositoryError>().unwrap(),
+            RepositoryError::NotFound(_)
+        ));
+        assert_eq!(vec![second], repository.all(1).await.unwrap());
+    }
+
+    // キャッシュを通らない書き込みは、発行された変更イベントで捨てる
+    #[tokio::test]
+    async fn should_invalidate_on_observed_changes() {
+        let database = MemoryDatabase::default();
+        let backend = TodoRepositoryForMemory::with_database(database.clone());
+        let repository = CachedTodoRepository::new(
+            backend.clone(),
+            "todo",
+            &CacheConfig::default(),
+            Metrics::new(),
+        );
+        let labels = LabelRepositoryForMemory::with_database(database);
+        let cached_labels = CachedLabelRepository::new(
+            labels.clone(),
+            "label",
+            &CacheConfig::default(),
+            Metrics::new(),
+        );
+
+        let todo = backend
+            .create(1, CreateTodo::new("before".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(todo, repository.find(1, todo.id).await.unwrap());
+        assert!(cached_labels.all(1).await.unwrap().is_empty());
+
+        let updated = backend
+            .update(1, todo.id, UpdateTodo::new(Some("after".to_string()), None))
+            .await
+            .unwrap();
+        let label = labels.create(1, "work".to_string()).await.unwrap();
+        assert_eq!(todo, repository.find(1, todo.id).await.unwrap());
+        assert!(cached_labels.all(1).await.unwrap().is_empty());
+
+        // 他のワークスペースの変更では捨てない
+        repository.observe(&ChangeEvent::imported(2));
+        assert_eq!(todo, repository.find(1, todo.id).await.unwrap());
+
+        repository.observe(&ChangeEvent::new(
+            ChangeKind::TodoUpdated,
+            1,
+            todo.id,
+            &updated,
+        ));
+        cached_labels.observe(&ChangeEvent::new(
+            ChangeKind::LabelCreated,
+            1,
+            label.id,
+            &label,
+        ));
+        assert_eq!(updated, repository.find(1, todo.id).await.unwrap());
+        assert_eq!(vec![label.clone()], cached_labels.all(1).await.unwrap());
+
+        // 一括で取り込んだ場合はワークスペースのすべてを捨てる
+        assert_eq!(vec![updated.clone()], repository.all(1).await.unwrap());
+        let imported = backend
+            .create(1, CreateTodo::new("imported".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(vec![updated.clone()], repository.all(1).await.unwrap());
+        repository.observe(&ChangeEvent::imported(1));
+        assert_eq!(vec![imported, updated], repository.all(1).await.unwrap());
+
+        // 他のインスタンスの変更を取りこぼしたかもしれない場合は、すべてのワークスペースで捨てる
+        let other = backend
+            .create(2, CreateTodo::new("other".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(vec![other.clone()], repository.all(2).await.unwrap());
+        backend.delete(2, other.id).await.unwrap();
+        labels.delete(1, label.id).await.unwrap();
+        repository.observe(&ChangeEvent::resync());
+        cached_labels.observe(&ChangeEvent::resync());
+        assert!(repository.all(2).await.unwrap().is_empty());
+        assert!(cached_labels.all(1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_bound_entries_and_expire() {
+        let metrics = Metrics::new();
+        let backend = TodoRepositoryForMemory::new();
+        let repository = CachedTodoRepository::new(
+            backend.clone(),
+            "todo",
+            &CacheConfig {
+                enabled: true,
+                ttl_secs: 1,
+                max_entries: 2,
+            },
+            metrics.clone(),
+        );
+        let mut todos = Vec::new();
+        for text in ["a", "b", "c"] {
+            todos.push(
+                backend
+                    .create(1, CreateTodo::new(text.to_string()))
+                    .await
+                    .unwrap(),
+            );
+        }
+        for todo in &todos {
+            repository.find(1, todo.id).await.unwrap();
+        }
+        // 最も古い `a` は追い出されている
+        repository.find(1, todos[2].id).await.unwrap();
+        repository.find(1, todos[0].id).await.unwrap();
+        assert_eq!((1, 4), metrics.cache_stats("todo"));
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        repository.find(1, todos[0].id).await.unwrap();
+        assert_eq!((1, 5), metrics.cache_stats("todo"));
+
+        // 無効にすると保存しない
+        let disabled = CacheConfig {
+            enabled: false,
+            ..CacheConfig::default()
+        };
+        let repository = CachedTodoRepository::new(backend, "disabled", &disabled, metrics.clone());
+        repository.find(1, todos[0].id).await.unwrap();
+        repository.find(1, todos[0].id).await.unwrap();
+        assert_eq!((0, 2), metrics.cache_stats("disabled"));
+    }
+}
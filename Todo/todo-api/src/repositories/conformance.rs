@@ -0,0 +1,651 @@
+// どのバックエンドのリポジトリも同じ振る舞いをすることを確かめる共通のテスト
+// 新しいバックエンドを追加したら、下の `test` にそのバックエンドで実行するテストを足す
+use crate::events::{ChangeEvent, ChangeKind};
+use chrono::{Duration, NaiveDate, Utc};
+
+use super::{
+    event::{EventRepository, StoredEvent},
+    label::{Label, LabelRepository},
+    todo::{CreateTodo, Todo, TodoRepository, UpdateTodo},
+    webhook::{Attempt, ClaimedDelivery, CreateWebhook, DeliveryStatus, WebhookRepository},
+    RepositoryError,
+};
+
+fn repository_error(result: anyhow::Result<impl std::fmt::Debug>) -> RepositoryError {
+    result
+        .expect_err("expected Err, but returned Ok")
+        .downcast::<RepositoryError>()
+        .expect("expected RepositoryError")
+}
+
+fn assert_not_found(result: anyhow::Result<impl std::fmt::Debug>, expected: i32) {
+    match repository_error(result) {
+        RepositoryError::NotFound(id) => assert_eq!(expected, id),
+        e => panic!("expected NotFound({}), but returned {:?}", expected, e),
+    }
+}
+
+// `user_id` と `other_user_id` はどちらもTodoを持っていないユーザー
+pub async fn todo_conformance<T: TodoRepository>(repository: &T, user_id: i32, other_user_id: i32) {
+    // create: IDは作るたびに大きくなり、未完了で作られる
+    let mut created = Vec::new();
+    for text in ["first", "second", "third"] {
+        let todo = repository
+            .create(user_id, CreateTodo::new(text.to_string()))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(text, todo.text);
+        assert!(!todo.completed);
+        created.push(todo);
+    }
+    assert!(created[0].id > 0);
+    assert!(created.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    // find
+    let todo = repository
+        .find(user_id, created[1].id)
+        .await
+        .expect("[find] returned Err");
+    assert_eq!(created[1], todo);
+
+    // all: 新しい順
+    let todos = repository.all(user_id).await.expect("[all] returned Err");
+    let expected: Vec<Todo> = created.iter().rev().cloned().collect();
+    assert_eq!(expected, todos);
+
+    // update: 指定しなかった項目はそのまま残す
+    let todo = repository
+        .update(user_id, created[0].id, UpdateTodo::new(None, Some(true)))
+        .await
+        .expect("[update] returned Err");
+    assert_eq!(created[0].text, todo.text);
+    assert!(todo.completed);
+    let todo = repository
+        .update(
+            user_id,
+            created[0].id,
+            UpdateTodo::new(Some("renamed".to_string()), None),
+        )
+        .await
+        .expect("[update] returned Err");
+    assert_eq!("renamed", todo.text);
+    assert!(todo.completed);
+    assert_eq!(todo, repository.find(user_id, created[0].id).await.unwrap());
+
+    // due: 作成時に指定でき、更新で指定しなければ残り、nullを指定すると消える
+    let due = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+    let todo = repository
+        .create(user_id, CreateTodo::new("due".to_string()).with_due(Some(due)))
+        .await
+        .expect("[create] returned Err");
+    assert_eq!(Some(due), todo.due);
+    assert_eq!(todo, repository.find(user_id, todo.id).await.unwrap());
+    let updated = repository
+        .update(user_id, todo.id, UpdateTodo::new(None, Some(true)))
+        .await
+        .expect("[update] returned Err");
+    assert_eq!(Some(due), updated.due);
+    let updated = repository
+        .update(user_id, todo.id, UpdateTodo::new(None, None).with_due(None))
+        .await
+        .expect("[update] returned Err");
+    assert_eq!(None, updated.due);
+    assert_eq!(updated, repository.find(user_id, todo.id).await.unwrap());
+    repository.delete(user_id, todo.id).await.unwrap();
+
+    // 他のユーザーのTodoは存在しないものとして扱う
+    let id = created[0].id;
+    assert!(repository.all(other_user_id).await.unwrap().is_empty());
+    assert_not_found(repository.find(other_user_id, id).await, id);
+    assert_not_found(
+        repository
+            .update(other_user_id, id, UpdateTodo::new(None, Some(false)))
+            .await,
+        id,
+    );
+    assert_not_found(repository.delete(other_user_id, id).await, id);
+
+    // delete
+    let last = created.pop().unwrap();
+    repository
+        .delete(user_id, last.id)
+        .await
+        .expect("[delete] returned Err");
+    assert_not_found(repository.find(user_id, last.id).await, last.id);
+    assert_not_found(repository.delete(user_id, last.id).await, last.id);
+    assert_not_found(
+        repository
+            .update(user_id, last.id, UpdateTodo::new(None, Some(true)))
+            .await,
+        last.id,
+    );
+    assert_eq!(2, repository.all(user_id).await.unwrap().len());
+
+    // 削除したIDは再利用しない
+    let todo = repository
+        .create(user_id, CreateTodo::new("fourth".to_string()))
+        .await
+        .expect("[create] returned Err");
+    assert!(todo.id > last.id);
+}
+
+// `user_id` と `other_user_id` はどちらもラベルを持っていないユーザー
+pub async fn label_conformance<L: LabelRepository>(
+    repository: &L,
+    user_id: i32,
+    other_user_id: i32,
+) {
+    // create: IDは作るたびに大きくなる
+    let mut created = Vec::new();
+    for name in ["work", "home", "errand"] {
+        let label = repository
+            .create(user_id, name.to_string())
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(name, label.name);
+        created.push(label);
+    }
+    assert!(created[0].id > 0);
+    assert!(created.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    // all: 作った順
+    let labels = repository.all(user_id).await.expect("[all] returned Err");
+    assert_eq!(created, labels);
+
+    // 同じユーザーで同名のラベルは作れず、既存のIDを返す
+    match repository_error(repository.create(user_id, "work".to_string()).await) {
+        RepositoryError::Duplicate(id) => assert_eq!(created[0].id, id),
+        e => panic!("expected Duplicate, but returned {:?}", e),
+    }
+    assert_eq!(3, repository.all(user_id).await.unwrap().len());
+
+    // 他のユーザーなら同名のラベルを作れ、互いに見えない
+    let other: Label = repository
+        .create(other_user_id, "work".to_string())
+        .await
+        .expect("[create] returned Err");
+    assert_ne!(created[0].id, other.id);
+    assert_eq!(
+        vec![other.clone()],
+        repository.all(other_user_id).await.unwrap()
+    );
+    assert_not_found(
+        repository.delete(other_user_id, created[0].id).await,
+        created[0].id,
+    );
+    assert_eq!(created, repository.all(user_id).await.unwrap());
+
+    // delete
+    let last = created.pop().unwrap();
+    repository
+        .delete(user_id, last.id)
+        .await
+        .expect("[delete] returned Err");
+    assert_not_found(repository.delete(user_id, last.id).await, last.id);
+    assert_eq!(created, repository.all(user_id).await.unwrap());
+
+    // 削除したIDは再利用せず、削除した名前は作り直せる
+    let label = repository
+        .create(user_id, last.name.clone())
+        .await
+        .expect("[create] returned Err");
+    assert!(label.id > other.id);
+}
+
+// `user_id` と `other_user_id` はどちらもイベントを持っていないユーザー
+// 履歴は全ワークスペースで共有するので、IDは前後関係だけを確かめる
+pub async fn event_conformance<E: EventRepository>(
+    repository: &E,
+    user_id: i32,
+    other_user_id: i32,
+) {
+    // append: IDは保存するたびに大きくなり、内容はそのまま読み出せる
+    let events = [
+        ChangeEvent::new(
+            ChangeKind::TodoCreated,
+            user_id,
+            1,
+            &serde_json::json!({ "text": "first" }),
+        ),
+        ChangeEvent::deleted(ChangeKind::LabelDeleted, other_user_id, 2),
+        ChangeEvent::imported(user_id),
+        ChangeEvent::deleted(ChangeKind::TodoDeleted, user_id, 1),
+    ];
+    let mut stored = Vec::new();
+    for event in events.iter().cloned() {
+        stored.push(repository.append(event).await.expect("[append] returned Err"));
+    }
+    assert!(stored.windows(2).all(|pair| pair[0].id < pair[1].id));
+    let appended: Vec<ChangeEvent> = stored.iter().map(|e| e.event.clone()).collect();
+    assert_eq!(events.to_vec(), appended);
+
+    // after: 指定したワークスペースのイベントだけを古い順に返す
+    let first = stored[0].id;
+    let after = repository
+        .after(user_id, first - 1, 10)
+        .await
+        .expect("[after] returned Err");
+    let expected = vec![stored[0].clone(), stored[2].clone(), stored[3].clone()];
+    assert_eq!(expected, after);
+    let after = repository.after(user_id, first, 1).await.unwrap();
+    assert_eq!(vec![stored[2].clone()], after);
+    assert!(repository.after(user_id, stored[3].id, 10).await.unwrap().is_empty());
+
+    // tail: すべてのワークスペースのイベントを古い順に返す
+    // 同じ表に他のテストが書き込んでいることもあるので、2つのワークスペースに絞って比べる
+    let tail: Vec<StoredEvent> = repository
+        .tail(first - 1, 100)
+        .await
+        .expect("[tail] returned Err")
+        .into_iter()
+        .filter(|event| [user_id, other_user_id].contains(&event.event.workspace_id))
+        .collect();
+    assert_eq!(stored, tail);
+    let tail = repository.tail(first, 1).await.unwrap();
+    assert_eq!(1, tail.len());
+    assert!(first < tail[0].id && tail[0].id <= stored[1].id);
+
+    // bounds
+    let (oldest, newest) = repository
+        .bounds()
+        .await
+        .expect("[bounds] returned Err")
+        .expect("[bounds] returned None");
+    assert!(oldest <= first);
+    assert_eq!(stored[3].id, newest);
+
+    // prune: 保持期間内のイベントは残し、期間を過ぎても最新の1件は残す
+    repository
+        .prune(Utc::now() - Duration::hours(1))
+        .await
+        .expect("[prune] returned Err");
+    assert_eq!(3, repository.after(user_id, first - 1, 10).await.unwrap().len());
+    repository
+        .prune(Utc::now() + Duration::minutes(1))
+        .await
+        .expect("[prune] returned Err");
+    assert_eq!(Some((newest, newest)), repository.bounds().await.unwrap());
+    let after = repository.after(user_id, first - 1, 10).await.unwrap();
+    assert_eq!(vec![stored[3].clone()], after);
+
+    // 消したイベントのIDは再利用しない
+    let event = repository
+        .append(ChangeEvent::imported(other_user_id))
+        .await
+        .unwrap();
+    assert!(event.id > newest);
+}
+
+// `user_id` と `other_user_id` はどちらもWebhookを持っていないユーザー
+// 配信の取り出しは全ワークスペースが対象なので、ここで作った配信だけを見る
+pub async fn webhook_conformance<W: WebhookRepository>(
+    repository: &W,
+    user_id: i32,
+    other_user_id: i32,
+) {
+    let create = |url: &str, events: &str| CreateWebhook {
+        url: url.to_string(),
+        events: events.to_string(),
+    };
+
+    // create / all / find: 他のワークスペースのWebhookは見えない
+    let webhook = repository
+        .create(user_id, create("http://localhost/hook", "todo.*"), "secret")
+        .await
+        .expect("[create] returned Err");
+    assert_eq!("http://localhost/hook", webhook.url);
+    assert_eq!("todo.*", webhook.events);
+    let second = repository
+        .create(user_id, create("http://localhost/second", ""), "second secret")
+        .await
+        .unwrap();
+    let other = repository
+        .create(other_user_id, create("http://localhost/other", ""), "other secret")
+        .await
+        .unwrap();
+    assert!(webhook.id < second.id);
+    assert_eq!(
+        vec![webhook.clone(), second.clone()],
+        repository.all(user_id).await.expect("[all] returned Err")
+    );
+    assert_eq!(webhook, repository.find(user_id, webhook.id).await.unwrap());
+    assert_not_found(repository.find(user_id, other.id).await, other.id);
+    assert_not_found(repository.delete(user_id, other.id).await, other.id);
+
+    // enqueue: 未送信の配信として保存する
+    let payload = serde_json::json!({ "type": "todo.created", "id": 1 });
+    let first = repository
+        .enqueue(webhook.id, "todo.created", payload.clone())
+        .await
+        .expect("[enqueue] returned Err");
+    assert_eq!(DeliveryStatus::Pending, first.status);
+    assert_eq!(0, first.attempts);
+    assert_eq!(payload, first.payload.0);
+    let last = repository
+        .enqueue(webhook.id, "todo.deleted", payload.clone())
+        .await
+        .unwrap();
+    assert_ne!(first.id, last.id);
+
+    // claim: 送り先とシークレットを付けて返し、確保している間は取り出さない
+    let ids = [first.id.clone(), last.id.clone()];
+    let ours = |claimed: Vec<ClaimedDelivery>| -> Vec<ClaimedDelivery> {
+        claimed
+            .into_iter()
+            .filter(|c| ids.contains(&c.delivery.id))
+            .collect()
+    };
+    let now = Utc::now() + Duration::seconds(1);
+    let claimed = ours(
+        repository
+            .claim(now, Duration::minutes(1), 100)
+            .await
+            .expect("[claim] returned Err"),
+    );
+    assert_eq!(2, claimed.len());
+    assert!(claimed.iter().all(|c| c.url == webhook.url && c.secret == "secret"));
+    assert!(ours(repository.claim(now, Duration::minutes(1), 100).await.unwrap()).is_empty());
+
+    // record: 回数を数え、送り直す時刻になれば再び取り出せる
+    let failed = repository
+        .record(
+            &first.id,
+            Attempt {
+                status: DeliveryStatus::Pending,
+                next_attempt_at: Some(Utc::now() - Duration::minutes(1)),
+                response_status: Some(500),
+                error: Some("unexpected status".to_string()),
+            },
+        )
+        .await
+        .expect("[record] returned Err");
+    assert_eq!(1, failed.attempts);
+    assert_eq!(Some(500), failed.response_status);
+    let claimed = ours(repository.claim(now, Duration::minutes(1), 100).await.unwrap());
+    assert_eq!(1, claimed.len());
+    assert_eq!(first.id, claimed[0].delivery.id);
+
+    let succeeded = repository
+        .record(
+            &last.id,
+            Attempt {
+                status: DeliveryStatus::Succeeded,
+                next_attempt_at: None,
+                response_status: Some(204),
+                error: None,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(DeliveryStatus::Succeeded, succeeded.status);
+    let dead = repository
+        .record(
+            &first.id,
+            Attempt {
+                status: DeliveryStatus::Dead,
+                next_attempt_at: None,
+                response_status: None,
+                error: Some("timeout".to_string()),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!((DeliveryStatus::Dead, 2), (dead.status, dead.attempts));
+    assert!(ours(repository.claim(now, Duration::minutes(1), 100).await.unwrap()).is_empty());
+
+    // deliveries: Webhookのワークスペースからだけ見える
+    let mut deliveries = repository
+        .deliveries(user_id, webhook.id, 10)
+        .await
+        .expect("[deliveries] returned Err");
+    deliveries.sort_by_key(|delivery| delivery.attempts);
+    assert_eq!(vec![succeeded, dead], deliveries);
+    assert_eq!(1, repository.deliveries(user_id, webhook.id, 1).await.unwrap().len());
+    assert!(repository
+        .deliveries(other_user_id, webhook.id, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // delete: 配信も一緒に消える
+    repository
+        .delete(user_id, webhook.id)
+        .await
+        .expect("[delete] returned Err");
+    assert_not_found(repository.find(user_id, webhook.id).await, webhook.id);
+    assert!(repository
+        .deliveries(user_id, webhook.id, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(vec![second], repository.all(user_id).await.unwrap());
+}
+
+mod test {
+    use super::*;
+    use crate::{
+        config::CacheConfig,
+        metrics::Metrics,
+        repositories::{
+            cached::{CachedLabelRepository, CachedTodoRepository},
+            event::{EventRepositoryForMemory, EventRepositoryForSqlite},
+            instrumented::InstrumentedRepository,
+            label::{LabelRepositoryForMemory, LabelRepositoryForSqlite},
+            memory::MemoryDatabase,
+            sqlite_test_pool,
+            todo::{TodoRepositoryForMemory, TodoRepositoryForSqlite},
+            user::{UserRepository, UserRepositoryForSqlite},
+            webhook::{WebhookRepositoryForMemory, WebhookRepositoryForSqlite},
+        },
+    };
+
+    #[tokio::test]
+    async fn memory_conformance() {
+        todo_conformance(&TodoRepositoryForMemory::new(), 1, 2).await;
+        label_conformance(&LabelRepositoryForMemory::new(), 1, 2).await;
+        event_conformance(&EventRepositoryForMemory::new(), 1, 2).await;
+        let webhooks = WebhookRepositoryForMemory::with_database(MemoryDatabase::default());
+        webhook_conformance(&webhooks, 1, 2).await;
+    }
+
+    // ファイルに書き出す場合も同じで、開き直した後もIDを再利用しない
+    #[tokio::test]
+    async fn persistent_memory_conformance() {
+        let path = std::env::temp_dir().join(format!("conformance-{}.json", uuid::Uuid::new_v4()));
+        let database = MemoryDatabase::open(&path, 4).unwrap();
+        todo_conformance(
+            &TodoRepositoryForMemory::with_database(database.clone()),
+            1,
+            2,
+        )
+        .await;
+        label_conformance(&LabelRepositoryForMemory::with_database(database), 1, 2).await;
+
+        let database = MemoryDatabase::open(&path, 4).unwrap();
+        todo_conformance(
+            &TodoRepositoryForMemory::with_database(database.clone()),
+            3,
+            4,
+        )
+        .await;
+        label_conformance(&LabelRepositoryForMemory::with_database(database), 3, 4).await;
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(path.with_extension("json.log")).ok();
+    }
+
+    #[tokio::test]
+    async fn sqlite_conformance() {
+        let pool = sqlite_test_pool().await;
+        let users = UserRepositoryForSqlite::new(pool.clone());
+        let user = users.create("conformance".to_string()).await.unwrap();
+        let other = users.create("conformance other".to_string()).await.unwrap();
+
+        todo_conformance(
+            &TodoRepositoryForSqlite::new(pool.clone()),
+            user.id,
+            other.id,
+        )
+        .await;
+        label_conformance(
+            &LabelRepositoryForSqlite::new(pool.clone()),
+            user.id,
+            other.id,
+        )
+        .await;
+        event_conformance(
+            &EventRepositoryForSqlite::new(pool.clone()),
+            user.id,
+            other.id,
+        )
+        .await;
+        webhook_conformance(&WebhookRepositoryForSqlite::new(pool), user.id, other.id).await;
+    }
+
+    #[tokio::test]
+    async fn instrumented_conformance() {
+        let metrics = Metrics::new();
+        todo_conformance(
+            &InstrumentedRepository::new(TodoRepositoryForMemory::new(), "todo", metrics.clone()),
+            1,
+            2,
+        )
+        .await;
+        label_conformance(
+            &InstrumentedRepository::new(LabelRepositoryForMemory::new(), "label", metrics),
+            1,
+            2,
+        )
+        .await;
+    }
+
+    // キャッシュで包んでも、書き込みの直後から同じ結果を返す。create_appと同じ順に包む
+    #[tokio::test]
+    async fn cached_conformance() {
+        let metrics = Metrics::new();
+        let config = CacheConfig::default();
+        todo_conformance(
+            &CachedTodoRepository::new(
+                InstrumentedRepository::new(
+                    TodoRepositoryForMemory::new(),
+                    "todo",
+                    metrics.clone(),
+                ),
+                "todo",
+                &config,
+                metrics.clone(),
+            ),
+            1,
+            2,
+        )
+        .await;
+        label_conformance(
+            &CachedLabelRepository::new(
+                InstrumentedRepository::new(
+                    LabelRepositoryForMemory::new(),
+                    "label",
+                    metrics.clone(),
+                ),
+                "label",
+                &config,
+                metrics,
+            ),
+            1,
+            2,
+        )
+        .await;
+    }
+
+    #[cfg(feature = "database-test")]
+    mod database {
+        use super::*;
+        use crate::repositories::{
+            event::EventRepositoryForDb,
+            label::LabelRepositoryForDb,
+            todo::TodoRepositoryForDb,
+            user::{User, UserRepositoryForDb},
+            webhook::WebhookRepositoryForDb,
+        };
+        use dotenv::dotenv;
+        use sqlx::PgPool;
+        use std::env;
+
+        async fn recreate_user(pool: &PgPool, name: &str) -> User {
+            sqlx::query("delete from users where name=$1")
+                .bind(name)
+                .execute(pool)
+                .await
+                .expect("[recreate_user] delete returned Err");
+            UserRepositoryForDb::new(pool.clone())
+                .create(name.to_string())
+                .await
+                .expect("[recreate_user] create returned Err")
+        }
+
+        #[tokio::test]
+        async fn postgres_conformance() {
+            dotenv().ok();
+            let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+            let pool = PgPool::connect(database_url)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+            let user = recreate_user(&pool, "conformance_user").await;
+            let other = recreate_user(&pool, "conformance_other").await;
+            todo_conformance(&TodoRepositoryForDb::new(pool.clone()), user.id, other.id).await;
+            label_conformance(&LabelRepositoryForDb::new(pool.clone()), user.id, other.id).await;
+            event_conformance(&EventRepositoryForDb::new(pool.clone()), user.id, other.id).await;
+            webhook_conformance(&WebhookRepositoryForDb::new(pool), user.id, other.id).await;
+        }
+
+        // 並行して保存しても、続きから読む側がIDを読み飛ばさない
+        #[tokio::test]
+        async fn postgres_events_without_gaps() {
+            dotenv().ok();
+            let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+            let pool = PgPool::connect(database_url)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+            let user = recreate_user(&pool, "conformance_events").await;
+            let repository = EventRepositoryForDb::new(pool);
+            let start = repository.bounds().await.unwrap().map_or(0, |(_, newest)| newest);
+
+            let writers: Vec<_> = (0..8)
+                .map(|_| {
+                    let repository = repository.clone();
+                    tokio::spawn(async move {
+                        for id in 0..25 {
+                            let event = ChangeEvent::deleted(ChangeKind::TodoDeleted, user.id, id);
+                            repository.append(event).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            let mut seen = Vec::new();
+            let mut last_id = start;
+            // 書き終えたのを確かめてから読んだ分までで止める
+            loop {
+                let finished = writers.iter().all(|writer| writer.is_finished());
+                for event in repository.tail(last_id, 1000).await.unwrap() {
+                    last_id = event.id;
+                    if event.event.workspace_id == user.id {
+                        seen.push(event.id);
+                    }
+                }
+                if finished {
+                    break;
+                }
+            }
+            for writer in writers {
+                writer.await.unwrap();
+            }
+            let appended: Vec<_> = repository
+                .after(user.id, start, 1000)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|event| event.id)
+                .collect();
+            assert_eq!(appended, seen);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, PgPool, SqlitePool};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+};
+
+use super::RepositoryError;
+use crate::events::ChangeEvent;
+
+// 発行した変更イベントの履歴。SSEで再接続したクライアントに取りこぼした分を返す
+// IDは保存するたびに大きくなり、消したイベントのIDも再利用しない
+#[async_trait]
+pub trait EventRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn append(&self, event: ChangeEvent) -> anyhow::Result<StoredEvent>;
+    // `after` より後のワークスペースのイベントを古い順に `limit` 件まで返す
+    async fn after(
+        &self,
+        workspace_id: i32,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<StoredEvent>>;
+    // `after` より後のすべてのワークスペースのイベントを古い順に `limit` 件まで返す
+    // 他のインスタンスが保存したイベントも含む
+    async fn tail(&self, after: i64, limit: i64) -> anyhow::Result<Vec<StoredEvent>>;
+    // 保持している最も古いIDと最も新しいID。1件もなければNone
+    async fn bounds(&self) -> anyhow::Result<Option<(i64, i64)>>;
+    // `before` より前に保存したイベントを消す
+    // 最新のイベントはどこまで消したかの目印として残し、表が空にならないようにする
+    async fn prune(&self, before: DateTime<Utc>) -> anyhow::Result<u64>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEvent {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub event: ChangeEvent,
+}
+
+#[derive(Debug, FromRow)]
+struct EventRow {
+    id: i64,
+    workspace_id: i32,
+    kind: String,
+    entity_id: Option<i32>,
+    data: Option<Json<Value>>,
+    created_at: DateTime<Utc>,
+}
+
+impl TryFrom<EventRow> for StoredEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(row: EventRow) -> anyhow::Result<Self> {
+        let kind = row
+            .kind
+            .parse()
+            .map_err(|_| RepositoryError::Unexpected(format!("unknown event kind {}", row.kind)))?;
+        Ok(Self {
+            id: row.id,
+            created_at: row.created_at,
+            event: ChangeEvent {
+                kind,
+                workspace_id: row.workspace_id,
+                id: row.entity_id,
+                data: row.data.map(|Json(data)| data),
+            },
+        })
+    }
+}
+
+fn stored_events(rows: Vec<EventRow>) -> anyhow::Result<Vec<StoredEvent>> {
+    rows.into_iter().map(StoredEvent::try_from).collect()
+}
+
+// BIGSERIALのIDは採番した順ではなくコミットした順に見えるようになるので、
+// 並行して保存すると小さいIDが後から見え、続きから読む側が読み飛ばしてしまう
+// 保存をこのアドバイザリロックで1件ずつにして、IDが見える順を保存した順にそろえる
+const APPEND_LOCK: i64 = 0x746f_646f_6576;
+
+#[derive(Debug, Clone)]
+pub struct EventRepositoryForDb {
+    pool: PgPool,
+}
+
+impl EventRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl EventRepository for EventRepositoryForDb {
+    async fn append(&self, event: ChangeEvent) -> anyhow::Result<StoredEvent> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            select pg_advisory_xact_lock($1)
+            "#,
+        )
+        .bind(APPEND_LOCK)
+        .execute(&mut tx)
+        .await?;
+        let row = sqlx::query_as::<_, EventRow>(
+            r#"
+            insert into events ( workspace_id, kind, entity_id, data )
+            values ( $1, $2, $3, $4 )
+            returning id, workspace_id, kind, entity_id, data, created_at
+            "#,
+        )
+        .bind(event.workspace_id)
+        .bind(event.kind.as_str())
+        .bind(event.id)
+        .bind(event.data.map(Json))
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        row.try_into()
+    }
+
+    async fn after(
+        &self,
+        workspace_id: i32,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<StoredEvent>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"
+            select id, workspace_id, kind, entity_id, data, created_at from events
+            where workspace_id=$1 and id>$2
+            order by id asc
+            limit $3
+            "#,
+        )
+        .bind(workspace_id)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        stored_events(rows)
+    }
+
+    async fn tail(&self, after: i64, limit: i64) -> anyhow::Result<Vec<StoredEvent>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"
+            select id, workspace_id, kind, entity_id, data, created_at from events
+            where id>$1
+            order by id asc
+            limit $2
+            "#,
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        stored_events(rows)
+    }
+
+    async fn bounds(&self) -> anyhow::Result<Option<(i64, i64)>> {
+        let bounds = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(
+            r#"
+            select min(id), max(id) from events
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(bounds.0.zip(bounds.1))
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            r#"
+            delete from events
+            where created_at<$1 and id<(select max(id) from events)
+            "#,
+        )
+        .bind(before)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl EventRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl EventRepository for EventRepositoryForSqlite {
+    async fn append(&self, event: ChangeEvent) -> anyhow::Result<StoredEvent> {
+        let result = sqlx::query(
+            r#"
+            insert into events ( workspace_id, kind, entity_id, data, created_at )
+            values ( $1, $2, $3, $4, $5 )
+            "#,
+        )
+        .bind(event.workspace_id)
+        .bind(event.kind.as_str())
+        .bind(event.id)
+        .bind(event.data.map(Json))
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        let row = sqlx::query_as::<_, EventRow>(
+            r#"
+            select id, workspace_id, kind, entity_id, data, created_at from events
+            where id=$1
+            "#,
+        )
+        .bind(result.last_insert_rowid())
+        .fetch_one(&self.pool)
+        .await?;
+
+        row.try_into()
+    }
+
+    async fn after(
+        &self,
+        workspace_id: i32,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<StoredEvent>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"
+            select id, workspace_id, kind, entity_id, data, created_at from events
+            where workspace_id=$1 and id>$2
+            order by id asc
+            limit $3
+            "#,
+        )
+        .bind(workspace_id)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        stored_events(rows)
+    }
+
+    async fn tail(&self, after: i64, limit: i64) -> anyhow::Result<Vec<StoredEvent>> {
+        let rows = sqlx::query_as::<_, EventRow>(
+            r#"
+            select id, workspace_id, kind, entity_id, data, created_at from events
+            where id>$1
+            order by id asc
+            limit $2
+            "#,
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        stored_events(rows)
+    }
+
+    async fn bounds(&self) -> anyhow::Result<Option<(i64, i64)>> {
+        let bounds = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(
+            r#"
+            select min(id), max(id) from events
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(bounds.0.zip(bounds.1))
+    }
+
+    // 日時は文字列で保存しているので、julianday()で比較する
+    async fn prune(&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            r#"
+            delete from events
+            where julianday(created_at)<julianday($1) and id<(select max(id) from events)
+            "#,
+        )
+        .bind(before)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+// メモリのストレージでは履歴をファイルに書き出さない
+// 再起動するとIDが振り直されるが、知らないIDで再接続したクライアントには再同期を求める
+#[derive(Debug, Clone, Default)]
+pub struct EventRepositoryForMemory {
+    log: Arc<RwLock<EventLog>>,
+}
+
+#[derive(Debug, Default)]
+struct EventLog {
+    last_id: i64,
+    events: VecDeque<StoredEvent>,
+}
+
+impl EventRepositoryForMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EventRepository for EventRepositoryForMemory {
+    async fn append(&self, event: ChangeEvent) -> anyhow::Result<StoredEvent> {
+        let mut log = self.log.write().unwrap();
+        log.last_id += 1;
+        let event = StoredEvent {
+            id: log.last_id,
+            created_at: Utc::now(),
+            event,
+        };
+        log.events.push_back(event.clone());
+        Ok(event)
+    }
+
+    async fn after(
+        &self,
+        workspace_id: i32,
+        after: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<StoredEvent>> {
+        let log = self.log.read().unwrap();
+        Ok(log
+            .events
+            .iter()
+            .filter(|event| event.id > after && event.event.workspace_id == workspace_id)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn tail(&self, after: i64, limit: i64) -> anyhow::Result<Vec<StoredEvent>> {
+        let log = self.log.read().unwrap();
+        Ok(log
+            .events
+            .iter()
+            .filter(|event| event.id > after)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn bounds(&self) -> anyhow::Result<Option<(i64, i64)>> {
+        let log = self.log.read().unwrap();
+        Ok(log.events.front().zip(log.events.back()).map(|(first, last)| (first.id, last.id)))
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        let mut log = self.log.write().unwrap();
+        let mut pruned = 0;
+        while log.events.len() > 1 && log.events[0].created_at < before {
+            log.events.pop_front();
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+}
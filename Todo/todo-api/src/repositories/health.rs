@@ -0,0 +1,184 @@
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, SqlitePool};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// `/readyz` から呼ばれ、データベースに接続できるかとコネクションプールの状態を返す
+#[async_trait]
+pub trait HealthRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn check(&self) -> anyhow::Result<PoolStatus>;
+    fn pool_status(&self) -> PoolStatus;
+    // シャットダウン開始時にfalseにして、新しいリクエストを受けないようにする
+    fn readiness(&self) -> &Readiness;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub size: u32,
+    pub idle: usize,
+}
+
+// クローンしたものは同じフラグを共有する
+#[derive(Debug, Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.0.store(ready, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthRepositoryForDb {
+    pool: PgPool,
+    readiness: Readiness,
+}
+
+impl HealthRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            readiness: Readiness::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthRepository for HealthRepositoryForDb {
+    async fn check(&self) -> anyhow::Result<PoolStatus> {
+        sqlx::query("select 1").execute(&self.pool).await?;
+
+        Ok(self.pool_status())
+    }
+
+    fn pool_status(&self) -> PoolStatus {
+        PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.num_idle(),
+        }
+    }
+
+    fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthRepositoryForSqlite {
+    pool: SqlitePool,
+    readiness: Readiness,
+}
+
+impl HealthRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            readiness: Readiness::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthRepository for HealthRepositoryForSqlite {
+    async fn check(&self) -> anyhow::Result<PoolStatus> {
+        sqlx::query("select 1").execute(&self.pool).await?;
+
+        Ok(self.pool_status())
+    }
+
+    fn pool_status(&self) -> PoolStatus {
+        PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.num_idle(),
+        }
+    }
+
+    fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+    use std::env;
+
+    #[tokio::test]
+    async fn check_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = HealthRepositoryForDb::new(pool.clone());
+        let status = repository.check().await.expect("[check] returned Err");
+        assert!(status.size >= 1);
+
+        // プールを閉じた後は失敗する
+        pool.close().await;
+        assert!(repository.check().await.is_err());
+    }
+}
+
+// メモリのストレージには接続がないので、常に正常として扱う
+#[derive(Debug, Clone, Default)]
+pub struct HealthRepositoryForMemory {
+    healthy: Arc<AtomicBool>,
+    readiness: Readiness,
+}
+
+impl HealthRepositoryForMemory {
+    pub fn new() -> Self {
+        HealthRepositoryForMemory {
+            healthy: Arc::new(AtomicBool::new(true)),
+            readiness: Readiness::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthRepository for HealthRepositoryForMemory {
+    async fn check(&self) -> anyhow::Result<PoolStatus> {
+        if !self.healthy.load(Ordering::SeqCst) {
+            anyhow::bail!("database is unavailable");
+        }
+        Ok(self.pool_status())
+    }
+
+    fn pool_status(&self) -> PoolStatus {
+        PoolStatus { size: 1, idle: 1 }
+    }
+
+    fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+
+    impl HealthRepositoryForMemory {
+        // データベースが落ちた状態を再現する
+        pub fn set_healthy(&self, healthy: bool) {
+            self.healthy.store(healthy, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use axum::async_trait;
+use std::{future::Future, time::Instant};
+use tracing::Instrument;
+
+use super::{
+    label::{Label, LabelRepository, LabelView},
+    todo::{CreateTodo, Todo, TodoRepository, TodoView, UpdateTodo},
+    unit_of_work::{Transaction, UnitOfWork},
+};
+use crate::metrics::Metrics;
+
+// 任意のリポジトリを包んで、メソッドごとにspanを開き、処理時間をメトリクスに記録する
+#[derive(Clone)]
+pub struct InstrumentedRepository<R> {
+    inner: R,
+    name: &'static str,
+    metrics: Metrics,
+}
+
+impl<R> InstrumentedRepository<R> {
+    pub fn new(inner: R, name: &'static str, metrics: Metrics) -> Self {
+        Self {
+            inner,
+            name,
+            metrics,
+        }
+    }
+
+    async fn observe<T>(
+        &self,
+        method: &'static str,
+        call: impl Future<Output = anyhow::Result<T>> + Send,
+    ) -> anyhow::Result<T> {
+        observe(&self.metrics, self.name, method, call).await
+    }
+}
+
+async fn observe<T>(
+    metrics: &Metrics,
+    name: &'static str,
+    method: &'static str,
+    call: impl Future<Output = anyhow::Result<T>> + Send,
+) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let result = call
+        .instrument(tracing::info_span!("repository", repository = name, method))
+        .await;
+    if let Err(e) = &result {
+        tracing::debug!(repository = name, method, "repository call failed: {}", e);
+    }
+    metrics.observe_repository(name, method, result.is_ok(), start.elapsed());
+    result
+}
+
+#[async_trait]
+impl<R: TodoRepository> TodoRepository for InstrumentedRepository<R> {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        self.observe("create", self.inner.create(user_id, payload))
+            .await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        self.observe("find", self.inner.find(user_id, id)).await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        self.observe("all", self.inner.all(user_id)).await
+    }
+
+    async fn update(&self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        self.observe("update", self.inner.update(user_id, id, payload))
+            .await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.observe("delete", self.inner.delete(user_id, id)).await
+    }
+}
+
+#[async_trait]
+impl<R: LabelRepository> LabelRepository for InstrumentedRepository<R> {
+    async fn create(&self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        self.observe("create", self.inner.create(user_id, name))
+            .await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        self.observe("all", self.inner.all(user_id)).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.observe("delete", self.inner.delete(user_id, id)).await
+    }
+}
+
+// ユニットオブワークも包んで、トランザクションの開始と確定、中で使うTodoとラベルの操作を記録する
+#[derive(Clone)]
+pub struct InstrumentedUnitOfWork<U> {
+    inner: U,
+    metrics: Metrics,
+}
+
+impl<U> InstrumentedUnitOfWork<U> {
+    pub fn new(inner: U, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl<U: UnitOfWork> UnitOfWork for InstrumentedUnitOfWork<U> {
+    type Transaction = InstrumentedTransaction<U::Transaction>;
+
+    async fn begin(&self) -> anyhow::Result<Self::Transaction> {
+        let inner = observe(&self.metrics, "unit_of_work", "begin", self.inner.begin()).await?;
+        Ok(InstrumentedTransaction {
+            inner,
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    async fn begin_snapshot(&self) -> anyhow::Result<Self::Transaction> {
+        let call = self.inner.begin_snapshot();
+        let inner = observe(&self.metrics, "unit_of_work", "begin_snapshot", call).await?;
+        Ok(InstrumentedTransaction {
+            inner,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+pub struct InstrumentedTransaction<T> {
+    inner: T,
+    metrics: Metrics,
+}
+
+#[async_trait]
+impl<T: Transaction> Transaction for InstrumentedTransaction<T> {
+    fn todos(&mut self) -> Box<dyn TodoView + '_> {
+        Box::new(InstrumentedView {
+            inner: self.inner.todos(),
+            name: "todo_view",
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    fn labels(&mut self) -> Box<dyn LabelView + '_> {
+        Box::new(InstrumentedView {
+            inner: self.inner.labels(),
+            name: "label_view",
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    async fn commit(self) -> anyhow::Result<()> {
+        let Self { inner, metrics } = self;
+        observe(&metrics, "unit_of_work", "commit", inner.commit()).await
+    }
+
+    async fn rollback(self) -> anyhow::Result<()> {
+        let Self { inner, metrics } = self;
+        observe(&metrics, "unit_of_work", "rollback", inner.rollback()).await
+    }
+}
+
+struct InstrumentedView<V> {
+    inner: V,
+    name: &'static str,
+    metrics: Metrics,
+}
+
+#[async_trait]
+impl<'a> TodoView for InstrumentedView<Box<dyn TodoView + 'a>> {
+    async fn create(&mut self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        observe(
+            &self.metrics,
+            self.name,
+            "create",
+            self.inner.create(user_id, payload),
+        )
+        .await
+    }
+
+    async fn find(&mut self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        observe(
+            &self.metrics,
+            self.name,
+            "find",
+            self.inner.find(user_id, id),
+        )
+        .await
+    }
+
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        observe(&self.metrics, self.name, "all", self.inner.all(user_id)).await
+    }
+
+    async fn update(&mut self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let call = self.inner.update(user_id, id, payload);
+        observe(&self.metrics, self.name, "update", call).await
+    }
+
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        observe(
+            &self.metrics,
+            self.name,
+            "delete",
+            self.inner.delete(user_id, id),
+        )
+        .await
+    }
+
+    async fn set_labels(
+        &mut self,
+        user_id: i32,
+        id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<Label>> {
+        let call = self.inner.set_labels(user_id, id, label_ids);
+        observe(&self.metrics, self.name, "set_labels", call).await
+    }
+
+    async fn labels(&mut self, user_id: i32, id: i32) -> anyhow::Result<Vec<Label>> {
+        observe(
+            &self.metrics,
+            self.name,
+            "labels",
+            self.inner.labels(user_id, id),
+        )
+        .await
+    }
+
+    async fn labels_of(&mut self, user_id: i32, ids: &[i32]) -> anyhow::Result<Vec<(i32, Label)>> {
+        observe(
+            &self.metrics,
+            self.name,
+            "labels_of",
+            self.inner.labels_of(user_id, ids),
+        )
+        .await
+    }
+
+    async fn todos_of(
+        &mut self,
+        user_id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Todo)>> {
+        let call = self.inner.todos_of(user_id, label_ids);
+        observe(&self.metrics, self.name, "todos_of", call).await
+    }
+}
+
+#[async_trait]
+impl<'a> LabelView for InstrumentedView<Box<dyn LabelView + 'a>> {
+    async fn create(&mut self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        observe(
+            &self.metrics,
+            self.name,
+            "create",
+            self.inner.create(user_id, name),
+        )
+        .await
+    }
+
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        observe(&self.metrics, self.name, "all", self.inner.all(user_id)).await
+    }
+
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        observe(
+            &self.metrics,
+            self.name,
+            "delete",
+            self.inner.delete(user_id, id),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::LabelRepositoryForMemory, memory::MemoryDatabase, todo::TodoRepositoryForMemory,
+        unit_of_work::UnitOfWorkForMemory,
+    };
+
+    #[tokio::test]
+    async fn should_record_repository_calls() {
+        let metrics = Metrics::new();
+        let todo_repository =
+            InstrumentedRepository::new(TodoRepositoryForMemory::new(), "todo", metrics.clone());
+        let label_repository =
+            InstrumentedRepository::new(LabelRepositoryForMemory::new(), "label", metrics.clone());
+
+        todo_repository
+            .create(1, CreateTodo::new("instrumented".to_string()))
+            .await
+            .expect("failed create todo");
+        assert!(todo_repository.find(1, 100).await.is_err());
+        label_repository
+            .create(1, "instrumented".to_string())
+            .await
+            .expect("failed create label");
+
+        let text = metrics.render().unwrap();
+        assert!(text.contains(
+            r#"repository_call_duration_seconds_count{method="create",repository="todo",result="ok"} 1"#
+        ));
+        assert!(text.contains(
+            r#"repository_call_duration_seconds_count{method="find",repository="todo",result="error"} 1"#
+        ));
+        assert!(text.contains(
+            r#"repository_call_duration_seconds_count{method="create",repository="label",result="ok"} 1"#
+        ));
+    }
+    #[tokio::test]
+    async fn should_record_unit_of_work_calls() {
+        let metrics = Metrics::new();
+        let unit_of_work = InstrumentedUnitOfWork::new(
+            UnitOfWorkForMemory::with_database(MemoryDatabase::default()),
+            metrics.clone(),
+        );
+
+        let mut tx = unit_of_work.begin().await.expect("failed begin");
+        let todo = tx
+            .todos()
+            .create(1, CreateTodo::new("instrumented".to_string()))
+            .await
+            .expect("failed create todo");
+        let label = tx
+            .labels()
+            .create(1, "instrumented".to_string())
+            .await
+            .expect("failed create label");
+        assert!(tx
+            .todos()
+            .set_labels(1, todo.id, &[label.id, 9999])
+            .await
+            .is_err());
+        tx.commit().await.expect("failed commit");
+
+        let text = metrics.render().unwrap();
+        for line in [
+            r#"{method="begin",repository="unit_of_work",result="ok"} 1"#,
+            r#"{method="create",repository="todo_view",result="ok"} 1"#,
+            r#"{method="create",repository="label_view",result="ok"} 1"#,
+            r#"{method="set_labels",repository="todo_view",result="error"} 1"#,
+            r#"{method="commit",repository="unit_of_work",result="ok"} 1"#,
+        ] {
+            assert!(text.contains(&format!("repository_call_duration_seconds_count{}", line)));
+        }
+    }
+}
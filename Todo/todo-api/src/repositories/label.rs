@@ -1,13 +1,31 @@
-use super::RepositoryError;
+use super::{
+    db_span,
+    memory::{MemoryDatabase, Tables},
+    sqlite_span, RepositoryError,
+};
+use crate::{
+    events::{ChangeEvent, ChangeKind},
+    fanout::notify,
+};
 use axum::async_trait;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool, SqliteConnection, SqlitePool};
+use tracing::Instrument;
 
 #[async_trait]
 pub trait LabelRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, name: String) -> anyhow::Result<Label>;
-    async fn all(&self) -> anyhow::Result<Vec<Label>>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+    async fn create(&self, user_id: i32, name: String) -> anyhow::Result<Label>;
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Label>>;
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()>;
+}
+
+// 1つのコネクションやトランザクションに束ねたラベルの操作。`UnitOfWork` から使う
+#[async_trait]
+pub trait LabelView: std::marker::Send {
+    async fn create(&mut self, user_id: i32, name: String) -> anyhow::Result<Label>;
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Label>>;
+    // 付いているTodoからも外す
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()>;
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow)]
@@ -16,12 +34,6 @@ pub struct Label {
     pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct UpdateLabel {
-    id: i32,
-    name: String,
-}
-
 #[derive(Debug, Clone)]
 pub struct LabelRepositoryForDb {
     pool: PgPool,
@@ -35,61 +47,331 @@ impl LabelRepositoryForDb {
 
 #[async_trait]
 impl LabelRepository for LabelRepositoryForDb {
-    async fn create(&self, name: String) -> anyhow::Result<Label> {
-        let optional_label = sqlx::query_as::<_, Label>(
-            r#"
-            select * from labels where name=$1
-            "#,
-        )
-        .bind(name.clone())
-        .fetch_optional(&self.pool)
-        .await?;
+    async fn create(&self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        let mut conn = self.pool.acquire().await?;
+        LabelViewForDb::new(&mut conn).create(user_id, name).await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        let mut conn = self.pool.acquire().await?;
+        LabelViewForDb::new(&mut conn).all(user_id).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        LabelViewForDb::new(&mut tx).delete(user_id, id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub struct LabelViewForDb<'a> {
+    conn: &'a mut PgConnection,
+}
+
+impl<'a> LabelViewForDb<'a> {
+    pub fn new(conn: &'a mut PgConnection) -> Self {
+        Self { conn }
+    }
+}
+
+#[async_trait]
+impl LabelView for LabelViewForDb<'_> {
+    async fn create(&mut self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        // ラベル名の重複はユーザーごとに判定する
+        let sql = r#"
+            select * from labels where name=$1 and user_id=$2
+            "#;
+        let optional_label = sqlx::query_as::<_, Label>(sql)
+            .bind(name.clone())
+            .bind(user_id)
+            .fetch_optional(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
 
         if let Some(label) = optional_label {
             return Err(RepositoryError::Duplicate(label.id).into());
         }
 
-        let label = sqlx::query_as::<_, Label>(
-            r#"
-            insert into labels ( name )
-            values ( $1 )
+        let sql = r#"
+            insert into labels ( name, user_id )
+            values ( $1, $2 )
             returning *
-            "#,
-        )
-        .bind(name.clone())
-        .fetch_one(&self.pool)
-        .await?;
+            "#;
+        let label = sqlx::query_as::<_, Label>(sql)
+            .bind(name.clone())
+            .bind(user_id)
+            .fetch_one(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+        let event = ChangeEvent::new(ChangeKind::LabelCreated, user_id, label.id, &label);
+        notify(&mut *self.conn, event).await?;
 
         Ok(label)
     }
 
-    async fn all(&self) -> anyhow::Result<Vec<Label>> {
-        let labels = sqlx::query_as::<_, Label> (
-            r#"
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        let sql = r#"
             select * from labels
+            where user_id=$1
             order by labels.id asc
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
+            "#;
+        let labels = sqlx::query_as::<_, Label>(sql)
+            .bind(user_id)
+            .fetch_all(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
 
         Ok(labels)
     }
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        sqlx::query(
-            r#"
-            delete from labels where id=$1
-            "#,
-        )
-        .bind(id)
-        .execute(&self.pool)//.poolとは？：https://docs.rs/sqlx/0.5.5/sqlx/struct.Pool.html
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let sql = r#"
+            delete from todo_labels
+            where label_id=(select id from labels where id=$1 and user_id=$2)
+            "#;
+        sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+
+        let sql = r#"
+            delete from labels where id=$1 and user_id=$2
+            "#;
+        let result = sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)//.poolとは？：https://docs.rs/sqlx/0.5.5/sqlx/struct.Pool.html
+            .instrument(db_span(sql))
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                _ => RepositoryError::Unexpected(e.to_string()),
+            })?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        let event = ChangeEvent::deleted(ChangeKind::LabelDeleted, user_id, id);
+        notify(&mut *self.conn, event).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LabelRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl LabelRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl LabelRepository for LabelRepositoryForSqlite {
+    async fn create(&self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        let mut conn = self.pool.acquire().await?;
+        LabelViewForSqlite::new(&mut conn).create(user_id, name).await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        let mut conn = self.pool.acquire().await?;
+        LabelViewForSqlite::new(&mut conn).all(user_id).await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        LabelViewForSqlite::new(&mut tx).delete(user_id, id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+pub struct LabelViewForSqlite<'a> {
+    conn: &'a mut SqliteConnection,
+}
+
+impl<'a> LabelViewForSqlite<'a> {
+    pub fn new(conn: &'a mut SqliteConnection) -> Self {
+        Self { conn }
+    }
+}
+
+#[async_trait]
+impl LabelView for LabelViewForSqlite<'_> {
+    async fn create(&mut self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        let sql = r#"
+            select id, name from labels where name=$1 and user_id=$2
+            "#;
+        let optional_label = sqlx::query_as::<_, Label>(sql)
+            .bind(name.clone())
+            .bind(user_id)
+            .fetch_optional(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        if let Some(label) = optional_label {
+            return Err(RepositoryError::Duplicate(label.id).into());
+        }
+
+        let sql = r#"
+            insert into labels ( name, user_id )
+            values ( $1, $2 )
+            "#;
+        let result = sqlx::query(sql)
+            .bind(name.clone())
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        Ok(Label {
+            id: result.last_insert_rowid() as i32,
+            name,
+        })
+    }
+
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        let sql = r#"
+            select id, name from labels
+            where user_id=$1
+            order by id asc
+            "#;
+        let labels = sqlx::query_as::<_, Label>(sql)
+            .bind(user_id)
+            .fetch_all(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        Ok(labels)
+    }
+
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let sql = r#"
+            delete from todo_labels
+            where label_id=(select id from labels where id=$1 and user_id=$2)
+            "#;
+        sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        let sql = r#"
+            delete from labels where id=$1 and user_id=$2
+            "#;
+        let result = sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+}
+
+// 1つの `MemoryDatabase` を他のメモリのリポジトリと共有する
+#[derive(Debug, Clone)]
+pub struct LabelRepositoryForMemory {
+    database: MemoryDatabase,
+}
+
+impl LabelRepositoryForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl LabelRepository for LabelRepositoryForMemory {
+    async fn create(&self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        self.database
+            .write(|tables| tables.create_label(user_id, name))
+            .await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        Ok(self
+            .database
+            .read(|tables| tables.all_labels(user_id))
+            .await)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.database
+            .write(|tables| tables.delete_label(user_id, id))
+            .await
+    }
+}
 
+// トランザクション中の複製したテーブルを操作する
+pub struct LabelViewForMemory<'a> {
+    tables: &'a mut Tables,
+}
+
+impl<'a> LabelViewForMemory<'a> {
+    pub fn new(tables: &'a mut Tables) -> Self {
+        Self { tables }
+    }
+}
+
+#[async_trait]
+impl LabelView for LabelViewForMemory<'_> {
+    async fn create(&mut self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        self.tables.create_label(user_id, name)
+    }
+
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Label>> {
+        Ok(self.tables.all_labels(user_id))
+    }
+
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.tables.delete_label(user_id, id)
+    }
+}
+
+// リポジトリとトランザクションで共有するメモリ上の操作
+impl Tables {
+    fn create_label(&mut self, user_id: i32, name: String) -> anyhow::Result<Label> {
+        if let Some((_key, label)) = self
+            .labels
+            .rows()
+            .find(|((owner, _id), label)| *owner == user_id && label.name == name)
+        {
+            return Err(RepositoryError::Duplicate(label.id).into());
+        };
+
+        let id = self.labels.next_id();
+        let label = Label { id, name };
+        self.labels.insert((user_id, id), label.clone());
+        Ok(label)
+    }
+
+    fn all_labels(&self, user_id: i32) -> Vec<Label> {
+        self.labels
+            .rows()
+            .filter(|((owner, _id), _label)| *owner == user_id)
+            .map(|(_key, label)| label.clone())
+            .collect()
+    }
+
+    fn delete_label(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.labels
+            .remove(&(user_id, id))
+            .ok_or(RepositoryError::NotFound(id))?;
+        self.detach_todo_labels(|(_todo_id, label_id)| *label_id == id);
         Ok(())
     }
 }
@@ -98,31 +380,45 @@ impl LabelRepository for LabelRepositoryForDb {
 #[cfg(feature = "database-test")]
 mod test {
     use super::*;
+    use crate::repositories::user::{User, UserRepository, UserRepositoryForDb};
     use dotenv::dotenv;
     use sqlx::PgPool;
     use std::env;
 
+    async fn recreate_user(pool: &PgPool, name: &str) -> User {
+        sqlx::query("delete from users where name=$1")
+            .bind(name)
+            .execute(pool)
+            .await
+            .expect("[recreate_user] delete returned Err");
+        UserRepositoryForDb::new(pool.clone())
+            .create(name.to_string())
+            .await
+            .expect("[recreate_user] create returned Err")
+    }
+
     #[tokio::test]
     async fn crud_scenario() {
         dotenv().ok();
     let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
     let pool = PgPool::connect(database_url)
         .await
-        .expect(&format!("fail connect database, url is [{}]", database_url));
+        .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
 
+    let user = recreate_user(&pool, "[label crud_scenario]").await;
     let repository = LabelRepositoryForDb::new(pool);
     let label_text = "test_label";
 
     //create
     let label = repository
-        .create(label_text.to_string())
+        .create(user.id, label_text.to_string())
         .await
         .expect("[create] returned Err");
     assert_eq!(label.name, label_text);
 
     //all
     let labels = repository
-        .all()
+        .all(user.id)
         .await
         .expect("[all] returned Err");
     let label = labels.last().unwrap();
@@ -130,20 +426,102 @@ mod test {
 
     //delete
     repository
-        .delete(label.id)
+        .delete(user.id, label.id)
         .await
         .expect("[delete] returned Err")
     }
+
+    #[tokio::test]
+    async fn isolation_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = recreate_user(&pool, "[label isolation_scenario] owner").await;
+        let other = recreate_user(&pool, "[label isolation_scenario] other").await;
+        let repository = LabelRepositoryForDb::new(pool);
+        let label_text = "shared_name";
+
+        let label = repository
+            .create(owner.id, label_text.to_string())
+            .await
+            .expect("[create] returned Err");
+
+        // 同じユーザーでの重複は許可しない
+        let res = repository.create(owner.id, label_text.to_string()).await;
+        assert!(res.is_err());
+
+        // 別のユーザーなら同じ名前を作成できる
+        let other_label = repository
+            .create(other.id, label_text.to_string())
+            .await
+            .expect("[create] returned Err for other user");
+        assert_ne!(label.id, other_label.id);
+
+        let labels = repository.all(other.id).await.expect("[all] returned Err");
+        assert_eq!(vec![other_label], labels);
+
+        // 他のユーザーのラベルは削除できない
+        assert!(repository.delete(other.id, label.id).await.is_err());
+    }
 }
 
 #[cfg(test)]
-pub mod test_utils {
-    use crate::repositories::label::{LabelRepository, RepositoryError};
-    use axum::async_trait;
-    use std::collections::HashMap;
-    use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+mod sqlite_test {
+    use super::*;
+    use crate::repositories::{
+        sqlite_test_pool,
+        user::{UserRepository, UserRepositoryForSqlite},
+    };
 
-    use super::Label;
+    #[tokio::test]
+    async fn sqlite_crud_scenario() {
+        let pool = sqlite_test_pool().await;
+        let owner = UserRepositoryForSqlite::new(pool.clone())
+            .create("owner".to_string())
+            .await
+            .expect("[create user] returned Err");
+        let other = UserRepositoryForSqlite::new(pool.clone())
+            .create("other".to_string())
+            .await
+            .expect("[create user] returned Err");
+        let repository = LabelRepositoryForSqlite::new(pool);
+
+        let work = repository
+            .create(owner.id, "work".to_string())
+            .await
+            .expect("[create] returned Err");
+        let home = repository
+            .create(owner.id, "home".to_string())
+            .await
+            .expect("[create] returned Err");
+
+        // 同じユーザーでの重複は許可しない
+        assert!(repository.create(owner.id, "work".to_string()).await.is_err());
+        // 別のユーザーなら同じ名前を作成できる
+        let other_work = repository
+            .create(other.id, "work".to_string())
+            .await
+            .expect("[create] returned Err for other user");
+
+        let labels = repository.all(owner.id).await.expect("[all] returned Err");
+        assert_eq!(vec![work.clone(), home], labels);
+        assert_eq!(vec![other_work], repository.all(other.id).await.unwrap());
+
+        assert!(repository.delete(other.id, work.id).await.is_err());
+        repository
+            .delete(owner.id, work.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.delete(owner.id, work.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
 
     impl Label {
         pub fn new(id: i32, name: String) -> Self {
@@ -154,53 +532,9 @@ pub mod test_utils {
         }
     }
 
-    type LabelData = HashMap<i32, Label>;
-
-    #[derive(Debug, Clone)]
-    pub struct LabelRepositoryForMemory {
-        store: Arc<RwLock<LabelData>>,
-    }
-
     impl LabelRepositoryForMemory {
         pub fn new() -> Self {
-            LabelRepositoryForMemory {
-                store: Arc::default()
-            }
-        }
-
-        fn write_store_ref(&self) -> RwLockWriteGuard<LabelData> {
-            self.store.write().unwrap()
-        }
-
-        fn read_store_ref(&self) -> RwLockReadGuard<LabelData> {
-            self.store.read().unwrap()
-        }
-    }
-
-    #[async_trait]
-    impl LabelRepository for LabelRepositoryForMemory {
-        async fn create(&self, name: String) -> anyhow::Result<Label> {
-            let mut store = self.write_store_ref();
-            if let Some((_key, label)) = store.iter().find(|(_key, label)| label.name == name) {
-                return Ok(label.clone());
-            };
-
-            let id = store.len() as i32 + 1;
-            let label = Label::new(id, name.clone());
-            store.insert(id, label.clone());
-            Ok(label)
-        }
-
-        async fn all(&self) -> anyhow::Result<Vec<Label>> {
-            let store = self.read_store_ref();
-            let labels = Vec::from_iter(store.values().map(|label| label.clone()));
-            Ok(labels)
-        }
-
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
-            let mut store = self.write_store_ref();
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?;
-            Ok(())
+            Self::with_database(MemoryDatabase::default())
         }
     }
 
@@ -214,23 +548,42 @@ pub mod test_utils {
         async fn label_crud_scenario() {
             let text = "label_text".to_string();
             let id = 1;
+            let user_id = 1;
             let expected = Label::new(id, text.clone());
 
             //create
             let repository = LabelRepositoryForMemory::new();
             let label = repository
-                .create(text.clone())
+                .create(user_id, text.clone())
                 .await
                 .expect("failed create label");
             assert_eq!(expected, label);
 
             //all
-            let label = repository.all().await.unwrap();
+            let label = repository.all(user_id).await.unwrap();
             assert_eq!(vec![expected], label);
 
             // delete
-            let res = repository.delete(id).await;
+            let res = repository.delete(user_id, id).await;
             assert!(res.is_ok())
         }
+
+        #[tokio::test]
+        async fn label_isolation_scenario() {
+            let repository = LabelRepositoryForMemory::new();
+            let label = repository
+                .create(1, "shared_name".to_string())
+                .await
+                .expect("failed create label");
+
+            // 別のユーザーなら同じ名前でも別のラベルになる
+            let other_label = repository
+                .create(2, "shared_name".to_string())
+                .await
+                .expect("failed create label");
+            assert_ne!(label.id, other_label.id);
+            assert_eq!(vec![other_label], repository.all(2).await.unwrap());
+            assert!(repository.delete(2, label.id).await.is_err());
+        }
     }
 }
@@ -0,0 +1,473 @@
+use super::{memory::MemoryDatabase, RepositoryError};
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, SqlitePool};
+
+// ワークスペース（あるユーザーのTodoとラベル）を共有するメンバーを管理する
+#[async_trait]
+pub trait MemberRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn save(&self, owner_id: i32, user_id: i32, role: Role) -> anyhow::Result<Member>;
+    async fn find(&self, owner_id: i32, user_id: i32) -> anyhow::Result<Member>;
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Member>>;
+    async fn shared_with(&self, user_id: i32) -> anyhow::Result<Vec<Member>>;
+    async fn delete(&self, owner_id: i32, user_id: i32) -> anyhow::Result<()>;
+}
+
+// 宣言順に Viewer < Editor < Owner となる
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "member_role", rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct Member {
+    pub owner_id: i32,
+    pub user_id: i32,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberRepositoryForDb {
+    pool: PgPool,
+}
+
+impl MemberRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl MemberRepository for MemberRepositoryForDb {
+    async fn save(&self, owner_id: i32, user_id: i32, role: Role) -> anyhow::Result<Member> {
+        // 招待済みのメンバーはロールを更新する
+        let member = sqlx::query_as::<_, Member>(
+            r#"
+            insert into members ( owner_id, user_id, role )
+            values ( $1, $2, $3 )
+            on conflict ( owner_id, user_id ) do update set role = excluded.role
+            returning owner_id, user_id, role
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user_id)
+        .bind(role)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(member)
+    }
+
+    async fn find(&self, owner_id: i32, user_id: i32) -> anyhow::Result<Member> {
+        let member = sqlx::query_as::<_, Member>(
+            r#"
+            select owner_id, user_id, role from members
+            where owner_id=$1 and user_id=$2
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(user_id),
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(member)
+    }
+
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Member>> {
+        let members = sqlx::query_as::<_, Member>(
+            r#"
+            select owner_id, user_id, role from members
+            where owner_id=$1
+            order by user_id asc
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    async fn shared_with(&self, user_id: i32) -> anyhow::Result<Vec<Member>> {
+        let members = sqlx::query_as::<_, Member>(
+            r#"
+            select owner_id, user_id, role from members
+            where user_id=$1
+            order by owner_id asc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    async fn delete(&self, owner_id: i32, user_id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from members where owner_id=$1 and user_id=$2
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(user_id).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl MemberRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl MemberRepository for MemberRepositoryForSqlite {
+    async fn save(&self, owner_id: i32, user_id: i32, role: Role) -> anyhow::Result<Member> {
+        sqlx::query(
+            r#"
+            insert into members ( owner_id, user_id, role )
+            values ( $1, $2, $3 )
+            on conflict ( owner_id, user_id ) do update set role = excluded.role
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user_id)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(Member {
+            owner_id,
+            user_id,
+            role,
+        })
+    }
+
+    async fn find(&self, owner_id: i32, user_id: i32) -> anyhow::Result<Member> {
+        let member = sqlx::query_as::<_, Member>(
+            r#"
+            select owner_id, user_id, role from members
+            where owner_id=$1 and user_id=$2
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(user_id),
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(member)
+    }
+
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Member>> {
+        let members = sqlx::query_as::<_, Member>(
+            r#"
+            select owner_id, user_id, role from members
+            where owner_id=$1
+            order by user_id asc
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    async fn shared_with(&self, user_id: i32) -> anyhow::Result<Vec<Member>> {
+        let members = sqlx::query_as::<_, Member>(
+            r#"
+            select owner_id, user_id, role from members
+            where user_id=$1
+            order by owner_id asc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    async fn delete(&self, owner_id: i32, user_id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from members where owner_id=$1 and user_id=$2
+            "#,
+        )
+        .bind(owner_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(user_id).into());
+        }
+
+        Ok(())
+    }
+}
+
+// 1つの `MemoryDatabase` を他のメモリのリポジトリと共有する
+#[derive(Debug, Clone)]
+pub struct MemberRepositoryForMemory {
+    database: MemoryDatabase,
+}
+
+impl MemberRepositoryForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl MemberRepository for MemberRepositoryForMemory {
+    async fn save(&self, owner_id: i32, user_id: i32, role: Role) -> anyhow::Result<Member> {
+        self.database
+            .write(|tables| {
+                let member = Member {
+                    owner_id,
+                    user_id,
+                    role,
+                };
+                tables.members.insert((owner_id, user_id), member.clone());
+                Ok(member)
+            })
+            .await
+    }
+
+    async fn find(&self, owner_id: i32, user_id: i32) -> anyhow::Result<Member> {
+        let member = self
+            .database
+            .read(|tables| tables.members.get(&(owner_id, user_id)).cloned())
+            .await
+            .ok_or(RepositoryError::NotFound(user_id))?;
+        Ok(member)
+    }
+
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Member>> {
+        let mut members: Vec<Member> = self
+            .database
+            .read(|tables| {
+                tables
+                    .members
+                    .values()
+                    .filter(|member| member.owner_id == owner_id)
+                    .cloned()
+                    .collect()
+            })
+            .await;
+        members.sort_by_key(|member| member.user_id);
+        Ok(members)
+    }
+
+    async fn shared_with(&self, user_id: i32) -> anyhow::Result<Vec<Member>> {
+        let mut members: Vec<Member> = self
+            .database
+            .read(|tables| {
+                tables
+                    .members
+                    .values()
+                    .filter(|member| member.user_id == user_id)
+                    .cloned()
+                    .collect()
+            })
+            .await;
+        members.sort_by_key(|member| member.owner_id);
+        Ok(members)
+    }
+
+    async fn delete(&self, owner_id: i32, user_id: i32) -> anyhow::Result<()> {
+        self.database
+            .write(|tables| {
+                tables
+                    .members
+                    .remove(&(owner_id, user_id))
+                    .ok_or(RepositoryError::NotFound(user_id))?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::user::{User, UserRepository, UserRepositoryForDb};
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+    use std::env;
+
+    async fn recreate_user(pool: &PgPool, name: &str) -> User {
+        sqlx::query("delete from users where name=$1")
+            .bind(name)
+            .execute(pool)
+            .await
+            .expect("[recreate_user] delete returned Err");
+        UserRepositoryForDb::new(pool.clone())
+            .create(name.to_string())
+            .await
+            .expect("[recreate_user] create returned Err")
+    }
+
+    #[tokio::test]
+    async fn crud_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = recreate_user(&pool, "[member crud_scenario] owner").await;
+        let user = recreate_user(&pool, "[member crud_scenario] user").await;
+        let repository = MemberRepositoryForDb::new(pool);
+
+        // save
+        let member = repository
+            .save(owner.id, user.id, Role::Viewer)
+            .await
+            .expect("[save] returned Err");
+        assert_eq!(Role::Viewer, member.role);
+
+        // save（ロールの更新）
+        let member = repository
+            .save(owner.id, user.id, Role::Editor)
+            .await
+            .expect("[save] returned Err");
+        assert_eq!(Role::Editor, member.role);
+
+        // find
+        let found = repository
+            .find(owner.id, user.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(member, found);
+
+        // all / shared_with
+        let members = repository.all(owner.id).await.expect("[all] returned Err");
+        assert_eq!(vec![member.clone()], members);
+        let shared = repository
+            .shared_with(user.id)
+            .await
+            .expect("[shared_with] returned Err");
+        assert_eq!(vec![member], shared);
+
+        // delete
+        repository
+            .delete(owner.id, user.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.find(owner.id, user.id).await.is_err());
+        assert!(repository.delete(owner.id, user.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod sqlite_test {
+    use super::*;
+    use crate::repositories::{
+        sqlite_test_pool,
+        user::{UserRepository, UserRepositoryForSqlite},
+    };
+
+    #[tokio::test]
+    async fn sqlite_crud_scenario() {
+        let pool = sqlite_test_pool().await;
+        let user_repository = UserRepositoryForSqlite::new(pool.clone());
+        let owner = user_repository.create("owner".to_string()).await.unwrap();
+        let user = user_repository.create("user".to_string()).await.unwrap();
+        let repository = MemberRepositoryForSqlite::new(pool);
+
+        repository
+            .save(owner.id, user.id, Role::Viewer)
+            .await
+            .expect("[save] returned Err");
+        // 招待済みのメンバーはロールを更新する
+        let member = repository
+            .save(owner.id, user.id, Role::Editor)
+            .await
+            .expect("[save] returned Err");
+        assert_eq!(Role::Editor, member.role);
+
+        let found = repository
+            .find(owner.id, user.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(member, found);
+        assert_eq!(vec![member.clone()], repository.all(owner.id).await.unwrap());
+        assert_eq!(vec![member], repository.shared_with(user.id).await.unwrap());
+
+        repository
+            .delete(owner.id, user.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.find(owner.id, user.id).await.is_err());
+        assert!(repository.delete(owner.id, user.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+
+    impl MemberRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::with_database(MemoryDatabase::default())
+        }
+    }
+
+    mod test {
+        use super::*;
+
+        #[tokio::test]
+        async fn member_crud_scenario() {
+            let repository = MemberRepositoryForMemory::new();
+            let expected = Member {
+                owner_id: 1,
+                user_id: 2,
+                role: Role::Viewer,
+            };
+
+            // save
+            let member = repository
+                .save(1, 2, Role::Viewer)
+                .await
+                .expect("failed save member");
+            assert_eq!(expected, member);
+
+            // find / all / shared_with
+            assert_eq!(expected, repository.find(1, 2).await.unwrap());
+            assert_eq!(vec![expected.clone()], repository.all(1).await.unwrap());
+            assert_eq!(vec![expected], repository.shared_with(2).await.unwrap());
+
+            // delete
+            assert!(repository.delete(1, 2).await.is_ok());
+            assert!(repository.find(1, 2).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,617 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock as Gate};
+
+use super::{
+    label::Label,
+    member::Member,
+    todo::Todo,
+    token::TokenRecord,
+    user::User,
+    webhook::{Delivery, WebhookRecord},
+};
+
+// スナップショットの形式を変えたときは上げる
+const SNAPSHOT_VERSION: u32 = 1;
+
+// メモリ上のテーブル。IDは削除しても再利用しないように、最後に払い出した値を持つ
+#[derive(Debug, Clone)]
+pub struct Table<K, V> {
+    rows: BTreeMap<K, V>,
+    last_id: i32,
+    // 追記ログに書き出す前の変更（keyと、削除の場合はNone）
+    changes: Vec<(Value, Option<Value>)>,
+    // 失敗したときに戻すための、変更した行の変更前の値（なかった場合はNone）
+    undo: Vec<(K, Option<V>)>,
+    undo_last_id: Option<i32>,
+}
+
+impl<K, V> Default for Table<K, V> {
+    fn default() -> Self {
+        Self {
+            rows: BTreeMap::new(),
+            last_id: 0,
+            changes: Vec::new(),
+            undo: Vec::new(),
+            undo_last_id: None,
+        }
+    }
+}
+
+impl<K, V> Table<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    pub fn next_id(&mut self) -> i32 {
+        self.undo_last_id.get_or_insert(self.last_id);
+        self.last_id += 1;
+        self.last_id
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.rows.get(key)
+    }
+
+    pub fn rows(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> {
+        self.rows.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.rows.values()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.changes.push((to_value(&key), Some(to_value(&value))));
+        let old = self.rows.insert(key.clone(), value);
+        self.undo.push((key, old));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let removed = self.rows.remove(key);
+        if let Some(value) = &removed {
+            self.changes.push((to_value(key), None));
+            self.undo.push((key.clone(), Some(value.clone())));
+        }
+        removed
+    }
+
+    // 最後に確定してからの変更を新しいものから順に戻す
+    fn rollback(&mut self) {
+        for (key, value) in self.undo.drain(..).rev() {
+            match value {
+                Some(value) => self.rows.insert(key, value),
+                None => self.rows.remove(&key),
+            };
+        }
+        if let Some(last_id) = self.undo_last_id.take() {
+            self.last_id = last_id;
+        }
+        self.changes.clear();
+    }
+
+    fn settle(&mut self) {
+        self.undo.clear();
+        self.undo_last_id = None;
+    }
+
+    fn take_changes(&mut self, table: &'static str) -> Vec<Change> {
+        let last_id = self.last_id;
+        self.changes
+            .drain(..)
+            .map(|(key, value)| Change {
+                table: table.to_string(),
+                last_id,
+                key,
+                value,
+            })
+            .collect()
+    }
+
+    fn apply(&mut self, change: Change) -> anyhow::Result<()> {
+        let key: K = serde_json::from_value(change.key)?;
+        match change.value {
+            Some(value) => {
+                self.rows.insert(key, serde_json::from_value(value)?);
+            }
+            None => {
+                self.rows.remove(&key);
+            }
+        }
+        self.last_id = self.last_id.max(change.last_id);
+        Ok(())
+    }
+}
+
+fn to_value(value: &impl Serialize) -> Value {
+    serde_json::to_value(value).expect("fail serialize memory row")
+}
+
+// JSONのオブジェクトのキーにタプルは使えないので、行は `[key, value]` の配列で持つ
+#[derive(Serialize, Deserialize)]
+struct TableSnapshot<K, V> {
+    last_id: i32,
+    rows: Vec<(K, V)>,
+}
+
+impl<K: Serialize + Clone, V: Serialize + Clone> Serialize for Table<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TableSnapshot {
+            last_id: self.last_id,
+            rows: self
+                .rows
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, K: Ord + DeserializeOwned, V: DeserializeOwned> Deserialize<'de> for Table<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let snapshot = TableSnapshot::<K, V>::deserialize(deserializer)?;
+        Ok(Self {
+            rows: snapshot.rows.into_iter().collect(),
+            last_id: snapshot.last_id,
+            ..Self::default()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tables {
+    // (user_id, id)をキーにしてユーザーごとに分ける
+    pub todos: Table<(i32, i32), Todo>,
+    pub labels: Table<(i32, i32), Label>,
+    pub users: Table<i32, User>,
+    // (owner_id, user_id)をキーにする
+    pub members: Table<(i32, i32), Member>,
+    pub tokens: Table<i32, TokenRecord>,
+    // (todo_id, label_id)をキーにする
+    pub todo_labels: Table<(i32, i32), ()>,
+    pub webhooks: Table<i32, WebhookRecord>,
+    // 配信のIDをキーにする
+    pub webhook_deliveries: Table<String, Delivery>,
+}
+
+impl Tables {
+    fn take_changes(&mut self) -> Vec<Change> {
+        let mut changes = self.todos.take_changes("todos");
+        changes.extend(self.labels.take_changes("labels"));
+        changes.extend(self.users.take_changes("users"));
+        changes.extend(self.members.take_changes("members"));
+        changes.extend(self.tokens.take_changes("tokens"));
+        changes.extend(self.todo_labels.take_changes("todo_labels"));
+        changes.extend(self.webhooks.take_changes("webhooks"));
+        changes.extend(self.webhook_deliveries.take_changes("webhook_deliveries"));
+        changes
+    }
+
+    fn rollback(&mut self) {
+        self.todos.rollback();
+        self.labels.rollback();
+        self.users.rollback();
+        self.members.rollback();
+        self.tokens.rollback();
+        self.todo_labels.rollback();
+        self.webhooks.rollback();
+        self.webhook_deliveries.rollback();
+    }
+
+    fn settle(&mut self) {
+        self.todos.settle();
+        self.labels.settle();
+        self.users.settle();
+        self.members.settle();
+        self.tokens.settle();
+        self.todo_labels.settle();
+        self.webhooks.settle();
+        self.webhook_deliveries.settle();
+    }
+
+    fn apply(&mut self, change: Change) -> anyhow::Result<()> {
+        match change.table.as_str() {
+            "todos" => self.todos.apply(change),
+            "labels" => self.labels.apply(change),
+            "users" => self.users.apply(change),
+            "members" => self.members.apply(change),
+            "tokens" => self.tokens.apply(change),
+            "todo_labels" => self.todo_labels.apply(change),
+            "webhooks" => self.webhooks.apply(change),
+            "webhook_deliveries" => self.webhook_deliveries.apply(change),
+            table => anyhow::bail!("unknown table [{}]", table),
+        }
+    }
+}
+
+// 追記ログに書く1件の変更。1回の書き込みの変更をまとめて1行に書く
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Change {
+    table: String,
+    last_id: i32,
+    key: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    version: u32,
+    #[serde(flatten)]
+    tables: Tables,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    #[serde(flatten)]
+    tables: &'a Tables,
+}
+
+// 変更は追記ログに書いてから応答し、一定件数ごとにスナップショットへまとめる
+// 1回の書き込みの変更はログの1行にまとめるので、落ちても一部の変更だけが残ることはない
+// スナップショットは一時ファイルに書いてからrenameするので、途中で落ちても壊れない
+#[derive(Debug)]
+struct Journal {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    log: File,
+    entries: usize,
+    snapshot_every: usize,
+}
+
+impl Journal {
+    fn append(&mut self, changes: &[Change]) -> anyhow::Result<()> {
+        let mut buffer = serde_json::to_vec(changes)?;
+        buffer.push(b'\n');
+        let len = self.log.metadata()?.len();
+        if let Err(e) = self
+            .log
+            .write_all(&buffer)
+            .and_then(|_| self.log.sync_data())
+        {
+            // 途中まで書けた行が残ると次の行とつながって読めなくなるので、書く前の長さに戻す
+            if let Err(e) = self.log.set_len(len) {
+                tracing::error!("fail truncate journal: {:?}", e);
+            }
+            return Err(e.into());
+        }
+        self.entries += changes.len();
+        Ok(())
+    }
+
+    fn compact(&mut self, tables: &Tables) -> anyhow::Result<()> {
+        let tmp_path = self.snapshot_path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("fail create file [{}]", tmp_path.display()))?;
+        serde_json::to_writer(
+            &mut file,
+            &SnapshotRef {
+                version: SNAPSHOT_VERSION,
+                tables,
+            },
+        )?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.snapshot_path)
+            .with_context(|| format!("fail write snapshot [{}]", self.snapshot_path.display()))?;
+        // スナップショットに含まれたので、ログは空にする
+        self.log.set_len(0)?;
+        self.log.sync_data()?;
+        self.entries = 0;
+        Ok(())
+    }
+}
+
+// メモリに保存するリポジトリで共有するデータ。クローンしたものは同じデータを参照する
+// 書き込みとトランザクションは `gate` で1つずつにし、失敗した場合は変更した行だけを元に戻す
+// 読み込みも `gate` を通すので、確定していないトランザクションの変更は見えない
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDatabase {
+    tables: Arc<RwLock<Tables>>,
+    gate: Arc<Gate<()>>,
+    journal: Option<Arc<Mutex<Journal>>>,
+}
+
+// トランザクションの間、他の読み書きを待たせてテーブルを預かる
+// `commit` も `rollback` もせずに破棄した場合は、変更を取り消してから返す
+pub struct Exclusive {
+    database: MemoryDatabase,
+    tables: Option<Tables>,
+    _gate: OwnedRwLockWriteGuard<()>,
+}
+
+impl Exclusive {
+    pub fn tables(&mut self) -> &mut Tables {
+        self.tables.as_mut().expect("tables are returned on commit")
+    }
+
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        let mut tables = self.tables.take().expect("tables are returned on commit");
+        let result = self.database.save(&mut tables);
+        if result.is_err() {
+            tables.rollback();
+        }
+        *self.database.tables.write().unwrap() = tables;
+        result
+    }
+}
+
+impl Drop for Exclusive {
+    fn drop(&mut self) {
+        if let Some(mut tables) = self.tables.take() {
+            tables.rollback();
+            *self.database.tables.write().unwrap() = tables;
+        }
+    }
+}
+
+impl MemoryDatabase {
+    // スナップショットを読み込んでから追記ログを適用し、最新の状態を復元する
+    pub fn open(path: &Path, snapshot_every: usize) -> anyhow::Result<Self> {
+        let mut tables = if path.exists() {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("fail read snapshot [{}]", path.display()))?;
+            let snapshot: Snapshot = serde_json::from_str(&text)
+                .with_context(|| format!("fail parse snapshot [{}]", path.display()))?;
+            if snapshot.version != SNAPSHOT_VERSION {
+                anyhow::bail!(
+                    "unsupported snapshot version [{}] in [{}]",
+                    snapshot.version,
+                    path.display()
+                );
+            }
+            snapshot.tables
+        } else {
+            Tables::default()
+        };
+
+        let log_path = log_path(path);
+        if log_path.exists() {
+            let text = fs::read_to_string(&log_path)
+                .with_context(|| format!("fail read journal [{}]", log_path.display()))?;
+            let lines: Vec<&str> = text.lines().collect();
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Vec<Change>>(line) {
+                    Ok(changes) => {
+                        for change in changes {
+                            tables.apply(change).with_context(|| {
+                                format!(
+                                    "fail apply journal [{}] line {}",
+                                    log_path.display(),
+                                    index + 1
+                                )
+                            })?;
+                        }
+                    }
+                    // 書き込み中に落ちた最後の行は応答していない書き込みなので、まとめて捨てる
+                    Err(_) if index + 1 == lines.len() => {
+                        tracing::warn!("discard incomplete journal entry at line {}", index + 1);
+                    }
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!(
+                                "corrupted journal [{}] line {}",
+                                log_path.display(),
+                                index + 1
+                            )
+                        })
+                    }
+                }
+            }
+        }
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .with_context(|| format!("fail open journal [{}]", log_path.display()))?;
+        let mut journal = Journal {
+            snapshot_path: path.to_path_buf(),
+            log_path,
+            log,
+            entries: 0,
+            snapshot_every,
+        };
+        journal.compact(&tables)?;
+        tracing::debug!(
+            "restored memory storage from {}",
+            journal.log_path.display()
+        );
+
+        Ok(Self {
+            tables: Arc::new(RwLock::new(tables)),
+            gate: Arc::default(),
+            journal: Some(Arc::new(Mutex::new(journal))),
+        })
+    }
+
+    pub async fn read<T>(&self, f: impl FnOnce(&Tables) -> T) -> T {
+        let _gate = self.gate.read().await;
+        let tables = self.tables.read().unwrap();
+        f(&tables)
+    }
+
+    pub async fn write<T>(
+        &self,
+        f: impl FnOnce(&mut Tables) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let _gate = self.gate.write().await;
+        let mut tables = self.tables.write().unwrap();
+        let result = f(&mut tables).and_then(|value| {
+            self.save(&mut tables)?;
+            Ok(value)
+        });
+        if result.is_err() {
+            tables.rollback();
+        }
+        result
+    }
+
+    // 他のトランザクションや書き込みが終わるのを待ってから始める
+    // 変更は `commit` するまで他からは見えず、始めた後に他の書き込みが割り込むこともない
+    pub async fn begin(&self) -> Exclusive {
+        let gate = self.gate.clone().write_owned().await;
+        let tables = std::mem::take(&mut *self.tables.write().unwrap());
+        Exclusive {
+            database: self.clone(),
+            tables: Some(tables),
+            _gate: gate,
+        }
+    }
+
+    fn save(&self, tables: &mut Tables) -> anyhow::Result<()> {
+        let changes = tables.take_changes();
+        if changes.is_empty() {
+            tables.settle();
+            return Ok(());
+        }
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.lock().unwrap();
+            journal
+                .append(&changes)
+                .context("fail write journal")?;
+            // ログには書けているので、スナップショットの失敗は次の機会に任せる
+            if journal.entries >= journal.snapshot_every {
+                if let Err(e) = journal.compact(tables) {
+                    tracing::warn!("fail write snapshot: {:?}", e);
+                }
+            }
+        }
+        tables.settle();
+        Ok(())
+    }
+}
+
+fn log_path(path: &Path) -> PathBuf {
+    let mut log_path = path.as_os_str().to_owned();
+    log_path.push(".log");
+    PathBuf::from(log_path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, uuid::Uuid::new_v4()));
+        fs::remove_file(&path).ok();
+        fs::remove_file(log_path(&path)).ok();
+        path
+    }
+
+    async fn insert_user(database: &MemoryDatabase, name: &str) -> i32 {
+        database
+            .write(|tables| {
+                let id = tables.users.next_id();
+                tables.users.insert(
+                    id,
+                    User {
+                        id,
+                        name: name.to_string(),
+                    },
+                );
+                Ok(id)
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn user_names(database: &MemoryDatabase) -> Vec<String> {
+        database
+            .read(|tables| {
+                tables
+                    .users
+                    .values()
+                    .map(|user| user.name.clone())
+                    .collect()
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn should_recover_from_snapshot_and_journal() {
+        let path = temp_path("memory-recover");
+        let database = MemoryDatabase::open(&path, 3).unwrap();
+        let alice = insert_user(&database, "alice").await;
+        insert_user(&database, "bob").await;
+        // 3件でスナップショットにまとめられ、4件目はログに残る
+        insert_user(&database, "carol").await;
+        database
+            .write(|tables| Ok(tables.users.remove(&alice)))
+            .await
+            .unwrap();
+        drop(database);
+
+        let database = MemoryDatabase::open(&path, 3).unwrap();
+        assert_eq!(vec!["bob", "carol"], user_names(&database).await);
+        // 削除したIDも再利用しない
+        assert_eq!(4, insert_user(&database, "dave").await);
+    }
+
+    #[tokio::test]
+    async fn should_discard_incomplete_last_entry() {
+        let path = temp_path("memory-crash");
+        let database = MemoryDatabase::open(&path, 100).unwrap();
+        insert_user(&database, "alice").await;
+        drop(database);
+
+        // 2件の変更を書いている途中で落ちた状態を再現する。書き終えた1件目も反映しない
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(log_path(&path))
+            .unwrap();
+        log.write_all(
+            br#"[{"table":"users","last_id":2,"key":2,"value":{"id":2,"name":"bob"}},{"table":"us"#,
+        )
+        .unwrap();
+        drop(log);
+
+        let database = MemoryDatabase::open(&path, 100).unwrap();
+        assert_eq!(vec!["alice"], user_names(&database).await);
+        assert_eq!(2, insert_user(&database, "bob").await);
+        drop(database);
+
+        // 途中の行が壊れている場合は復元しない
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(log_path(&path))
+            .unwrap();
+        log.write_all(b"[broken\n[]\n").unwrap();
+        drop(log);
+        assert!(MemoryDatabase::open(&path, 100).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_roll_back_failed_write() {
+        let database = MemoryDatabase::default();
+        let res: anyhow::Result<()> = database
+            .write(|tables| {
+                let id = tables.users.next_id();
+                tables.users.insert(
+                    id,
+                    User {
+                        id,
+                        name: "rollback".to_string(),
+                    },
+                );
+                anyhow::bail!("failed after insert")
+            })
+            .await;
+        assert!(res.is_err());
+        assert!(user_names(&database).await.is_empty());
+        assert_eq!(1, insert_user(&database, "alice").await);
+    }
+}
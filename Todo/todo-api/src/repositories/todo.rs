@@ -1,10 +1,22 @@
 use anyhow::Ok;
 use axum::async_trait;
-use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use chrono::NaiveDate;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{FromRow, PgConnection, PgPool, SqliteConnection, SqlitePool};
+use tracing::Instrument;
 use validator::Validate;
 
-use super::RepositoryError;
+use crate::{
+    events::{ChangeEvent, ChangeKind},
+    fanout::notify,
+};
+
+use super::{
+    db_span,
+    label::Label,
+    memory::{MemoryDatabase, Tables},
+    sqlite_span, RepositoryError,
+};
 
 #[derive(Debug, Clone)]
 pub struct TodoRepositoryForDb {
@@ -17,96 +29,612 @@ impl TodoRepositoryForDb {
     }
 }
 
+// 1つのクエリで済む操作はプールのコネクションで、複数のクエリが必要な操作はトランザクションで行う
 #[async_trait]
 impl TodoRepository for TodoRepositoryForDb {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<Todo> {
-        let todo = sqlx::query_as::<_, Todo>(
-            r#"
-            insert into todos (text, completed)
-            values ($1, false)
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        let mut conn = self.pool.acquire().await?;
+        TodoViewForDb::new(&mut conn).create(user_id, payload).await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        let mut conn = self.pool.acquire().await?;
+        TodoViewForDb::new(&mut conn).find(user_id, id).await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        let mut conn = self.pool.acquire().await?;
+        TodoViewForDb::new(&mut conn).all(user_id).await
+    }
+
+    async fn update(&self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let mut tx = self.pool.begin().await?;
+        let todo = TodoViewForDb::new(&mut tx).update(user_id, id, payload).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        TodoViewForDb::new(&mut tx).delete(user_id, id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+// コネクションかトランザクションを借りてTodoを操作する
+pub struct TodoViewForDb<'a> {
+    conn: &'a mut PgConnection,
+}
+
+impl<'a> TodoViewForDb<'a> {
+    pub fn new(conn: &'a mut PgConnection) -> Self {
+        Self { conn }
+    }
+}
+
+#[async_trait]
+impl TodoView for TodoViewForDb<'_> {
+    async fn create(&mut self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        let sql = r#"
+            insert into todos (text, completed, due, user_id)
+            values ($1, false, $2, $3)
             returning *
-            "#,
-        )
-        .bind(payload.text.clone())
-        .fetch_one(&self.pool)
-        .await?;
+            "#;
+        let todo = sqlx::query_as::<_, Todo>(sql)
+            .bind(payload.text.clone())
+            .bind(payload.due)
+            .bind(user_id)
+            .fetch_one(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+        let event = ChangeEvent::new(ChangeKind::TodoCreated, user_id, todo.id, &todo);
+        notify(&mut *self.conn, event).await?;
 
         Ok(todo)
     }
 
-    async fn find(&self, id: i32) -> anyhow::Result<Todo> {
-        let todo = sqlx::query_as::<_,Todo>(
-            r#"
-            select * from todos where id=$1
-            "#,
-        )
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
-        
+    async fn find(&mut self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        let sql = r#"
+            select * from todos where id=$1 and user_id=$2
+            "#;
+        let todo = sqlx::query_as::<_, Todo>(sql)
+            .bind(id)
+            .bind(user_id)
+            .fetch_one(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                _ => RepositoryError::Unexpected(e.to_string()),
+            })?;
+
         Ok(todo)
     }
 
-    async fn all(&self) -> anyhow::Result<Vec<Todo>> {
-        let todos = sqlx::query_as::<_, Todo>(
-            r#"
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        let sql = r#"
             select * from todos
+            where user_id=$1
             order by id desc;
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
+            "#;
+        let todos = sqlx::query_as::<_, Todo>(sql)
+            .bind(user_id)
+            .fetch_all(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
 
         Ok(todos)
     }
 
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
-        let old_todo = self.find(id).await?;
-        let todo = sqlx::query_as::<_, Todo>(
-            r#"
-            update todos set text=$1, completed=$2
-            where id=$3
+    async fn update(&mut self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let old_todo = self.find(user_id, id).await?;
+        let sql = r#"
+            update todos set text=$1, completed=$2, due=$3
+            where id=$4 and user_id=$5
             returning *
-            "#,
-        )
-        .bind(payload.text.unwrap_or(old_todo.text))
-        .bind(payload.completed.unwrap_or(old_todo.completed))
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await?;
+            "#;
+        let todo = sqlx::query_as::<_, Todo>(sql)
+            .bind(payload.text.unwrap_or(old_todo.text))
+            .bind(payload.completed.unwrap_or(old_todo.completed))
+            .bind(payload.due.unwrap_or(old_todo.due))
+            .bind(id)
+            .bind(user_id)
+            .fetch_one(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+        let event = ChangeEvent::new(ChangeKind::TodoUpdated, user_id, id, &todo);
+        notify(&mut *self.conn, event).await?;
+        if todo.completed && !old_todo.completed {
+            let event = ChangeEvent::new(ChangeKind::TodoCompleted, user_id, id, &todo);
+            notify(&mut *self.conn, event).await?;
+        }
 
         Ok(todo)
     }
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        sqlx::query(
-            r#"
-            delete from todos where id=$1
-            "#,
-        )
-        .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let sql = r#"
+            delete from todo_labels
+            where todo_id=(select id from todos where id=$1 and user_id=$2)
+            "#;
+        sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+
+        let sql = r#"
+            delete from todos where id=$1 and user_id=$2
+            "#;
+        let result = sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                _ => RepositoryError::Unexpected(e.to_string()),
+            })?;
+
+        // 他のユーザーのTodoは存在しないものとして扱う
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        let event = ChangeEvent::deleted(ChangeKind::TodoDeleted, user_id, id);
+        notify(&mut *self.conn, event).await?;
+
+        Ok(())
+    }
+
+    async fn set_labels(
+        &mut self,
+        user_id: i32,
+        id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<Label>> {
+        let todo = self.find(user_id, id).await?;
+        let sql = r#"
+            select id, name from labels where user_id=$1 and id = any($2)
+            "#;
+        let found = sqlx::query_as::<_, Label>(sql)
+            .bind(user_id)
+            .bind(label_ids)
+            .fetch_all(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+        check_labels(label_ids, &found)?;
+
+        let sql = r#"
+            delete from todo_labels where todo_id=$1
+            "#;
+        sqlx::query(sql)
+            .bind(id)
+            .execute(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+
+        let sql = r#"
+            insert into todo_labels (todo_id, label_id)
+            select $1, id from unnest($2) as t(id)
+            "#;
+        let label_ids: Vec<i32> = found.iter().map(|label| label.id).collect();
+        sqlx::query(sql)
+            .bind(id)
+            .bind(label_ids)
+            .execute(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+        let event = ChangeEvent::new(ChangeKind::TodoUpdated, user_id, id, &todo);
+        notify(&mut *self.conn, event).await?;
+
+        self.labels(user_id, id).await
+    }
+
+    async fn labels(&mut self, user_id: i32, id: i32) -> anyhow::Result<Vec<Label>> {
+        self.find(user_id, id).await?;
+        let sql = r#"
+            select labels.id, labels.name from labels
+            inner join todo_labels on todo_labels.label_id = labels.id
+            where todo_labels.todo_id=$1
+            order by labels.id asc
+            "#;
+        let labels = sqlx::query_as::<_, Label>(sql)
+            .bind(id)
+            .fetch_all(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+
+        Ok(labels)
+    }
+    async fn labels_of(
+        &mut self,
+        user_id: i32,
+        ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Label)>> {
+        let sql = r#"
+            select todo_labels.todo_id, labels.id, labels.name from labels
+            inner join todo_labels on todo_labels.label_id = labels.id
+            where labels.user_id=$1 and todo_labels.todo_id = any($2)
+            order by todo_labels.todo_id asc, labels.id asc
+            "#;
+        let rows = sqlx::query_as::<_, (i32, i32, String)>(sql)
+            .bind(user_id)
+            .bind(ids)
+            .fetch_all(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(todo_id, id, name)| (todo_id, Label { id, name }))
+            .collect())
+    }
+
+    async fn todos_of(
+        &mut self,
+        user_id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Todo)>> {
+        let sql = r#"
+            select todo_labels.label_id, todos.id, todos.text, todos.completed, todos.due
+            from todos
+            inner join todo_labels on todo_labels.todo_id = todos.id
+            where todos.user_id=$1 and todo_labels.label_id = any($2)
+            order by todo_labels.label_id asc, todos.id desc
+            "#;
+        let rows = sqlx::query_as::<_, (i32, i32, String, bool, Option<NaiveDate>)>(sql)
+            .bind(user_id)
+            .bind(label_ids)
+            .fetch_all(&mut *self.conn)
+            .instrument(db_span(sql))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(label_id, id, text, completed, due)| {
+                let todo = Todo {
+                    id,
+                    text,
+                    completed,
+                    due,
+                };
+                (label_id, todo)
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TodoRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl TodoRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl TodoRepository for TodoRepositoryForSqlite {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        let mut conn = self.pool.acquire().await?;
+        TodoViewForSqlite::new(&mut conn).create(user_id, payload).await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        let mut conn = self.pool.acquire().await?;
+        TodoViewForSqlite::new(&mut conn).find(user_id, id).await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        let mut conn = self.pool.acquire().await?;
+        TodoViewForSqlite::new(&mut conn).all(user_id).await
+    }
+
+    async fn update(&self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let mut tx = self.pool.begin().await?;
+        let todo = TodoViewForSqlite::new(&mut tx).update(user_id, id, payload).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
 
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        TodoViewForSqlite::new(&mut tx).delete(user_id, id).await?;
+        tx.commit().await?;
         Ok(())
     }
 }
 
+pub struct TodoViewForSqlite<'a> {
+    conn: &'a mut SqliteConnection,
+}
+
+impl<'a> TodoViewForSqlite<'a> {
+    pub fn new(conn: &'a mut SqliteConnection) -> Self {
+        Self { conn }
+    }
+}
+
+#[async_trait]
+impl TodoView for TodoViewForSqlite<'_> {
+    // sqlxのSQLiteでは `returning` の行を `fetch_one` で読むとステートメントが完了せず、
+    // 他のコネクションから書き込みが見えないので、実行してからIDで読み直す
+    async fn create(&mut self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        let sql = r#"
+            insert into todos (text, completed, due, user_id)
+            values ($1, false, $2, $3)
+            "#;
+        let result = sqlx::query(sql)
+            .bind(payload.text)
+            .bind(payload.due)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        self.find(user_id, result.last_insert_rowid() as i32).await
+    }
+
+    async fn find(&mut self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        let sql = r#"
+            select id, text, completed, due from todos where id=$1 and user_id=$2
+            "#;
+        let todo = sqlx::query_as::<_, Todo>(sql)
+            .bind(id)
+            .bind(user_id)
+            .fetch_one(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                _ => RepositoryError::Unexpected(e.to_string()),
+            })?;
+
+        Ok(todo)
+    }
+
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        let sql = r#"
+            select id, text, completed, due from todos
+            where user_id=$1
+            order by id desc
+            "#;
+        let todos = sqlx::query_as::<_, Todo>(sql)
+            .bind(user_id)
+            .fetch_all(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn update(&mut self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let old_todo = self.find(user_id, id).await?;
+        let sql = r#"
+            update todos set text=$1, completed=$2, due=$3
+            where id=$4 and user_id=$5
+            "#;
+        sqlx::query(sql)
+            .bind(payload.text.unwrap_or(old_todo.text))
+            .bind(payload.completed.unwrap_or(old_todo.completed))
+            .bind(payload.due.unwrap_or(old_todo.due))
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        self.find(user_id, id).await
+    }
+
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let sql = r#"
+            delete from todo_labels
+            where todo_id=(select id from todos where id=$1 and user_id=$2)
+            "#;
+        sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        let sql = r#"
+            delete from todos where id=$1 and user_id=$2
+            "#;
+        let result = sqlx::query(sql)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+
+    // SQLiteには配列を渡せないので、ラベルは1件ずつ確認して付ける
+    async fn set_labels(
+        &mut self,
+        user_id: i32,
+        id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<Label>> {
+        self.find(user_id, id).await?;
+        let mut found = Vec::new();
+        for label_id in label_ids {
+            let sql = r#"
+                select id, name from labels where id=$1 and user_id=$2
+                "#;
+            let label = sqlx::query_as::<_, Label>(sql)
+                .bind(label_id)
+                .bind(user_id)
+                .fetch_optional(&mut *self.conn)
+                .instrument(sqlite_span(sql))
+                .await?;
+            found.extend(label);
+        }
+        check_labels(label_ids, &found)?;
+
+        let sql = r#"
+            delete from todo_labels where todo_id=$1
+            "#;
+        sqlx::query(sql)
+            .bind(id)
+            .execute(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        found.sort_by_key(|label| label.id);
+        found.dedup();
+        for label in &found {
+            let sql = r#"
+                insert into todo_labels (todo_id, label_id) values ($1, $2)
+                "#;
+            sqlx::query(sql)
+                .bind(id)
+                .bind(label.id)
+                .execute(&mut *self.conn)
+                .instrument(sqlite_span(sql))
+                .await?;
+        }
+
+        self.labels(user_id, id).await
+    }
+
+    async fn labels(&mut self, user_id: i32, id: i32) -> anyhow::Result<Vec<Label>> {
+        self.find(user_id, id).await?;
+        let sql = r#"
+            select labels.id, labels.name from labels
+            inner join todo_labels on todo_labels.label_id = labels.id
+            where todo_labels.todo_id=$1
+            order by labels.id asc
+            "#;
+        let labels = sqlx::query_as::<_, Label>(sql)
+            .bind(id)
+            .fetch_all(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        Ok(labels)
+    }
+    // 配列の代わりにJSONの配列を渡して展開する
+    async fn labels_of(
+        &mut self,
+        user_id: i32,
+        ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Label)>> {
+        let sql = r#"
+            select todo_labels.todo_id, labels.id, labels.name from labels
+            inner join todo_labels on todo_labels.label_id = labels.id
+            where labels.user_id=$1 and todo_labels.todo_id in (select value from json_each($2))
+            order by todo_labels.todo_id asc, labels.id asc
+            "#;
+        let rows = sqlx::query_as::<_, (i32, i32, String)>(sql)
+            .bind(user_id)
+            .bind(serde_json::to_string(ids)?)
+            .fetch_all(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(todo_id, id, name)| (todo_id, Label { id, name }))
+            .collect())
+    }
+
+    async fn todos_of(
+        &mut self,
+        user_id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Todo)>> {
+        let sql = r#"
+            select todo_labels.label_id, todos.id, todos.text, todos.completed, todos.due
+            from todos
+            inner join todo_labels on todo_labels.todo_id = todos.id
+            where todos.user_id=$1 and todo_labels.label_id in (select value from json_each($2))
+            order by todo_labels.label_id asc, todos.id desc
+            "#;
+        let rows = sqlx::query_as::<_, (i32, i32, String, bool, Option<NaiveDate>)>(sql)
+            .bind(user_id)
+            .bind(serde_json::to_string(label_ids)?)
+            .fetch_all(&mut *self.conn)
+            .instrument(sqlite_span(sql))
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(label_id, id, text, completed, due)| {
+                let todo = Todo {
+                    id,
+                    text,
+                    completed,
+                    due,
+                };
+                (label_id, todo)
+            })
+            .collect())
+    }
+}
+
+// 指定したラベルがすべてユーザーのものか確かめる。見つからないIDはNotFoundにする
+fn check_labels(label_ids: &[i32], found: &[Label]) -> anyhow::Result<()> {
+    if let Some(id) = label_ids
+        .iter()
+        .find(|id| !found.iter().any(|label| label.id == **id))
+    {
+        return Err(RepositoryError::NotFound(*id).into());
+    }
+    Ok(())
+}
+
 #[async_trait]
 pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<Todo>;
-    async fn find(&self, id: i32) -> anyhow::Result<Todo>;
-    async fn all(&self) -> anyhow::Result<Vec<Todo>>;
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo>;
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Todo>;
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Todo>>;
+    async fn update(&self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo>;
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()>;
+}
+
+// 1つのコネクションやトランザクションに束ねたTodoの操作。`UnitOfWork` から使う
+#[async_trait]
+pub trait TodoView: std::marker::Send {
+    async fn create(&mut self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo>;
+    async fn find(&mut self, user_id: i32, id: i32) -> anyhow::Result<Todo>;
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Todo>>;
+    async fn update(&mut self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo>;
+    // 付いているラベルも外す
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()>;
+    // 付いているラベルを置き換える。他のユーザーのラベルは存在しないものとして扱う
+    async fn set_labels(
+        &mut self,
+        user_id: i32,
+        id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<Label>>;
+    async fn labels(&mut self, user_id: i32, id: i32) -> anyhow::Result<Vec<Label>>;
+    // 複数のTodoのラベルを1回で読み込む。(TodoのID, ラベル) の組を返し、他のユーザーのTodoは含めない
+    async fn labels_of(
+        &mut self,
+        user_id: i32,
+        ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Label)>>;
+    // 複数のラベルが付いたTodoを1回で読み込む。(ラベルのID, Todo) の組を新しい順に返す
+    async fn todos_of(
+        &mut self,
+        user_id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Todo)>>;
 }
 
 // Todo自体やTodoの更新に必要な構造体を定義
@@ -115,6 +643,9 @@ pub struct Todo {
     pub id: i32,
     pub text: String,
     pub completed: bool,
+    // 期限日。期限日を持つ前に書き出したデータや保存したファイルにはないので、なければNone
+    #[serde(default)]
+    pub due: Option<NaiveDate>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
@@ -122,6 +653,18 @@ pub struct CreateTodo {
     #[validate(length(min = 1, message = "Can not be empty"))]
     #[validate(length(max = 100, message = "Over text length"))]
     text: String,
+    #[serde(default)]
+    due: Option<NaiveDate>,
+}
+
+impl CreateTodo {
+    pub fn new(text: String) -> Self {
+        Self { text, due: None }
+    }
+
+    pub fn with_due(self, due: Option<NaiveDate>) -> Self {
+        Self { due, ..self }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
@@ -130,43 +673,321 @@ pub struct UpdateTodo {
     #[validate(length(max = 100, message = "Over text length"))]
     text: Option<String>,
     completed: Option<bool>,
+    // 省略すれば変えず、`null` なら期限日を外す
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    due: Option<Option<NaiveDate>>,
+}
+
+impl UpdateTodo {
+    pub fn new(text: Option<String>, completed: Option<bool>) -> Self {
+        Self {
+            text,
+            completed,
+            due: None,
+        }
+    }
+
+    pub fn completed(&self) -> Option<bool> {
+        self.completed
+    }
+}
+
+// 項目があれば `null` でもSomeにして、省略した場合と区別する
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+// 1つの `MemoryDatabase` を他のメモリのリポジトリと共有する
+#[derive(Debug, Clone)]
+pub struct TodoRepositoryForMemory {
+    database: MemoryDatabase,
+}
+
+impl TodoRepositoryForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl TodoRepository for TodoRepositoryForMemory {
+    async fn create(&self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        self.database
+            .write(|tables| tables.create_todo(user_id, payload))
+            .await
+    }
+
+    async fn find(&self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        self.database
+            .read(|tables| tables.find_todo(user_id, id))
+            .await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        Ok(self.database.read(|tables| tables.all_todos(user_id)).await)
+    }
+
+    async fn update(&self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        self.database
+            .write(|tables| tables.update_todo(user_id, id, payload))
+            .await
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.database
+            .write(|tables| tables.delete_todo(user_id, id))
+            .await
+    }
+}
+
+// トランザクション中の複製したテーブルを操作する
+pub struct TodoViewForMemory<'a> {
+    tables: &'a mut Tables,
+}
+
+impl<'a> TodoViewForMemory<'a> {
+    pub fn new(tables: &'a mut Tables) -> Self {
+        Self { tables }
+    }
+}
+
+#[async_trait]
+impl TodoView for TodoViewForMemory<'_> {
+    async fn create(&mut self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        self.tables.create_todo(user_id, payload)
+    }
+
+    async fn find(&mut self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        self.tables.find_todo(user_id, id)
+    }
+
+    async fn all(&mut self, user_id: i32) -> anyhow::Result<Vec<Todo>> {
+        Ok(self.tables.all_todos(user_id))
+    }
+
+    async fn update(&mut self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        self.tables.update_todo(user_id, id, payload)
+    }
+
+    async fn delete(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.tables.delete_todo(user_id, id)
+    }
+
+    async fn set_labels(
+        &mut self,
+        user_id: i32,
+        id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<Label>> {
+        self.tables.set_todo_labels(user_id, id, label_ids)
+    }
+
+    async fn labels(&mut self, user_id: i32, id: i32) -> anyhow::Result<Vec<Label>> {
+        self.tables.todo_labels(user_id, id)
+    }
+    async fn labels_of(
+        &mut self,
+        user_id: i32,
+        ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Label)>> {
+        Ok(self.tables.labels_of(user_id, ids))
+    }
+
+    async fn todos_of(
+        &mut self,
+        user_id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<(i32, Todo)>> {
+        Ok(self.tables.todos_of(user_id, label_ids))
+    }
+}
+
+// リポジトリとトランザクションで共有するメモリ上の操作
+impl Tables {
+    fn create_todo(&mut self, user_id: i32, payload: CreateTodo) -> anyhow::Result<Todo> {
+        let id = self.todos.next_id();
+        let todo = Todo {
+            id,
+            text: payload.text,
+            completed: false,
+            due: payload.due,
+        };
+        self.todos.insert((user_id, id), todo.clone());
+        Ok(todo)
+    }
+
+    fn find_todo(&self, user_id: i32, id: i32) -> anyhow::Result<Todo> {
+        let todo = self
+            .todos
+            .get(&(user_id, id))
+            .cloned()
+            .ok_or(RepositoryError::NotFound(id))?;
+        Ok(todo)
+    }
+
+    fn all_todos(&self, user_id: i32) -> Vec<Todo> {
+        self.todos
+            .rows()
+            .filter(|((owner, _id), _todo)| *owner == user_id)
+            // データベースと同じく新しい順に返す
+            .rev()
+            .map(|(_key, todo)| todo.clone())
+            .collect()
+    }
+
+    fn update_todo(&mut self, user_id: i32, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
+        let old_todo = self.find_todo(user_id, id)?;
+        let todo = Todo {
+            id,
+            text: payload.text.unwrap_or(old_todo.text),
+            completed: payload.completed.unwrap_or(old_todo.completed),
+            due: payload.due.unwrap_or(old_todo.due),
+        };
+        self.todos.insert((user_id, id), todo.clone());
+        Ok(todo)
+    }
+
+    fn delete_todo(&mut self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.todos
+            .remove(&(user_id, id))
+            .ok_or(RepositoryError::NotFound(id))?;
+        self.detach_todo_labels(|(todo_id, _label_id)| *todo_id == id);
+        Ok(())
+    }
+
+    fn set_todo_labels(
+        &mut self,
+        user_id: i32,
+        id: i32,
+        label_ids: &[i32],
+    ) -> anyhow::Result<Vec<Label>> {
+        self.find_todo(user_id, id)?;
+        let found: Vec<Label> = label_ids
+            .iter()
+            .filter_map(|label_id| self.labels.get(&(user_id, *label_id)).cloned())
+            .collect();
+        check_labels(label_ids, &found)?;
+
+        self.detach_todo_labels(|(todo_id, _label_id)| *todo_id == id);
+        for label in found {
+            self.todo_labels.insert((id, label.id), ());
+        }
+        self.todo_labels(user_id, id)
+    }
+
+    fn todo_labels(&self, user_id: i32, id: i32) -> anyhow::Result<Vec<Label>> {
+        self.find_todo(user_id, id)?;
+        let mut labels: Vec<Label> = self
+            .todo_labels
+            .rows()
+            .filter(|((todo_id, _label_id), _)| *todo_id == id)
+            .filter_map(|((_todo_id, label_id), _)| self.labels.get(&(user_id, *label_id)).cloned())
+            .collect();
+        labels.sort_by_key(|label| label.id);
+        Ok(labels)
+    }
+
+    fn labels_of(&self, user_id: i32, ids: &[i32]) -> Vec<(i32, Label)> {
+        let mut labels: Vec<(i32, Label)> = self
+            .todo_labels
+            .rows()
+            .filter(|((todo_id, _label_id), _)| ids.contains(todo_id))
+            .filter(|((todo_id, _label_id), _)| self.todos.get(&(user_id, *todo_id)).is_some())
+            .filter_map(|((todo_id, label_id), _)| {
+                let label = self.labels.get(&(user_id, *label_id)).cloned()?;
+                Some((*todo_id, label))
+            })
+            .collect();
+        labels.sort_by_key(|(todo_id, label)| (*todo_id, label.id));
+        labels
+    }
+
+    fn todos_of(&self, user_id: i32, label_ids: &[i32]) -> Vec<(i32, Todo)> {
+        let mut todos: Vec<(i32, Todo)> = self
+            .todo_labels
+            .rows()
+            .filter(|((_todo_id, label_id), _)| label_ids.contains(label_id))
+            .filter(|((_todo_id, label_id), _)| self.labels.get(&(user_id, *label_id)).is_some())
+            .filter_map(|((todo_id, label_id), _)| {
+                let todo = self.todos.get(&(user_id, *todo_id)).cloned()?;
+                Some((*label_id, todo))
+            })
+            .collect();
+        todos.sort_by_key(|(label_id, todo)| (*label_id, std::cmp::Reverse(todo.id)));
+        todos
+    }
+
+    pub(super) fn detach_todo_labels(&mut self, f: impl Fn(&(i32, i32)) -> bool) {
+        let keys: Vec<(i32, i32)> = self
+            .todo_labels
+            .rows()
+            .map(|(key, _)| *key)
+            .filter(|key| f(key))
+            .collect();
+        for key in keys {
+            self.todo_labels.remove(&key);
+        }
+    }
 }
 
 #[cfg(test)]
 #[cfg(feature = "database-test")]
 mod test {
     use super::*;
+    use crate::repositories::user::{User, UserRepository, UserRepositoryForDb};
     use dotenv::dotenv;
     use sqlx::PgPool;
     use std::env;
 
+    // テストの度に同じユーザーを作り直す（紐づくTodoはcascadeで削除される）
+    async fn recreate_user(pool: &PgPool, name: &str) -> User {
+        sqlx::query("delete from users where name=$1")
+            .bind(name)
+            .execute(pool)
+            .await
+            .expect("[recreate_user] delete returned Err");
+        UserRepositoryForDb::new(pool.clone())
+            .create(name.to_string())
+            .await
+            .expect("[recreate_user] create returned Err")
+    }
+
     #[tokio::test]
     async fn crud_scenario() {
         dotenv().ok();
         let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
         let pool = PgPool::connect(database_url)
             .await
-            .expect(&format!("fail connect database, url is [{}]", database_url));
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
 
+        let user = recreate_user(&pool, "[todo crud_scenario]").await;
         let repository = TodoRepositoryForDb::new(pool.clone());
         let todo_text = "[crud_scenario] text";
 
         // create
         let created = repository
-            .create(CreateTodo::new(todo_text.to_string()))
+            .create(user.id, CreateTodo::new(todo_text.to_string()))
             .await.expect("[create] returned Err");
         assert_eq!(created.text, todo_text);
         assert!(!created.completed);
 
         // find
         let todo = repository
-            .find(created.id)
+            .find(user.id, created.id)
             .await
             .expect("[find] returned Err");
         assert_eq!(created, todo);
 
         // all
-        let todos = repository.all().await.expect("[all] returned Err");
+        let todos = repository.all(user.id).await.expect("[all] returned Err");
         let todo = todos.first().unwrap();
         assert_eq!(created, *todo);
 
@@ -174,10 +995,12 @@ mod test {
         let updated_text = "[crud_scenario] updated text";
         let todo = repository
             .update(
+                user.id,
                 todo.id,
                 UpdateTodo {
                     text: Some(updated_text.to_string()),
                     completed: Some(true),
+                    due: None,
                 }
             )
             .await
@@ -186,11 +1009,11 @@ mod test {
         assert_eq!(todo.text, updated_text);
 
         // delete
-        let _ = repository
-            .delete(todo.id)
+        repository
+            .delete(user.id, todo.id)
             .await
             .expect("[delete] returned Err");
-        let res = repository.find(todo.id).await; //expect not found err
+        let res = repository.find(user.id, todo.id).await; //expect not found err
         assert!(res.is_err());
 
         // delete cascade（削除された主キーと同じ値の外部キーを持つすべての行を削除）
@@ -203,19 +1026,116 @@ mod test {
         .fetch_all(&pool)
         .await
         .expect("[delete] todo_labels fetch error");
-        assert!(todo_rows.len() == 0);
+        assert!(todo_rows.is_empty());
+    }
+
+    #[tokio::test]
+    async fn isolation_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = recreate_user(&pool, "[todo isolation_scenario] owner").await;
+        let other = recreate_user(&pool, "[todo isolation_scenario] other").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+
+        let created = repository
+            .create(owner.id, CreateTodo::new("[isolation_scenario] text".to_string()))
+            .await
+            .expect("[create] returned Err");
+
+        // 他のユーザーからは見えない
+        assert!(repository.find(other.id, created.id).await.is_err());
+        let todos = repository.all(other.id).await.expect("[all] returned Err");
+        assert!(todos.is_empty());
+        let res = repository
+            .update(
+                other.id,
+                created.id,
+                UpdateTodo {
+                    text: Some("[isolation_scenario] hijacked".to_string()),
+                    completed: None,
+                    due: None,
+                },
+            )
+            .await;
+        assert!(res.is_err());
+        assert!(repository.delete(other.id, created.id).await.is_err());
+
+        // 所有者のTodoは変更されていない
+        let todo = repository
+            .find(owner.id, created.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(created, todo);
     }
 }
 
 #[cfg(test)]
-pub mod test_utils {
-    use anyhow::Context;
-    use axum::async_trait;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+mod sqlite_test {
+    use super::*;
+    use crate::repositories::{
+        sqlite_test_pool,
+        user::{UserRepository, UserRepositoryForSqlite},
     };
 
+    #[tokio::test]
+    async fn sqlite_crud_scenario() {
+        let pool = sqlite_test_pool().await;
+        let owner = UserRepositoryForSqlite::new(pool.clone())
+            .create("owner".to_string())
+            .await
+            .expect("[create user] returned Err");
+        let other = UserRepositoryForSqlite::new(pool.clone())
+            .create("other".to_string())
+            .await
+            .expect("[create user] returned Err");
+        let repository = TodoRepositoryForSqlite::new(pool);
+
+        let first = repository
+            .create(owner.id, CreateTodo::new("first".to_string()))
+            .await
+            .expect("[create] returned Err");
+        let second = repository
+            .create(owner.id, CreateTodo::new("second".to_string()))
+            .await
+            .expect("[create] returned Err");
+        assert!(!first.completed);
+
+        // 新しい順に返す
+        let todos = repository.all(owner.id).await.expect("[all] returned Err");
+        assert_eq!(vec![second.clone(), first.clone()], todos);
+
+        let updated = repository
+            .update(owner.id, first.id, UpdateTodo::new(None, Some(true)))
+            .await
+            .expect("[update] returned Err");
+        assert!(updated.completed);
+        assert_eq!(first.text, updated.text);
+
+        // 他のユーザーからは見えない
+        assert!(repository.find(other.id, first.id).await.is_err());
+        assert!(repository.delete(other.id, first.id).await.is_err());
+
+        repository
+            .delete(owner.id, second.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.find(owner.id, second.id).await.is_err());
+
+        // 削除したIDは再利用しない
+        let third = repository
+            .create(owner.id, CreateTodo::new("third".to_string()))
+            .await
+            .expect("[create] returned Err");
+        assert!(third.id > second.id);
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
     use super::*;
 
     impl Todo {
@@ -224,81 +1144,24 @@ pub mod test_utils {
                 id,
                 text,
                 completed: false,
+                due: None,
             }
         }
     }
 
-    impl CreateTodo {
-        pub fn new(text: String) -> Self {
-            Self { text }
+    impl UpdateTodo {
+        pub fn with_due(self, due: Option<NaiveDate>) -> Self {
+            Self {
+                due: Some(due),
+                ..self
+            }
         }
     }
 
-    type TodoDatas = HashMap<i32, Todo>;
-
-    #[derive(Debug, Clone)]
-    pub struct TodoRepositoryForMemory {
-        store: Arc<RwLock<TodoDatas>>,
-    }
 
     impl TodoRepositoryForMemory {
         pub fn new() -> Self {
-            TodoRepositoryForMemory {
-                store: Arc::default(),//default()
-            }
-        }
-
-        fn write_store_ref(&self) -> RwLockWriteGuard<TodoDatas> {
-            self.store.write().unwrap()
-        }
-
-        fn read_store_ref(&self) -> RwLockReadGuard<TodoDatas> {
-            self.store.read().unwrap()
-        }
-    }
-
-    #[async_trait]
-    impl TodoRepository for TodoRepositoryForMemory {
-        async fn create(&self, payload: CreateTodo) -> anyhow::Result<Todo> {
-            let mut store = self.write_store_ref();
-            let id = (store.len() + 1) as i32; //保存済みの長さ＋１、asは明示的な型の変換
-            let todo = Todo::new(id, payload.text.clone());
-            store.insert(id, todo.clone()); // insertで追加storeへ
-            Ok(todo)
-        }
-
-        async fn find(&self, id: i32) -> anyhow::Result<Todo> {
-            let store = self.read_store_ref();
-            let todo = store
-                .get(&id)
-                .map(|todo| todo.clone())
-                .ok_or(RepositoryError::NotFound(id))?;
-            Ok(todo)
-        }
-
-        async fn all(&self) -> anyhow::Result<Vec<Todo>> {
-            let store = self.read_store_ref();
-            Ok(Vec::from_iter(store.values().map(|todo| todo.clone())))
-        }
-
-        async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<Todo> {
-            let mut store = self.write_store_ref();
-            let todo = store.get(&id).context(RepositoryError::NotFound(id))?;
-            let text = payload.text.unwrap_or(todo.text.clone());
-            let completed = payload.completed.unwrap_or(todo.completed);
-            let todo = Todo {
-                id,
-                text,
-                completed,
-            };
-            store.insert(id, todo.clone());
-            Ok(todo)
-        }
-
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
-            let mut store = self.write_store_ref();
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?;
-            Ok(())
+            Self::with_database(MemoryDatabase::default())
         }
     }
 
@@ -309,32 +1172,35 @@ pub mod test_utils {
         async fn todo_crud_scenario() {
             let text = "todo text".to_string();
             let id = 1;
+            let user_id = 1;
             let expected = Todo::new(id, text.clone());
 
             // create
             let repository = TodoRepositoryForMemory::new();
             let todo = repository
-                .create(CreateTodo { text })
+                .create(user_id, CreateTodo { text, due: None })
                 .await
                 .expect("failed create todo");
             assert_eq!(expected, todo);
 
             // find
-            let todo = repository.find(todo.id).await.unwrap();
+            let todo = repository.find(user_id, todo.id).await.unwrap();
             assert_eq!(expected, todo);
 
             // all
-            let todo = repository.all().await.expect("failed get all todo");
+            let todo = repository.all(user_id).await.expect("failed get all todo");
             assert_eq!(vec![expected], todo);
 
             // update
             let text = "update todo text".to_string();
             let todo = repository
                 .update(
+                    user_id,
                     1,
                     UpdateTodo {
                         text: Some(text.clone()),
                         completed: Some(true),
+                        due: None,
                     },
                 )
                 .await
@@ -344,13 +1210,29 @@ pub mod test_utils {
                     id,
                     text,
                     completed: true,
+                    due: None,
                 },
                 todo
             );
 
             // delete
-            let res = repository.delete(id).await;
+            let res = repository.delete(user_id, id).await;
             assert!(res.is_ok())
         }
+
+        #[tokio::test]
+        async fn todo_isolation_scenario() {
+            let repository = TodoRepositoryForMemory::new();
+            let todo = repository
+                .create(1, CreateTodo::new("owner todo".to_string()))
+                .await
+                .expect("failed create todo");
+
+            // 他のユーザーからは見えない
+            assert!(repository.find(2, todo.id).await.is_err());
+            assert!(repository.all(2).await.unwrap().is_empty());
+            assert!(repository.delete(2, todo.id).await.is_err());
+            assert_eq!(todo, repository.find(1, todo.id).await.unwrap());
+        }
     }
 }
@@ -0,0 +1,684 @@
+use super::{memory::MemoryDatabase, RepositoryError};
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool, SqlitePool};
+use validator::Validate;
+
+// APIトークンは平文を保存せず、SHA-256のハッシュのみを保存する
+#[async_trait]
+pub trait TokenRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn create(&self, user_id: i32, payload: CreateToken, token: &str) -> anyhow::Result<Token>;
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Token>>;
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()>;
+    // 有効なトークンであれば最終利用日時を更新して、持ち主とスコープを返す
+    async fn authenticate(&self, token: &str) -> anyhow::Result<Credential>;
+    // `authenticate` と同じく確かめるが、最終利用日時は更新しない。接続中の定期的な確認に使う
+    async fn verify(&self, token: &str) -> anyhow::Result<Credential>;
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "token_scope", rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    ReadWrite,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct Token {
+    pub id: i32,
+    pub name: String,
+    pub scope: Scope,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct Credential {
+    pub user_id: i32,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+pub struct CreateToken {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    pub name: String,
+    pub scope: Scope,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenRepositoryForDb {
+    pool: PgPool,
+}
+
+impl TokenRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl TokenRepository for TokenRepositoryForDb {
+    async fn create(&self, user_id: i32, payload: CreateToken, token: &str) -> anyhow::Result<Token> {
+        let token = sqlx::query_as::<_, Token>(
+            r#"
+            insert into tokens ( user_id, name, token_hash, scope, expires_at )
+            values ( $1, $2, $3, $4, $5 )
+            returning id, name, scope, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(payload.name)
+        .bind(hash_token(token))
+        .bind(payload.scope)
+        .bind(payload.expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Token>> {
+        let tokens = sqlx::query_as::<_, Token>(
+            r#"
+            select id, name, scope, expires_at, last_used_at, created_at from tokens
+            where user_id=$1
+            order by id asc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from tokens where id=$1 and user_id=$2
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+
+    async fn authenticate(&self, token: &str) -> anyhow::Result<Credential> {
+        let credential = sqlx::query_as::<_, Credential>(
+            r#"
+            update tokens set last_used_at = now()
+            where token_hash=$1 and (expires_at is null or expires_at > now())
+            returning user_id, scope
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::Unauthorized,
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(credential)
+    }
+
+    async fn verify(&self, token: &str) -> anyhow::Result<Credential> {
+        let credential = sqlx::query_as::<_, Credential>(
+            r#"
+            select user_id, scope from tokens
+            where token_hash=$1 and (expires_at is null or expires_at > now())
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::Unauthorized,
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(credential)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl TokenRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl TokenRepository for TokenRepositoryForSqlite {
+    async fn create(&self, user_id: i32, payload: CreateToken, token: &str) -> anyhow::Result<Token> {
+        let result = sqlx::query(
+            r#"
+            insert into tokens ( user_id, name, token_hash, scope, expires_at )
+            values ( $1, $2, $3, $4, $5 )
+            "#,
+        )
+        .bind(user_id)
+        .bind(payload.name)
+        .bind(hash_token(token))
+        .bind(payload.scope)
+        .bind(payload.expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        let token = sqlx::query_as::<_, Token>(
+            r#"
+            select id, name, scope, expires_at, last_used_at, created_at from tokens
+            where id=$1
+            "#,
+        )
+        .bind(result.last_insert_rowid())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Token>> {
+        let tokens = sqlx::query_as::<_, Token>(
+            r#"
+            select id, name, scope, expires_at, last_used_at, created_at from tokens
+            where user_id=$1
+            order by id asc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from tokens where id=$1 and user_id=$2
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+
+    // 日時は文字列で保存しているので、julianday()で比較する
+    async fn authenticate(&self, token: &str) -> anyhow::Result<Credential> {
+        let token_hash = hash_token(token);
+        let result = sqlx::query(
+            r#"
+            update tokens set last_used_at = $2
+            where token_hash=$1 and (expires_at is null or julianday(expires_at) > julianday($2))
+            "#,
+        )
+        .bind(&token_hash)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::Unauthorized.into());
+        }
+
+        let credential = sqlx::query_as::<_, Credential>(
+            r#"
+            select user_id, scope from tokens where token_hash=$1
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(credential)
+    }
+
+    async fn verify(&self, token: &str) -> anyhow::Result<Credential> {
+        let credential = sqlx::query_as::<_, Credential>(
+            r#"
+            select user_id, scope from tokens
+            where token_hash=$1 and (expires_at is null or julianday(expires_at) > julianday($2))
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::Unauthorized,
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(credential)
+    }
+}
+
+// メモリのストレージではハッシュと持ち主をトークンと一緒に保存する
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TokenRecord {
+    pub user_id: i32,
+    pub token_hash: String,
+    pub token: Token,
+}
+
+// 1つの `MemoryDatabase` を他のメモリのリポジトリと共有する
+#[derive(Debug, Clone)]
+pub struct TokenRepositoryForMemory {
+    database: MemoryDatabase,
+}
+
+impl TokenRepositoryForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl TokenRepository for TokenRepositoryForMemory {
+    async fn create(&self, user_id: i32, payload: CreateToken, token: &str) -> anyhow::Result<Token> {
+        self.database
+            .write(|tables| {
+                let id = tables.tokens.next_id();
+                let record = TokenRecord {
+                    user_id,
+                    token_hash: hash_token(token),
+                    token: Token {
+                        id,
+                        name: payload.name,
+                        scope: payload.scope,
+                        expires_at: payload.expires_at,
+                        last_used_at: None,
+                        created_at: Utc::now(),
+                    },
+                };
+                tables.tokens.insert(id, record.clone());
+                Ok(record.token)
+            })
+            .await
+    }
+
+    async fn all(&self, user_id: i32) -> anyhow::Result<Vec<Token>> {
+        let tokens = self
+            .database
+            .read(|tables| {
+                tables
+                    .tokens
+                    .values()
+                    .filter(|record| record.user_id == user_id)
+                    .map(|record| record.token.clone())
+                    .collect()
+            })
+            .await;
+        Ok(tokens)
+    }
+
+    async fn delete(&self, user_id: i32, id: i32) -> anyhow::Result<()> {
+        self.database
+            .write(|tables| match tables.tokens.get(&id) {
+                Some(record) if record.user_id == user_id => {
+                    tables.tokens.remove(&id);
+                    Ok(())
+                }
+                _ => Err(RepositoryError::NotFound(id).into()),
+            })
+            .await
+    }
+
+    async fn authenticate(&self, token: &str) -> anyhow::Result<Credential> {
+        let token_hash = hash_token(token);
+        let now = Utc::now();
+        self.database
+            .write(|tables| {
+                let mut record = tables
+                    .tokens
+                    .values()
+                    .find(|record| record.token_hash == token_hash)
+                    .filter(|record| {
+                        record
+                            .token
+                            .expires_at
+                            .is_none_or(|expires_at| expires_at > now)
+                    })
+                    .cloned()
+                    .ok_or(RepositoryError::Unauthorized)?;
+                record.token.last_used_at = Some(now);
+                tables.tokens.insert(record.token.id, record.clone());
+                Ok(Credential {
+                    user_id: record.user_id,
+                    scope: record.token.scope,
+                })
+            })
+            .await
+    }
+
+    async fn verify(&self, token: &str) -> anyhow::Result<Credential> {
+        let token_hash = hash_token(token);
+        let now = Utc::now();
+        let credential = self
+            .database
+            .read(|tables| {
+                tables
+                    .tokens
+                    .values()
+                    .find(|record| record.token_hash == token_hash)
+                    .filter(|record| {
+                        record
+                            .token
+                            .expires_at
+                            .is_none_or(|expires_at| expires_at > now)
+                    })
+                    .map(|record| Credential {
+                        user_id: record.user_id,
+                        scope: record.token.scope,
+                    })
+            })
+            .await
+            .ok_or(RepositoryError::Unauthorized)?;
+        Ok(credential)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::user::{User, UserRepository, UserRepositoryForDb};
+    use chrono::Duration;
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+    use std::env;
+
+    async fn recreate_user(pool: &PgPool, name: &str) -> User {
+        sqlx::query("delete from users where name=$1")
+            .bind(name)
+            .execute(pool)
+            .await
+            .expect("[recreate_user] delete returned Err");
+        UserRepositoryForDb::new(pool.clone())
+            .create(name.to_string())
+            .await
+            .expect("[recreate_user] create returned Err")
+    }
+
+    #[tokio::test]
+    async fn crud_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let user = recreate_user(&pool, "[token crud_scenario]").await;
+        let repository = TokenRepositoryForDb::new(pool.clone());
+        let plaintext = "[token crud_scenario] token";
+
+        // create
+        let token = repository
+            .create(
+                user.id,
+                CreateToken {
+                    name: "cron".to_string(),
+                    scope: Scope::Read,
+                    expires_at: None,
+                },
+                plaintext,
+            )
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(token.name, "cron");
+        assert!(token.last_used_at.is_none());
+
+        // 平文は保存されない
+        let stored: (String,) = sqlx::query_as("select token_hash from tokens where id=$1")
+            .bind(token.id)
+            .fetch_one(&pool)
+            .await
+            .expect("[create] fetch token_hash error");
+        assert_eq!(hash_token(plaintext), stored.0);
+
+        // verifyは最終利用日時を更新しない
+        let credential = repository
+            .verify(plaintext)
+            .await
+            .expect("[verify] returned Err");
+        assert_eq!(user.id, credential.user_id);
+        assert!(repository.verify("unknown").await.is_err());
+        let tokens = repository.all(user.id).await.expect("[all] returned Err");
+        assert!(tokens[0].last_used_at.is_none());
+
+        // authenticate
+        let credential = repository
+            .authenticate(plaintext)
+            .await
+            .expect("[authenticate] returned Err");
+        assert_eq!(
+            Credential {
+                user_id: user.id,
+                scope: Scope::Read
+            },
+            credential
+        );
+        assert!(repository.authenticate("unknown").await.is_err());
+
+        // all
+        let tokens = repository.all(user.id).await.expect("[all] returned Err");
+        assert_eq!(1, tokens.len());
+        assert!(tokens[0].last_used_at.is_some());
+
+        // 期限切れのトークンは使えない
+        let expired = "[token crud_scenario] expired";
+        repository
+            .create(
+                user.id,
+                CreateToken {
+                    name: "expired".to_string(),
+                    scope: Scope::ReadWrite,
+                    expires_at: Some(Utc::now() - Duration::minutes(1)),
+                },
+                expired,
+            )
+            .await
+            .expect("[create] returned Err");
+        assert!(repository.authenticate(expired).await.is_err());
+        assert!(repository.verify(expired).await.is_err());
+
+        // delete
+        repository
+            .delete(user.id, token.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.authenticate(plaintext).await.is_err());
+        assert!(repository.verify(plaintext).await.is_err());
+        assert!(repository.delete(user.id, token.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod sqlite_test {
+    use super::*;
+    use crate::repositories::{
+        sqlite_test_pool,
+        user::{UserRepository, UserRepositoryForSqlite},
+    };
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn sqlite_crud_scenario() {
+        let pool = sqlite_test_pool().await;
+        let user = UserRepositoryForSqlite::new(pool.clone())
+            .create("user".to_string())
+            .await
+            .expect("[create user] returned Err");
+        let repository = TokenRepositoryForSqlite::new(pool);
+
+        let token = repository
+            .create(
+                user.id,
+                CreateToken::new("cron".to_string(), Scope::Read, None),
+                "plaintext",
+            )
+            .await
+            .expect("[create] returned Err");
+        assert!(token.last_used_at.is_none());
+
+        // verifyは最終利用日時を更新しない
+        let credential = repository
+            .verify("plaintext")
+            .await
+            .expect("[verify] returned Err");
+        assert_eq!(user.id, credential.user_id);
+        assert!(repository.verify("unknown").await.is_err());
+        let tokens = repository.all(user.id).await.expect("[all] returned Err");
+        assert!(tokens[0].last_used_at.is_none());
+
+        let credential = repository
+            .authenticate("plaintext")
+            .await
+            .expect("[authenticate] returned Err");
+        assert_eq!(
+            Credential {
+                user_id: user.id,
+                scope: Scope::Read
+            },
+            credential
+        );
+        assert!(repository.authenticate("unknown").await.is_err());
+        let tokens = repository.all(user.id).await.expect("[all] returned Err");
+        assert!(tokens[0].last_used_at.is_some());
+
+        // 期限切れのトークンは使えない
+        repository
+            .create(
+                user.id,
+                CreateToken::new(
+                    "expired".to_string(),
+                    Scope::ReadWrite,
+                    Some(Utc::now() - Duration::minutes(1)),
+                ),
+                "expired",
+            )
+            .await
+            .expect("[create] returned Err");
+        assert!(repository.authenticate("expired").await.is_err());
+        assert!(repository.verify("expired").await.is_err());
+
+        repository
+            .delete(user.id, token.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.authenticate("plaintext").await.is_err());
+        assert!(repository.verify("plaintext").await.is_err());
+        assert!(repository.delete(user.id, token.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+
+    impl CreateToken {
+        pub fn new(name: String, scope: Scope, expires_at: Option<DateTime<Utc>>) -> Self {
+            Self {
+                name,
+                scope,
+                expires_at,
+            }
+        }
+    }
+
+    impl TokenRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::with_database(MemoryDatabase::default())
+        }
+    }
+
+    mod test {
+        use super::*;
+        use chrono::Duration;
+
+        #[tokio::test]
+        async fn token_crud_scenario() {
+            let repository = TokenRepositoryForMemory::new();
+
+            // create
+            let token = repository
+                .create(1, CreateToken::new("ci".to_string(), Scope::ReadWrite, None), "plaintext")
+                .await
+                .expect("failed create token");
+            assert_eq!(1, token.id);
+
+            // verifyは最終利用日時を更新しない
+            let credential = repository.verify("plaintext").await.unwrap();
+            assert_eq!(1, credential.user_id);
+            assert!(repository.verify("unknown").await.is_err());
+            assert!(repository.all(1).await.unwrap()[0].last_used_at.is_none());
+
+            // authenticate
+            let credential = repository.authenticate("plaintext").await.unwrap();
+            assert_eq!(
+                Credential {
+                    user_id: 1,
+                    scope: Scope::ReadWrite
+                },
+                credential
+            );
+            assert!(repository.authenticate("unknown").await.is_err());
+
+            // all
+            let tokens = repository.all(1).await.unwrap();
+            assert!(tokens[0].last_used_at.is_some());
+            assert!(repository.all(2).await.unwrap().is_empty());
+
+            // expired
+            repository
+                .create(
+                    1,
+                    CreateToken::new(
+                        "expired".to_string(),
+                        Scope::Read,
+                        Some(Utc::now() - Duration::minutes(1)),
+                    ),
+                    "expired",
+                )
+                .await
+                .expect("failed create token");
+            assert!(repository.authenticate("expired").await.is_err());
+            assert!(repository.verify("expired").await.is_err());
+
+            // delete
+            assert!(repository.delete(2, token.id).await.is_err());
+            assert!(repository.delete(1, token.id).await.is_ok());
+            assert!(repository.authenticate("plaintext").await.is_err());
+            assert!(repository.verify("plaintext").await.is_err());
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use axum::async_trait;
+use sqlx::{PgPool, Postgres, Sqlite, SqlitePool};
+
+use super::{
+    label::{LabelView, LabelViewForDb, LabelViewForMemory, LabelViewForSqlite},
+    memory::{Exclusive, MemoryDatabase},
+    todo::{TodoView, TodoViewForDb, TodoViewForMemory, TodoViewForSqlite},
+};
+
+// 複数のリポジトリにまたがる操作を1つのトランザクションで行う
+// `begin` で始めて `commit` するまでの変更は、途中で失敗したりcommitせずにdropしたりすると取り消される
+#[async_trait]
+pub trait UnitOfWork: Clone + std::marker::Send + std::marker::Sync + 'static {
+    type Transaction: Transaction;
+
+    async fn begin(&self) -> anyhow::Result<Self::Transaction>;
+
+    // 読み取りだけのトランザクション。中の読み込みはすべて始めた時点の同じ状態を見る
+    // 書き出しのように、何回かに分けて読んだ結果を突き合わせるときに使う
+    async fn begin_snapshot(&self) -> anyhow::Result<Self::Transaction> {
+        self.begin().await
+    }
+}
+
+#[async_trait]
+pub trait Transaction: std::marker::Send + Sized {
+    fn todos(&mut self) -> Box<dyn TodoView + '_>;
+    fn labels(&mut self) -> Box<dyn LabelView + '_>;
+    async fn commit(self) -> anyhow::Result<()>;
+    async fn rollback(self) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct UnitOfWorkForDb {
+    pool: PgPool,
+}
+
+impl UnitOfWorkForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for UnitOfWorkForDb {
+    type Transaction = TransactionForDb;
+
+    async fn begin(&self) -> anyhow::Result<TransactionForDb> {
+        Ok(TransactionForDb {
+            tx: self.pool.begin().await?,
+        })
+    }
+
+    // 既定のREAD COMMITTEDでは読み込むたびに他のトランザクションのcommitが見えてしまう
+    async fn begin_snapshot(&self) -> anyhow::Result<TransactionForDb> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("set transaction isolation level repeatable read, read only")
+            .execute(&mut tx)
+            .await?;
+        Ok(TransactionForDb { tx })
+    }
+}
+
+pub struct TransactionForDb {
+    tx: sqlx::Transaction<'static, Postgres>,
+}
+
+#[async_trait]
+impl Transaction for TransactionForDb {
+    fn todos(&mut self) -> Box<dyn TodoView + '_> {
+        Box::new(TodoViewForDb::new(&mut self.tx))
+    }
+
+    fn labels(&mut self) -> Box<dyn LabelView + '_> {
+        Box::new(LabelViewForDb::new(&mut self.tx))
+    }
+
+    async fn commit(self) -> anyhow::Result<()> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+
+    async fn rollback(self) -> anyhow::Result<()> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnitOfWorkForSqlite {
+    pool: SqlitePool,
+}
+
+impl UnitOfWorkForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+// SQLiteのトランザクションは直列化されるので、`begin_snapshot` も `begin` と同じでよい
+#[async_trait]
+impl UnitOfWork for UnitOfWorkForSqlite {
+    type Transaction = TransactionForSqlite;
+
+    async fn begin(&self) -> anyhow::Result<TransactionForSqlite> {
+        Ok(TransactionForSqlite {
+            tx: self.pool.begin().await?,
+        })
+    }
+}
+
+pub struct TransactionForSqlite {
+    tx: sqlx::Transaction<'static, Sqlite>,
+}
+
+#[async_trait]
+impl Transaction for TransactionForSqlite {
+    fn todos(&mut self) -> Box<dyn TodoView + '_> {
+        Box::new(TodoViewForSqlite::new(&mut self.tx))
+    }
+
+    fn labels(&mut self) -> Box<dyn LabelView + '_> {
+        Box::new(LabelViewForSqlite::new(&mut self.tx))
+    }
+
+    async fn commit(self) -> anyhow::Result<()> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+
+    async fn rollback(self) -> anyhow::Result<()> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+}
+
+// トランザクションの間は他の読み書きを待たせるので、commitが他の書き込みとぶつかることはない
+#[derive(Debug, Clone)]
+pub struct UnitOfWorkForMemory {
+    database: MemoryDatabase,
+}
+
+impl UnitOfWorkForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for UnitOfWorkForMemory {
+    type Transaction = TransactionForMemory;
+
+    async fn begin(&self) -> anyhow::Result<TransactionForMemory> {
+        Ok(TransactionForMemory {
+            exclusive: self.database.begin().await,
+        })
+    }
+}
+
+pub struct TransactionForMemory {
+    exclusive: Exclusive,
+}
+
+#[async_trait]
+impl Transaction for TransactionForMemory {
+    fn todos(&mut self) -> Box<dyn TodoView + '_> {
+        Box::new(TodoViewForMemory::new(self.exclusive.tables()))
+    }
+
+    fn labels(&mut self) -> Box<dyn LabelView + '_> {
+        Box::new(LabelViewForMemory::new(self.exclusive.tables()))
+    }
+
+    async fn commit(self) -> anyhow::Result<()> {
+        self.exclusive.commit()
+    }
+
+    // 破棄すると変更を取り消す
+    async fn rollback(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::{LabelRepository, LabelRepositoryForMemory, LabelRepositoryForSqlite},
+        sqlite_test_pool,
+        todo::{CreateTodo, TodoRepository, TodoRepositoryForMemory, TodoRepositoryForSqlite},
+        user::{UserRepository, UserRepositoryForSqlite},
+    };
+
+    // Todoを作ってラベルを付ける途中で失敗すると、Todoも残らない
+    async fn rollback_scenario<U, T, L>(unit_of_work: &U, todos: &T, labels: &L, user_id: i32)
+    where
+        U: UnitOfWork,
+        T: TodoRepository,
+        L: LabelRepository,
+    {
+        let label = labels.create(user_id, "work".to_string()).await.unwrap();
+
+        let mut tx = unit_of_work.begin().await.expect("[begin] returned Err");
+        let todo = tx
+            .todos()
+            .create(user_id, CreateTodo::new("with labels".to_string()))
+            .await
+            .expect("[create] returned Err");
+        let res = tx
+            .todos()
+            .set_labels(user_id, todo.id, &[label.id, 9999])
+            .await;
+        assert!(res.is_err());
+        tx.rollback().await.expect("[rollback] returned Err");
+        assert!(todos.all(user_id).await.unwrap().is_empty());
+
+        // commitしなければ反映されない
+        let mut tx = unit_of_work.begin().await.unwrap();
+        tx.todos()
+            .create(user_id, CreateTodo::new("dropped".to_string()))
+            .await
+            .unwrap();
+        drop(tx);
+        assert!(todos.all(user_id).await.unwrap().is_empty());
+
+        // 成功すればTodoとラベルの付与がまとめて反映される
+        let mut tx = unit_of_work.begin().await.unwrap();
+        let todo = tx
+            .todos()
+            .create(user_id, CreateTodo::new("with labels".to_string()))
+            .await
+            .unwrap();
+        let attached = tx
+            .todos()
+            .set_labels(user_id, todo.id, &[label.id])
+            .await
+            .expect("[set_labels] returned Err");
+        assert_eq!(vec![label.clone()], attached);
+        tx.commit().await.expect("[commit] returned Err");
+        assert_eq!(vec![todo.clone()], todos.all(user_id).await.unwrap());
+
+        // まとめて読み込んでも同じ。他のユーザーのTodoやラベルは含めない
+        let mut tx = unit_of_work.begin().await.unwrap();
+        let batched = tx.todos().labels_of(user_id, &[todo.id, 9999]).await;
+        assert_eq!(vec![(todo.id, label.clone())], batched.expect("[labels_of] returned Err"));
+        let batched = tx.todos().todos_of(user_id, &[label.id, 9999]).await;
+        assert_eq!(vec![(label.id, todo.clone())], batched.expect("[todos_of] returned Err"));
+        assert!(tx
+            .todos()
+            .labels_of(user_id + 1, &[todo.id])
+            .await
+            .unwrap()
+            .is_empty());
+        tx.rollback().await.unwrap();
+
+        // ラベルを消すとTodoからも外れる
+        labels.delete(user_id, label.id).await.expect("[delete] returned Err");
+        let mut tx = unit_of_work.begin().await.unwrap();
+        assert!(tx
+            .todos()
+            .labels(user_id, todo.id)
+            .await
+            .unwrap()
+            .is_empty());
+        tx.rollback().await.unwrap();
+        todos
+            .delete(user_id, todo.id)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn memory_rollback_scenario() {
+        let database = MemoryDatabase::default();
+        rollback_scenario(
+            &UnitOfWorkForMemory::with_database(database.clone()),
+            &TodoRepositoryForMemory::with_database(database.clone()),
+            &LabelRepositoryForMemory::with_database(database),
+            1,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_rollback_scenario() {
+        let pool = sqlite_test_pool().await;
+        let user = UserRepositoryForSqlite::new(pool.clone())
+            .create("unit of work".to_string())
+            .await
+            .unwrap();
+        rollback_scenario(
+            &UnitOfWorkForSqlite::new(pool.clone()),
+            &TodoRepositoryForSqlite::new(pool.clone()),
+            &LabelRepositoryForSqlite::new(pool),
+            user.id,
+        )
+        .await;
+    }
+
+    // トランザクションの間の読み書きは、commitするまで待たされる
+    #[tokio::test]
+    async fn memory_should_serialize_transactions() {
+        let database = MemoryDatabase::default();
+        let unit_of_work = UnitOfWorkForMemory::with_database(database.clone());
+        let todos = TodoRepositoryForMemory::with_database(database);
+
+        let mut tx = unit_of_work.begin().await.unwrap();
+        tx.todos()
+            .create(1, CreateTodo::new("in transaction".to_string()))
+            .await
+            .unwrap();
+        let outside = tokio::spawn({
+            let todos = todos.clone();
+            async move {
+                todos
+                    .create(1, CreateTodo::new("outside".to_string()))
+                    .await
+                    .unwrap();
+                todos.all(1).await.unwrap()
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!outside.is_finished());
+        tx.commit().await.expect("[commit] returned Err");
+
+        let texts: Vec<String> = outside
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|todo| todo.text)
+            .collect();
+        assert_eq!(
+            vec!["outside".to_string(), "in transaction".to_string()],
+            texts
+        );
+    }
+
+    #[cfg(feature = "database-test")]
+    mod database {
+        use super::*;
+        use crate::repositories::{
+            label::LabelRepositoryForDb, todo::TodoRepositoryForDb, user::UserRepositoryForDb,
+        };
+        use dotenv::dotenv;
+        use std::env;
+
+        #[tokio::test]
+        async fn postgres_rollback_scenario() {
+            dotenv().ok();
+            let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+            let pool = PgPool::connect(database_url)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+            sqlx::query("delete from users where name=$1")
+                .bind("unit_of_work_user")
+                .execute(&pool)
+                .await
+                .unwrap();
+            let user = UserRepositoryForDb::new(pool.clone())
+                .create("unit_of_work_user".to_string())
+                .await
+                .unwrap();
+            rollback_scenario(
+                &UnitOfWorkForDb::new(pool.clone()),
+                &TodoRepositoryForDb::new(pool.clone()),
+                &LabelRepositoryForDb::new(pool),
+                user.id,
+            )
+            .await;
+        }
+
+        // 始めた後にcommitされた書き込みは、スナップショットのトランザクションからは見えない
+        #[tokio::test]
+        async fn postgres_snapshot_should_not_see_later_commits() {
+            dotenv().ok();
+            let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+            let pool = PgPool::connect(database_url)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+            sqlx::query("delete from users where name=$1")
+                .bind("unit_of_work_snapshot_user")
+                .execute(&pool)
+                .await
+                .unwrap();
+            let user = UserRepositoryForDb::new(pool.clone())
+                .create("unit_of_work_snapshot_user".to_string())
+                .await
+                .unwrap();
+            let unit_of_work = UnitOfWorkForDb::new(pool.clone());
+            let todos = TodoRepositoryForDb::new(pool);
+
+            let mut tx = unit_of_work
+                .begin_snapshot()
+                .await
+                .expect("[begin_snapshot] returned Err");
+            assert!(tx.todos().all(user.id).await.unwrap().is_empty());
+            todos
+                .create(user.id, CreateTodo::new("later".to_string()))
+                .await
+                .unwrap();
+            assert!(tx.todos().all(user.id).await.unwrap().is_empty());
+            // 読み取りだけなので書き込めない
+            assert!(tx
+                .todos()
+                .create(user.id, CreateTodo::new("in snapshot".to_string()))
+                .await
+                .is_err());
+            tx.rollback().await.unwrap();
+            assert_eq!(1, todos.all(user.id).await.unwrap().len());
+        }
+    }
+}
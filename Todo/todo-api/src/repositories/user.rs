@@ -0,0 +1,373 @@
+use super::{memory::MemoryDatabase, RepositoryError};
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, SqlitePool};
+
+// 認証に使うトークンはTokenRepositoryで管理する
+#[async_trait]
+pub trait UserRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn create(&self, name: String) -> anyhow::Result<User>;
+    async fn find_by_name(&self, name: &str) -> anyhow::Result<User>;
+    // 発行したトークンなど、ユーザーに紐づくものもまとめて消す
+    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct User {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserRepositoryForDb {
+    pool: PgPool,
+}
+
+impl UserRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepository for UserRepositoryForDb {
+    async fn create(&self, name: String) -> anyhow::Result<User> {
+        let optional_user = sqlx::query_as::<_, User>(
+            r#"
+            select id, name from users where name=$1
+            "#,
+        )
+        .bind(name.clone())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(user) = optional_user {
+            return Err(RepositoryError::Duplicate(user.id).into());
+        }
+
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            insert into users ( name )
+            values ( $1 )
+            returning id, name
+            "#,
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    async fn find_by_name(&self, name: &str) -> anyhow::Result<User> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            select id, name from users where name=$1
+            "#,
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFoundByName(name.to_string()),
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(user)
+    }
+
+    async fn delete(&self, id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from users where id=$1
+            "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl UserRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepository for UserRepositoryForSqlite {
+    async fn create(&self, name: String) -> anyhow::Result<User> {
+        let optional_user = sqlx::query_as::<_, User>(
+            r#"
+            select id, name from users where name=$1
+            "#,
+        )
+        .bind(name.clone())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(user) = optional_user {
+            return Err(RepositoryError::Duplicate(user.id).into());
+        }
+
+        let result = sqlx::query(
+            r#"
+            insert into users ( name )
+            values ( $1 )
+            "#,
+        )
+        .bind(name.clone())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(User {
+            id: result.last_insert_rowid() as i32,
+            name,
+        })
+    }
+
+    async fn find_by_name(&self, name: &str) -> anyhow::Result<User> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            select id, name from users where name=$1
+            "#,
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFoundByName(name.to_string()),
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(user)
+    }
+
+    async fn delete(&self, id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from users where id=$1
+            "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+}
+
+// 1つの `MemoryDatabase` を他のメモリのリポジトリと共有する
+#[derive(Debug, Clone)]
+pub struct UserRepositoryForMemory {
+    database: MemoryDatabase,
+}
+
+impl UserRepositoryForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl UserRepository for UserRepositoryForMemory {
+    async fn create(&self, name: String) -> anyhow::Result<User> {
+        self.database
+            .write(|tables| {
+                if let Some(user) = tables.users.values().find(|user| user.name == name) {
+                    return Err(RepositoryError::Duplicate(user.id).into());
+                }
+
+                let id = tables.users.next_id();
+                let user = User { id, name };
+                tables.users.insert(id, user.clone());
+                Ok(user)
+            })
+            .await
+    }
+
+    async fn find_by_name(&self, name: &str) -> anyhow::Result<User> {
+        let user = self
+            .database
+            .read(|tables| {
+                tables
+                    .users
+                    .values()
+                    .find(|user| user.name == name)
+                    .cloned()
+            })
+            .await
+            .ok_or_else(|| RepositoryError::NotFoundByName(name.to_string()))?;
+        Ok(user)
+    }
+
+    async fn delete(&self, id: i32) -> anyhow::Result<()> {
+        self.database
+            .write(|tables| {
+                if tables.users.remove(&id).is_none() {
+                    return Err(RepositoryError::NotFound(id).into());
+                }
+                let tokens = tables
+                    .tokens
+                    .rows()
+                    .filter(|(_, record)| record.user_id == id)
+                    .map(|(token_id, _)| *token_id)
+                    .collect::<Vec<_>>();
+                for token_id in tokens {
+                    tables.tokens.remove(&token_id);
+                }
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+    use std::env;
+
+    #[tokio::test]
+    async fn crud_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        sqlx::query("delete from users where name=$1")
+            .bind("[user crud_scenario]")
+            .execute(&pool)
+            .await
+            .expect("[cleanup] returned Err");
+
+        let repository = UserRepositoryForDb::new(pool);
+
+        // create
+        let user = repository
+            .create("[user crud_scenario]".to_string())
+            .await
+            .expect("[create] returned Err");
+
+        // duplicate
+        let res = repository.create("[user crud_scenario]".to_string()).await;
+        assert!(res.is_err());
+
+        // find_by_name
+        let found = repository
+            .find_by_name("[user crud_scenario]")
+            .await
+            .expect("[find_by_name] returned Err");
+        assert_eq!(user, found);
+
+        let res = repository.find_by_name("[user crud_scenario] unknown").await;
+        assert!(res.is_err());
+
+        // delete
+        repository
+            .delete(user.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository
+            .find_by_name("[user crud_scenario]")
+            .await
+            .is_err());
+        assert!(repository.delete(user.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod sqlite_test {
+    use super::*;
+    use crate::repositories::sqlite_test_pool;
+
+    #[tokio::test]
+    async fn sqlite_crud_scenario() {
+        let repository = UserRepositoryForSqlite::new(sqlite_test_pool().await);
+
+        let user = repository
+            .create("user".to_string())
+            .await
+            .expect("[create] returned Err");
+        assert!(repository.create("user".to_string()).await.is_err());
+
+        let found = repository
+            .find_by_name("user")
+            .await
+            .expect("[find_by_name] returned Err");
+        assert_eq!(user, found);
+        assert!(repository.find_by_name("unknown").await.is_err());
+
+        repository
+            .delete(user.id)
+            .await
+            .expect("[delete] returned Err");
+        assert!(repository.find_by_name("user").await.is_err());
+        assert!(repository.delete(user.id).await.is_err());
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+
+    impl UserRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::with_database(MemoryDatabase::default())
+        }
+    }
+
+    mod test {
+        use super::*;
+
+        #[tokio::test]
+        async fn user_scenario() {
+            let repository = UserRepositoryForMemory::new();
+
+            // create
+            let user = repository
+                .create("user".to_string())
+                .await
+                .expect("failed create user");
+            assert_eq!(
+                User {
+                    id: 1,
+                    name: "user".to_string()
+                },
+                user
+            );
+            assert!(repository.create("user".to_string()).await.is_err());
+
+            // find_by_name
+            let found = repository.find_by_name("user").await.unwrap();
+            assert_eq!(user, found);
+            assert!(repository.find_by_name("unknown").await.is_err());
+
+            // delete
+            repository
+                .delete(user.id)
+                .await
+                .expect("failed delete user");
+            assert!(repository.find_by_name("user").await.is_err());
+            assert!(repository.delete(user.id).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,786 @@
+use super::{memory::MemoryDatabase, RepositoryError};
+use axum::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Json, FromRow, PgPool, SqlitePool};
+use validator::{Validate, ValidationError};
+
+// ワークスペースの変更を外部のURLへ通知する。配信は送信に成功するか諦めるまで残す
+#[async_trait]
+pub trait WebhookRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn create(
+        &self,
+        owner_id: i32,
+        payload: CreateWebhook,
+        secret: &str,
+    ) -> anyhow::Result<Webhook>;
+    async fn find(&self, owner_id: i32, id: i32) -> anyhow::Result<Webhook>;
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Webhook>>;
+    async fn delete(&self, owner_id: i32, id: i32) -> anyhow::Result<()>;
+    async fn enqueue(
+        &self,
+        webhook_id: i32,
+        event: &str,
+        payload: Value,
+    ) -> anyhow::Result<Delivery>;
+    // 送信する時刻を過ぎた配信を古い順に取り出す
+    // 取り出した配信は `lease` の間は取り出されないので、複数のインスタンスから同時に送らない
+    async fn claim(
+        &self,
+        now: DateTime<Utc>,
+        lease: Duration,
+        limit: i64,
+    ) -> anyhow::Result<Vec<ClaimedDelivery>>;
+    // 送信を1回試した結果を残す
+    async fn record(&self, id: &str, attempt: Attempt) -> anyhow::Result<Delivery>;
+    // 新しい順に `limit` 件まで
+    async fn deliveries(
+        &self,
+        owner_id: i32,
+        webhook_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Delivery>>;
+}
+
+// シークレットは作成時にだけ返す
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    // `todo.*,label.created` のようにカンマで区切った購読するイベントの種類。空ならすべて
+    pub events: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+pub struct CreateWebhook {
+    #[validate(url(message = "Invalid url"))]
+    #[validate(custom(function = "validate_scheme", message = "Url must be http or https"))]
+    #[validate(length(max = 2048, message = "Over url length"))]
+    pub url: String,
+    #[serde(default)]
+    #[validate(length(max = 200, message = "Over events length"))]
+    pub events: String,
+}
+
+fn validate_scheme(url: &str) -> Result<(), ValidationError> {
+    match reqwest::Url::parse(url) {
+        Ok(url) if !crate::webhooks::is_allowed_scheme(&url) => Err(ValidationError::new("scheme")),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Succeeded,
+    // 再送の上限に達して諦めた配信
+    Dead,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, FromRow)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: Json<Value>,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 送信に必要な宛先とシークレットを付けた配信
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimedDelivery {
+    pub delivery: Delivery,
+    pub url: String,
+    pub secret: String,
+}
+
+// sqlx 0.5のFromRowは入れ子にできないので、配信の列を並べて受け取る
+#[derive(Debug, FromRow)]
+struct ClaimedRow {
+    id: String,
+    webhook_id: i32,
+    event: String,
+    payload: Json<Value>,
+    status: DeliveryStatus,
+    attempts: i32,
+    next_attempt_at: Option<DateTime<Utc>>,
+    response_status: Option<i32>,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    url: String,
+    secret: String,
+}
+
+impl From<ClaimedRow> for ClaimedDelivery {
+    fn from(row: ClaimedRow) -> Self {
+        Self {
+            delivery: Delivery {
+                id: row.id,
+                webhook_id: row.webhook_id,
+                event: row.event,
+                payload: row.payload,
+                status: row.status,
+                attempts: row.attempts,
+                next_attempt_at: row.next_attempt_at,
+                response_status: row.response_status,
+                error: row.error,
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+            },
+            url: row.url,
+            secret: row.secret,
+        }
+    }
+}
+
+// 1回の送信の結果。次に送る時刻がなければ、成功したか諦めたかのどちらか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub status: DeliveryStatus,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookRepositoryForDb {
+    pool: PgPool,
+}
+
+impl WebhookRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl WebhookRepository for WebhookRepositoryForDb {
+    async fn create(
+        &self,
+        owner_id: i32,
+        payload: CreateWebhook,
+        secret: &str,
+    ) -> anyhow::Result<Webhook> {
+        let webhook = sqlx::query_as::<_, Webhook>(
+            r#"
+            insert into webhooks ( owner_id, url, events, secret )
+            values ( $1, $2, $3, $4 )
+            returning id, url, events, created_at
+            "#,
+        )
+        .bind(owner_id)
+        .bind(payload.url)
+        .bind(payload.events)
+        .bind(secret)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    async fn find(&self, owner_id: i32, id: i32) -> anyhow::Result<Webhook> {
+        let webhook = sqlx::query_as::<_, Webhook>(
+            r#"
+            select id, url, events, created_at from webhooks
+            where id=$1 and owner_id=$2
+            "#,
+        )
+        .bind(id)
+        .bind(owner_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(webhook)
+    }
+
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Webhook>> {
+        let webhooks = sqlx::query_as::<_, Webhook>(
+            r#"
+            select id, url, events, created_at from webhooks
+            where owner_id=$1
+            order by id asc
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    async fn delete(&self, owner_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from webhooks where id=$1 and owner_id=$2
+            "#,
+        )
+        .bind(id)
+        .bind(owner_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+
+    async fn enqueue(
+        &self,
+        webhook_id: i32,
+        event: &str,
+        payload: Value,
+    ) -> anyhow::Result<Delivery> {
+        let delivery = sqlx::query_as::<_, Delivery>(
+            r#"
+            insert into webhook_deliveries ( id, webhook_id, event, payload, next_attempt_at )
+            values ( $1, $2, $3, $4, now() )
+            returning *
+            "#,
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(webhook_id)
+        .bind(event)
+        .bind(Json(payload))
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    // 他のインスタンスが取り出している行は `skip locked` で飛ばす
+    async fn claim(
+        &self,
+        now: DateTime<Utc>,
+        lease: Duration,
+        limit: i64,
+    ) -> anyhow::Result<Vec<ClaimedDelivery>> {
+        let rows = sqlx::query_as::<_, ClaimedRow>(
+            r#"
+            update webhook_deliveries as d set next_attempt_at=$2
+            from webhooks as w
+            where w.id=d.webhook_id and d.id in (
+                select id from webhook_deliveries
+                where status='pending' and next_attempt_at<=$1
+                order by next_attempt_at asc
+                limit $3
+                for update skip locked
+            )
+            returning d.*, w.url, w.secret
+            "#,
+        )
+        .bind(now)
+        .bind(now + lease)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(ClaimedDelivery::from).collect())
+    }
+
+    async fn record(&self, id: &str, attempt: Attempt) -> anyhow::Result<Delivery> {
+        let delivery = sqlx::query_as::<_, Delivery>(
+            r#"
+            update webhook_deliveries
+            set status=$2, attempts=attempts+1, next_attempt_at=$3, response_status=$4, error=$5,
+                updated_at=now()
+            where id=$1
+            returning *
+            "#,
+        )
+        .bind(id)
+        .bind(attempt.status)
+        .bind(attempt.next_attempt_at)
+        .bind(attempt.response_status)
+        .bind(attempt.error)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    async fn deliveries(
+        &self,
+        owner_id: i32,
+        webhook_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Delivery>> {
+        let deliveries = sqlx::query_as::<_, Delivery>(
+            r#"
+            select d.* from webhook_deliveries as d
+            inner join webhooks as w on w.id=d.webhook_id
+            where d.webhook_id=$1 and w.owner_id=$2
+            order by d.created_at desc, d.id asc
+            limit $3
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(owner_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookRepositoryForSqlite {
+    pool: SqlitePool,
+}
+
+impl WebhookRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl WebhookRepository for WebhookRepositoryForSqlite {
+    async fn create(
+        &self,
+        owner_id: i32,
+        payload: CreateWebhook,
+        secret: &str,
+    ) -> anyhow::Result<Webhook> {
+        let result = sqlx::query(
+            r#"
+            insert into webhooks ( owner_id, url, events, secret )
+            values ( $1, $2, $3, $4 )
+            "#,
+        )
+        .bind(owner_id)
+        .bind(payload.url)
+        .bind(payload.events)
+        .bind(secret)
+        .execute(&self.pool)
+        .await?;
+
+        self.find(owner_id, result.last_insert_rowid() as i32).await
+    }
+
+    async fn find(&self, owner_id: i32, id: i32) -> anyhow::Result<Webhook> {
+        let webhook = sqlx::query_as::<_, Webhook>(
+            r#"
+            select id, url, events, created_at from webhooks
+            where id=$1 and owner_id=$2
+            "#,
+        )
+        .bind(id)
+        .bind(owner_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+            _ => RepositoryError::Unexpected(e.to_string()),
+        })?;
+
+        Ok(webhook)
+    }
+
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Webhook>> {
+        let webhooks = sqlx::query_as::<_, Webhook>(
+            r#"
+            select id, url, events, created_at from webhooks
+            where owner_id=$1
+            order by id asc
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    async fn delete(&self, owner_id: i32, id: i32) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            r#"
+            delete from webhooks where id=$1 and owner_id=$2
+            "#,
+        )
+        .bind(id)
+        .bind(owner_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+
+        Ok(())
+    }
+
+    async fn enqueue(
+        &self,
+        webhook_id: i32,
+        event: &str,
+        payload: Value,
+    ) -> anyhow::Result<Delivery> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = Utc::now();
+        sqlx::query(
+            r#"
+            insert into webhook_deliveries
+                ( id, webhook_id, event, payload, next_attempt_at, created_at, updated_at )
+            values ( $1, $2, $3, $4, $5, $5, $5 )
+            "#,
+        )
+        .bind(&id)
+        .bind(webhook_id)
+        .bind(event)
+        .bind(Json(payload))
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        self.get(&id).await
+    }
+
+    // SQLiteは書き込みを1つずつしか実行しないので、トランザクションの中で選んでから更新する
+    async fn claim(
+        &self,
+        now: DateTime<Utc>,
+        lease: Duration,
+        limit: i64,
+    ) -> anyhow::Result<Vec<ClaimedDelivery>> {
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query_as::<_, ClaimedRow>(
+            r#"
+            select d.*, w.url, w.secret from webhook_deliveries as d
+            inner join webhooks as w on w.id=d.webhook_id
+            where d.status='pending' and julianday(d.next_attempt_at)<=julianday($1)
+            order by julianday(d.next_attempt_at) asc
+            limit $2
+            "#,
+        )
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&mut tx)
+        .await?;
+
+        let leased_until = now + lease;
+        for row in &rows {
+            sqlx::query(
+                r#"
+                update webhook_deliveries set next_attempt_at=$2 where id=$1
+                "#,
+            )
+            .bind(&row.id)
+            .bind(leased_until)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|mut row| {
+                row.next_attempt_at = Some(leased_until);
+                ClaimedDelivery::from(row)
+            })
+            .collect())
+    }
+
+    async fn record(&self, id: &str, attempt: Attempt) -> anyhow::Result<Delivery> {
+        let result = sqlx::query(
+            r#"
+            update webhook_deliveries
+            set status=$2, attempts=attempts+1, next_attempt_at=$3, response_status=$4, error=$5,
+                updated_at=$6
+            where id=$1
+            "#,
+        )
+        .bind(id)
+        .bind(attempt.status)
+        .bind(attempt.next_attempt_at)
+        .bind(attempt.response_status)
+        .bind(attempt.error)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::Unexpected(format!("delivery {} not found", id)).into());
+        }
+
+        self.get(id).await
+    }
+
+    async fn deliveries(
+        &self,
+        owner_id: i32,
+        webhook_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Delivery>> {
+        let deliveries = sqlx::query_as::<_, Delivery>(
+            r#"
+            select d.* from webhook_deliveries as d
+            inner join webhooks as w on w.id=d.webhook_id
+            where d.webhook_id=$1 and w.owner_id=$2
+            order by julianday(d.created_at) desc, d.id asc
+            limit $3
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(owner_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+}
+
+impl WebhookRepositoryForSqlite {
+    async fn get(&self, id: &str) -> anyhow::Result<Delivery> {
+        let delivery = sqlx::query_as::<_, Delivery>(
+            r#"
+            select * from webhook_deliveries where id=$1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+}
+
+// メモリのストレージではワークスペースとシークレットをWebhookと一緒に保存する
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WebhookRecord {
+    pub owner_id: i32,
+    pub secret: String,
+    pub webhook: Webhook,
+}
+
+// 1つの `MemoryDatabase` を他のメモリのリポジトリと共有する
+#[derive(Debug, Clone)]
+pub struct WebhookRepositoryForMemory {
+    database: MemoryDatabase,
+}
+
+impl WebhookRepositoryForMemory {
+    pub fn with_database(database: MemoryDatabase) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl WebhookRepository for WebhookRepositoryForMemory {
+    async fn create(
+        &self,
+        owner_id: i32,
+        payload: CreateWebhook,
+        secret: &str,
+    ) -> anyhow::Result<Webhook> {
+        self.database
+            .write(|tables| {
+                let id = tables.webhooks.next_id();
+                let record = WebhookRecord {
+                    owner_id,
+                    secret: secret.to_string(),
+                    webhook: Webhook {
+                        id,
+                        url: payload.url,
+                        events: payload.events,
+                        created_at: Utc::now(),
+                    },
+                };
+                tables.webhooks.insert(id, record.clone());
+                Ok(record.webhook)
+            })
+            .await
+    }
+
+    async fn find(&self, owner_id: i32, id: i32) -> anyhow::Result<Webhook> {
+        self.database
+            .read(|tables| {
+                tables
+                    .webhooks
+                    .get(&id)
+                    .filter(|record| record.owner_id == owner_id)
+                    .map(|record| record.webhook.clone())
+                    .ok_or_else(|| RepositoryError::NotFound(id).into())
+            })
+            .await
+    }
+
+    async fn all(&self, owner_id: i32) -> anyhow::Result<Vec<Webhook>> {
+        let webhooks = self
+            .database
+            .read(|tables| {
+                tables
+                    .webhooks
+                    .values()
+                    .filter(|record| record.owner_id == owner_id)
+                    .map(|record| record.webhook.clone())
+                    .collect()
+            })
+            .await;
+        Ok(webhooks)
+    }
+
+    async fn delete(&self, owner_id: i32, id: i32) -> anyhow::Result<()> {
+        self.database
+            .write(|tables| match tables.webhooks.get(&id) {
+                Some(record) if record.owner_id == owner_id => {
+                    tables.webhooks.remove(&id);
+                    let deliveries: Vec<String> = tables
+                        .webhook_deliveries
+                        .values()
+                        .filter(|delivery| delivery.webhook_id == id)
+                        .map(|delivery| delivery.id.clone())
+                        .collect();
+                    for delivery in deliveries {
+                        tables.webhook_deliveries.remove(&delivery);
+                    }
+                    Ok(())
+                }
+                _ => Err(RepositoryError::NotFound(id).into()),
+            })
+            .await
+    }
+
+    async fn enqueue(
+        &self,
+        webhook_id: i32,
+        event: &str,
+        payload: Value,
+    ) -> anyhow::Result<Delivery> {
+        self.database
+            .write(|tables| {
+                if tables.webhooks.get(&webhook_id).is_none() {
+                    return Err(RepositoryError::NotFound(webhook_id).into());
+                }
+                let now = Utc::now();
+                let delivery = Delivery {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    webhook_id,
+                    event: event.to_string(),
+                    payload: Json(payload),
+                    status: DeliveryStatus::Pending,
+                    attempts: 0,
+                    next_attempt_at: Some(now),
+                    response_status: None,
+                    error: None,
+                    created_at: now,
+                    updated_at: now,
+                };
+                tables
+                    .webhook_deliveries
+                    .insert(delivery.id.clone(), delivery.clone());
+                Ok(delivery)
+            })
+            .await
+    }
+
+    async fn claim(
+        &self,
+        now: DateTime<Utc>,
+        lease: Duration,
+        limit: i64,
+    ) -> anyhow::Result<Vec<ClaimedDelivery>> {
+        self.database
+            .write(|tables| {
+                let mut due: Vec<Delivery> = tables
+                    .webhook_deliveries
+                    .values()
+                    .filter(|delivery| {
+                        delivery.status == DeliveryStatus::Pending
+                            && delivery.next_attempt_at.is_some_and(|at| at <= now)
+                    })
+                    .cloned()
+                    .collect();
+                due.sort_by_key(|delivery| delivery.next_attempt_at);
+                due.truncate(limit.max(0) as usize);
+
+                let mut claimed = Vec::new();
+                for mut delivery in due {
+                    let record = match tables.webhooks.get(&delivery.webhook_id) {
+                        Some(record) => record.clone(),
+                        None => continue,
+                    };
+                    delivery.next_attempt_at = Some(now + lease);
+                    tables
+                        .webhook_deliveries
+                        .insert(delivery.id.clone(), delivery.clone());
+                    claimed.push(ClaimedDelivery {
+                        delivery,
+                        url: record.webhook.url,
+                        secret: record.secret,
+                    });
+                }
+                Ok(claimed)
+            })
+            .await
+    }
+
+    async fn record(&self, id: &str, attempt: Attempt) -> anyhow::Result<Delivery> {
+        self.database
+            .write(|tables| {
+                let mut delivery = tables
+                    .webhook_deliveries
+                    .get(&id.to_string())
+                    .cloned()
+                    .ok_or_else(|| {
+                        RepositoryError::Unexpected(format!("delivery {} not found", id))
+                    })?;
+                delivery.status = attempt.status;
+                delivery.attempts += 1;
+                delivery.next_attempt_at = attempt.next_attempt_at;
+                delivery.response_status = attempt.response_status;
+                delivery.error = attempt.error;
+                delivery.updated_at = Utc::now();
+                tables
+                    .webhook_deliveries
+                    .insert(delivery.id.clone(), delivery.clone());
+                Ok(delivery)
+            })
+            .await
+    }
+
+    async fn deliveries(
+        &self,
+        owner_id: i32,
+        webhook_id: i32,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Delivery>> {
+        let deliveries = self
+            .database
+            .read(|tables| {
+                let owned = tables
+                    .webhooks
+                    .get(&webhook_id)
+                    .is_some_and(|record| record.owner_id == owner_id);
+                let mut deliveries: Vec<Delivery> = tables
+                    .webhook_deliveries
+                    .values()
+                    .filter(|delivery| owned && delivery.webhook_id == webhook_id)
+                    .cloned()
+                    .collect();
+                deliveries.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+                deliveries.truncate(limit.max(0) as usize);
+                deliveries
+            })
+            .await;
+        Ok(deliveries)
+    }
+}
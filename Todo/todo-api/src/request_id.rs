@@ -0,0 +1,184 @@
+use axum::{
+    body::{boxed, Full},
+    extract::MatchedPath,
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+use std::time::Instant;
+use opentelemetry::global;
+use opentelemetry_http::HeaderExtractor;
+use tracing::{field, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// ハンドラーからは `Extension<RequestId>` で参照できる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+// 受け取った `X-Request-Id` を引き継ぎ、なければ生成する
+fn request_id<B>(req: &Request<B>) -> RequestId {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    RequestId(id)
+}
+
+// リクエストごとにspanを開き、完了時にステータスと処理時間を記録する
+// `traceparent` ヘッダーがあれば、そのトレースの子としてspanを作る
+// エラーレスポンスのボディには問い合わせに使えるようにリクエストIDを含める
+pub async fn trace_request<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let start = Instant::now();
+    let request_id = request_id(&req);
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        request_id = %request_id.0,
+        method = %req.method(),
+        route = %route,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+    req.extensions_mut().insert(request_id.clone());
+
+    async move {
+        let res = next.run(req).await;
+        let status = res.status();
+        let latency_ms = start.elapsed().as_millis() as u64;
+        let span = tracing::Span::current();
+        span.record("status", status.as_u16());
+        span.record("latency_ms", latency_ms);
+        if status.is_server_error() {
+            span.record("otel.status_code", "ERROR");
+            tracing::error!("request failed");
+        } else {
+            tracing::info!("request completed");
+        }
+
+        let mut res = if status.is_client_error() || status.is_server_error() {
+            with_request_id(res, &request_id).await
+        } else {
+            res
+        };
+        if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+// JSONのオブジェクトであれば `request_id` を追加し、それ以外は `{ "error", "request_id" }` に包む
+async fn with_request_id(res: Response, request_id: &RequestId) -> Response {
+    let (mut parts, body) = res.into_parts();
+    let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("request_id".to_string(), json!(request_id.0));
+            Value::Object(object)
+        }
+        _ => {
+            let text = String::from_utf8_lossy(&bytes);
+            let message = if text.is_empty() {
+                parts
+                    .status
+                    .canonical_reason()
+                    .unwrap_or_default()
+                    .to_string()
+            } else {
+                text.to_string()
+            };
+            json!({ "error": message, "request_id": request_id.0 })
+        }
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, boxed(Full::from(body.to_string())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn, routing::get, Router};
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn should_log_request_span() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route(
+                "/todos/:id",
+                get(|| async {
+                    tracing::info!("in handler");
+                    "ok"
+                }),
+            )
+            .layer(from_fn(trace_request));
+        let req = Request::builder()
+            .uri("/todos/1")
+            .header(REQUEST_ID_HEADER, "trace-test")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // ハンドラー内のログにもリクエストのspanが付く
+        assert_eq!("in handler", lines[0]["fields"]["message"]);
+        assert_eq!("trace-test", lines[0]["span"]["request_id"]);
+        assert_eq!("/todos/:id", lines[0]["span"]["route"]);
+
+        let completed = &lines[1];
+        assert_eq!("request completed", completed["fields"]["message"]);
+        assert_eq!(200, completed["span"]["status"]);
+        assert!(completed["span"]["latency_ms"].is_u64());
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::Context;
+use opentelemetry::{
+    global,
+    sdk::{
+        propagation::TraceContextPropagator,
+        trace::{self as sdktrace, Sampler, TracerProvider},
+        Resource,
+    },
+    trace::TracerProvider as _,
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::config::{Config, LogFormat, OtlpProtocol, TelemetryConfig};
+
+// ログの出力と、設定されていればOTLPへのトレースの送信を開始する
+// 受け取ったリクエストの `traceparent` を親にできるようにW3C Trace Contextを使う
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let provider = tracer_provider(&config.telemetry)?;
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("todo-api")));
+    if let Some(provider) = provider {
+        global::set_tracer_provider(provider);
+    }
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let (text_layer, json_layer) = match config.log.format {
+        LogFormat::Text => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json())),
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_new(&config.log.level)?)
+        .with(text_layer)
+        .with(json_layer)
+        .with(otel_layer)
+        .try_init()?;
+    Ok(())
+}
+
+// 送信待ちのspanを送ってから終了する
+pub async fn shutdown() {
+    tokio::task::spawn_blocking(global::shutdown_tracer_provider)
+        .await
+        .ok();
+}
+
+pub fn tracer_provider(config: &TelemetryConfig) -> anyhow::Result<Option<TracerProvider>> {
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+
+    let exporter: SpanExporterBuilder = match config.protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(config.timeout())
+            .into(),
+        // HTTPの場合はOTLPの仕様どおりエンドポイントに `/v1/traces` を付ける
+        OtlpProtocol::Http => {
+            let endpoint = endpoint.trim_end_matches('/');
+            let endpoint = if endpoint.ends_with("/v1/traces") {
+                endpoint.to_string()
+            } else {
+                format!("{}/v1/traces", endpoint)
+            };
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint)
+                .with_timeout(config.timeout())
+                .into()
+        }
+    };
+    let exporter = exporter
+        .build_span_exporter()
+        .context("fail build otlp exporter")?;
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry::runtime::Tokio)
+        .with_config(
+            sdktrace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    config.sample_ratio,
+                ))))
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    config.service_name.clone(),
+                )])),
+        )
+        .build();
+    Ok(Some(provider))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{create_app, Repositories};
+    use crate::repositories::token::{CreateToken, Scope, TokenRepository};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use opentelemetry_otlp::proto::{
+        collector::trace::v1::{
+            trace_service_server::{TraceService, TraceServiceServer},
+            ExportTraceServiceRequest, ExportTraceServiceResponse,
+        },
+        trace::v1::Span,
+    };
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    // 受け取ったspanを保存するだけのコレクター
+    #[derive(Clone, Default)]
+    struct FakeCollector {
+        spans: Arc<Mutex<Vec<Span>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for FakeCollector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let mut spans = self.spans.lock().unwrap();
+            for resource_spans in request.into_inner().resource_spans {
+                for library_spans in resource_spans.instrumentation_library_spans {
+                    spans.extend(library_spans.spans);
+                }
+            }
+            Ok(tonic::Response::new(ExportTraceServiceResponse {}))
+        }
+    }
+
+    async fn start_collector() -> (String, FakeCollector) {
+        let collector = FakeCollector::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = TraceServiceServer::new(collector.clone());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        (format!("http://{}", addr), collector)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn should_export_spans_to_collector() {
+        let (endpoint, collector) = start_collector().await;
+        let provider = tracer_provider(&TelemetryConfig {
+            otlp_endpoint: Some(endpoint),
+            ..TelemetryConfig::default()
+        })
+        .expect("failed build tracer provider")
+        .expect("exporter is disabled");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("todo-api")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let repositories = Repositories::memory();
+        repositories
+            .token
+            .create(1, CreateToken::new("otel".to_string(), Scope::ReadWrite, None), "otel")
+            .await
+            .expect("failed create token");
+        let app = create_app(repositories, &Config::default());
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::AUTHORIZATION, "Bearer otel")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .body(Body::from(r#"{ "text": "traced" }"#))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        let flushed = tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap();
+        assert!(flushed.iter().all(|result| result.is_ok()));
+
+        let spans = collector.spans.lock().unwrap().clone();
+        let request_span = spans
+            .iter()
+            .find(|span| span.name == "POST /todos")
+            .unwrap_or_else(|| panic!("request span was not exported: {:?}", spans));
+        // 受け取ったtraceparentのトレースに続く
+        assert_eq!(
+            "0af7651916cd43dd8448eb211c80319c",
+            hex::encode(&request_span.trace_id)
+        );
+        assert_eq!("b7ad6b7169203331", hex::encode(&request_span.parent_span_id));
+
+        let repository_span = spans
+            .iter()
+            .find(|span| span.name == "repository")
+            .expect("repository span was not exported");
+        assert_eq!(request_span.trace_id, repository_span.trace_id);
+        assert_eq!(request_span.span_id, repository_span.parent_span_id);
+    }
+}
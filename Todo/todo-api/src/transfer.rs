@@ -0,0 +1,715 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+mod icalendar;
+mod markdown;
+mod todotxt;
+
+use crate::repositories::{
+    label::Label,
+    todo::{CreateTodo, Todo, UpdateTodo},
+    unit_of_work::{Transaction, UnitOfWork},
+};
+
+// 形式を変えたときは上げて、古い形式の読み込みは `Document::from_json` で扱う
+// 1: labels, todos
+// 2: Todoとラベルの対応 `links` を追加
+pub const FORMAT_VERSION: u32 = 2;
+
+const MAX_TEXT_LENGTH: usize = 100;
+
+// ユーザーのTodoとラベルをまとめて書き出す形式
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExportData {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub labels: Vec<Label>,
+    pub todos: Vec<Todo>,
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Link {
+    pub todo_id: i32,
+    pub label_id: i32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+    #[serde(rename = "todotxt")]
+    #[value(name = "todotxt")]
+    TodoTxt,
+    Ics,
+    Markdown,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::TodoTxt => "text/plain; charset=utf-8",
+            Format::Ics => "text/calendar; charset=utf-8",
+            Format::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::TodoTxt => "txt",
+            Format::Ics => "ics",
+            Format::Markdown => "md",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    // 既存のデータを残して追加する。同名のラベルは既存のものを使う
+    #[default]
+    Merge,
+    // 既存のTodoとラベルをすべて消してから取り込む
+    Replace,
+}
+
+// 取り込めなかった行。`row` はCSVなら `line 3`、JSONなら `todos[0]` のように示す
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct RowError {
+    pub row: String,
+    pub message: String,
+}
+
+impl RowError {
+    fn new(row: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            row: row.into(),
+            message: message.into(),
+        }
+    }
+}
+
+// `errors` が1つでもあれば何も取り込まず、`committed` はfalseになる
+// `todo_ids` と `label_ids` はファイル中のIDから取り込み先で振り直したIDへの対応
+#[derive(Debug, Default, Serialize, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub committed: bool,
+    pub todos_deleted: usize,
+    pub labels_deleted: usize,
+    pub labels_created: usize,
+    pub labels_merged: usize,
+    pub todos_created: usize,
+    pub links_created: usize,
+    pub todo_ids: BTreeMap<i32, i32>,
+    pub label_ids: BTreeMap<i32, i32>,
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    pub fn rejected(errors: Vec<RowError>) -> Self {
+        Self {
+            errors,
+            ..Self::default()
+        }
+    }
+}
+
+// 取り込むデータと、エラーの表示に使う各要素の位置
+// 読み込めなかった行は `errors` に残して読み進め、`validate` で他の誤りと合わせて返す
+#[derive(Debug, Clone)]
+pub struct Document {
+    data: ExportData,
+    label_rows: Vec<String>,
+    todo_rows: Vec<String>,
+    link_rows: Vec<String>,
+    errors: Vec<RowError>,
+}
+
+impl From<ExportData> for Document {
+    fn from(data: ExportData) -> Self {
+        let rows = |name: &str, len: usize| (0..len).map(|i| format!("{}[{}]", name, i)).collect();
+        Self {
+            label_rows: rows("labels", data.labels.len()),
+            todo_rows: rows("todos", data.todos.len()),
+            link_rows: rows("links", data.links.len()),
+            errors: vec![],
+            data,
+        }
+    }
+}
+
+impl Document {
+    // 全体を読めない場合だけErrを返す。行ごとの誤りは `validate` で返す
+    pub fn parse(format: Format, bytes: &[u8]) -> Result<Self, Vec<RowError>> {
+        match format {
+            Format::Json => Self::from_json(bytes),
+            Format::Csv => Self::from_csv(bytes),
+            Format::TodoTxt => todotxt::parse(bytes),
+            Format::Ics => icalendar::parse(bytes),
+            Format::Markdown => markdown::parse(bytes),
+        }
+    }
+
+    // 新しいバージョンの形式は読み方がわからないので受け付けない
+    pub fn from_json(bytes: &[u8]) -> Result<Self, Vec<RowError>> {
+        let data: ExportData = serde_json::from_slice(bytes).map_err(|e| {
+            vec![RowError::new(
+                format!("line {}, column {}", e.line(), e.column()),
+                e.to_string(),
+            )]
+        })?;
+        if data.version == 0 || data.version > FORMAT_VERSION {
+            return Err(vec![RowError::new(
+                "version",
+                format!(
+                    "unsupported version [{}], expected [1] to [{}]",
+                    data.version, FORMAT_VERSION
+                ),
+            )]);
+        }
+        Ok(data.into())
+    }
+
+    // 1行目のヘッダーは `CSV_HEADER` と同じ列を持つ必要がある。後から加えた `due` 列はなくてもよい
+    pub fn from_csv(bytes: &[u8]) -> Result<Self, Vec<RowError>> {
+        let mut reader = csv::ReaderBuilder::new().from_reader(bytes);
+        let headers = reader
+            .headers()
+            .map_err(|e| vec![csv_error("line 1", &e)])?
+            .clone();
+        let missing: Vec<&str> = CSV_HEADER
+            .iter()
+            .filter(|column| !OPTIONAL_COLUMNS.contains(column))
+            .filter(|column| !headers.iter().any(|header| header == **column))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(vec![RowError::new(
+                "line 1",
+                format!("missing columns [{}]", missing.join(", ")),
+            )]);
+        }
+
+        let mut document = Document::from(ExportData {
+            version: FORMAT_VERSION,
+            exported_at: Utc::now(),
+            labels: vec![],
+            todos: vec![],
+            links: vec![],
+        });
+        let mut errors = Vec::new();
+        for record in reader.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    let row = match e.position() {
+                        Some(position) => format!("line {}", position.line()),
+                        None => "line ?".to_string(),
+                    };
+                    errors.push(csv_error(&row, &e));
+                    continue;
+                }
+            };
+            let row = format!(
+                "line {}",
+                record.position().map_or(0, |position| position.line())
+            );
+            let parsed = record
+                .deserialize::<CsvRow>(Some(&headers))
+                .map_err(|e| csv_error(&row, &e))
+                .and_then(|parsed| document.push_csv_row(parsed, &row));
+            if let Err(e) = parsed {
+                errors.push(e);
+            }
+        }
+        document.errors = errors;
+        Ok(document)
+    }
+
+    fn push_csv_row(&mut self, row: CsvRow, location: &str) -> Result<(), RowError> {
+        let required = |value: Option<i32>, column: &str| {
+            value.ok_or_else(|| RowError::new(location, format!("[{}] is required", column)))
+        };
+        match row.record {
+            CsvRecord::Label => {
+                self.data.labels.push(Label {
+                    id: required(row.id, "id")?,
+                    name: row.text.unwrap_or_default(),
+                });
+                self.label_rows.push(location.to_string());
+            }
+            CsvRecord::Todo => {
+                self.data.todos.push(Todo {
+                    id: required(row.id, "id")?,
+                    text: row.text.unwrap_or_default(),
+                    completed: row.completed.unwrap_or(false),
+                    due: row.due,
+                });
+                self.todo_rows.push(location.to_string());
+            }
+            CsvRecord::Link => {
+                self.data.links.push(Link {
+                    todo_id: required(row.todo_id, "todo_id")?,
+                    label_id: required(row.label_id, "label_id")?,
+                });
+                self.link_rows.push(location.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // 書き込む前に、読み込めなかった行とファイルの中だけで判断できる誤りをすべて集める
+    fn validate(&self) -> Vec<RowError> {
+        let mut errors = self.errors.clone();
+        let mut label_ids = HashSet::new();
+        for (label, row) in self.data.labels.iter().zip(&self.label_rows) {
+            if let Err(message) = check_text(&label.name) {
+                errors.push(RowError::new(row, format!("name {}", message)));
+            }
+            if !label_ids.insert(label.id) {
+                errors.push(RowError::new(
+                    row,
+                    format!("duplicate label id [{}]", label.id),
+                ));
+            }
+        }
+        let mut todo_ids = HashSet::new();
+        for (todo, row) in self.data.todos.iter().zip(&self.todo_rows) {
+            if let Err(message) = check_text(&todo.text) {
+                errors.push(RowError::new(row, format!("text {}", message)));
+            }
+            if !todo_ids.insert(todo.id) {
+                errors.push(RowError::new(
+                    row,
+                    format!("duplicate todo id [{}]", todo.id),
+                ));
+            }
+        }
+        for (link, row) in self.data.links.iter().zip(&self.link_rows) {
+            if !todo_ids.contains(&link.todo_id) {
+                errors.push(RowError::new(
+                    row,
+                    format!("unknown todo id [{}]", link.todo_id),
+                ));
+            }
+            if !label_ids.contains(&link.label_id) {
+                errors.push(RowError::new(
+                    row,
+                    format!("unknown label id [{}]", link.label_id),
+                ));
+            }
+        }
+        // 行で示せるものは行の順に並べる
+        errors.sort_by_key(|e| line_number(&e.row));
+        errors
+    }
+}
+
+fn line_number(row: &str) -> Option<u64> {
+    row.strip_prefix("line ")?.parse().ok()
+}
+
+fn check_text(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Err("can not be empty".to_string());
+    }
+    if value.chars().count() > MAX_TEXT_LENGTH {
+        return Err(format!("is over {} characters", MAX_TEXT_LENGTH));
+    }
+    Ok(())
+}
+
+fn csv_error(row: &str, e: &csv::Error) -> RowError {
+    RowError::new(row, e.to_string())
+}
+
+// CSVは1行に1つのラベル・Todo・対応を `record` 列で区別して並べる
+// ラベルの名前もTodoと同じ `text` 列に書く
+const CSV_HEADER: [&str; 7] = ["record", "id", "text", "completed", "todo_id", "label_id", "due"];
+const OPTIONAL_COLUMNS: [&str; 1] = ["due"];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CsvRecord {
+    Label,
+    Todo,
+    Link,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRow {
+    record: CsvRecord,
+    id: Option<i32>,
+    text: Option<String>,
+    completed: Option<bool>,
+    todo_id: Option<i32>,
+    label_id: Option<i32>,
+    due: Option<NaiveDate>,
+}
+
+impl CsvRow {
+    fn new(record: CsvRecord) -> Self {
+        Self {
+            record,
+            id: None,
+            text: None,
+            completed: None,
+            todo_id: None,
+            label_id: None,
+            due: None,
+        }
+    }
+}
+
+impl ExportData {
+    pub fn render(&self, format: Format) -> anyhow::Result<Vec<u8>> {
+        match format {
+            Format::Json => Ok(serde_json::to_vec_pretty(self)?),
+            Format::Csv => self.to_csv(),
+            Format::TodoTxt => Ok(todotxt::render(self).into_bytes()),
+            Format::Ics => Ok(icalendar::render(self).into_bytes()),
+            Format::Markdown => Ok(markdown::render(self).into_bytes()),
+        }
+    }
+
+    fn to_csv(&self) -> anyhow::Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for label in &self.labels {
+            writer.serialize(CsvRow {
+                id: Some(label.id),
+                text: Some(label.name.clone()),
+                ..CsvRow::new(CsvRecord::Label)
+            })?;
+        }
+        for todo in &self.todos {
+            writer.serialize(CsvRow {
+                id: Some(todo.id),
+                text: Some(todo.text.clone()),
+                completed: Some(todo.completed),
+                due: todo.due,
+                ..CsvRow::new(CsvRecord::Todo)
+            })?;
+        }
+        for link in &self.links {
+            writer.serialize(CsvRow {
+                todo_id: Some(link.todo_id),
+                label_id: Some(link.label_id),
+                ..CsvRow::new(CsvRecord::Link)
+            })?;
+        }
+        // 行がない場合もヘッダーは書く
+        if self.labels.is_empty() && self.todos.is_empty() && self.links.is_empty() {
+            writer.write_record(CSV_HEADER)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+}
+
+// 1つのスナップショットから読み、書き出し中の変更が混ざらないようにする
+// Todoは作った順に並べる
+pub async fn export<U: UnitOfWork>(unit_of_work: &U, user_id: i32) -> anyhow::Result<ExportData> {
+    let mut tx = unit_of_work.begin_snapshot().await?;
+    let labels = tx.labels().all(user_id).await?;
+    let mut todos = tx.todos().all(user_id).await?;
+    todos.sort_by_key(|todo| todo.id);
+    let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+    let links = tx
+        .todos()
+        .labels_of(user_id, &ids)
+        .await?
+        .into_iter()
+        .map(|(todo_id, label)| Link {
+            todo_id,
+            label_id: label.id,
+        })
+        .collect();
+    tx.rollback().await?;
+    Ok(ExportData {
+        version: FORMAT_VERSION,
+        exported_at: Utc::now(),
+        labels,
+        todos,
+        links,
+    })
+}
+
+// IDは取り込み先で振り直し、ラベルの付与もそのIDに付け替える
+// すべての行を確かめてから1つのトランザクションで書き込むので、途中で失敗しても何も残らない
+pub async fn import<U: UnitOfWork>(
+    unit_of_work: &U,
+    user_id: i32,
+    document: Document,
+    mode: ImportMode,
+) -> anyhow::Result<ImportReport> {
+    let errors = document.validate();
+    if !errors.is_empty() {
+        return Ok(ImportReport::rejected(errors));
+    }
+
+    let mut report = ImportReport::default();
+    let mut tx = unit_of_work.begin().await?;
+    if mode == ImportMode::Replace {
+        let todos = tx.todos().all(user_id).await?;
+        for todo in todos {
+            tx.todos().delete(user_id, todo.id).await?;
+            report.todos_deleted += 1;
+        }
+        let labels = tx.labels().all(user_id).await?;
+        for label in labels {
+            tx.labels().delete(user_id, label.id).await?;
+            report.labels_deleted += 1;
+        }
+    }
+
+    let data = document.data;
+    let mut names: HashMap<String, i32> = tx
+        .labels()
+        .all(user_id)
+        .await?
+        .into_iter()
+        .map(|label| (label.name, label.id))
+        .collect();
+    for label in data.labels {
+        let id = match names.get(&label.name) {
+            Some(id) => {
+                report.labels_merged += 1;
+                *id
+            }
+            None => {
+                let created = tx.labels().create(user_id, label.name.clone()).await?;
+                names.insert(label.name, created.id);
+                report.labels_created += 1;
+                created.id
+            }
+        };
+        report.label_ids.insert(label.id, id);
+    }
+
+    // 古いものから作り直して順序を保つ
+    let mut todos = data.todos;
+    todos.sort_by_key(|todo| todo.id);
+    for todo in todos {
+        let created = tx
+            .todos()
+            .create(user_id, CreateTodo::new(todo.text).with_due(todo.due))
+            .await?;
+        if todo.completed {
+            tx.todos()
+                .update(user_id, created.id, UpdateTodo::new(None, Some(true)))
+                .await?;
+        }
+        report.todo_ids.insert(todo.id, created.id);
+        report.todos_created += 1;
+    }
+
+    let mut links: BTreeMap<i32, BTreeSet<i32>> = BTreeMap::new();
+    for link in data.links {
+        links
+            .entry(report.todo_ids[&link.todo_id])
+            .or_default()
+            .insert(report.label_ids[&link.label_id]);
+    }
+    for (todo_id, label_ids) in links {
+        let label_ids: Vec<i32> = label_ids.into_iter().collect();
+        tx.todos().set_labels(user_id, todo_id, &label_ids).await?;
+        report.links_created += label_ids.len();
+    }
+
+    tx.commit().await?;
+    report.committed = true;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        label::{LabelRepository, LabelRepositoryForMemory},
+        memory::MemoryDatabase,
+        todo::{TodoRepository, TodoRepositoryForMemory},
+        unit_of_work::UnitOfWorkForMemory,
+    };
+
+    struct Fixture {
+        unit_of_work: UnitOfWorkForMemory,
+        todos: TodoRepositoryForMemory,
+        labels: LabelRepositoryForMemory,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let database = MemoryDatabase::default();
+            Self {
+                unit_of_work: UnitOfWorkForMemory::with_database(database.clone()),
+                todos: TodoRepositoryForMemory::with_database(database.clone()),
+                labels: LabelRepositoryForMemory::with_database(database),
+            }
+        }
+
+        // 完了済みで `work` ラベル付きのTodoと、未完了のTodoを作る
+        async fn seed(&self, user_id: i32) {
+            let label = self
+                .labels
+                .create(user_id, "work".to_string())
+                .await
+                .unwrap();
+            let done = self
+                .todos
+                .create(user_id, CreateTodo::new("done".to_string()))
+                .await
+                .unwrap();
+            self.todos
+                .update(user_id, done.id, UpdateTodo::new(None, Some(true)))
+                .await
+                .unwrap();
+            self.todos
+                .create(user_id, CreateTodo::new("pending".to_string()))
+                .await
+                .unwrap();
+            let mut tx = self.unit_of_work.begin().await.unwrap();
+            tx.todos()
+                .set_labels(user_id, done.id, &[label.id])
+                .await
+                .unwrap();
+            tx.commit().await.unwrap();
+        }
+
+        // IDに依存しない形で比べる
+        async fn snapshot(&self, user_id: i32) -> Vec<(String, bool, Vec<String>)> {
+            let data = export(&self.unit_of_work, user_id).await.unwrap();
+            data.todos
+                .iter()
+                .map(|todo| {
+                    let labels = data
+                        .links
+                        .iter()
+                        .filter(|link| link.todo_id == todo.id)
+                        .filter_map(|link| data.labels.iter().find(|l| l.id == link.label_id))
+                        .map(|label| label.name.clone())
+                        .collect();
+                    (todo.text.clone(), todo.completed, labels)
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn should_round_trip_json_and_csv() {
+        let fixture = Fixture::new();
+        fixture.seed(1).await;
+        let expected = fixture.snapshot(1).await;
+        let data = export(&fixture.unit_of_work, 1).await.unwrap();
+
+        let json = serde_json::to_vec(&data).unwrap();
+        let document = Document::parse(Format::Json, &json).unwrap();
+        let report = import(&fixture.unit_of_work, 2, document, ImportMode::Merge)
+            .await
+            .unwrap();
+        assert!(report.committed);
+        assert_eq!(
+            (1, 2, 1),
+            (
+                report.labels_created,
+                report.todos_created,
+                report.links_created
+            )
+        );
+        assert_eq!(expected, fixture.snapshot(2).await);
+
+        let csv = data.render(Format::Csv).unwrap();
+        let document = Document::parse(Format::Csv, &csv).unwrap();
+        let report = import(&fixture.unit_of_work, 3, document, ImportMode::Merge)
+            .await
+            .unwrap();
+        assert!(report.committed);
+        assert_eq!(expected, fixture.snapshot(3).await);
+        // 元のIDから振り直したIDへの対応を返す
+        let new_ids: Vec<i32> = fixture
+            .todos
+            .all(3)
+            .await
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        assert!(report.todo_ids.values().all(|id| new_ids.contains(id)));
+    }
+
+    #[tokio::test]
+    async fn should_merge_or_replace_existing_data() {
+        let fixture = Fixture::new();
+        fixture.seed(1).await;
+        let data = export(&fixture.unit_of_work, 1).await.unwrap();
+
+        // mergeでは同名のラベルを使い回し、Todoは追加する
+        let report = import(
+            &fixture.unit_of_work,
+            1,
+            data.clone().into(),
+            ImportMode::Merge,
+        )
+        .await
+        .unwrap();
+        assert_eq!((0, 1), (report.labels_created, report.labels_merged));
+        assert_eq!(1, fixture.labels.all(1).await.unwrap().len());
+        assert_eq!(4, fixture.todos.all(1).await.unwrap().len());
+
+        // replaceでは既存のものを消してから取り込む
+        let report = import(&fixture.unit_of_work, 1, data.into(), ImportMode::Replace)
+            .await
+            .unwrap();
+        assert_eq!((4, 1), (report.todos_deleted, report.labels_deleted));
+        assert_eq!(1, fixture.labels.all(1).await.unwrap().len());
+        assert_eq!(2, fixture.todos.all(1).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn should_report_bad_rows_without_importing() {
+        let fixture = Fixture::new();
+        let csv = "\
+record,id,text,completed,todo_id,label_id
+label,1,work,,,
+todo,1,ok,false,,
+todo,2,,true,,
+todo,3,broken,maybe,,
+link,,,,1,9
+";
+        // 読み込めない値と、読み込めた行どうしの誤りを1回でまとめて返す
+        let document = Document::parse(Format::Csv, csv.as_bytes()).unwrap();
+        let report = import(&fixture.unit_of_work, 1, document, ImportMode::Replace)
+            .await
+            .unwrap();
+        assert!(!report.committed);
+        assert_eq!(vec!["line 4", "line 5", "line 6"], rows(&report.errors));
+        assert!(fixture.todos.all(1).await.unwrap().is_empty());
+        assert!(fixture.labels.all(1).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_read_older_versions_and_reject_newer() {
+        let v1 = r#"{"version":1,"exported_at":"2024-01-01T00:00:00Z",
+            "labels":[{"id":1,"name":"work"}],"todos":[{"id":1,"text":"a","completed":false}]}"#;
+        let document = Document::from_json(v1.as_bytes()).unwrap();
+        assert!(document.data.links.is_empty());
+
+        let future = v1.replace(
+            r#""version":1"#,
+            &format!(r#""version":{}"#, FORMAT_VERSION + 1),
+        );
+        let errors = Document::from_json(future.as_bytes()).unwrap_err();
+        assert_eq!(vec!["version"], rows(&errors));
+    }
+
+    fn rows(errors: &[RowError]) -> Vec<&str> {
+        errors.iter().map(|e| e.row.as_str()).collect()
+    }
+}
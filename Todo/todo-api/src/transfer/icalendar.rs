@@ -0,0 +1,353 @@
+use chrono::{NaiveDate, Utc};
+use ical::{parser::ical::component::IcalTodo, IcalParser};
+use std::collections::HashMap;
+
+use super::{Document, ExportData, Link, RowError, FORMAT_VERSION};
+use crate::repositories::{label::Label, todo::Todo};
+
+// RFC 5545では1行を75オクテット以内に折り返す
+const MAX_LINE_OCTETS: usize = 75;
+const DATE_FORMAT: &str = "%Y%m%d";
+
+// TodoをVTODOとして並べたVCALENDAR (RFC 5545)
+// 状態は `STATUS`、ラベルは `CATEGORIES`、期限日は日付だけの `DUE` に対応させる
+pub(super) fn render(data: &ExportData) -> String {
+    let names: HashMap<i32, &str> = data
+        .labels
+        .iter()
+        .map(|label| (label.id, label.name.as_str()))
+        .collect();
+    let stamp = data.exported_at.format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//my-todo//todo-api//EN".to_string(),
+    ];
+    for todo in &data.todos {
+        lines.push("BEGIN:VTODO".to_string());
+        lines.push(format!("UID:todo-{}@my-todo", todo.id));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("SUMMARY:{}", escape(&todo.text)));
+        if todo.completed {
+            lines.push("STATUS:COMPLETED".to_string());
+            lines.push("PERCENT-COMPLETE:100".to_string());
+        } else {
+            lines.push("STATUS:NEEDS-ACTION".to_string());
+        }
+        if let Some(due) = todo.due {
+            lines.push(format!("DUE;VALUE=DATE:{}", due.format(DATE_FORMAT)));
+        }
+        let categories: Vec<String> = data
+            .links
+            .iter()
+            .filter(|link| link.todo_id == todo.id)
+            .filter_map(|link| names.get(&link.label_id))
+            .map(|name| escape(name))
+            .collect();
+        if !categories.is_empty() {
+            lines.push(format!("CATEGORIES:{}", categories.join(",")));
+        }
+        lines.push("END:VTODO".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line) + "\r\n").collect()
+}
+
+// VEVENTなどVTODO以外の要素は無視する
+// `STATUS:COMPLETED` か `COMPLETED` があれば完了済みとし、`CATEGORIES` はラベルにする
+// `DUE` は日時で書かれていても日付だけを期限日にする
+pub(super) fn parse(bytes: &[u8]) -> Result<Document, Vec<RowError>> {
+    let mut document = Document::from(ExportData {
+        version: FORMAT_VERSION,
+        exported_at: Utc::now(),
+        labels: vec![],
+        todos: vec![],
+        links: vec![],
+    });
+    let mut label_ids: HashMap<String, i32> = HashMap::new();
+    let mut errors = Vec::new();
+    for calendar in IcalParser::new(bytes) {
+        let calendar = calendar.map_err(|e| vec![RowError::new("calendar", e.to_string())])?;
+        for todo in calendar.todos {
+            let row = format!("VTODO[{}]", document.data.todos.len());
+            let todo_id = document.data.todos.len() as i32 + 1;
+            for name in categories(&todo) {
+                let next_id = label_ids.len() as i32 + 1;
+                let label_id = *label_ids.entry(name.clone()).or_insert_with(|| {
+                    document.data.labels.push(Label { id: next_id, name });
+                    document.label_rows.push(row.clone());
+                    next_id
+                });
+                document.data.links.push(Link { todo_id, label_id });
+                document.link_rows.push(row.clone());
+            }
+            let due = match property(&todo, "DUE").map(due_date) {
+                Some(Ok(due)) => Some(due),
+                Some(Err(message)) => {
+                    errors.push(RowError::new(row.clone(), message));
+                    None
+                }
+                None => None,
+            };
+            document.data.todos.push(Todo {
+                id: todo_id,
+                text: property(&todo, "SUMMARY").map(unescape).unwrap_or_default(),
+                completed: is_completed(&todo),
+                due,
+            });
+            document.todo_rows.push(row);
+        }
+    }
+    document.errors = errors;
+    Ok(document)
+}
+
+// `20240305` と `20240305T170000Z` のどちらも先頭の日付を読む
+fn due_date(value: &str) -> Result<NaiveDate, String> {
+    value
+        .get(..8)
+        .and_then(|date| NaiveDate::parse_from_str(date, DATE_FORMAT).ok())
+        .ok_or_else(|| format!("invalid DUE [{}]", value))
+}
+
+fn property<'a>(todo: &'a IcalTodo, name: &str) -> Option<&'a str> {
+    todo.properties
+        .iter()
+        .find(|property| property.name.eq_ignore_ascii_case(name))
+        .and_then(|property| property.value.as_deref())
+}
+
+fn is_completed(todo: &IcalTodo) -> bool {
+    property(todo, "STATUS").is_some_and(|status| status.eq_ignore_ascii_case("COMPLETED"))
+        || property(todo, "COMPLETED").is_some()
+}
+
+// `CATEGORIES` は複数回書け、1つの値にもカンマ区切りで複数を書ける
+fn categories(todo: &IcalTodo) -> Vec<String> {
+    let mut names = Vec::new();
+    for property in &todo.properties {
+        if !property.name.eq_ignore_ascii_case("CATEGORIES") {
+            continue;
+        }
+        for name in split_list(property.value.as_deref().unwrap_or_default()) {
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let mut values = split(value, false);
+    values.pop().unwrap_or_default()
+}
+
+// エスケープされていないカンマで区切る
+fn split_list(value: &str) -> Vec<String> {
+    split(value, true)
+}
+
+fn split(value: &str, on_comma: bool) -> Vec<String> {
+    let mut values = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let current = values.last_mut().expect("values is never empty");
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => current.push('\n'),
+                Some(escaped) => current.push(escaped),
+                None => current.push('\\'),
+            },
+            ',' if on_comma => values.push(String::new()),
+            _ => current.push(c),
+        }
+    }
+    values
+}
+
+// 続きの行は先頭に空白を1つ置く。マルチバイト文字の途中では折り返さない
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transfer::Format;
+
+    fn data() -> ExportData {
+        ExportData {
+            version: FORMAT_VERSION,
+            exported_at: Utc::now(),
+            labels: vec![
+                Label {
+                    id: 1,
+                    name: "work, urgent".to_string(),
+                },
+                Label {
+                    id: 2,
+                    name: "home".to_string(),
+                },
+            ],
+            todos: vec![
+                Todo {
+                    id: 1,
+                    text: "write; docs".to_string(),
+                    completed: true,
+                    due: NaiveDate::from_ymd_opt(2024, 3, 5),
+                },
+                Todo {
+                    id: 2,
+                    text: "とても長いTodoの本文は75オクテットを超えると折り返して書き出される"
+                        .to_string(),
+                    completed: false,
+                    due: None,
+                },
+            ],
+            links: vec![
+                Link {
+                    todo_id: 1,
+                    label_id: 1,
+                },
+                Link {
+                    todo_id: 1,
+                    label_id: 2,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn should_render_vtodo() {
+        let rendered = render(&data());
+        assert!(rendered.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(rendered.contains("SUMMARY:write\\; docs\r\nSTATUS:COMPLETED\r\n"));
+        assert!(rendered.contains("DUE;VALUE=DATE:20240305\r\n"));
+        assert_eq!(1, rendered.matches("DUE").count());
+        assert!(rendered.contains("CATEGORIES:work\\, urgent,home\r\n"));
+        assert!(rendered.contains("STATUS:NEEDS-ACTION\r\n"));
+        assert!(rendered
+            .split("\r\n")
+            .all(|line| line.len() <= MAX_LINE_OCTETS));
+    }
+
+    #[test]
+    fn should_round_trip_through_ics() {
+        let data = data();
+        let document = Document::parse(Format::Ics, render(&data).as_bytes()).unwrap();
+        assert_eq!(
+            data.todos
+                .iter()
+                .map(|todo| (todo.text.as_str(), todo.completed, todo.due))
+                .collect::<Vec<_>>(),
+            document
+                .data
+                .todos
+                .iter()
+                .map(|todo| (todo.text.as_str(), todo.completed, todo.due))
+                .collect::<Vec<_>>()
+        );
+        let names: Vec<&str> = document
+            .data
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect();
+        assert_eq!(vec!["work, urgent", "home"], names);
+        assert_eq!(2, document.data.links.len());
+    }
+
+    // 他のアプリから書き出したファイルも読める
+    #[test]
+    fn should_parse_calendar_from_other_apps() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+VERSION:2.0\r\n\
+PRODID:-//Example//EN\r\n\
+BEGIN:VEVENT\r\n\
+UID:event-1\r\n\
+SUMMARY:not a todo\r\n\
+END:VEVENT\r\n\
+BEGIN:VTODO\r\n\
+UID:a\r\n\
+SUMMARY:submit\r\n  report\r\n\
+COMPLETED:20240301T100000Z\r\n\
+CATEGORIES:work\r\n\
+CATEGORIES:review,work\r\n\
+DUE;VALUE=DATE:20240305\r\n\
+END:VTODO\r\n\
+BEGIN:VTODO\r\n\
+UID:b\r\n\
+END:VTODO\r\n\
+END:VCALENDAR\r\n";
+        let document = parse(ics.as_bytes()).unwrap();
+        let todos: Vec<(&str, bool, Option<NaiveDate>)> = document
+            .data
+            .todos
+            .iter()
+            .map(|todo| (todo.text.as_str(), todo.completed, todo.due))
+            .collect();
+        assert_eq!(
+            vec![
+                ("submit report", true, NaiveDate::from_ymd_opt(2024, 3, 5)),
+                ("", false, None)
+            ],
+            todos
+        );
+        assert_eq!(2, document.data.labels.len());
+
+        // SUMMARYのないVTODOは取り込めない行として報告する
+        let errors = document.validate();
+        assert_eq!(1, errors.len());
+        assert_eq!("VTODO[1]", errors[0].row);
+    }
+
+    #[test]
+    fn should_read_due_date_from_date_time() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+BEGIN:VTODO\r\n\
+SUMMARY:timed\r\n\
+DUE:20240305T170000Z\r\n\
+END:VTODO\r\n\
+BEGIN:VTODO\r\n\
+SUMMARY:broken\r\n\
+DUE:next week\r\n\
+END:VTODO\r\n\
+END:VCALENDAR\r\n";
+        let document = parse(ics.as_bytes()).unwrap();
+        assert_eq!(NaiveDate::from_ymd_opt(2024, 3, 5), document.data.todos[0].due);
+
+        // 読めない期限日は取り込めない行として報告する
+        let errors = document.validate();
+        assert_eq!(1, errors.len());
+        assert_eq!("VTODO[1]", errors[0].row);
+    }
+}
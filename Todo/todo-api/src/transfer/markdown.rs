@@ -0,0 +1,328 @@
+use chrono::Utc;
+use std::collections::HashMap;
+
+use super::{Document, ExportData, Link, RowError, FORMAT_VERSION};
+use crate::repositories::{label::Label, todo::Todo};
+
+// GitHub形式のチェックリスト。ラベルごとに見出しを付けて並べる
+// 複数のラベルが付いたTodoはそれぞれの見出しの下に書き、ラベルのないTodoは先頭に置く
+// チェックリストには期限日を書く決まりがないので、期限日は書き出さない
+pub(super) fn render(data: &ExportData) -> String {
+    let mut lines = vec!["# Todos".to_string()];
+    let unlabeled: Vec<&Todo> = data
+        .todos
+        .iter()
+        .filter(|todo| !data.links.iter().any(|link| link.todo_id == todo.id))
+        .collect();
+    push_items(&mut lines, &unlabeled);
+
+    for label in &data.labels {
+        let todos: Vec<&Todo> = data
+            .todos
+            .iter()
+            .filter(|todo| {
+                data.links
+                    .iter()
+                    .any(|link| link.todo_id == todo.id && link.label_id == label.id)
+            })
+            .collect();
+        if todos.is_empty() {
+            continue;
+        }
+        lines.push(String::new());
+        lines.push(format!("## {}", label.name));
+        push_items(&mut lines, &todos);
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+fn push_items(lines: &mut Vec<String>, todos: &[&Todo]) {
+    if todos.is_empty() {
+        return;
+    }
+    lines.push(String::new());
+    for todo in todos {
+        let mark = if todo.completed { 'x' } else { ' ' };
+        let text = todo.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        lines.push(format!("- [{}] {}", mark, text));
+    }
+}
+
+// 貼り付けた議事録などからチェックリストの項目だけを取り出す
+// 2段目以降の見出しは、その下の項目のラベルにする。1段目の見出しはラベルを外す
+// 別の見出しの下に同じ項目があれば、1つのTodoにラベルをまとめる
+pub(super) fn parse(bytes: &[u8]) -> Result<Document, Vec<RowError>> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|e| vec![RowError::new("line 1", format!("invalid utf-8: {}", e))])?;
+    let mut document = Document::from(ExportData {
+        version: FORMAT_VERSION,
+        exported_at: Utc::now(),
+        labels: vec![],
+        todos: vec![],
+        links: vec![],
+    });
+    let mut label_ids: HashMap<String, i32> = HashMap::new();
+    let mut seen: HashMap<(String, bool), Vec<i32>> = HashMap::new();
+    let mut label_id: Option<i32> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let row = format!("line {}", index + 1);
+        if let Some((level, title)) = heading(line) {
+            label_id = (level > 1 && !title.is_empty()).then(|| {
+                let next_id = label_ids.len() as i32 + 1;
+                *label_ids.entry(title.to_string()).or_insert_with(|| {
+                    document.data.labels.push(Label {
+                        id: next_id,
+                        name: title.to_string(),
+                    });
+                    document.label_rows.push(row.clone());
+                    next_id
+                })
+            });
+            continue;
+        }
+        let (completed, text) = match checklist_item(line) {
+            Some(item) => item,
+            None => continue,
+        };
+
+        // まだこの見出しのラベルが付いていない同じ項目があれば、それに付ける
+        let todo_ids = seen.entry((text.to_string(), completed)).or_default();
+        let existing = label_id.and_then(|label_id| {
+            todo_ids.iter().copied().find(|todo_id| {
+                !document.data.links.contains(&Link {
+                    todo_id: *todo_id,
+                    label_id,
+                })
+            })
+        });
+        let todo_id = match existing {
+            Some(todo_id) => todo_id,
+            None => {
+                let todo_id = document.data.todos.len() as i32 + 1;
+                document.data.todos.push(Todo {
+                    id: todo_id,
+                    text: text.to_string(),
+                    completed,
+                    due: None,
+                });
+                document.todo_rows.push(row.clone());
+                todo_ids.push(todo_id);
+                todo_id
+            }
+        };
+        if let Some(label_id) = label_id {
+            document.data.links.push(Link { todo_id, label_id });
+            document.link_rows.push(row);
+        }
+    }
+    Ok(document)
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim_start();
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+// `- [ ]`、`* [x]`、`1. [X]` のような項目。入れ子の項目も1つのTodoとして扱う
+fn checklist_item(line: &str) -> Option<(bool, &str)> {
+    let line = line.trim_start();
+    let rest = if let Some(rest) = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("+ "))
+    {
+        rest
+    } else {
+        let digits = line.chars().take_while(char::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        line[digits..]
+            .strip_prefix(". ")
+            .or_else(|| line[digits..].strip_prefix(") "))?
+    };
+    let rest = rest.trim_start();
+    let completed = match rest.get(..3)? {
+        "[ ]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+    Some((completed, rest[3..].trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data() -> ExportData {
+        ExportData {
+            version: FORMAT_VERSION,
+            exported_at: Utc::now(),
+            labels: vec![
+                Label {
+                    id: 1,
+                    name: "work".to_string(),
+                },
+                Label {
+                    id: 2,
+                    name: "home".to_string(),
+                },
+            ],
+            todos: vec![
+                Todo {
+                    id: 1,
+                    text: "write docs".to_string(),
+                    completed: true,
+                    due: None,
+                },
+                Todo {
+                    id: 2,
+                    text: "buy milk".to_string(),
+                    completed: false,
+                    due: None,
+                },
+                Todo {
+                    id: 3,
+                    text: "call plumber".to_string(),
+                    completed: false,
+                    due: None,
+                },
+            ],
+            links: vec![
+                Link {
+                    todo_id: 1,
+                    label_id: 1,
+                },
+                Link {
+                    todo_id: 3,
+                    label_id: 1,
+                },
+                Link {
+                    todo_id: 3,
+                    label_id: 2,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn should_render_checklist_grouped_by_label() {
+        assert_eq!(
+            "\
+# Todos
+
+- [ ] buy milk
+
+## work
+
+- [x] write docs
+- [ ] call plumber
+
+## home
+
+- [ ] call plumber
+",
+            render(&data())
+        );
+    }
+
+    #[test]
+    fn should_round_trip_labels_across_headings() {
+        let document = parse(render(&data()).as_bytes()).unwrap();
+        let todos: Vec<(&str, bool)> = document
+            .data
+            .todos
+            .iter()
+            .map(|todo| (todo.text.as_str(), todo.completed))
+            .collect();
+        assert_eq!(
+            vec![
+                ("buy milk", false),
+                ("write docs", true),
+                ("call plumber", false)
+            ],
+            todos
+        );
+        // 2つの見出しの下にある項目は1つのTodoにまとめる
+        assert_eq!(
+            vec![
+                Link {
+                    todo_id: 2,
+                    label_id: 1
+                },
+                Link {
+                    todo_id: 3,
+                    label_id: 1
+                },
+                Link {
+                    todo_id: 3,
+                    label_id: 2
+                },
+            ],
+            document.data.links
+        );
+    }
+
+    #[test]
+    fn should_pick_checklist_items_from_notes() {
+        let notes = "\
+# Weekly sync 2024-03-01
+
+Attendees: alice, bob
+
+## Action items
+- [ ] send the minutes
+  - [x] book a room
+* [X] review budget
+1. [ ] update roadmap
+- plain bullet is not a todo
+- [ ]
+
+#hashtag is not a heading
+### Follow-ups ###
+- [ ] send the minutes
+- [ ] send the minutes
+";
+        let document = parse(notes.as_bytes()).unwrap();
+        let todos: Vec<(&str, bool)> = document
+            .data
+            .todos
+            .iter()
+            .map(|todo| (todo.text.as_str(), todo.completed))
+            .collect();
+        assert_eq!(
+            vec![
+                ("send the minutes", false),
+                ("book a room", true),
+                ("review budget", true),
+                ("update roadmap", false),
+                ("", false),
+                ("send the minutes", false),
+            ],
+            todos
+        );
+        let names: Vec<&str> = document
+            .data
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect();
+        assert_eq!(vec!["Action items", "Follow-ups"], names);
+
+        // 空の項目はその行を報告する
+        let errors = document.validate();
+        assert_eq!(
+            vec!["line 11"],
+            errors.iter().map(|e| e.row.as_str()).collect::<Vec<_>>()
+        );
+    }
+}
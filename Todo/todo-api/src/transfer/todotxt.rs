@@ -0,0 +1,400 @@
+use chrono::{NaiveDate, Utc};
+use std::{collections::HashMap, fmt};
+
+use super::{Document, ExportData, Link, RowError, FORMAT_VERSION};
+use crate::repositories::{label::Label, todo::Todo};
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// todo.txtの1行 (https://github.com/todotxt/todo.txt)
+// `description` は `+project` や `@context` を含んだままの本文
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    pub completed: bool,
+    pub priority: Option<char>,
+    pub completion_date: Option<NaiveDate>,
+    pub creation_date: Option<NaiveDate>,
+    pub description: String,
+}
+
+impl Task {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut rest = line.trim();
+        let completed = match rest.strip_prefix("x ") {
+            Some(stripped) => {
+                rest = stripped.trim_start();
+                true
+            }
+            None => false,
+        };
+
+        let priority = match rest.as_bytes() {
+            [b'(', priority @ b'A'..=b'Z', b')']
+            | [b'(', priority @ b'A'..=b'Z', b')', b' ', ..] => {
+                let priority = *priority as char;
+                rest = rest[3..].trim_start();
+                Some(priority)
+            }
+            _ => None,
+        };
+
+        // 完了済みなら完了日、作成日の順に書かれる
+        let mut dates = Vec::new();
+        while dates.len() < if completed { 2 } else { 1 } {
+            match take_date(rest) {
+                Some((date, stripped)) => {
+                    dates.push(date);
+                    rest = stripped;
+                }
+                None => break,
+            }
+        }
+        let (completion_date, creation_date) = match (completed, dates.as_slice()) {
+            (true, [completion, creation]) => (Some(*completion), Some(*creation)),
+            (true, [completion]) => (Some(*completion), None),
+            (false, [creation]) => (None, Some(*creation)),
+            _ => (None, None),
+        };
+
+        if rest.is_empty() {
+            return Err("description can not be empty".to_string());
+        }
+        Ok(Self {
+            completed,
+            priority,
+            completion_date,
+            creation_date,
+            description: rest.to_string(),
+        })
+    }
+
+    pub fn projects(&self) -> impl Iterator<Item = &str> {
+        self.tags('+')
+    }
+
+    pub fn contexts(&self) -> impl Iterator<Item = &str> {
+        self.tags('@')
+    }
+
+    // タグと期限日を除いた本文
+    pub fn text(&self) -> String {
+        self.description
+            .split_whitespace()
+            .filter(|word| tag(word, '+').is_none() && tag(word, '@').is_none())
+            .filter(|word| due(word).is_none())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // 広く使われている `due:YYYY-MM-DD` の拡張。日付として読めなければ本文の一部とみなす
+    pub fn due(&self) -> Option<NaiveDate> {
+        self.description.split_whitespace().find_map(due)
+    }
+
+    fn tags(&self, prefix: char) -> impl Iterator<Item = &str> {
+        self.description
+            .split_whitespace()
+            .filter_map(move |word| tag(word, prefix))
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.completed {
+            write!(f, "x ")?;
+        }
+        if let Some(priority) = self.priority {
+            write!(f, "({}) ", priority)?;
+        }
+        if let Some(date) = self.completion_date.filter(|_| self.completed) {
+            write!(f, "{} ", date.format(DATE_FORMAT))?;
+        }
+        if let Some(date) = self.creation_date {
+            write!(f, "{} ", date.format(DATE_FORMAT))?;
+        }
+        write!(f, "{}", self.description)
+    }
+}
+
+fn take_date(value: &str) -> Option<(NaiveDate, &str)> {
+    let (word, rest) = value.split_once(' ').unwrap_or((value, ""));
+    let date = NaiveDate::parse_from_str(word, DATE_FORMAT).ok()?;
+    Some((date, rest.trim_start()))
+}
+
+fn tag(word: &str, prefix: char) -> Option<&str> {
+    word.strip_prefix(prefix).filter(|name| !name.is_empty())
+}
+
+fn due(word: &str) -> Option<NaiveDate> {
+    let date = word.strip_prefix("due:")?;
+    NaiveDate::parse_from_str(date, DATE_FORMAT).ok()
+}
+
+// ラベルは `+project` として書き出す。`@` で始まるラベルは `@context` のまま書き出す
+// タグは空白を含められないので、ラベル名の空白は `_` に置き換える
+fn label_tag(name: &str) -> String {
+    let name = name.split_whitespace().collect::<Vec<_>>().join("_");
+    if name.starts_with('@') {
+        name
+    } else {
+        format!("+{}", name)
+    }
+}
+
+// 期限日は `due:` として書き出す。優先度と作成日・完了日は `Todo` に対応する項目がないので書き出さない
+// どのTodoにも付いていないラベルは書き出されない
+pub(super) fn render(data: &ExportData) -> String {
+    let names: HashMap<i32, &str> = data
+        .labels
+        .iter()
+        .map(|label| (label.id, label.name.as_str()))
+        .collect();
+    let mut lines = String::new();
+    for todo in &data.todos {
+        let tags = data
+            .links
+            .iter()
+            .filter(|link| link.todo_id == todo.id)
+            .filter_map(|link| names.get(&link.label_id))
+            .map(|name| label_tag(name));
+        let due = todo
+            .due
+            .map(|due| format!("due:{}", due.format(DATE_FORMAT)));
+        let description = std::iter::once(todo.text.clone())
+            .chain(tags)
+            .chain(due)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let task = Task {
+            completed: todo.completed,
+            priority: None,
+            completion_date: None,
+            creation_date: None,
+            description,
+        };
+        lines.push_str(&format!("{}\n", task));
+    }
+    lines
+}
+
+// `+project` はそのままの名前、`@context` は `@` を付けた名前のラベルにする
+// IDはファイル中にないので、行の順に振る
+pub(super) fn parse(bytes: &[u8]) -> Result<Document, Vec<RowError>> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|e| vec![RowError::new("line 1", format!("invalid utf-8: {}", e))])?;
+    let mut document = Document::from(ExportData {
+        version: FORMAT_VERSION,
+        exported_at: Utc::now(),
+        labels: vec![],
+        todos: vec![],
+        links: vec![],
+    });
+    let mut label_ids: HashMap<String, i32> = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = format!("line {}", index + 1);
+        let task = match Task::parse(line) {
+            Ok(task) => task,
+            Err(message) => {
+                errors.push(RowError::new(row, message));
+                continue;
+            }
+        };
+
+        let todo_id = document.data.todos.len() as i32 + 1;
+        let names = task
+            .projects()
+            .map(str::to_string)
+            .chain(task.contexts().map(|context| format!("@{}", context)));
+        for name in names {
+            let next_id = label_ids.len() as i32 + 1;
+            let label_id = *label_ids.entry(name.clone()).or_insert_with(|| {
+                document.data.labels.push(Label { id: next_id, name });
+                document.label_rows.push(row.clone());
+                next_id
+            });
+            document.data.links.push(Link { todo_id, label_id });
+            document.link_rows.push(row.clone());
+        }
+        document.data.todos.push(Todo {
+            id: todo_id,
+            text: task.text(),
+            completed: task.completed,
+            due: task.due(),
+        });
+        document.todo_rows.push(row);
+    }
+    document.errors = errors;
+    Ok(document)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn should_parse_all_fields() {
+        let task =
+            Task::parse("x (A) 2024-03-02 2024-03-01 call mom +family @phone due:2024-03-05")
+                .unwrap();
+        assert!(task.completed);
+        assert_eq!(Some('A'), task.priority);
+        assert_eq!(NaiveDate::from_ymd_opt(2024, 3, 2), task.completion_date);
+        assert_eq!(NaiveDate::from_ymd_opt(2024, 3, 1), task.creation_date);
+        assert_eq!("call mom", task.text());
+        assert_eq!(NaiveDate::from_ymd_opt(2024, 3, 5), task.due());
+        assert_eq!(vec!["family"], task.projects().collect::<Vec<_>>());
+        assert_eq!(vec!["phone"], task.contexts().collect::<Vec<_>>());
+        assert_eq!(
+            "x (A) 2024-03-02 2024-03-01 call mom +family @phone due:2024-03-05",
+            task.to_string()
+        );
+
+        // 未完了なら日付は作成日だけ。小文字の優先度や先頭以外の `x` は本文として扱う
+        let task = Task::parse("2024-03-01 (a) xylophone lesson").unwrap();
+        assert!(!task.completed);
+        assert_eq!(None, task.priority);
+        assert_eq!(None, task.completion_date);
+        assert_eq!(NaiveDate::from_ymd_opt(2024, 3, 1), task.creation_date);
+        assert_eq!("(a) xylophone lesson", task.description);
+
+        assert!(Task::parse("x 2024-03-02").is_err());
+    }
+
+    #[test]
+    fn should_report_empty_lines_by_number() {
+        let errors = parse(b"first +work\n\n(B) \nsecond @home\n")
+            .unwrap()
+            .validate();
+        assert_eq!(
+            vec!["line 3"],
+            errors.iter().map(|e| e.row.as_str()).collect::<Vec<_>>()
+        );
+
+        let document = parse(b"first +work\n\nsecond +work @home\n").unwrap();
+        let names: Vec<&str> = document
+            .data
+            .labels
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(vec!["work", "@home"], names);
+        assert_eq!(
+            vec!["line 1", "line 3", "line 3"],
+            document
+                .link_rows
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    // 書き出しと取り込みで比べる、IDに依存しない内容
+    fn contents(data: &ExportData) -> Vec<(String, bool, Option<NaiveDate>, BTreeSet<String>)> {
+        data.todos
+            .iter()
+            .map(|todo| {
+                let labels = data
+                    .links
+                    .iter()
+                    .filter(|link| link.todo_id == todo.id)
+                    .filter_map(|link| data.labels.iter().find(|l| l.id == link.label_id))
+                    .map(|label| label.name.clone())
+                    .collect();
+                (todo.text.clone(), todo.completed, todo.due, labels)
+            })
+            .collect()
+    }
+
+    // 本文はタグや日付、優先度と紛れない単語を1つ以上の空白でつなげたもの
+    fn text() -> impl Strategy<Value = String> {
+        proptest::collection::vec("[a-zA-Z][a-zA-Z0-9:']{1,8}", 1..6)
+            .prop_map(|words| words.join(" "))
+    }
+
+    fn label_name() -> impl Strategy<Value = String> {
+        "@?[a-z][a-z0-9_-]{0,8}"
+    }
+
+    prop_compose! {
+        fn export_data()(
+            names in proptest::collection::btree_set(label_name(), 0..5),
+            todos in proptest::collection::vec(
+                (
+                    text(),
+                    any::<bool>(),
+                    proptest::option::of(1u32..=28),
+                    proptest::collection::btree_set(0usize..5, 0..3),
+                ),
+                0..8,
+            ),
+        ) -> ExportData {
+            let labels: Vec<Label> = names
+                .into_iter()
+                .enumerate()
+                .map(|(i, name)| Label { id: i as i32 + 10, name })
+                .collect();
+            let mut links = Vec::new();
+            let todos = todos
+                .into_iter()
+                .enumerate()
+                .map(|(i, (text, completed, due_day, label_indexes))| {
+                    let id = i as i32 + 100;
+                    for label in label_indexes.into_iter().filter_map(|index| labels.get(index)) {
+                        links.push(Link { todo_id: id, label_id: label.id });
+                    }
+                    let due = due_day.and_then(|day| NaiveDate::from_ymd_opt(2024, 6, day));
+                    Todo { id, text, completed, due }
+                })
+                .collect();
+            ExportData {
+                version: FORMAT_VERSION,
+                exported_at: Utc::now(),
+                labels,
+                todos,
+                links,
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn should_round_trip_text_completion_due_and_labels(data in export_data()) {
+            let rendered = render(&data);
+            let document = parse(rendered.as_bytes()).expect("failed parse rendered todo.txt");
+            prop_assert_eq!(contents(&data), contents(&document.data));
+            prop_assert!(document.validate().is_empty());
+        }
+
+        #[test]
+        fn should_round_trip_any_task_line(
+            completed in any::<bool>(),
+            priority in proptest::option::of(proptest::char::range('A', 'Z')),
+            completion_day in proptest::option::of(1u32..=28),
+            creation_day in proptest::option::of(1u32..=28),
+            description in text(),
+        ) {
+            let task = Task {
+                completed,
+                priority,
+                // 完了日だけを書く場合は作成日を省く
+                completion_date: completion_day
+                    .filter(|_| completed)
+                    .and_then(|day| NaiveDate::from_ymd_opt(2024, 5, day)),
+                creation_date: creation_day.and_then(|day| NaiveDate::from_ymd_opt(2024, 4, day)),
+                description,
+            };
+            let task = Task {
+                creation_date: task.creation_date.filter(|_| !completed || task.completion_date.is_some()),
+                ..task
+            };
+            prop_assert_eq!(&task, &Task::parse(&task.to_string()).unwrap());
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    header::CONTENT_TYPE,
+    redirect::Policy,
+    Url,
+};
+use sha2::Sha256;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::WebhooksConfig,
+    events::{ChangeEvent, EventFilter},
+    repositories::webhook::{Attempt, ClaimedDelivery, DeliveryStatus, WebhookRepository},
+};
+
+// 受け手は `sha256=<hex>` をボディとシークレットから計算し直して検証する
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+// 送り直しても同じIDなので、受け手はこれで重複を除ける
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+pub const EVENT_HEADER: &str = "x-webhook-event";
+
+// 新しい配信がなくても、送り直す時刻を過ぎた配信を探す間隔
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// 一度に取り出す配信の数
+const CLAIM_BATCH: i64 = 32;
+// 送り直しの間隔の上限
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// `attempts` 回失敗した後に待つ時間。1回目は `base` で、失敗するたびに倍にする
+pub fn backoff(base: Duration, attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(31);
+    base.checked_mul(1 << exponent)
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+// 送れるのはhttpとhttpsのURLだけ
+pub fn is_allowed_scheme(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+}
+
+// ループバック、プライベート、リンクローカルなど、外から届かないはずのアドレスには送らない
+// 登録したURLを使って内部のサービスにリクエストを送らせないため
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+// IPv4のアドレスを中に含むIPv6のアドレスは、変換されて届く先のIPv4のアドレスで調べる
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+    match segments {
+        // IPv4射影の::ffff:a.b.c.d
+        [0, 0, 0, 0, 0, 0xffff, high, low]
+        // IPv4互換の::a.b.c.d
+        | [0, 0, 0, 0, 0, 0, high, low]
+        // NAT64の64:ff9b::/96
+        | [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        // 6to4の2002::/16は、続く32ビットがIPv4のアドレス
+        [0x2002, high, low, ..] => Some(v4(high, low)),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8、キャリアグレードNATの100.64.0.0/10
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // ユニークローカルのfc00::/7、リンクローカルのfe80::/10、廃止されたサイトローカルのfec0::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xffc0) == 0xfec0)
+}
+
+// 名前解決の結果から外に出せないアドレスを除く。接続するアドレスそのものを調べるので、
+// 確かめた後に名前解決の結果を変えて内部のアドレスにつながせることはできない
+struct PublicResolver {
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self
+            .allowed_hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(name.as_str()));
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("no public address for [{}]", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// リダイレクトで内部のアドレスに送らされないように、リダイレクトはたどらない
+// プロキシを経由すると名前解決をプロキシがするので、直接つなぐ
+fn client(config: &WebhooksConfig) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .redirect(Policy::none())
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver {
+            allowed_hosts: Arc::new(config.allowed_hosts.clone()),
+        }))
+        .build()
+}
+
+// 送る前に分かる送れない宛先はエラーにする。ホスト名のアドレスは接続するときに確かめる
+fn check_url(config: &WebhooksConfig, url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("invalid url: {}", e))?;
+    if !is_allowed_scheme(&url) {
+        return Err(format!("unsupported scheme [{}]", url.scheme()));
+    }
+    let host = url.host_str().ok_or("url has no host")?;
+    if config
+        .allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(());
+    }
+    // IPアドレスは名前解決しないので、ここで確かめる。IPv6は `[::1]` のように括弧で囲まれている
+    let ip = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => ip,
+        Err(_) => return Ok(()),
+    };
+    if !is_public(ip) {
+        return Err(format!("address [{}] is not allowed", ip));
+    }
+    Ok(())
+}
+
+// 変更イベントを購読しているWebhookの配信として保存し、保存した配信を送る
+// 配信は先に保存するので、送信中に再起動しても次の起動で送り直す
+// すべての `ChangeBus` が破棄されるまで続ける
+pub async fn dispatch<W: WebhookRepository>(
+    repository: W,
+    mut events: mpsc::UnboundedReceiver<ChangeEvent>,
+    config: WebhooksConfig,
+) {
+    let client = match client(&config) {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("fail build webhook client: {:?}", e);
+            return;
+        }
+    };
+    // 送信中の配信を他のワーカーが取り出さないように、タイムアウトより長く確保する
+    let lease = chrono::Duration::from_std(config.timeout() * 2).unwrap_or(chrono::Duration::MAX);
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => enqueue(&repository, event).await,
+                None => break,
+            },
+            _ = poll.tick() => {}
+        }
+        let claimed = match repository.claim(Utc::now(), lease, CLAIM_BATCH).await {
+            Ok(claimed) => claimed,
+            Err(e) => {
+                tracing::error!("fail claim webhook deliveries: {:?}", e);
+                continue;
+            }
+        };
+        for delivery in claimed {
+            let repository = repository.clone();
+            let client = client.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let id = delivery.delivery.id.clone();
+                let attempt = deliver(&client, &config, delivery).await;
+                if let Err(e) = repository.record(&id, attempt).await {
+                    tracing::error!("fail record webhook delivery: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn enqueue<W: WebhookRepository>(repository: &W, event: ChangeEvent) {
+    let webhooks = match repository.all(event.workspace_id).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            tracing::error!("fail find webhooks: {:?}", e);
+            return;
+        }
+    };
+    let payload = match serde_json::to_value(&event) {
+        Ok(payload) => payload,
+        Err(_) => return,
+    };
+    for webhook in webhooks {
+        if !EventFilter::parse(&webhook.events).matches(event.kind) {
+            continue;
+        }
+        if let Err(e) = repository
+            .enqueue(webhook.id, event.kind.as_str(), payload.clone())
+            .await
+        {
+            tracing::error!("fail enqueue webhook delivery: {:?}", e);
+        }
+    }
+}
+
+// 2xxが返れば成功。それ以外は送り直すか、回数の上限に達していれば諦める
+async fn deliver(
+    client: &reqwest::Client,
+    config: &WebhooksConfig,
+    claimed: ClaimedDelivery,
+) -> Attempt {
+    let ClaimedDelivery {
+        delivery,
+        url,
+        secret,
+    } = claimed;
+    // 送り直しても送れないので、すぐに諦める
+    if let Err(error) = check_url(config, &url) {
+        tracing::warn!(id = delivery.id.as_str(), "refuse webhook delivery: {}", error);
+        return Attempt {
+            status: DeliveryStatus::Dead,
+            next_attempt_at: None,
+            response_status: None,
+            error: Some(error),
+        };
+    }
+    let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+    let response = client
+        .post(&url)
+        .timeout(config.timeout())
+        .header(CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(&secret, &body))
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(EVENT_HEADER, &delivery.event)
+        .body(body)
+        .send()
+        .await;
+
+    let (response_status, error) = match response {
+        Ok(response) if response.status().is_success() => {
+            tracing::debug!(id = delivery.id.as_str(), "webhook delivered");
+            return Attempt {
+                status: DeliveryStatus::Succeeded,
+                next_attempt_at: None,
+                response_status: Some(response.status().as_u16() as i32),
+                error: None,
+            };
+        }
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            format!("unexpected status {}", response.status()),
+        ),
+        Err(e) => (None, e.to_string()),
+    };
+
+    let attempts = delivery.attempts as u32 + 1;
+    if attempts >= config.max_attempts {
+        tracing::warn!(id = delivery.id.as_str(), attempts, "give up webhook delivery");
+        return Attempt {
+            status: DeliveryStatus::Dead,
+            next_attempt_at: None,
+            response_status,
+            error: Some(error),
+        };
+    }
+    let next_attempt_at = chrono::Duration::from_std(backoff(config.retry_base(), attempts))
+        .ok()
+        .and_then(|backoff| Utc::now().checked_add_signed(backoff));
+    Attempt {
+        status: DeliveryStatus::Pending,
+        next_attempt_at,
+        response_status,
+        error: Some(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_sign_body_with_hmac_sha256() {
+        // RFC 4231 のテストケース2
+        assert_eq!(
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign("Jefe", b"what do ya want for nothing?")
+        );
+    }
+
+    #[test]
+    fn should_refuse_internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "2002:a00:1::",
+            "2002:a9fe:a9fe::1",
+            "fec0::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+
+        let mut config = WebhooksConfig::default();
+        assert!(check_url(&config, "https://example.com/hook").is_ok());
+        assert!(check_url(&config, "ftp://example.com/hook").is_err());
+        assert!(check_url(&config, "http://127.0.0.1:8080/hook").is_err());
+        assert!(check_url(&config, "http://[::1]/hook").is_err());
+        config.allowed_hosts = vec!["127.0.0.1".to_string()];
+        assert!(check_url(&config, "http://127.0.0.1:8080/hook").is_ok());
+    }
+
+    #[test]
+    fn should_double_backoff_up_to_limit() {
+        let base = Duration::from_secs(30);
+        assert_eq!(Duration::from_secs(30), backoff(base, 1));
+        assert_eq!(Duration::from_secs(60), backoff(base, 2));
+        assert_eq!(Duration::from_secs(240), backoff(base, 4));
+        assert_eq!(MAX_BACKOFF, backoff(base, 20));
+        assert_eq!(MAX_BACKOFF, backoff(base, u32::MAX));
+    }
+}
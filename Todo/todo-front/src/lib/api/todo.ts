@@ -1,9 +1,14 @@
 import type { NewTodoPayload, Todo } from "../../types/todo";
 
+const authHeader = {
+  Authorization: `Bearer ${import.meta.env.VITE_API_TOKEN}`,
+};
+
 export const addTodoItem = async (payload: NewTodoPayload) => {
   const res = await fetch("http://localhost:3000/todos", {
     method: "POST",
     headers: {
+      ...authHeader,
       "Content-Type": "application/json",
     },
     body: JSON.stringify(payload),
@@ -16,7 +21,9 @@ export const addTodoItem = async (payload: NewTodoPayload) => {
 };
 
 export const getTodoItems = async () => {
-  const res = await fetch("http://localhost:3000/todos");
+  const res = await fetch("http://localhost:3000/todos", {
+    headers: authHeader,
+  });
   if (!res.ok) {
     throw new Error("get todo request failed");
   }
@@ -29,6 +36,7 @@ export const updateTodoItem = async (todo: Todo) => {
   const res = await fetch(`http://localhost:3000/todos/${id}`, {
     method: "PATCH",
     headers: {
+      ...authHeader,
       "Content-Type": "application/json",
     },
     body: JSON.stringify(updateTodo),
@@ -43,6 +51,7 @@ export const updateTodoItem = async (todo: Todo) => {
 export const deleteTodoItem = async (id: number) => {
   const res = await fetch(`http://localhost:3000/todos/${id}`, {
     method: "DELETE",
+    headers: authHeader,
   });
   if (!res.ok) {
     throw new Error("delette todo request failed");
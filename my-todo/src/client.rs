@@ -0,0 +1,161 @@
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::profile::Target;
+
+// todo-apiの `X-Workspace-Id` ヘッダー
+const WORKSPACE_HEADER: &str = "x-workspace-id";
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("authentication failed, check the token")]
+    Unauthorized,
+    #[error("permission denied in this workspace")]
+    Forbidden,
+    #[error("not found")]
+    NotFound,
+    #[error("request failed with {0}: {1}")]
+    Status(StatusCode, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Todo {
+    pub id: i32,
+    pub text: String,
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Label {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    pub todo_id: i32,
+    pub label_id: i32,
+}
+
+// `/export` のJSON。Todoとラベルとその対応を1回のリクエストでそろえて受け取る
+#[derive(Debug, Clone, Deserialize)]
+pub struct Snapshot {
+    pub labels: Vec<Label>,
+    pub todos: Vec<Todo>,
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UpdateTodo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed: Option<bool>,
+}
+
+// todo-apiのREST APIをたたくクライアント
+pub struct Client {
+    http: reqwest::Client,
+    target: Target,
+}
+
+impl Client {
+    pub fn new(target: Target) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            target,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{}", self.target.url, path))
+            .bearer_auth(&self.target.token);
+        match self.target.workspace {
+            Some(workspace) => request.header(WORKSPACE_HEADER, workspace),
+            None => request,
+        }
+    }
+
+    // 失敗したレスポンスは、ボディのメッセージを付けてエラーにする
+    async fn send(&self, request: RequestBuilder) -> anyhow::Result<reqwest::Response> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let error = match status {
+            StatusCode::UNAUTHORIZED => ApiError::Unauthorized,
+            StatusCode::FORBIDDEN => ApiError::Forbidden,
+            StatusCode::NOT_FOUND => ApiError::NotFound,
+            _ => ApiError::Status(status, response.text().await.unwrap_or_default()),
+        };
+        Err(error.into())
+    }
+
+    async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    pub async fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.json(self.request(Method::GET, "/export")).await
+    }
+
+    pub async fn create_todo(&self, text: &str, label_ids: &[i32]) -> anyhow::Result<Todo> {
+        let body = json!({ "text": text, "labels": label_ids });
+        self.json(self.request(Method::POST, "/todos").json(&body))
+            .await
+    }
+
+    pub async fn update_todo(&self, id: i32, payload: &UpdateTodo) -> anyhow::Result<Todo> {
+        let path = format!("/todos/{}", id);
+        self.json(self.request(Method::PATCH, &path).json(payload))
+            .await
+    }
+
+    pub async fn delete_todo(&self, id: i32) -> anyhow::Result<()> {
+        let path = format!("/todos/{}", id);
+        self.send(self.request(Method::DELETE, &path)).await?;
+        Ok(())
+    }
+
+    pub async fn set_todo_labels(&self, id: i32, label_ids: &[i32]) -> anyhow::Result<Vec<Label>> {
+        let path = format!("/todos/{}/labels", id);
+        let body = json!({ "labels": label_ids });
+        self.json(self.request(Method::PUT, &path).json(&body))
+            .await
+    }
+
+    pub async fn todo_labels(&self, id: i32) -> anyhow::Result<Vec<Label>> {
+        let path = format!("/todos/{}/labels", id);
+        self.json(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn labels(&self) -> anyhow::Result<Vec<Label>> {
+        self.json(self.request(Method::GET, "/labels")).await
+    }
+
+    pub async fn create_label(&self, name: &str) -> anyhow::Result<Label> {
+        let body = json!({ "name": name });
+        self.json(self.request(Method::POST, "/labels").json(&body))
+            .await
+    }
+
+    pub async fn delete_label(&self, id: i32) -> anyhow::Result<()> {
+        let path = format!("/labels/{}", id);
+        self.send(self.request(Method::DELETE, &path)).await?;
+        Ok(())
+    }
+
+    // サーバーが書き出した内容をそのまま返す
+    pub async fn export(&self, format: &str) -> anyhow::Result<Vec<u8>> {
+        let request = self
+            .request(Method::GET, "/export")
+            .query(&[("format", format)]);
+        Ok(self.send(request).await?.bytes().await?.to_vec())
+    }
+}
@@ -1,113 +1,667 @@
-use axum::{
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
-};
-use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::env;
+mod client;
+mod output;
+mod profile;
 
-#[tokio::main]
-async fn main() {
-    //loggingの初期化
-    let log_level = env::var("RUST_LOG").unwrap_or("info".to_string());
-    env::set_var("RUST_LOG", log_level);
-    tracing_subscriber::fmt::init();
-    // ルートを定義する
-    let app = create_app();
-    // ルートをバインドしてサーバーを起動する
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    
-    tracing::debug!("listening on {}", addr);
-
-    // `app` は `MakeService` トレイトを実装しているので、
-    // `axum::Server` に渡すことができる
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{io::Write, path::PathBuf};
+
+use client::{Client, Label, UpdateTodo};
+use output::{label_line, todo_line, Item, Printer};
+use profile::{Profile, Profiles};
+
+// 接続先はプロファイル < 環境変数・コマンドライン引数 の順に上書きする
+#[derive(Debug, Parser)]
+#[command(name = "todo", about = "Command-line client for todo-api")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// プロファイルを保存するTOMLファイル
+    #[arg(long, global = true, env = "TODO_CONFIG")]
+    config: Option<PathBuf>,
+    /// 省略時は `profile use` で選んだプロファイル
+    #[arg(long, short, global = true, env = "TODO_PROFILE")]
+    profile: Option<String>,
+    /// todo-apiのURL
+    #[arg(long, global = true, env = "TODO_URL")]
+    url: Option<String>,
+    #[arg(long, global = true, env = "TODO_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// 他のユーザーのワークスペースを操作する場合に、そのユーザーのID
+    #[arg(long, global = true, env = "TODO_WORKSPACE")]
+    workspace: Option<i32>,
+    /// 結果をJSONで出力する
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Todoを追加する
+    Add {
+        text: String,
+        /// ラベルの名前か `#<ID>`。繰り返し指定できる
+        #[arg(long = "label", short)]
+        labels: Vec<String>,
+    },
+    /// Todoを一覧する
+    Ls {
+        /// 完了したものだけ
+        #[arg(long, conflicts_with = "pending")]
+        done: bool,
+        /// 完了していないものだけ
+        #[arg(long)]
+        pending: bool,
+        /// このラベルが付いたものだけ
+        #[arg(long, short)]
+        label: Option<String>,
+        /// 本文にこの文字列を含むものだけ
+        #[arg(long, short)]
+        search: Option<String>,
+    },
+    /// Todoを完了にする
+    Done {
+        #[arg(required = true)]
+        ids: Vec<i32>,
+    },
+    /// Todoの本文・完了状態・ラベルを変える
+    Edit {
+        id: i32,
+        /// 新しい本文
+        text: Option<String>,
+        #[arg(long, conflicts_with = "undone")]
+        done: bool,
+        /// 未完了に戻す
+        #[arg(long)]
+        undone: bool,
+        /// 付いているラベルをすべて置き換える。繰り返し指定できる
+        #[arg(long = "label", short)]
+        labels: Vec<String>,
+        /// ラベルをすべて外す
+        #[arg(long, conflicts_with = "labels")]
+        clear_labels: bool,
+    },
+    /// Todoを削除する
+    Rm {
+        #[arg(required = true)]
+        ids: Vec<i32>,
+    },
+    /// ラベルを操作する
+    Label {
+        #[command(subcommand)]
+        action: LabelAction,
+    },
+    /// TodoとラベルをJSON・CSV・todo.txt・iCalendar・Markdownで書き出す
+    Export {
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+        /// 省略時は標準出力
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// 接続先のプロファイルを操作する
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum LabelAction {
+    /// ラベルを作る
+    Add { name: String },
+    /// ラベルを一覧する
+    Ls,
+    /// ラベルを削除する。付いているTodoからも外れる
+    Rm {
+        /// ラベルの名前か `#<ID>`
+        #[arg(required = true)]
+        labels: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ProfileAction {
+    /// `--url`・`--token`・`--workspace` の値をプロファイルに保存する
+    Set { name: String },
+    /// プロファイルを一覧する。トークンは表示しない
+    Ls,
+    /// 以降のコマンドで使うプロファイルを選ぶ
+    Use { name: String },
 }
 
-// テスト対象を切り出す
-fn create_app() -> Router {
-    Router::new()
-        .route("/", get(root))
-        .route("/users", post(create_user))
+// todo-apiの `/export` が受け付ける形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Json,
+    Csv,
+    #[value(name = "todotxt")]
+    TodoTxt,
+    Ics,
+    Markdown,
 }
 
-// ルートハンドラーは `async fn` でなければならない
-async fn root() -> &'static str {
-    "Hello, world!"
+impl Format {
+    fn as_str(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::TodoTxt => "todotxt",
+            Format::Ics => "ics",
+            Format::Markdown => "markdown",
+        }
+    }
 }
 
-async fn create_user(
-    //ここでDeserializeを実装した構造体を受け取る
-    Json(payload): Json<CreateUser>,
-) -> impl IntoResponse {
-    let user = User {
-        id: 1337,
-        username: payload.username,
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    run(cli, std::io::stdout().lock()).await
+}
+
+async fn run(cli: Cli, out: impl Write) -> anyhow::Result<()> {
+    let path = match &cli.config {
+        Some(path) => path.clone(),
+        None => profile::default_path()?,
+    };
+    let mut profiles = Profiles::load(&path)?;
+    let mut printer = Printer::new(out, cli.json);
+    let overrides = Profile {
+        url: cli.url,
+        token: cli.token,
+        workspace: cli.workspace,
     };
 
-    (StatusCode::CREATED, Json(user))
+    let command = match cli.command {
+        Command::Profile { action } => {
+            return run_profile(action, &mut profiles, &path, overrides, &mut printer)
+        }
+        command => command,
+    };
+    let target = profiles.resolve(cli.profile.as_deref(), &overrides)?;
+    let client = Client::new(target);
+
+    match command {
+        Command::Add { text, labels } => {
+            let label_ids = label_ids(&client.labels().await?, &labels)?;
+            let todo = client.create_todo(&text, &label_ids).await?;
+            printer.print(&todo, |out| {
+                writeln!(out, "added {}", todo_line(&todo, &[]))
+            })
+        }
+        Command::Ls {
+            done,
+            pending,
+            label,
+            search,
+        } => {
+            let snapshot = client.snapshot().await?;
+            let label_id = match &label {
+                Some(label) => Some(label_ids(&snapshot.labels, std::slice::from_ref(label))?[0]),
+                None => None,
+            };
+            let items: Vec<Item> = snapshot
+                .todos
+                .iter()
+                .filter(|todo| !done || todo.completed)
+                .filter(|todo| !pending || !todo.completed)
+                .filter(|todo| search.as_ref().is_none_or(|text| todo.text.contains(text)))
+                .map(|todo| Item {
+                    todo,
+                    labels: snapshot
+                        .links
+                        .iter()
+                        .filter(|link| link.todo_id == todo.id)
+                        .filter_map(|link| snapshot.labels.iter().find(|l| l.id == link.label_id))
+                        .collect(),
+                })
+                .filter(|item| label_id.is_none_or(|id| item.labels.iter().any(|l| l.id == id)))
+                .collect();
+            printer.print(&items, |out| {
+                if items.is_empty() {
+                    return writeln!(out, "no todos");
+                }
+                for item in &items {
+                    writeln!(out, "{}", todo_line(item.todo, &item.labels))?;
+                }
+                Ok(())
+            })
+        }
+        Command::Done { ids } => {
+            let mut todos = Vec::new();
+            for id in ids {
+                let payload = UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                };
+                let todo = client
+                    .update_todo(id, &payload)
+                    .await
+                    .with_context(|| format!("fail complete todo #{}", id))?;
+                todos.push(todo);
+            }
+            printer.print(&todos, |out| {
+                for todo in &todos {
+                    writeln!(out, "completed {}", todo_line(todo, &[]))?;
+                }
+                Ok(())
+            })
+        }
+        Command::Edit {
+            id,
+            text,
+            done,
+            undone,
+            labels,
+            clear_labels,
+        } => {
+            let payload = UpdateTodo {
+                text,
+                completed: (done || undone).then_some(done),
+            };
+            let relabel = clear_labels || !labels.is_empty();
+            if payload.text.is_none() && payload.completed.is_none() && !relabel {
+                bail!("nothing to edit, give a new text or --done, --undone, --label");
+            }
+            // ラベルの指定が誤っていれば、本文なども変えずに終える
+            let label_ids = match relabel {
+                true => Some(label_ids(&client.labels().await?, &labels)?),
+                false => None,
+            };
+            let todo = if payload.text.is_some() || payload.completed.is_some() {
+                client.update_todo(id, &payload).await?
+            } else {
+                client
+                    .snapshot()
+                    .await?
+                    .todos
+                    .into_iter()
+                    .find(|todo| todo.id == id)
+                    .with_context(|| format!("todo #{} is not found", id))?
+            };
+            let labels = match label_ids {
+                Some(label_ids) => client.set_todo_labels(id, &label_ids).await?,
+                // ラベルを変えない場合も、付いているラベルを表示する
+                None => client.todo_labels(id).await?,
+            };
+            let labels: Vec<&Label> = labels.iter().collect();
+            let item = Item {
+                todo: &todo,
+                labels,
+            };
+            printer.print(&item, |out| {
+                writeln!(out, "edited {}", todo_line(item.todo, &item.labels))
+            })
+        }
+        Command::Rm { ids } => {
+            for id in &ids {
+                client
+                    .delete_todo(*id)
+                    .await
+                    .with_context(|| format!("fail remove todo #{}", id))?;
+            }
+            printer.print(&ids, |out| {
+                for id in &ids {
+                    writeln!(out, "removed #{}", id)?;
+                }
+                Ok(())
+            })
+        }
+        Command::Label { action } => match action {
+            LabelAction::Add { name } => {
+                let label = client.create_label(&name).await?;
+                printer.print(&label, |out| writeln!(out, "added {}", label_line(&label)))
+            }
+            LabelAction::Ls => {
+                let labels = client.labels().await?;
+                printer.print(&labels, |out| {
+                    if labels.is_empty() {
+                        return writeln!(out, "no labels");
+                    }
+                    for label in &labels {
+                        writeln!(out, "{}", label_line(label))?;
+                    }
+                    Ok(())
+                })
+            }
+            LabelAction::Rm { labels } => {
+                let ids = label_ids(&client.labels().await?, &labels)?;
+                for id in &ids {
+                    client.delete_label(*id).await?;
+                }
+                printer.print(&ids, |out| {
+                    for id in &ids {
+                        writeln!(out, "removed label #{}", id)?;
+                    }
+                    Ok(())
+                })
+            }
+        },
+        Command::Export { format, output } => {
+            let body = client.export(format.as_str()).await?;
+            match output {
+                Some(path) => std::fs::write(&path, body)
+                    .with_context(|| format!("fail write export [{}]", path.display())),
+                None => printer.raw(&body),
+            }
+        }
+        Command::Profile { .. } => unreachable!("profiles are handled before connecting"),
+    }
 }
 
-//current_user関数と関連する構造体
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)] //テストで必要なSerializeとDeserializeを実装する
-struct CreateUser {
-    username: String,
+fn run_profile(
+    action: ProfileAction,
+    profiles: &mut Profiles,
+    path: &std::path::Path,
+    values: Profile,
+    printer: &mut Printer<impl Write>,
+) -> anyhow::Result<()> {
+    match action {
+        ProfileAction::Set { name } => {
+            // 指定しなかった値は、保存済みの値を残す
+            let profile = profiles.profiles.entry(name.clone()).or_default();
+            profile.url = values.url.or(profile.url.take());
+            profile.token = values.token.or(profile.token.take());
+            profile.workspace = values.workspace.or(profile.workspace);
+            profiles.save(path)?;
+            printer.print(&name, |out| writeln!(out, "saved profile [{}]", name))
+        }
+        ProfileAction::Ls => {
+            let current = profiles.current_name(None);
+            let names: Vec<&String> = profiles.profiles.keys().collect();
+            printer.print(&names, |out| {
+                for (name, profile) in &profiles.profiles {
+                    let mark = if name == current { '*' } else { ' ' };
+                    let url = profile.url.as_deref().unwrap_or(profile::DEFAULT_URL);
+                    writeln!(out, "{} {} {}", mark, name, url)?;
+                }
+                Ok(())
+            })
+        }
+        ProfileAction::Use { name } => {
+            if !profiles.profiles.contains_key(&name) {
+                bail!("profile [{}] is not found", name);
+            }
+            profiles.current = Some(name.clone());
+            profiles.save(path)?;
+            printer.print(&name, |out| writeln!(out, "using profile [{}]", name))
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)] //テストで必要なSerializeとDeserializeを実装する
-struct User {
-    id: u64,
-    username: String,
+// ラベルは名前でも `#<ID>` でも指定できる。同じ名前のラベルがあれば名前を優先し、
+// なければ `#` を付けない数字もIDとして扱う。数字だけの名前のラベルとIDを取り違えない
+fn label_ids(labels: &[Label], names: &[String]) -> anyhow::Result<Vec<i32>> {
+    names
+        .iter()
+        .map(|name| {
+            let by_name = labels.iter().find(|label| label.name == *name);
+            let by_id = || {
+                let id: i32 = name.strip_prefix('#').unwrap_or(name).parse().ok()?;
+                labels.iter().find(|label| label.id == id)
+            };
+            by_name
+                .or_else(by_id)
+                .map(|label| label.id)
+                .with_context(|| format!("label [{}] is not found", name))
+        })
+        .collect()
 }
 
 //testモジュールはプロダクションコードからは削除される
 #[cfg(test)]
 mod test {
     use super::*;
-    use axum::{
-        body::Body,
-        http::{header, Method, Request},
-    };
-    use tower::ServiceExt;
+    use std::net::TcpListener;
+
+    // テストごとに別のプロファイルのファイルを使う
+    struct TempConfig(PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str) -> Self {
+            let file = format!("todo-cli-{}-{}.toml", std::process::id(), name);
+            let path = std::env::temp_dir().join(file);
+            std::fs::remove_file(&path).ok();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    // todo-apiをメモリのリポジトリでプロセス内に立ち上げ、ユーザーを登録してトークンを受け取る
+    struct Server {
+        url: String,
+        token: String,
+        config: TempConfig,
+    }
+
+    impl Server {
+        async fn start(name: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(todo_api::memory_app().into_make_service());
+            tokio::spawn(server);
 
-    // axum;;http;;Request::builder()でリクエストを作成する
-    // tower::ServiceExt;;oneshotでリクエストを送信する
-    // hyper::body::to_bytesでレスポンスのボディを取得する
+            let user: serde_json::Value = reqwest::Client::new()
+                .post(format!("{}/users", url))
+                .json(&serde_json::json!({ "name": "cli_user" }))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            let token = user["token"].as_str().unwrap().to_string();
+            Self {
+                url,
+                token,
+                config: TempConfig::new(name),
+            }
+        }
 
-    #[tokio::test]  //ルート関数のテスト
-    async fn should_return_hello_world() {
-        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
-        let res = create_app().oneshot(req).await.unwrap();
+        // 接続先を引数で渡してコマンドを実行し、標準出力に書いた内容を返す
+        async fn todo(&self, args: &[&str]) -> anyhow::Result<String> {
+            let connection = ["--url", &self.url, "--token", &self.token];
+            self.todo_with_profile(&[args, &connection].concat()).await
+        }
 
-        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();  //Bytes型からString型に変換する
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        assert_eq!(body, "Hello, world!");
+        // 接続先はプロファイルから読む
+        async fn todo_with_profile(&self, args: &[&str]) -> anyhow::Result<String> {
+            let config = ["todo", "--config", self.config.0.to_str().unwrap()];
+            let cli = Cli::try_parse_from([&config, args].concat())?;
+            let mut out = Vec::new();
+            run(cli, &mut out).await?;
+            Ok(String::from_utf8(out)?)
+        }
     }
 
-    #[tokio::test]  //JSON bodyのテスト
-    async fn should_return_user_data() {
-        let req = Request::builder()
-            .uri("/users")
-            .method(Method::POST) //POST,contents-typeは、axum::httpのconstで定義されている
-            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref()) //application/jsonは、mimeのパッケージで定義されている
-            .body(Body::from(r#"{"username": "大久保 璃子"}"#))
+    #[tokio::test]
+    async fn should_manage_todos_and_labels() {
+        let server = Server::start("manage").await;
+
+        assert_eq!("no todos\n", server.todo(&["ls"]).await.unwrap());
+        let out = server.todo(&["label", "add", "work"]).await.unwrap();
+        assert_eq!("added    1 work\n", out);
+        let out = server
+            .todo(&["add", "buy milk", "--label", "work"])
+            .await
+            .unwrap();
+        assert_eq!("added    1 [ ] buy milk\n", out);
+        server
+            .todo(&["add", "write report", "-l", "1"])
+            .await
             .unwrap();
-        let res = create_app().oneshot(req).await.unwrap();
+        server.todo(&["add", "call home"]).await.unwrap();
+        let err = server
+            .todo(&["add", "unknown", "-l", "home"])
+            .await
+            .unwrap_err();
+        assert_eq!("label [home] is not found", err.to_string());
 
-        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap(); 
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let user: User = serde_json::from_str(&body).expect("cannot conver User instance.");    //JSONをUser型に変換する
+        let out = server.todo(&["ls"]).await.unwrap();
+        let lines = [
+            "   1 [ ] buy milk #work",
+            "   2 [ ] write report #work",
+            "   3 [ ] call home",
+        ];
+        assert_eq!(lines.join("\n") + "\n", out);
+
+        let out = server.todo(&["done", "1", "3"]).await.unwrap();
         assert_eq!(
-            user,
-            User {
-                id: 1337,
-                username: "大久保 璃子".to_string(),
-            }
+            "completed    1 [x] buy milk\ncompleted    3 [x] call home\n",
+            out
         );
+        let out = server
+            .todo(&["ls", "--done", "--label", "work"])
+            .await
+            .unwrap();
+        assert_eq!("   1 [x] buy milk #work\n", out);
+        let out = server.todo(&["ls", "--pending"]).await.unwrap();
+        assert_eq!("   2 [ ] write report #work\n", out);
+        let out = server.todo(&["ls", "--search", "home"]).await.unwrap();
+        assert_eq!("   3 [x] call home\n", out);
+
+        let edit = ["edit", "3", "call mom", "--undone", "-l", "work"];
+        let out = server.todo(&edit).await.unwrap();
+        assert_eq!("edited    3 [ ] call mom #work\n", out);
+        // ラベルを変えなくても、付いているラベルを表示する
+        let out = server.todo(&["edit", "3", "--done"]).await.unwrap();
+        assert_eq!("edited    3 [x] call mom #work\n", out);
+        let out = server.todo(&["edit", "3", "--undone"]).await.unwrap();
+        assert_eq!("edited    3 [ ] call mom #work\n", out);
+        let out = server.todo(&["edit", "2", "--clear-labels"]).await.unwrap();
+        assert_eq!("edited    2 [ ] write report\n", out);
+        assert!(server.todo(&["edit", "2"]).await.is_err());
+
+        assert_eq!("removed #1\n", server.todo(&["rm", "1"]).await.unwrap());
+        let err = server.todo(&["done", "1"]).await.unwrap_err();
+        assert_eq!("not found", err.root_cause().to_string());
+        let out = server.todo(&["label", "rm", "work"]).await.unwrap();
+        assert_eq!("removed label #1\n", out);
+        assert_eq!("no labels\n", server.todo(&["label", "ls"]).await.unwrap());
+
+        let out = server
+            .todo(&["export", "--format", "todotxt"])
+            .await
+            .unwrap();
+        assert_eq!("write report\ncall mom\n", out);
+    }
+
+    #[test]
+    fn should_prefer_label_names_over_ids() {
+        let labels = vec![
+            Label {
+                id: 1,
+                name: "2".to_string(),
+            },
+            Label {
+                id: 2,
+                name: "work".to_string(),
+            },
+        ];
+        let ids = |names: &[&str]| {
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            label_ids(&labels, &names)
+        };
+        assert_eq!(vec![1, 2, 2], ids(&["2", "#2", "work"]).unwrap());
+        assert_eq!(vec![1], ids(&["1"]).unwrap());
+        assert!(ids(&["#3"]).is_err());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn should_output_json() {
+        let server = Server::start("json").await;
+
+        let out = server
+            .todo(&["label", "add", "work", "--json"])
+            .await
+            .unwrap();
+        let label: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(serde_json::json!({ "id": 1, "name": "work" }), label);
+        let out = server
+            .todo(&["add", "buy milk", "-l", "work", "--json"])
+            .await
+            .unwrap();
+        let todo: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            serde_json::json!({ "id": 1, "text": "buy milk", "completed": false }),
+            todo
+        );
+
+        let out = server.todo(&["ls", "--json"]).await.unwrap();
+        let items: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            serde_json::json!([{
+                "id": 1,
+                "text": "buy milk",
+                "completed": false,
+                "labels": [{ "id": 1, "name": "work" }],
+            }]),
+            items
+        );
+        let out = server.todo(&["rm", "1", "--json"]).await.unwrap();
+        assert_eq!(
+            serde_json::json!([1]),
+            serde_json::from_str::<serde_json::Value>(&out).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_connect_with_profiles() {
+        let server = Server::start("profiles").await;
+
+        let err = server.todo_with_profile(&["ls"]).await.unwrap_err();
+        assert!(err.to_string().contains("no token"), "{}", err);
+        server
+            .todo_with_profile(&[
+                "profile",
+                "set",
+                "local",
+                "--url",
+                &server.url,
+                "--token",
+                "wrong",
+            ])
+            .await
+            .unwrap();
+        let err = server
+            .todo_with_profile(&["ls", "--profile", "local"])
+            .await
+            .unwrap_err();
+        assert_eq!("authentication failed, check the token", err.to_string());
+
+        // 指定しなかったURLは保存済みのものを残す
+        server
+            .todo_with_profile(&["profile", "set", "local", "--token", &server.token])
+            .await
+            .unwrap();
+        let out = server
+            .todo_with_profile(&["profile", "use", "local"])
+            .await
+            .unwrap();
+        assert_eq!("using profile [local]\n", out);
+        assert_eq!(
+            "no todos\n",
+            server.todo_with_profile(&["ls"]).await.unwrap()
+        );
+        let out = server.todo_with_profile(&["profile", "ls"]).await.unwrap();
+        assert_eq!(format!("* local {}\n", server.url), out);
+        let saved = std::fs::read_to_string(&server.config.0).unwrap();
+        assert!(!saved.contains("wrong"));
+
+        assert!(server
+            .todo_with_profile(&["profile", "use", "other"])
+            .await
+            .is_err());
+        assert!(server
+            .todo_with_profile(&["ls", "-p", "other"])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::client::{Label, Todo};
+
+// `--json` なら値をそのままJSONで、そうでなければ人が読む形式で書き出す
+pub struct Printer<W> {
+    out: W,
+    json: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, json: bool) -> Self {
+        Self { out, json }
+    }
+
+    pub fn print<T, F>(&mut self, value: &T, human: F) -> anyhow::Result<()>
+    where
+        T: Serialize + ?Sized,
+        F: FnOnce(&mut W) -> io::Result<()>,
+    {
+        if self.json {
+            serde_json::to_writer_pretty(&mut self.out, value)?;
+            writeln!(self.out)?;
+        } else {
+            human(&mut self.out)?;
+        }
+        Ok(())
+    }
+
+    // エクスポートのように、形式をサーバーが決める出力はそのまま書き出す
+    pub fn raw(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.out.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+// `ls` のJSONでは、Todoに付いているラベルも一緒に出す
+#[derive(Debug, Serialize)]
+pub struct Item<'a> {
+    #[serde(flatten)]
+    pub todo: &'a Todo,
+    pub labels: Vec<&'a Label>,
+}
+
+//    3 [x] buy milk #shopping
+pub fn todo_line(todo: &Todo, labels: &[&Label]) -> String {
+    let mark = if todo.completed { 'x' } else { ' ' };
+    let mut line = format!("{:>4} [{}] {}", todo.id, mark, todo.text);
+    for label in labels {
+        line.push_str(" #");
+        line.push_str(&label.name);
+    }
+    line
+}
+
+pub fn label_line(label: &Label) -> String {
+    format!("{:>4} {}", label.id, label.name)
+}
@@ -0,0 +1,204 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// プロファイルを指定せず、既定のプロファイルも選んでいない場合に使う
+pub const DEFAULT_PROFILE: &str = "default";
+// URLをどこにも指定していない場合は、ローカルで動かしているサーバーにつなぐ
+pub const DEFAULT_URL: &str = "http://localhost:3000";
+
+// 接続先ごとのURLとトークンをTOMLファイルにまとめて保存する
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profiles {
+    // `profile use` で選んだプロファイル
+    pub current: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub token: Option<String>,
+    // 他のユーザーのワークスペースを操作する場合に、そのユーザーのID
+    pub workspace: Option<i32>,
+}
+
+// コマンドの実行に使う接続先。プロファイルの値をコマンドライン引数と環境変数で上書きしたもの
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub url: String,
+    pub token: String,
+    pub workspace: Option<i32>,
+}
+
+// `$XDG_CONFIG_HOME/todo/config.toml`、なければ `~/.config/todo/config.toml`
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => bail!("cannot find the config directory, use --config"),
+        },
+    };
+    Ok(base.join("todo").join("config.toml"))
+}
+
+impl Profiles {
+    // ファイルがなければプロファイルがないものとして扱う
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("fail read profiles [{}]", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid profiles [{}]", path.display()))
+    }
+
+    // トークンを含むので、Unixでは本人だけが読めるファイルとして作る
+    // 一時ファイルに書いてから置き換えるので、他のユーザーに読める状態や書きかけの状態にならない
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("fail create directory [{}]", dir.display()))?;
+        }
+        let text = toml::to_string(self)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        // 前回残った一時ファイルは権限が違うかもしれないので、作り直す
+        std::fs::remove_file(&temp).ok();
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let written = options.open(&temp).and_then(|mut file| {
+            file.write_all(text.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| std::fs::rename(&temp, path)) {
+            std::fs::remove_file(&temp).ok();
+            return Err(e).with_context(|| format!("fail write profiles [{}]", path.display()));
+        }
+        Ok(())
+    }
+
+    // `--profile` で指定したもの、`profile use` で選んだもの、`default` の順に使う
+    pub fn current_name<'a>(&'a self, name: Option<&'a str>) -> &'a str {
+        name.or(self.current.as_deref()).unwrap_or(DEFAULT_PROFILE)
+    }
+
+    // 明示的に指定したプロファイルがなければエラーにする
+    // 何も指定していない場合は、プロファイルがなくてもコマンドライン引数と環境変数だけで動く
+    pub fn resolve(&self, name: Option<&str>, overrides: &Profile) -> anyhow::Result<Target> {
+        let current = self.current_name(name);
+        let profile = match self.profiles.get(current) {
+            Some(profile) => profile.clone(),
+            None if name.is_some() || self.current.is_some() => {
+                bail!("profile [{}] is not found", current)
+            }
+            None => Profile::default(),
+        };
+        let token = overrides.token.clone().or(profile.token).with_context(|| {
+            format!(
+                "no token for profile [{}], use `todo profile set` or --token",
+                current
+            )
+        })?;
+        let url = overrides
+            .url
+            .clone()
+            .or(profile.url)
+            .unwrap_or_else(|| DEFAULT_URL.to_string());
+        Ok(Target {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            workspace: overrides.workspace.or(profile.workspace),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profiles() -> Profiles {
+        let mut profiles = Profiles::default();
+        profiles.profiles.insert(
+            "work".to_string(),
+            Profile {
+                url: Some("https://todo.example.com/".to_string()),
+                token: Some("work-token".to_string()),
+                workspace: Some(2),
+            },
+        );
+        profiles
+    }
+
+    #[test]
+    fn should_override_profile_with_arguments() {
+        let profiles = profiles();
+        let target = profiles.resolve(Some("work"), &Profile::default()).unwrap();
+        assert_eq!(
+            Target {
+                url: "https://todo.example.com".to_string(),
+                token: "work-token".to_string(),
+                workspace: Some(2),
+            },
+            target
+        );
+
+        let overrides = Profile {
+            url: None,
+            token: Some("other".to_string()),
+            workspace: None,
+        };
+        let target = profiles.resolve(Some("work"), &overrides).unwrap();
+        assert_eq!("other", target.token);
+        assert_eq!(Some(2), target.workspace);
+    }
+
+    #[test]
+    fn should_save_profiles_readable_only_by_owner() {
+        let dir = std::env::temp_dir().join(format!("todo-profile-{}", std::process::id()));
+        let path = dir.join("config.toml");
+        // 他のユーザーも読めるファイルがあっても置き換える
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "").unwrap();
+
+        let profiles = profiles();
+        profiles.save(&path).unwrap();
+        assert_eq!(profiles, Profiles::load(&path).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_fall_back_to_arguments_without_profile() {
+        let profiles = profiles();
+        let overrides = Profile {
+            url: None,
+            token: Some("token".to_string()),
+            workspace: None,
+        };
+        let target = profiles.resolve(None, &overrides).unwrap();
+        assert_eq!(DEFAULT_URL, target.url);
+        assert!(profiles.resolve(None, &Profile::default()).is_err());
+        // 指定したプロファイルがなければ、引数がそろっていてもエラーにする
+        assert!(profiles.resolve(Some("home"), &overrides).is_err());
+    }
+}